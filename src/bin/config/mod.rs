@@ -1,28 +1,73 @@
-use std::ffi::OsStr;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
+use clap::builder::Resettable;
 use clap::ValueEnum;
-use clap::{arg, Parser};
-use ipnet::Ipv6Net;
+use clap::{error::ErrorKind, ArgAction, Args, CommandFactory, FromArgMatches, Parser};
+use hyper::Uri;
+use ipnet::{Ipv4Net, Ipv6Net};
 use log::LevelFilter;
+use metallb_v6_prefix_helper::{
+    metallb::{PatchStrategy, ReplaceStrategy, UpdateMarker, DEFAULT_FIELD_MANAGER},
+    prefix::{AddressHint, AddressScope, LeaseFormat, FRITZBOX_DEFAULT_PORT},
+    reconcile::{
+        host_mask, ChangeWindow, HostCombine, PrefixTransform, ReconcileOptions, V4Options,
+    },
+};
+use serde_json::Value;
 use strum::IntoStaticStr;
 
 // Currently available Ipv6 Prefix sources
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ValueEnum, IntoStaticStr)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ValueEnum, IntoStaticStr, Default)]
+#[strum(serialize_all = "lowercase")]
 pub enum Source {
+    #[default]
     Iface,
+    /// Like `iface`, but only queries the configured interfaces over rtnetlink.
+    /// Requires the `netlink` feature on Linux, falls back to `iface` otherwise
+    Netlink,
+    Kea,
+    Ppp,
+    DhcpPd,
+    RouterAdvert,
+    Static,
+    Http,
+    Dns,
+    Command,
+    File,
+    #[value(name = "configmap")]
+    ConfigMap,
+    Env,
+    Fritzbox,
+    /// Discovers the UPnP Internet Gateway Device on the LAN and asks it for the prefix
+    Upnp,
+    /// Asks an OpenWrt router for the prefix delegated to one of its interfaces over ubus
+    Openwrt,
 }
-impl Default for Source {
-    fn default() -> Self {
-        Source::Iface
-    }
+
+/// What triggers a run besides the interval
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ValueEnum, Default)]
+pub enum RunMode {
+    /// Only run at the interval
+    #[default]
+    Poll,
+    /// Also run as soon as the pool or the interface addresses change.
+    /// The interval then only serves as a periodic resync
+    Watch,
 }
 
 /// Used to set the applications loglevel
 // This is essentially a re-creation of log:Level. However, that enum doesn't derive ValueEnum, so we have to do it manually here
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ValueEnum)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ValueEnum, Default)]
 pub enum Loglevel {
     Error,
     Warn,
+    #[default]
     Info,
     Debug,
     Trace,
@@ -38,11 +83,6 @@ impl From<Loglevel> for LevelFilter {
         }
     }
 }
-impl Default for Loglevel {
-    fn default() -> Self {
-        Loglevel::Info
-    }
-}
 
 macro_rules! env_prefix {
     () => {
@@ -50,25 +90,181 @@ macro_rules! env_prefix {
     };
 }
 
+/// Host range for a single pool, given as `<pool>=<host range>`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolRange {
+    pub pool: String,
+    pub host_range: Ipv6Net,
+}
+impl FromStr for PoolRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pool, host_range) = s
+            .split_once('=')
+            .ok_or_else(|| format!("`{}` is not of the form <pool>=<host range>", s))?;
+        Ok(PoolRange {
+            pool: pool.trim().to_string(),
+            host_range: Ipv6Net::from_str(host_range.trim())
+                .map_err(|e| format!("Invalid host range `{}`: {}", host_range, e))?,
+        })
+    }
+}
+
+/// A value that must not show up in logs, such as a password. `Debug` only prints a placeholder
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Secret(pub String);
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+/// Environment variable that aborts pending changes during the canary window when set.
+/// It is read from the helper's own environment, so it can only be set at startup, e.g. to hold back all changes
+pub const ABORT_ENV: &str = concat!(env_prefix!(), "ABORT");
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Parser, Default)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
-    /// Name of the IpAddressPool resource to update in k8s
-    pub metallb_address_pool: String,
+    /// Name of the IpAddressPool resource to update in k8s.
+    /// Multiple pools can be given as a comma-separated list, they are reconciled independently.
+    /// Left out with `--pool-selector`, the only argument is the host range then
+    #[arg(
+        value_delimiter = ',',
+        num_args = 1,
+        required_unless_present = "pool_selector",
+        action = ArgAction::Set
+    )]
+    pub metallb_address_pool: Vec<String>,
     /// Host range to assign to MetalLB in CIDR notation.
-    /// The network part of the address is ignored.
-    /// Example ::beef:0:0:0/80 + <dynamic prefix+subnet>, => 2003:abc:def:aaaa:beef:0:0:0/80
-    pub metallb_host_range: Ipv6Net,
+    /// The network part of the address, which is taken from the source, has to be zero.
+    /// Example ::beef:0:0:0/80 + <dynamic prefix+subnet>, => 2003:abc:def:aaaa:beef:0:0:0/80.
+    /// Multiple host ranges can be given as a comma-separated list, each one is matched against the pool by its host bits and updated independently.
+    /// Used for all pools without a `--pool-range`, can be omitted if every pool has one
+    #[arg(value_delimiter = ',')]
+    pub metallb_host_range: Vec<Ipv6Net>,
+
+    /// Host range for a single pool, e.g. `pool-a=::a:0:0:0/80`, overriding the `metallb_host_range` argument.
+    /// Can be given multiple times or as a comma-separated list, repeating a pool gives it multiple host ranges
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = concat!(env_prefix!(), "POOL_RANGE")
+    )]
+    pub pool_range: Vec<PoolRange>,
+
+    /// Reconcile all pools matching this label selector instead of the named pools, e.g. `v6helper.io/managed=true`.
+    /// The pools are looked up again in every `--interval`, so that new pools are picked up without a restart
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "POOL_SELECTOR"),
+        conflicts_with_all = ["pool_uid", "create_pool"]
+    )]
+    pub pool_selector: Option<String>,
+
+    /// TOML (`.toml`) or YAML file with settings keyed by their long option names, e.g. `network-length = 56`.
+    /// The arguments are keyed `metallb-address-pool` and `metallb-host-range`, lists are given as arrays.
+    /// Command line options take precedence over environment variables, which take precedence over the file
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "CONFIG")
+    )]
+    pub config: Option<PathBuf>,
+
+    /// Watch the `--config` file and restart the reconcilers with the changed settings when it is edited.
+    /// Settings that the reconcilers don't use, e.g. the source, are only logged and need a restart of the helper
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        requires = "config",
+        env = concat!(env_prefix!(), "RECONCILE_ON_CONFIG_CHANGE"),
+    )]
+    pub reconcile_on_config_change: bool,
+
+    /// How the network and the host range are combined, see [`config::HostCombine`]
+    #[arg(
+        value_enum,
+        long,
+        env = concat!(env_prefix!(), "HOST_COMBINE"),
+        default_value_t = HostCombine::default()
+    )]
+    pub host_combine: HostCombine,
 
     /// Length of the dynamically changing v6 network (prefix + subnet).
-    /// Should be 64 unless you have a weird Ipv6 setup with custom addressing.
+    /// Should be 64 unless you have a weird Ipv6 setup with custom addressing, other lengths need `--host-combine replace`.
     #[arg(
         long,
-        env = concat!(env_prefix!(), "SOURCE"),
+        env = concat!(env_prefix!(), "NETWORK_LENGTH"),
+        value_parser = clap::value_parser!(u8).range(1..=128),
         default_value_t = 64
     )]
     pub network_length: u8,
 
+    /// Also keep an IPv4 range in the pool up to date, derived from the public IPv4 address of the source.
+    /// Supported by the `iface` and `dns` sources
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        requires = "v4_host_range",
+        env = concat!(env_prefix!(), "IPV4"),
+    )]
+    pub ipv4: bool,
+
+    /// IPv4 host range to assign to MetalLB with `--ipv4`, in CIDR notation.
+    /// Everything below `--v4-network-length` is taken from it, e.g. 0.0.0.8/29 + 198.51.100.0/24 => 198.51.100.8/29
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "V4_HOST_RANGE")
+    )]
+    pub v4_host_range: Option<Ipv4Net>,
+
+    /// Length of the dynamically changing v4 network around the address of the source.
+    /// The default of 32 uses just the address itself
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "V4_NETWORK_LENGTH"),
+        value_parser = clap::value_parser!(u8).range(1..=32),
+        default_value_t = 32
+    )]
+    pub v4_network_length: u8,
+
+    /// Shortest prefix length that is expected from the source.
+    /// Shorter (larger) networks are still used, but a warning is logged as they likely indicate a detection error
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "EXPECTED_PREFIX_MIN"),
+        default_value_t = 48
+    )]
+    pub expected_prefix_min: u8,
+
+    /// Longest prefix length that is expected from the source.
+    /// Longer (smaller) networks are still used, but a warning is logged as they likely indicate a detection error
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "EXPECTED_PREFIX_MAX"),
+        default_value_t = 64
+    )]
+    pub expected_prefix_max: u8,
+
+    /// Rules applied to the network from the source before it is combined with the host range,
+    /// e.g. `extend=60,subnet=3` to use the fourth /60 out of a delegated /56.
+    /// `extend=<length>` makes the network longer, `subnet=<index>` selects a subnet of the extended length
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "PREFIX_TRANSFORM")
+    )]
+    pub prefix_transform: Option<PrefixTransform>,
+
     /// Source from which to retrieve the desired IPv6 prefix from. Can be any of [`config:Source`]
     #[arg(
         value_enum,
@@ -77,15 +273,375 @@ pub struct Config {
         env = concat!(env_prefix!(), "SOURCE"),
         default_value_t = Source::default(),
         requires_if(OsStr::new(Source::Iface.into()), "iface"),
+        requires_if(OsStr::new(Source::Netlink.into()), "iface"),
+        requires_if(OsStr::new(Source::Static.into()), "static_prefix"),
+        requires_if(OsStr::new(Source::Http.into()), "http_url"),
+        requires_if(OsStr::new(Source::Dns.into()), "dns_name"),
+        requires_if(OsStr::new(Source::Command.into()), "command"),
+        requires_if(OsStr::new(Source::File.into()), "prefix_file"),
+        requires_if(OsStr::new(Source::ConfigMap.into()), "configmap_name"),
+        requires_if(OsStr::new(Source::Fritzbox.into()), "fritzbox_user"),
+        requires_if(OsStr::new(Source::Fritzbox.into()), "fritzbox_password"),
+        requires_if(OsStr::new(Source::Openwrt.into()), "openwrt_password"),
     )]
     pub source: Source,
 
-    /// Name of the interface to check for a public prefix when using the `interface` source
+    /// Name of the interface to check for a public prefix when using the `iface`, `netlink` or `router-advert` source.
+    /// The `iface` and `netlink` sources accept several interfaces as a comma-separated list, e.g. `eth0,pppoe-wan`,
+    /// and uses the first one that has a suitable address.
+    /// Both also accept an interface index in the form `if<index>`, e.g. `if3`, in place of a name
     #[arg(
         long,
+        value_delimiter = ',',
         env = concat!(env_prefix!(), "IFACE")
     )]
-    pub iface: String,
+    pub iface: Vec<String>,
+
+    /// Path to the control socket of the Kea DHCPv6 server when using the `kea` source.
+    /// Kea needs to have the `lease_cmds` hook loaded
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "KEA_SOCKET"),
+        default_value = "/run/kea/kea6-ctrl-socket"
+    )]
+    pub kea_socket: PathBuf,
+
+    /// URL of the Kea control agent when using the `kea` source, e.g. http://kea.lan:8000/.
+    /// Used instead of the control socket when set, for a Kea server on another host
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "KEA_URL")
+    )]
+    pub kea_url: Option<String>,
+
+    /// Only consider prefix delegations leased to this DUID when using the `kea` source.
+    /// Defaults to the most recently renewed delegation
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "KEA_DUID")
+    )]
+    pub kea_duid: Option<String>,
+
+    /// PPP uplink interface when using the `ppp` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "PPP_IFACE"),
+        default_value = "ppp0"
+    )]
+    pub ppp_iface: String,
+
+    /// Comma separated list of interfaces carrying the delegated prefix when using the `ppp` source.
+    /// All interfaces except the PPP one are searched if not set
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = concat!(env_prefix!(), "PPP_DOWNSTREAM")
+    )]
+    pub ppp_downstream: Vec<String>,
+
+    /// Network to use when using the `static` source, e.g. 2001:db8:1:2::/64.
+    /// Its length has to match `--network-length`
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "STATIC_PREFIX")
+    )]
+    pub static_prefix: Option<Ipv6Net>,
+
+    /// URL to fetch the prefix from when using the `http` source, e.g. http://router.lan/prefix.
+    /// Redirects are followed, https:// URLs are verified against the CA certificates of the system
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "HTTP_URL")
+    )]
+    pub http_url: Option<String>,
+
+    /// JSON pointer to the prefix in the response when using the `http` source, e.g. `/wan/prefix`.
+    /// The response is treated as plain text containing only the prefix if not set
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "HTTP_JSON_POINTER")
+    )]
+    pub http_json_pointer: Option<String>,
+
+    /// Number of seconds to wait for the response when using the `http`, `fritzbox`, `upnp` or `openwrt` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "HTTP_TIMEOUT"),
+        default_value_t = 10
+    )]
+    pub http_timeout: u64,
+
+    /// Host name or address of the FRITZ!Box when using the `fritzbox` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "FRITZBOX_HOST"),
+        default_value = "fritz.box"
+    )]
+    pub fritzbox_host: String,
+
+    /// Port of the TR-064 API of the FRITZ!Box when using the `fritzbox` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "FRITZBOX_PORT"),
+        default_value_t = FRITZBOX_DEFAULT_PORT
+    )]
+    pub fritzbox_port: u16,
+
+    /// User to log into the FRITZ!Box with when using the `fritzbox` source.
+    /// It needs the "FRITZ!Box settings" permission
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "FRITZBOX_USER")
+    )]
+    pub fritzbox_user: Option<String>,
+
+    /// Password of `--fritzbox-user`, preferably passed through the environment
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "FRITZBOX_PASSWORD"),
+        hide_env_values = true
+    )]
+    pub fritzbox_password: Option<Secret>,
+
+    /// URL of the ubus JSON-RPC endpoint of the router when using the `openwrt` source.
+    /// It is served by `uhttpd-mod-ubus`, which comes with LuCI. https:// URLs are verified against the CA certificates of the system
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "OPENWRT_URL"),
+        default_value = "http://openwrt.lan/ubus"
+    )]
+    pub openwrt_url: String,
+
+    /// User to log into the router with when using the `openwrt` source.
+    /// It needs read access to `network.interface.<interface>` in its rpcd ACL
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "OPENWRT_USER"),
+        default_value = "root"
+    )]
+    pub openwrt_user: String,
+
+    /// Password of `--openwrt-user`, preferably passed through the environment
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "OPENWRT_PASSWORD"),
+        hide_env_values = true
+    )]
+    pub openwrt_password: Option<Secret>,
+
+    /// Logical interface in the OpenWrt network config that receives the delegated prefix when using the `openwrt` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "OPENWRT_INTERFACE"),
+        default_value = "wan6"
+    )]
+    pub openwrt_interface: String,
+
+    /// Environment variable that contains the prefix when using the `env` source.
+    /// It is read again on every run
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "PREFIX_ENV"),
+        default_value = concat!(env_prefix!(), "CURRENT_PREFIX")
+    )]
+    pub prefix_env: String,
+
+    /// Host name whose AAAA record contains an address from the prefix when using the `dns` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "DNS_NAME")
+    )]
+    pub dns_name: Option<String>,
+
+    /// Shell command printing the prefix to stdout when using the `command` source, run with `sh -c`
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "COMMAND")
+    )]
+    pub command: Option<String>,
+
+    /// Number of seconds after which the command is killed when using the `command` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "COMMAND_TIMEOUT"),
+        default_value_t = 30
+    )]
+    pub command_timeout: u64,
+
+    /// File containing the prefix when using the `file` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "PREFIX_FILE")
+    )]
+    pub prefix_file: Option<PathBuf>,
+
+    /// Watch the prefix file and reconcile as soon as it changes, instead of waiting for the next run
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "WATCH_PREFIX_FILE"),
+    )]
+    pub watch_prefix_file: bool,
+
+    /// Name of the ConfigMap containing the prefix when using the `configmap` source.
+    /// The ConfigMap is watched, so changes are picked up right away
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "CONFIGMAP_NAME")
+    )]
+    pub configmap_name: Option<String>,
+
+    /// Key of the prefix in the ConfigMap when using the `configmap` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "CONFIGMAP_KEY"),
+        default_value = "prefix"
+    )]
+    pub configmap_key: String,
+
+    /// Namespace of the ConfigMap when using the `configmap` source.
+    /// Defaults to the namespace of the kube config or the service account
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "CONFIGMAP_NAMESPACE")
+    )]
+    pub configmap_namespace: Option<String>,
+
+    /// Number of seconds to wait for a router advertisement on `--iface` when using the `router-advert` source.
+    /// The helper needs the CAP_NET_RAW capability for this source. Only supported on Linux
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "RA_TIMEOUT"),
+        default_value_t = 10
+    )]
+    pub ra_timeout: u64,
+
+    /// Lease file of the DHCPv6 client when using the `dhcp-pd` source
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "DHCP_PD_LEASE_FILE"),
+        default_value = "/var/lib/dhcp/dhclient6.leases"
+    )]
+    pub dhcp_pd_lease_file: PathBuf,
+
+    /// Format of the lease file when using the `dhcp-pd` source
+    #[arg(
+        value_enum,
+        long,
+        env = concat!(env_prefix!(), "DHCP_PD_FORMAT"),
+        default_value_t = LeaseFormat::default()
+    )]
+    pub dhcp_pd_format: LeaseFormat,
+
+    /// If the interface has multiple global addresses, prefer the one covered by the route with the lowest metric.
+    /// This makes the helper follow the active uplink in multi-WAN setups. Only supported on Linux
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "PREFER_ROUTE_METRIC"),
+    )]
+    pub prefer_route_metric: bool,
+
+    /// Ignore temporary (privacy extension) addresses on the interface, which change regularly.
+    /// Enabled by default, pass `--exclude-temporary false` to disable. Only supported on Linux
+    #[arg(
+        long,
+        action = ArgAction::Set,
+        default_value_t = true,
+        env = concat!(env_prefix!(), "EXCLUDE_TEMPORARY"),
+    )]
+    pub exclude_temporary: bool,
+
+    /// Ignore deprecated addresses on the interface, so that the pool doesn't follow a prefix that is being phased out.
+    /// Enabled by default, pass `--exclude-deprecated false` to disable. Only supported on Linux
+    #[arg(
+        long,
+        action = ArgAction::Set,
+        default_value_t = true,
+        env = concat!(env_prefix!(), "EXCLUDE_DEPRECATED"),
+    )]
+    pub exclude_deprecated: bool,
+
+    /// If the interface has multiple global addresses, prefer the one with the longest remaining preferred lifetime.
+    /// Deprecated addresses are only used if nothing else is available, which follows the current prefix during renumbering.
+    /// Takes precedence over `--prefer-route-metric`. Only supported on Linux
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "PREFER_LIFETIME"),
+    )]
+    pub prefer_lifetime: bool,
+
+    /// Prefer stable interface addresses (EUI-64 or manually configured) over temporary and deprecated ones,
+    /// which are only used if no stable address is available.
+    /// Enabled by default, pass `--prefer-stable-addr false` to disable. Only supported on Linux
+    #[arg(
+        long,
+        action = ArgAction::Set,
+        default_value_t = true,
+        env = concat!(env_prefix!(), "PREFER_STABLE_ADDR"),
+    )]
+    pub prefer_stable_addr: bool,
+
+    /// Which interface addresses are considered when using the `iface` or `netlink` source.
+    /// Use `ula` to manage a pool of unique local addresses (fc00::/7)
+    #[arg(
+        value_enum,
+        long,
+        env = concat!(env_prefix!(), "ADDRESS_SCOPE"),
+        default_value_t = AddressScope::default()
+    )]
+    pub address_scope: AddressScope,
+
+    /// Only consider interface addresses within this network when using the `iface` or `netlink` source, e.g. the supernet of one ISP.
+    /// Can be given multiple times or as a comma-separated list, addresses in any of the networks are accepted
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = concat!(env_prefix!(), "PREFIX_FILTER")
+    )]
+    pub prefix_filter: Vec<Ipv6Net>,
+
+    /// Only consider interface addresses starting with these hex digits when using the `iface` or `netlink` source,
+    /// e.g. `2003` to pick the address of one ISP on a multihomed host without knowing its exact supernet
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "ADDR_HINT")
+    )]
+    pub addr_hint: Option<AddressHint>,
+
+    /// Take the network length from the on-link prefix of the interface address when using the `iface` or `netlink` source,
+    /// e.g. for a /62 assigned to the interface. `--network-length` is used for addresses without a reported netmask
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "USE_IFACE_PREFIXLEN")
+    )]
+    pub use_iface_prefixlen: bool,
+
+    /// Ignore interface addresses whose on-link prefix is longer than this when using the `iface` or `netlink` source,
+    /// e.g. a /128 that some ISPs hand out briefly while the link comes up.
+    /// If no other address qualifies, the run fails instead of acting on a bogus prefix
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u8).range(1..=128),
+        env = concat!(env_prefix!(), "MIN_PREFIX_LEN")
+    )]
+    pub min_prefix_len: Option<u8>,
+
+    /// Only trust interface addresses that were assigned or refreshed (e.g. by a router advertisement)
+    /// within this number of seconds, and never trust tentative addresses.
+    /// Guards against publishing a prefix from a stale address, manually configured addresses are exempt.
+    /// Only supported on Linux, ignored elsewhere
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "ONLY_IF_CHANGED_SINCE")
+    )]
+    pub only_if_changed_since: Option<u64>,
 
     #[arg(
         value_enum,
@@ -96,7 +652,7 @@ pub struct Config {
     )]
     pub loglevel: Loglevel,
 
-    /// Number of seconds to wait between each run
+    /// Number of seconds to wait between each run. Ignored with `--once`
     #[arg(
         long,
         short = 'i',
@@ -105,16 +661,829 @@ pub struct Config {
     )]
     pub interval: u64,
 
+    /// Longest number of seconds to wait before retrying after consecutive errors.
+    /// Failed runs are retried after 5 seconds at first, doubling with every further error
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "MAX_BACKOFF"),
+        default_value_t = 600
+    )]
+    pub max_backoff: u64,
+
+    /// Number of seconds during which an error that keeps failing the runs is not logged again.
+    /// The first occurrence is always logged, as is a different error and the recovery. 0 logs every error
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "ERROR_LOG_INTERVAL"),
+        default_value_t = 600
+    )]
+    pub error_log_interval: u64,
+
+    /// Whether to only poll at the interval, or to also watch the pool and the source for changes.
+    /// Watching is supported by the `iface`, `netlink` (Linux only) and `file` sources
+    #[arg(
+        value_enum,
+        long,
+        env = concat!(env_prefix!(), "MODE"),
+        default_value_t = RunMode::default()
+    )]
+    pub mode: RunMode,
+
+    /// Number of seconds to wait between polling the source, overrides `--interval`.
+    /// Use together with `--apply-interval` to detect changes quickly without writing to the API on every run
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "OBSERVE_INTERVAL")
+    )]
+    pub observe_interval: Option<u64>,
+
+    /// Minimum number of seconds between two changes to the pool. Changes detected earlier are deferred
+    /// and coalesced, only the latest range is applied once the interval has passed.
+    /// Counted from the last applied change, a network held back by `--stabilize-count` is not applied before it is stable
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "APPLY_INTERVAL"),
+        default_value_t = 0
+    )]
+    pub apply_interval: u64,
+
+    /// Number of seconds a new range has to stay the same before it replaces the range in the pool,
+    /// e.g. to ride out an old prefix that briefly reappears during a router advertisement transition.
+    /// The range is only checked once per run, so the change is applied in the first run after the cooldown,
+    /// i.e. after up to `--interval` seconds more. Any other range in between, including the current one, restarts the cooldown
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "CHANGE_COOLDOWN"),
+        default_value_t = 0
+    )]
+    pub change_cooldown: u64,
+
+    /// If the source fails, e.g. because the interface briefly lost its address during a WAN flap,
+    /// keep using the last network it reported instead of failing the run. A warning is logged instead of an error
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "USE_LAST_KNOWN"),
+    )]
+    pub use_last_known: bool,
+
+    /// Number of seconds the last known network is reused for with `--use-last-known`,
+    /// after that the source errors are reported again
+    #[arg(
+        long,
+        default_value_t = 300,
+        env = concat!(env_prefix!(), "LAST_KNOWN_MAX_AGE"),
+    )]
+    pub last_known_max_age: u64,
+
+    /// Number of seconds to wait between detecting a change and applying it.
+    /// During this window, the change can be cancelled by creating the `--abort-file`.
+    /// V6HELPER_ABORT is only read from the helper's own environment, so it has to be set at startup
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "CANARY_DELAY"),
+        default_value_t = 0
+    )]
+    pub canary_delay: u64,
+
+    /// File whose existence cancels a pending change during the canary window
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "ABORT_FILE")
+    )]
+    pub abort_file: Option<PathBuf>,
+
+    /// Don't run until this file exists, e.g. to wait for provisioning to finish.
+    /// The file is only checked before the first run
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "WAIT_FOR_FILE")
+    )]
+    pub wait_for_file: Option<PathBuf>,
+
+    /// Wait a random number of seconds up to this value before the first run, so that many instances
+    /// started at the same time (e.g. after a cluster upgrade) don't all hit the API server at once.
+    /// Only the very first run is delayed, and the delay is skipped with `--once`
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "STARTUP_JITTER"),
+        default_value_t = 0
+    )]
+    pub startup_jitter: u64,
+
+    /// Number of consecutive runs in which the same network has to be observed before it is published for the first time.
+    /// Protects against publishing a transitional address seen during boot, later changes are applied as usual
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "STABILIZE_COUNT"),
+        default_value_t = 1
+    )]
+    pub stabilize_count: u32,
+
+    /// Log the internal state and the resolved config whenever the helper receives SIGUSR1
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "DUMP_STATE_ON_SIGNAL"),
+    )]
+    pub dump_state_on_signal: bool,
+
+    /// Reconcile every pool a single time and exit instead of running in a loop, e.g. from a CronJob.
+    /// Exits with status 0 if no pool was changed, 10 if at least one pool was changed
+    /// and 1 if any pool failed, regardless of the changes made to the other pools
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "ONCE"),
+    )]
+    pub once: bool,
+
+    /// Print the range each pool should contain and the change that would be made as JSON, then exit without changing anything.
+    /// One line is printed per pool and host range, e.g. `{"action":"replace","pool":"default","prefix":"2001:db8:1::beef:0:0:0/80"}`
+    #[arg(long, action, default_value_t = false)]
+    pub print_prefix: bool,
+
+    /// Serve Prometheus metrics on this address, e.g. `[::]:9100`. Disabled if not set
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "METRICS_ADDR")
+    )]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Serve `/healthz` and `/readyz` for k8s probes on this address, e.g. `[::]:8080`. Disabled if not set.
+    /// Ready once every pool was reconciled successfully
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "HEALTH_ADDR")
+    )]
+    pub health_addr: Option<SocketAddr>,
+
+    /// Number of failed runs in a row after which `/healthz` reports the helper as unhealthy
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "UNHEALTHY_AFTER"),
+        value_parser = clap::value_parser!(u32).range(1..),
+        default_value_t = 3
+    )]
+    pub unhealthy_after: u32,
+
+    /// Only apply changes to the pool within this daily time window, e.g. 22:00-04:00.
+    /// Changes detected outside of the window are logged and applied once it opens.
+    /// Times are in the local time zone of the helper, as set by the TZ environment variable (UTC in most containers)
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "CHANGE_WINDOW")
+    )]
+    pub change_window: Option<ChangeWindow>,
+
+    /// NATS server to publish pool changes to, e.g. nats://nats.example.com:4222
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "NATS_URL")
+    )]
+    pub nats_url: Option<String>,
+
+    /// NATS subject on which pool changes are published
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "NATS_SUBJECT"),
+        default_value = "metallb-v6-helper.reconcile"
+    )]
+    pub nats_subject: String,
+
+    /// URL to POST pool changes to as JSON, e.g. a Slack incoming webhook.
+    /// Failing to notify is logged, but doesn't fail the run
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "NOTIFY_WEBHOOK")
+    )]
+    pub notify_webhook: Option<Uri>,
+
     /// Do not make any changes to the pool, only show what would happen
     #[arg(long, short = 'd', action, default_value_t = false)]
     pub dry_run: bool,
 
+    /// Together with `--dry-run`, send the changes to the k8s API server as dry-run requests.
+    /// They are validated (including admission webhooks) but not persisted
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        requires = "dry_run",
+        env = concat!(env_prefix!(), "SERVER_DRY_RUN"),
+    )]
+    pub server_dry_run: bool,
+
+    /// After changing the pool, read it back and fail the run if the new range isn't present within `--verify-timeout` seconds.
+    /// Catches changes that were accepted but not persisted, e.g. because a webhook dropped them
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "VERIFY_PROPAGATION"),
+    )]
+    pub verify_propagation: bool,
+
+    /// Number of seconds to wait for the new range to show up with `--verify-propagation`
+    #[arg(
+        long,
+        default_value_t = 10,
+        env = concat!(env_prefix!(), "VERIFY_TIMEOUT"),
+    )]
+    pub verify_timeout: u64,
+
+    /// Create the IpAddressPool if it doesn't exist yet
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "CREATE_POOL"),
+    )]
+    pub create_pool: bool,
+
+    /// YAML or JSON IpAddressPool manifest to use as a template when creating the pool.
+    /// Its name and the dynamic range are filled in by the helper, all other fields are kept
+    #[arg(
+        long,
+        requires = "create_pool",
+        env = concat!(env_prefix!(), "POOL_CREATION_SPEC")
+    )]
+    pub pool_creation_spec: Option<PathBuf>,
+
+    /// Try to repair the pool if its addresses are malformed, e.g. written as a single string instead of a list
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "REPAIR_POOL"),
+    )]
+    pub repair_pool: bool,
+
+    /// Keep comments attached to the dynamic range when it is replaced.
+    /// Comments are read from the `metallb-v6-helper/address-comments` annotation, a JSON object mapping addresses to comments
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "PRESERVE_ADDRESS_COMMENTS"),
+    )]
+    pub preserve_address_comments: bool,
+
+    /// Find the pool by its UID instead of by name, so that it keeps being managed if it is renamed.
+    /// The configured pool name is then ignored, so only a single pool may be given
+    #[arg(
+        long,
+        conflicts_with = "create_pool",
+        env = concat!(env_prefix!(), "POOL_UID")
+    )]
+    pub pool_uid: Option<String>,
+
+    /// Namespace of the IpAddressPool, usually `metallb-system`.
+    /// Defaults to the namespace of the kube config or the service account
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "NAMESPACE")
+    )]
+    pub namespace: Option<String>,
+
+    /// Version of the MetalLB pool CRD to use, e.g. `v1beta2`.
+    /// By default v1beta1 is used if the cluster serves it, otherwise the version the pools are stored in
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "CRD_VERSION")
+    )]
+    pub crd_version: Option<String>,
+
+    /// Record a k8s Event on the pool whenever its range is changed, visible with `kubectl get events`.
+    /// Requires permission to create events in the namespace of the pool
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "EMIT_EVENTS"),
+    )]
+    pub emit_events: bool,
+
+    /// Log the addresses added to and removed from the pool at info level before every change,
+    /// e.g. for reviewing changes in `kubectl logs`. The full patch is still only logged at debug level
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "DIFF"),
+    )]
+    pub diff: bool,
+
+    /// How updates are recorded in the pool annotations
+    #[arg(
+        value_enum,
+        long,
+        env = concat!(env_prefix!(), "UPDATE_MARKER"),
+        default_value_t = UpdateMarker::default()
+    )]
+    pub update_marker: UpdateMarker,
+
+    /// How changes to the pool are sent to the API server.
+    /// `apply` uses server-side apply, so that conflicts with other managers of the addresses (e.g. GitOps tools) are reported
+    #[arg(
+        value_enum,
+        long,
+        env = concat!(env_prefix!(), "PATCH_STRATEGY"),
+        default_value_t = PatchStrategy::default()
+    )]
+    pub patch_strategy: PatchStrategy,
+
+    /// Field manager recorded for changes to the pool
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "FIELD_MANAGER"),
+        default_value = DEFAULT_FIELD_MANAGER
+    )]
+    pub field_manager: String,
+
+    /// Number of times a failed change to the pool is retried right away, e.g. after a conflict with another writer.
+    /// The pool is read again before every retry
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "PATCH_RETRIES"),
+        default_value_t = 3
+    )]
+    pub patch_retries: u32,
+
+    /// How a range is replaced when the prefix changes.
+    /// `add-then-remove` adds the new range first and removes the old one in a second patch once the new one is in the pool,
+    /// for MetalLB versions that reject a pool update which momentarily lacks any range
+    #[arg(
+        value_enum,
+        long,
+        env = concat!(env_prefix!(), "REPLACE_STRATEGY"),
+        default_value_t = ReplaceStrategy::default()
+    )]
+    pub replace_strategy: ReplaceStrategy,
+
+    /// Name of a BGPAdvertisement in the namespace of the pool that is kept consistent with the pool.
+    /// After every change, the pool is added to its `ipAddressPools` if it lists pools explicitly,
+    /// and its aggregation length is raised to the prefix length of the new range if it is shorter.
+    /// Requires `get` and `patch` on `bgpadvertisements` in the `metallb.io` API group
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "BGP_ADVERTISEMENT")
+    )]
+    pub bgp_advertisement: Option<String>,
+
+    /// Only reconcile while holding a Lease in the namespace of the helper, so that multiple replicas don't race each other.
+    /// The other replicas idle and take over once the lease expires.
+    /// Requires `get`, `create` and `update` on `leases` in the `coordination.k8s.io` API group
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        env = concat!(env_prefix!(), "ENABLE_LEADER_ELECTION"),
+    )]
+    pub enable_leader_election: bool,
+
+    /// Name of the Lease used for leader election. Replicas managing the same pools have to use the same name
+    #[arg(
+        long,
+        env = concat!(env_prefix!(), "LEASE_NAME"),
+        default_value = "metallb-dynv6-helper"
+    )]
+    pub lease_name: String,
+
     /// Don't validate the k8s API server certificates
     #[arg(
         long,
         action,
         default_value_t = false,
-        env = concat!(env_prefix!(), "VERIFY"),
+        env = concat!(env_prefix!(), "NO_VERIFY"),
     )]
     pub no_verify: bool,
 }
+
+impl Config {
+    /// Takes the settings that apply when the reconcilers are restarted from `new` and keeps all others.
+    /// Also returns whether any of the other settings differ, which only take effect after restarting the helper
+    pub fn reloaded(&self, new: &Config) -> Result<(Config, bool), clap::Error> {
+        let mut reloaded = self.clone();
+        reloaded.metallb_address_pool = new.metallb_address_pool.clone();
+        reloaded.metallb_host_range = new.metallb_host_range.clone();
+        reloaded.pool_range = new.pool_range.clone();
+        reloaded.host_combine = new.host_combine;
+        reloaded.ipv4 = new.ipv4;
+        reloaded.v4_host_range = new.v4_host_range;
+        reloaded.v4_network_length = new.v4_network_length;
+        reloaded.expected_prefix_min = new.expected_prefix_min;
+        reloaded.expected_prefix_max = new.expected_prefix_max;
+        reloaded.prefix_transform = new.prefix_transform.clone();
+        reloaded.interval = new.interval;
+        reloaded.max_backoff = new.max_backoff;
+        reloaded.error_log_interval = new.error_log_interval;
+        reloaded.observe_interval = new.observe_interval;
+        reloaded.apply_interval = new.apply_interval;
+        reloaded.change_cooldown = new.change_cooldown;
+        reloaded.use_last_known = new.use_last_known;
+        reloaded.last_known_max_age = new.last_known_max_age;
+        reloaded.canary_delay = new.canary_delay;
+        reloaded.abort_file = new.abort_file.clone();
+        reloaded.stabilize_count = new.stabilize_count;
+        reloaded.change_window = new.change_window;
+        reloaded.nats_url = new.nats_url.clone();
+        reloaded.nats_subject = new.nats_subject.clone();
+        reloaded.notify_webhook = new.notify_webhook.clone();
+        reloaded.dry_run = new.dry_run;
+        reloaded.verify_propagation = new.verify_propagation;
+        reloaded.verify_timeout = new.verify_timeout;
+        // The new host ranges may not fit the network length that is still in use
+        reloaded.validate()?;
+        let restart_required = reloaded != *new;
+        Ok((reloaded, restart_required))
+    }
+
+    /// Moves the only positional argument to the host ranges with `--pool-selector`, as no pool names are given then
+    pub fn shift_positionals(mut self) -> Result<Config, clap::Error> {
+        if self.pool_selector.is_none() || !self.metallb_host_range.is_empty() {
+            return Ok(self);
+        }
+        let parsed: Result<Vec<_>, _> = self
+            .metallb_address_pool
+            .iter()
+            .map(|r| Ipv6Net::from_str(r).map_err(|e| (r, e)))
+            .collect();
+        match parsed {
+            Ok(host_ranges) => {
+                self.metallb_host_range = host_ranges;
+                self.metallb_address_pool.clear();
+            }
+            // A single value can only have been meant as the host range
+            Err((host_range, e)) if self.metallb_address_pool.len() == 1 => {
+                return Err(Config::command().error(
+                    ErrorKind::ValueValidation,
+                    format!("Invalid host range `{}`: {}", host_range, e),
+                ))
+            }
+            Err(_) => {
+                return Err(Config::command().error(
+                    ErrorKind::ArgumentConflict,
+                    "Pool names cannot be combined with --pool-selector",
+                ))
+            }
+        }
+        Ok(self)
+    }
+
+    /// Checks settings that depend on each other
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        validate_host_combine(self.host_combine, self.network_length)?;
+        if self.pool_selector.is_some() {
+            if !self.metallb_address_pool.is_empty() {
+                return Err(Config::command().error(
+                    ErrorKind::ArgumentConflict,
+                    "Pool names cannot be combined with --pool-selector",
+                ));
+            }
+            // Ranges for pools that match later on can't be checked yet
+            for range in &self.pool_range {
+                self.validate_host_ranges(
+                    &format!("pool `{}`", range.pool),
+                    &self.host_ranges(&range.pool)?,
+                )?;
+            }
+            if self.metallb_host_range.is_empty() {
+                return Err(Config::command().error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--pool-selector requires the `metallb_host_range` argument for the matching pools",
+                ));
+            }
+            return self.validate_host_ranges(
+                "the pools matching --pool-selector",
+                &self.metallb_host_range,
+            );
+        }
+        if let Some(unknown) = self
+            .pool_range
+            .iter()
+            .find(|r| !self.metallb_address_pool.contains(&r.pool))
+        {
+            return Err(Config::command().error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "--pool-range is given for `{}`, which is not one of the pools {:?}",
+                    unknown.pool, self.metallb_address_pool
+                ),
+            ));
+        }
+        for pool in &self.metallb_address_pool {
+            self.validate_host_ranges(&format!("pool `{}`", pool), &self.host_ranges(pool)?)?;
+        }
+        Ok(())
+    }
+
+    /// Host ranges of the given pool, from `--pool-range` or the `metallb_host_range` argument
+    pub fn host_ranges(&self, pool: &str) -> Result<Vec<Ipv6Net>, clap::Error> {
+        let ranges: Vec<_> = self
+            .pool_range
+            .iter()
+            .filter(|r| r.pool == pool)
+            .map(|r| r.host_range)
+            .collect();
+        match (ranges.is_empty(), self.metallb_host_range.is_empty()) {
+            (false, _) => Ok(ranges),
+            (true, false) => Ok(self.metallb_host_range.clone()),
+            (true, true) => Err(Config::command().error(
+                ErrorKind::MissingRequiredArgument,
+                format!(
+                    "Pool `{}` has no host range, pass the `metallb_host_range` argument or --pool-range {}=<host range>",
+                    pool, pool
+                ),
+            )),
+        }
+    }
+
+    // Checks each host range and that no two of them would match the same range in the pool.
+    // `pools` names the pools the ranges belong to in errors
+    fn validate_host_ranges(
+        &self,
+        pools: &str,
+        host_ranges: &[Ipv6Net],
+    ) -> Result<(), clap::Error> {
+        let mask = host_mask(self.host_combine, self.network_length);
+        for (i, host_range) in host_ranges.iter().enumerate() {
+            self.validate_host_range(*host_range)?;
+            let host_bits = u128::from(host_range.addr()) & mask;
+            if let Some(other) = host_ranges[..i]
+                .iter()
+                .find(|r| u128::from(r.addr()) & mask == host_bits)
+            {
+                return Err(Config::command().error(
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "The host ranges {} and {} of {} have the same host bits, so they would match the same range",
+                        other, host_range, pools
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_host_range(&self, host_range: Ipv6Net) -> Result<(), clap::Error> {
+        let mask = host_mask(self.host_combine, self.network_length);
+        if u128::from(host_range.addr()) & !mask != 0 {
+            let host_bits_from = match self.host_combine {
+                HostCombine::Or => 64,
+                HostCombine::Replace => self.network_length,
+            };
+            let suggestion = Ipv6Net::new(
+                Ipv6Addr::from(u128::from(host_range.addr()) & mask),
+                host_range.prefix_len(),
+            )
+            .unwrap_or(host_range);
+            return Err(Config::command().error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "The host range {} sets bits above /{}, which are taken from the network with --network-length {} and --host-combine {}. Did you mean {}?",
+                    host_range,
+                    host_bits_from,
+                    self.network_length,
+                    self.host_combine
+                        .to_possible_value()
+                        .map(|v| v.get_name().to_string())
+                        .unwrap_or_default(),
+                    suggestion
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Settings for the [`metallb_v6_prefix_helper::reconcile::Reconciler`] of the given pool
+    pub fn reconcile_options(&self, pool: &str) -> Result<ReconcileOptions, clap::Error> {
+        Ok(ReconcileOptions {
+            host_ranges: self.host_ranges(pool)?,
+            host_combine: self.host_combine,
+            prefix_transform: self.prefix_transform.clone(),
+            expected_prefix_min: self.expected_prefix_min,
+            expected_prefix_max: self.expected_prefix_max,
+            stabilize_count: self.stabilize_count,
+            change_window: self.change_window,
+            apply_interval: Duration::from_secs(self.apply_interval),
+            change_cooldown: Duration::from_secs(self.change_cooldown),
+            last_known_max_age: match self.use_last_known {
+                true => Some(Duration::from_secs(self.last_known_max_age)),
+                false => None,
+            },
+            canary_delay: Duration::from_secs(self.canary_delay),
+            abort_env: Some(ABORT_ENV.to_string()),
+            abort_file: self.abort_file.clone(),
+            dry_run: self.dry_run,
+            validate_dry_run: self.server_dry_run,
+            verify_propagation: match self.verify_propagation {
+                true => Some(Duration::from_secs(self.verify_timeout)),
+                false => None,
+            },
+            interval: Duration::from_secs(self.observe_interval.unwrap_or(self.interval)),
+            max_backoff: Duration::from_secs(self.max_backoff),
+            error_log_interval: Duration::from_secs(self.error_log_interval),
+            v4: match (self.ipv4, self.v4_host_range) {
+                (true, Some(host_range)) => Some(V4Options {
+                    host_range,
+                    network_length: self.v4_network_length,
+                }),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// Print the MetalLB range generated from a prefix and host range, then exit
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Args)]
+pub struct ComputeArgs {
+    /// Dynamic prefix to use as the network part, e.g. 2003:ee:970c::/48
+    #[arg(long)]
+    pub prefix: Ipv6Net,
+
+    /// Host range to combine with the prefix, same as the `metallb_host_range` argument
+    #[arg(long)]
+    pub host_range: Ipv6Net,
+
+    /// Length of the dynamically changing v6 network (prefix + subnet)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=128), default_value_t = 64)]
+    pub network_length: u8,
+
+    /// How the prefix and the host range are combined
+    #[arg(value_enum, long, default_value_t = HostCombine::default())]
+    pub host_combine: HostCombine,
+
+    /// Also print the masked network and host parts that are combined
+    #[arg(long, short = 'v', action, default_value_t = false)]
+    pub verbose: bool,
+}
+
+impl ComputeArgs {
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        validate_host_combine(self.host_combine, self.network_length)
+    }
+}
+
+// `or` takes a fixed /64 boundary, so any other network length would silently zero the bits in between
+fn validate_host_combine(combine: HostCombine, network_length: u8) -> Result<(), clap::Error> {
+    match combine {
+        HostCombine::Or if network_length != 64 => Err(Config::command().error(
+            ErrorKind::ArgumentConflict,
+            format!(
+                "--host-combine or always splits the range at /64, use --host-combine replace with --network-length {}",
+                network_length
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// What the helper has been asked to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mode {
+    Run(Box<Config>),
+    Compute(ComputeArgs),
+}
+
+/// Parses the command line into the requested mode, exiting on invalid arguments
+pub fn parse_from<I, T>(args: I) -> Mode
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    try_parse_from(args).unwrap_or_else(|e| e.exit())
+}
+
+/// Parses the command line along with the `--config` file into the requested mode.
+// Subcommands are added manually, as deriving them on `Config` would force all of its required arguments onto them
+pub fn try_parse_from<I, T>(args: I) -> Result<Mode, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let mut command = Config::command();
+    // The file only provides defaults, so that the command line and the environment take precedence.
+    // Its path is looked up leniently first, as required arguments may come from the file
+    let config_path = Config::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .ok()
+        .and_then(|m| m.get_one::<PathBuf>("config").cloned());
+    if let Some(path) = config_path {
+        command = with_config_file(command, &path)?;
+    }
+
+    let matches = command
+        .subcommand(ComputeArgs::augment_args(clap::Command::new("compute")))
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .try_get_matches_from(args)?;
+    match matches.subcommand() {
+        Some(("compute", sub)) => Ok(Mode::Compute(
+            ComputeArgs::from_arg_matches(sub).and_then(|a| a.validate().map(|_| a))?,
+        )),
+        _ => Ok(Mode::Run(Box::new(
+            Config::from_arg_matches(&matches)
+                .and_then(Config::shift_positionals)
+                .and_then(|c| c.validate().map(|_| c))?,
+        ))),
+    }
+}
+
+/// Makes the settings in the file the defaults of their arguments
+fn with_config_file(mut command: clap::Command, path: &Path) -> Result<clap::Command, clap::Error> {
+    let settings = match read_config_file(path) {
+        Ok(settings) => settings,
+        Err(msg) => return Err(command.error(ErrorKind::Io, msg)),
+    };
+    for (key, values) in settings {
+        let name = key.replace('_', "-");
+        let Some(arg) = command.get_arguments().find(|a| {
+            a.get_long() == Some(name.as_str()) || a.get_id().as_str().replace('_', "-") == name
+        }) else {
+            return Err(command.error(
+                ErrorKind::UnknownArgument,
+                format!("Unknown setting `{}` in {}", key, path.display()),
+            ));
+        };
+        let id = arg.get_id().clone();
+        // clap only validates defaults in debug builds, and by panicking
+        let check = clap::Arg::new(name.clone())
+            .value_parser(arg.get_value_parser().clone())
+            .value_delimiter(arg.get_value_delimiter())
+            .allow_hyphen_values(true);
+        for value in &values {
+            if let Err(e) = clap::Command::new("check")
+                .arg(check.clone())
+                .try_get_matches_from(["check", value])
+            {
+                // Only the first line of the rendered error carries the problem
+                let rendered = e.to_string();
+                let detail = rendered.lines().next().unwrap_or_default();
+                return Err(command.error(
+                    ErrorKind::InvalidValue,
+                    format!(
+                        "Invalid `{}` in {}: {}",
+                        key,
+                        path.display(),
+                        detail.trim_start_matches("error: ")
+                    ),
+                ));
+            }
+        }
+        // A default satisfies required arguments here, unlike in clap
+        command = command.mut_arg(id, |a| {
+            a.default_values(values)
+                .required_unless_present(Resettable::Reset)
+        });
+    }
+    Ok(command)
+}
+
+/// Reads the settings in the file as the values they would have on the command line
+fn read_config_file(path: &Path) -> Result<Vec<(String, Vec<String>)>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read config file {}: {}", path.display(), e))?;
+    let settings: BTreeMap<String, Value> = match path.extension().and_then(OsStr::to_str) {
+        Some("toml") => toml::from_str(&content).map_err(|e| e.to_string()),
+        _ => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("Unable to parse config file {}: {}", path.display(), e))?;
+
+    let plain = |key: &str, value: &Value| match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        _ => Err(format!(
+            "Setting `{}` in {} is neither a plain value nor a list of them",
+            key,
+            path.display()
+        )),
+    };
+    settings
+        .iter()
+        .map(|(key, value)| {
+            let values = match value {
+                Value::Array(items) => items
+                    .iter()
+                    .map(|v| plain(key, v))
+                    .collect::<Result<_, _>>()?,
+                value => vec![plain(key, value)?],
+            };
+            Ok((key.clone(), values))
+        })
+        .collect()
+}
+
+pub fn parse() -> Mode {
+    parse_from(std::env::args_os())
+}
+
+/// Parses the command line of the process again, e.g. after the `--config` file changed
+pub fn try_parse() -> Result<Mode, clap::Error> {
+    try_parse_from(std::env::args_os())
+}
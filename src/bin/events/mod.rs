@@ -0,0 +1,198 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use metallb_v6_prefix_helper::reconcile::{OutcomeListener, ReconcileOutcome};
+use serde_json::json;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+const NATS_DEFAULT_PORT: u16 = 4222;
+const NATS_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum EventError {
+    #[error("Error while talking to the NATS server: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("Unexpected response from the NATS server: `{0}`")]
+    Protocol(String),
+    #[error("Timed out while publishing to the NATS server")]
+    Timeout,
+}
+
+/// Publishes reconcile outcomes to a NATS subject.
+// This only speaks the small subset of the NATS text protocol needed to publish a message.
+// A new connection is opened for every event, as changes are rare and this avoids having to handle reconnects.
+pub struct NatsPublisher {
+    server: String,
+    subject: String,
+    /// Name of the pool included in the events
+    pool: String,
+}
+
+impl NatsPublisher {
+    pub fn new(url: &str, subject: &str, pool: &str) -> NatsPublisher {
+        let server = url.trim_start_matches("nats://").trim_end_matches('/');
+        let server = match server.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => server.to_string(),
+            _ => format!("{}:{}", server, NATS_DEFAULT_PORT),
+        };
+        NatsPublisher {
+            server,
+            subject: subject.to_string(),
+            pool: pool.to_string(),
+        }
+    }
+
+    async fn publish(&self, payload: &[u8]) -> Result<(), EventError> {
+        timeout(NATS_TIMEOUT, self.publish_inner(payload))
+            .await
+            .map_err(|_| EventError::Timeout)?
+    }
+
+    async fn publish_inner(&self, payload: &[u8]) -> Result<(), EventError> {
+        let (read, mut write) = TcpStream::connect(&self.server).await?.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        match lines.next_line().await? {
+            Some(info) if info.starts_with("INFO") => debug!("Connected to NATS server: {}", info),
+            other => return Err(EventError::Protocol(other.unwrap_or_default())),
+        }
+
+        let mut msg = format!(
+            "CONNECT {}\r\nPUB {} {}\r\n",
+            json!({"verbose": false, "pedantic": false, "name": "metallb-v6-helper"}),
+            self.subject,
+            payload.len()
+        )
+        .into_bytes();
+        msg.extend_from_slice(payload);
+        // The server answers our PING only after processing everything before it, so a PONG confirms the publish
+        msg.extend_from_slice(b"\r\nPING\r\n");
+        write.write_all(&msg).await?;
+
+        while let Some(line) = lines.next_line().await? {
+            if line == "PONG" {
+                return Ok(());
+            } else if line.starts_with("-ERR") {
+                return Err(EventError::Protocol(line));
+            }
+        }
+        Err(EventError::Protocol("connection closed".to_string()))
+    }
+}
+
+#[async_trait]
+impl OutcomeListener for NatsPublisher {
+    /// Publishes each outcome that changed the pool. Errors are logged and otherwise ignored
+    async fn outcome(&self, outcomes: &[ReconcileOutcome]) {
+        for outcome in outcomes {
+            let Some(payload) = event_payload(&self.pool, outcome) else {
+                continue;
+            };
+            match self.publish(payload.as_bytes()).await {
+                Ok(_) => info!("Published pool change to NATS subject {}", self.subject),
+                Err(e) => warn!("Unable to publish pool change to NATS: {}", e),
+            }
+        }
+    }
+}
+
+/// Builds the JSON event for an outcome, or `None` if the pool was not changed
+fn event_payload(pool: &str, outcome: &ReconcileOutcome) -> Option<String> {
+    let event = match outcome {
+        ReconcileOutcome::NoChange | ReconcileOutcome::Deferred(_) => return None,
+        ReconcileOutcome::Inserted(range) => json!({
+            "event": "inserted",
+            "pool": pool,
+            "old": null,
+            "new": range.to_string(),
+        }),
+        ReconcileOutcome::Replaced { old, new } => json!({
+            "event": "replaced",
+            "pool": pool,
+            "old": old.to_string(),
+            "new": new.to_string(),
+        }),
+    };
+    Some(event.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ipnet::Ipv6Net;
+    use metallb_v6_prefix_helper::reconcile::ReconcileOutcome;
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::{event_payload, NatsPublisher};
+
+    #[test]
+    fn builds_event_payload() {
+        let old = Ipv6Net::from_str("2001:db8:0:0:abab:cdcd:0:0/80").unwrap();
+        let new = Ipv6Net::from_str("2001:db8:1111:1111:abab:cdcd:0:0/80").unwrap();
+
+        assert!(event_payload("my-pool", &ReconcileOutcome::NoChange).is_none());
+
+        let payload: Value = serde_json::from_str(
+            &event_payload("my-pool", &ReconcileOutcome::Replaced { old, new }).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(payload["event"], "replaced");
+        assert_eq!(payload["pool"], "my-pool");
+        assert_eq!(payload["old"], "2001:db8::abab:cdcd:0:0/80");
+        assert_eq!(payload["new"], "2001:db8:1111:1111:abab:cdcd::/80");
+    }
+
+    #[test]
+    fn parses_nats_url() {
+        assert_eq!(
+            NatsPublisher::new("nats://nats.example.com", "s", "p").server,
+            "nats.example.com:4222"
+        );
+        assert_eq!(
+            NatsPublisher::new("nats://127.0.0.1:4223", "s", "p").server,
+            "127.0.0.1:4223"
+        );
+    }
+
+    #[tokio::test]
+    async fn publishes_to_nats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"INFO {}\r\n").await.unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            assert!(line.starts_with("CONNECT "));
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PUB pool.events 5\r\n");
+            let mut payload = [0u8; 7];
+            reader.read_exact(&mut payload).await.unwrap();
+            assert_eq!(&payload, b"hello\r\n");
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PING\r\n");
+            reader.get_mut().write_all(b"PONG\r\n").await.unwrap();
+        });
+
+        NatsPublisher::new(&url, "pool.events", "my-pool")
+            .publish(b"hello")
+            .await
+            .unwrap();
+        server.await.unwrap();
+    }
+}
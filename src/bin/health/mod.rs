@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use metallb_v6_prefix_helper::reconcile::{OutcomeListener, ReconcileError, ReconcileOutcome};
+
+use crate::http;
+
+#[derive(Debug, Default)]
+struct PoolHealth {
+    /// Whether a run has succeeded since startup
+    succeeded: bool,
+    consecutive_failures: u32,
+}
+
+/// Liveness and readiness of all pools, for k8s probes.
+/// Ready once every pool was reconciled successfully, unhealthy while any pool failed `max_failures` times in a row
+#[derive(Clone)]
+pub struct Health {
+    pools: Arc<Mutex<HashMap<String, PoolHealth>>>,
+    max_failures: u32,
+}
+
+impl Health {
+    pub fn new(max_failures: u32) -> Health {
+        Health {
+            pools: Arc::default(),
+            max_failures,
+        }
+    }
+
+    fn pools(&self) -> MutexGuard<'_, HashMap<String, PoolHealth>> {
+        match self.pools.lock() {
+            Ok(pools) => pools,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Registers the pool, whose reconciler then has to report its runs to the returned listener.
+    /// A pool that is already registered keeps its state
+    pub fn listener(&self, pool: &str) -> HealthListener {
+        self.pools().entry(pool.to_string()).or_default();
+        HealthListener {
+            health: self.clone(),
+            pool: pool.to_string(),
+        }
+    }
+
+    /// Forgets all pools except the given ones, e.g. after they stopped matching the pool selector
+    pub fn retain(&self, pools: &[String]) {
+        self.pools().retain(|pool, _| pools.contains(pool));
+    }
+
+    fn is_ready(&self) -> bool {
+        let pools = self.pools();
+        !pools.is_empty() && pools.values().all(|p| p.succeeded)
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.pools()
+            .values()
+            .all(|p| p.consecutive_failures < self.max_failures)
+    }
+
+    /// Serves `/healthz` and `/readyz` until the process exits
+    pub fn serve(&self, addr: SocketAddr) -> Result<(), hyper::Error> {
+        let health = self.clone();
+        http::serve(addr, "health probes", move |req| health.respond(req))
+    }
+
+    fn respond(&self, req: &Request<Body>) -> Response<Body> {
+        let ok = match (req.method(), req.uri().path()) {
+            (&Method::GET, "/healthz") => self.is_healthy(),
+            (&Method::GET, "/readyz") => self.is_ready(),
+            _ => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                return response;
+            }
+        };
+        let mut response = Response::new(Body::from(if ok { "ok" } else { "not ok" }));
+        if !ok {
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        }
+        response
+    }
+}
+
+/// Records the runs of the reconciler of one pool
+pub struct HealthListener {
+    health: Health,
+    pool: String,
+}
+
+#[async_trait]
+impl OutcomeListener for HealthListener {
+    async fn outcome(&self, _outcomes: &[ReconcileOutcome]) {
+        if let Some(pool) = self.health.pools().get_mut(&self.pool) {
+            pool.succeeded = true;
+            pool.consecutive_failures = 0;
+        }
+    }
+
+    async fn error(&self, _error: &ReconcileError) {
+        if let Some(pool) = self.health.pools().get_mut(&self.pool) {
+            pool.consecutive_failures = pool.consecutive_failures.saturating_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request, StatusCode};
+    use metallb_v6_prefix_helper::reconcile::{OutcomeListener, ReconcileError, ReconcileOutcome};
+
+    use super::Health;
+
+    fn status(health: &Health, path: &str) -> StatusCode {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        health.respond(&req).status()
+    }
+
+    #[tokio::test]
+    async fn reports_readiness_and_health() {
+        let health = Health::new(2);
+        // Nothing to reconcile yet
+        assert_eq!(status(&health, "/readyz"), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status(&health, "/healthz"), StatusCode::OK);
+
+        let a = health.listener("a");
+        let b = health.listener("b");
+        a.outcome(&[ReconcileOutcome::NoChange]).await;
+        assert_eq!(status(&health, "/readyz"), StatusCode::SERVICE_UNAVAILABLE);
+        b.outcome(&[ReconcileOutcome::NoChange]).await;
+        assert_eq!(status(&health, "/readyz"), StatusCode::OK);
+
+        let error = ReconcileError::Panic("test".to_string());
+        b.error(&error).await;
+        assert_eq!(status(&health, "/healthz"), StatusCode::OK);
+        b.error(&error).await;
+        assert_eq!(status(&health, "/healthz"), StatusCode::SERVICE_UNAVAILABLE);
+        // Once ready, errors don't affect readiness
+        assert_eq!(status(&health, "/readyz"), StatusCode::OK);
+        b.outcome(&[ReconcileOutcome::NoChange]).await;
+        assert_eq!(status(&health, "/healthz"), StatusCode::OK);
+
+        assert_eq!(status(&health, "/other"), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn keeps_state_of_matching_pools() {
+        let health = Health::new(2);
+        health
+            .listener("a")
+            .outcome(&[ReconcileOutcome::NoChange])
+            .await;
+        let _b = health.listener("b");
+        assert_eq!(status(&health, "/readyz"), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Pool b no longer matches, a is registered again by its new reconciler
+        health.retain(&["a".to_string()]);
+        let _a = health.listener("a");
+        assert_eq!(status(&health, "/readyz"), StatusCode::OK);
+    }
+}
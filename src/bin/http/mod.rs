@@ -0,0 +1,32 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use log::{error, info};
+
+/// Serves HTTP requests with `handler` on a background task until the process exits.
+/// Only binding the address can fail, `name` identifies the server in logs
+pub fn serve<F>(addr: SocketAddr, name: &'static str, handler: F) -> Result<(), hyper::Error>
+where
+    F: Fn(&Request<Body>) -> Response<Body> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let response = handler(&req);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    }));
+    info!("Serving {} on http://{}", name, addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("The {} server failed: {}", name, e);
+        }
+    });
+    Ok(())
+}
@@ -1,277 +1,1097 @@
 mod config;
+mod events;
+mod health;
+mod http;
+mod metrics;
+mod status;
+mod webhook;
 
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{error::Error, net::Ipv6Addr};
 
-use clap::Parser;
 use env_logger::Builder;
-use ipnet::{Ipv6Net, PrefixLenError};
-use log::{debug, error, info};
+use ipnet::Ipv6Net;
+use log::{debug, error, info, warn};
 
-use config::Config;
+use config::{ComputeArgs, Config, Mode, RunMode};
+use events::NatsPublisher;
+use health::Health;
+use metrics::Metrics;
+use webhook::WebhookNotifier;
 
 use metallb_v6_prefix_helper::{
-    metallb::{Connector, KubeClient},
-    prefix::{IfaceSource, PrefixSource},
-    IPV6_NETMASK,
+    metallb::{KubeClient, KubeClientOptions, LeaderElector, DEFAULT_LEASE_DURATION},
+    prefix::{
+        watch_file, CommandSource, ConfigMapSource, DhcpPdSource, DnsSource, EnvSource, FileSource,
+        FritzboxSource, HttpSource, IfaceOptions, IfaceSource, KeaEndpoint, KeaSource,
+        OpenwrtSource, PppSource, PrefixSource, RaSource, StaticSource, UpnpSource,
+    },
+    reconcile::{generate_target_range, host_mask, ReconcileOutcome, Reconciler},
 };
-use tokio::time::sleep;
+use tokio::{sync::Notify, time::sleep};
+
+const GATE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Time to let a changed config file settle, as it is often written in several steps
+const CONFIG_SETTLE_DELAY: Duration = Duration::from_millis(500);
+/// Exit status of `--once` when at least one pool was changed and none failed
+const EXIT_CHANGED: i32 = 10;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let config = Config::parse();
+    let mut config = match config::parse() {
+        Mode::Run(config) => *config,
+        Mode::Compute(args) => return compute(&args),
+    };
     Builder::new().filter_level(config.loglevel.into()).init();
     debug!("Parsed config: {:?}", config);
 
+    let iface_options = IfaceOptions {
+        prefer_route_metric: config.prefer_route_metric,
+        exclude_temporary: config.exclude_temporary,
+        exclude_deprecated: config.exclude_deprecated,
+        prefer_lifetime: config.prefer_lifetime,
+        max_address_age: config.only_if_changed_since.map(Duration::from_secs),
+        prefer_stable: config.prefer_stable_addr,
+        watch: config.mode == RunMode::Watch,
+        address_scope: config.address_scope,
+        prefix_filter: config.prefix_filter.clone(),
+        min_prefix_len: config.min_prefix_len,
+        addr_hint: config.addr_hint.clone(),
+        use_iface_prefixlen: config.use_iface_prefixlen,
+    };
     let source = match config.source {
-        config::Source::Iface => IfaceSource::try_new(config.iface.clone(), config.network_length)?,
+        config::Source::Iface => {
+            IfaceSource::try_new(config.iface.clone(), config.network_length, iface_options)?
+        }
+        #[cfg(all(target_os = "linux", feature = "netlink"))]
+        config::Source::Netlink => {
+            metallb_v6_prefix_helper::prefix::NetlinkSource::try_new(
+                config.iface.clone(),
+                config.network_length,
+                iface_options,
+            )
+            .await?
+        }
+        #[cfg(not(all(target_os = "linux", feature = "netlink")))]
+        config::Source::Netlink => {
+            warn!("The netlink source is not available in this build, using the iface source");
+            IfaceSource::try_new(config.iface.clone(), config.network_length, iface_options)?
+        }
+        config::Source::Kea => {
+            KeaSource::try_new(
+                match &config.kea_url {
+                    Some(url) => KeaEndpoint::ControlAgent(url.clone()),
+                    None => KeaEndpoint::Socket(config.kea_socket.clone()),
+                },
+                config.kea_duid.clone(),
+                config.network_length,
+            )
+            .await?
+        }
+        config::Source::Ppp => PppSource::try_new(
+            config.ppp_iface.clone(),
+            config.ppp_downstream.clone(),
+            config.network_length,
+        )?,
+        config::Source::RouterAdvert => {
+            RaSource::try_new(
+                match config.iface.as_slice() {
+                    [iface] => iface.clone(),
+                    [] => return Err("--iface is required for the router-advert source".into()),
+                    _ => {
+                        return Err(
+                            "--iface takes a single interface for the router-advert source".into(),
+                        )
+                    }
+                },
+                Duration::from_secs(config.ra_timeout),
+                config.network_length,
+            )
+            .await?
+        }
+        config::Source::Fritzbox => {
+            FritzboxSource::try_new(
+                config.fritzbox_host.clone(),
+                config.fritzbox_port,
+                config
+                    .fritzbox_user
+                    .clone()
+                    .ok_or("--fritzbox-user is required for the fritzbox source")?,
+                config
+                    .fritzbox_password
+                    .clone()
+                    .ok_or("--fritzbox-password is required for the fritzbox source")?
+                    .0,
+                Duration::from_secs(config.http_timeout),
+                config.network_length,
+            )
+            .await?
+        }
+        config::Source::Upnp => {
+            UpnpSource::try_new(
+                Duration::from_secs(config.http_timeout),
+                config.network_length,
+            )
+            .await?
+        }
+        config::Source::Openwrt => {
+            OpenwrtSource::try_new(
+                config.openwrt_url.clone(),
+                config.openwrt_user.clone(),
+                config
+                    .openwrt_password
+                    .clone()
+                    .ok_or("--openwrt-password is required for the openwrt source")?
+                    .0,
+                config.openwrt_interface.clone(),
+                Duration::from_secs(config.http_timeout),
+                config.network_length,
+            )
+            .await?
+        }
+        config::Source::Env => {
+            EnvSource::try_new(config.prefix_env.clone(), config.network_length)?
+        }
+        config::Source::Static => StaticSource::try_new(
+            config
+                .static_prefix
+                .ok_or("--static-prefix is required for the static source")?,
+            config.network_length,
+        )?,
+        config::Source::Http => {
+            HttpSource::try_new(
+                config
+                    .http_url
+                    .clone()
+                    .ok_or("--http-url is required for the http source")?,
+                config.http_json_pointer.clone(),
+                Duration::from_secs(config.http_timeout),
+                config.network_length,
+            )
+            .await?
+        }
+        config::Source::Dns => {
+            DnsSource::try_new(
+                config
+                    .dns_name
+                    .clone()
+                    .ok_or("--dns-name is required for the dns source")?,
+                config.network_length,
+            )
+            .await?
+        }
+        config::Source::Command => {
+            CommandSource::try_new(
+                config
+                    .command
+                    .clone()
+                    .ok_or("--command is required for the command source")?,
+                Duration::from_secs(config.command_timeout),
+                config.network_length,
+            )
+            .await?
+        }
+        config::Source::File => FileSource::try_new(
+            config
+                .prefix_file
+                .clone()
+                .ok_or("--prefix-file is required for the file source")?,
+            config.watch_prefix_file || config.mode == RunMode::Watch,
+            config.network_length,
+        )?,
+        config::Source::ConfigMap => {
+            ConfigMapSource::try_new(
+                config.configmap_namespace.clone(),
+                config
+                    .configmap_name
+                    .clone()
+                    .ok_or("--configmap-name is required for the configmap source")?,
+                config.configmap_key.clone(),
+                config.no_verify,
+                config.network_length,
+            )
+            .await?
+        }
+        config::Source::DhcpPd => DhcpPdSource::try_new(
+            config.dhcp_pd_lease_file.clone(),
+            config.dhcp_pd_format,
+            config.network_length,
+        )?,
+    };
+    info!("Initialized {}", source.describe());
+    if config.pool_uid.is_some() && config.metallb_address_pool.len() > 1 {
+        return Err("--pool-uid can only be used with a single pool".into());
+    }
+    // All pools follow the same source
+    let source: Arc<dyn PrefixSource> = Arc::from(source);
+
+    let metrics = match config.metrics_addr {
+        Some(addr) => {
+            let metrics = Metrics::new()?;
+            metrics.serve(addr)?;
+            Some(metrics)
+        }
+        None => None,
+    };
+    let health = match config.health_addr {
+        Some(addr) => {
+            let health = Health::new(config.unhealthy_after);
+            health.serve(addr)?;
+            Some(health)
+        }
+        None => None,
+    };
+
+    let kube_options = KubeClientOptions {
+        no_verify: config.no_verify,
+        create_pool: config.create_pool,
+        pool_creation_spec: config.pool_creation_spec.clone(),
+        update_marker: config.update_marker,
+        repair_pool: config.repair_pool,
+        preserve_address_comments: config.preserve_address_comments,
+        pool_uid: config.pool_uid.clone(),
+        dry_run: config.server_dry_run,
+        namespace: config.namespace.clone(),
+        watch_pool: config.mode == RunMode::Watch,
+        emit_events: config.emit_events,
+        log_diff: config.diff,
+        crd_version: config.crd_version.clone(),
+        patch_strategy: config.patch_strategy,
+        field_manager: Some(config.field_manager.clone()),
+        patch_retries: config.patch_retries,
+        replace_strategy: config.replace_strategy,
+        bgp_advertisement: config.bgp_advertisement.clone(),
+    };
+    let leader = match config.enable_leader_election {
+        true => {
+            let leader = LeaderElector::try_new(
+                &config.lease_name,
+                leader_identity(),
+                DEFAULT_LEASE_DURATION,
+                &kube_options,
+            )
+            .await?;
+            info!("Initialized {}", leader.describe());
+            Some(leader)
+        }
+        false => None,
+    };
+    let mut pools = match &config.pool_selector {
+        Some(selector) => {
+            let pools = KubeClient::matching_pools(selector, &kube_options).await?;
+            log_matching_pools(selector, &pools);
+            pools
+        }
+        None => config.metallb_address_pool.clone(),
     };
-    debug!("Initialized source {:?}", config.source);
-    let pool = KubeClient::try_new(config.metallb_address_pool.as_str(), config.no_verify).await?;
-    debug!("initialized MetalLB pool {:?}", config.metallb_address_pool);
 
+    let config_changes = Arc::new(Notify::new());
+    // Kept for as long as the helper runs, dropping it stops the watch
+    let _config_watcher = match (&config.config, config.reconcile_on_config_change) {
+        (Some(path), true) => Some(watch_file(path, config_changes.clone())?),
+        _ => None,
+    };
+
+    let mut first_run = true;
     loop {
-        match run(source.as_ref(), pool.as_ref(), &config).await {
-            Ok(_) => {}
-            Err(e) => error!("Error: {}", e),
+        // Reconcilers borrow the pool names, so they are dropped before the pools are looked up again
+        let restart = {
+            let mut reconcilers = Vec::new();
+            for name in &pools {
+                let pool = KubeClient::try_new(name, kube_options.clone()).await?;
+                info!("Initialized {}", pool.describe());
+                let mut reconciler = Reconciler::new(
+                    Box::new(source.clone()),
+                    pool,
+                    config.reconcile_options(name)?,
+                );
+                if let Some(url) = &config.nats_url {
+                    reconciler.add_listener(Box::new(NatsPublisher::new(
+                        url,
+                        &config.nats_subject,
+                        name,
+                    )));
+                }
+                if let Some(url) = &config.notify_webhook {
+                    reconciler.add_listener(Box::new(WebhookNotifier::new(url, name)));
+                }
+                if let Some(metrics) = &metrics {
+                    reconciler.add_listener(Box::new(metrics.listener(name, reconciler.status())));
+                }
+                if let Some(health) = &health {
+                    reconciler.add_listener(Box::new(health.listener(name)));
+                }
+                if config.dump_state_on_signal {
+                    status::dump_on_signal(
+                        name.clone(),
+                        reconciler.status(),
+                        format!("{:?}", config),
+                    );
+                }
+                reconcilers.push(reconciler);
+            }
+
+            if config.print_prefix {
+                for (name, reconciler) in pools.iter().zip(&reconcilers) {
+                    for plan in reconciler.plan().await? {
+                        println!("{}", plan.to_json(name));
+                    }
+                }
+                return Ok(());
+            }
+
+            if first_run {
+                if let Some(gate) = &config.wait_for_file {
+                    wait_for_file(gate).await;
+                }
+            }
+
+            if config.once {
+                let mut failed = 0;
+                let mut changed = false;
+                for (name, reconciler) in pools.iter().zip(&mut reconcilers) {
+                    match reconciler.reconcile_once().await {
+                        Ok(outcomes) => changed |= outcomes.iter().any(ReconcileOutcome::changed),
+                        Err(e) => {
+                            error!("Error reconciling pool {}: {}", name, e);
+                            failed += 1;
+                        }
+                    }
+                }
+                return match failed {
+                    0 if changed => std::process::exit(EXIT_CHANGED),
+                    0 => Ok(()),
+                    n => Err(
+                        format!("Reconciling {} of {} pools failed", n, reconcilers.len()).into(),
+                    ),
+                };
+            }
+
+            if first_run {
+                let jitter = jitter_delay(Duration::from_secs(config.startup_jitter));
+                if !jitter.is_zero() {
+                    info!("Waiting {:?} before the first run", jitter);
+                    sleep(jitter).await;
+                }
+                first_run = false;
+            }
+
+            if let Some(leader) = &leader {
+                info!("Waiting to become the leader");
+                tokio::select! {
+                    _ = leader.acquire() => {}
+                    _ = shutdown_signal() => return Ok(()),
+                }
+            }
+
+            // Each pool runs its own loop, so errors in one pool don't hold up the others
+            let loops = async {
+                match reconcilers.is_empty() {
+                    true => shutdown_signal().await,
+                    false => {
+                        futures::future::join_all(
+                            reconcilers
+                                .iter_mut()
+                                .map(|reconciler| reconciler.run_loop(shutdown_signal())),
+                        )
+                        .await;
+                    }
+                }
+            };
+            let changed = async {
+                match &config.pool_selector {
+                    Some(selector) => {
+                        pools_changed(
+                            selector,
+                            &kube_options,
+                            &pools,
+                            Duration::from_secs(config.interval),
+                        )
+                        .await
+                    }
+                    None => futures::future::pending().await,
+                }
+            };
+            let lost = async {
+                match &leader {
+                    Some(leader) => leader.hold().await,
+                    None => futures::future::pending().await,
+                }
+            };
+            let reloaded = async {
+                match config.reconcile_on_config_change {
+                    true => config_changed(&config_changes, &config).await,
+                    false => futures::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = loops => {
+                    if let Some(leader) = &leader {
+                        leader.release().await;
+                    }
+                    return Ok(());
+                }
+                matching = changed => Restart::Pools(matching),
+                reloaded = reloaded => Restart::Config(reloaded),
+                _ = lost => Restart::LostLeadership,
+            }
         };
-        sleep(Duration::from_secs(config.interval)).await;
+        match restart {
+            Restart::Pools(matching) => {
+                info!("Restarting the reconcilers for the changed pools");
+                if let Some(health) = &health {
+                    health.retain(&matching);
+                }
+                pools = matching;
+            }
+            Restart::Config(reloaded) => {
+                info!("Restarting the reconcilers with the changed config");
+                if reloaded.pool_selector.is_none() {
+                    if let Some(health) = &health {
+                        health.retain(&reloaded.metallb_address_pool);
+                    }
+                    pools = reloaded.metallb_address_pool.clone();
+                }
+                config = *reloaded;
+            }
+            Restart::LostLeadership => {
+                warn!("Lost the leadership, stopping the reconcilers until it is regained")
+            }
+        }
     }
 }
 
-#[cfg(test)]
-#[tokio::main]
-async fn test_run(
-    source: &dyn PrefixSource,
-    pool_conn: &dyn Connector,
-    config: &Config,
-) -> Result<(), Box<dyn Error>> {
-    run(source, pool_conn, config).await
+/// Why the reconcilers are stopped and started again
+enum Restart {
+    /// Different pools match the pool selector
+    Pools(Vec<String>),
+    /// The `--config` file changed settings that the reconcilers use
+    Config(Box<Config>),
+    LostLeadership,
 }
 
-async fn run(
-    source: &dyn PrefixSource,
-    pool_conn: &dyn Connector,
-    config: &Config,
-) -> Result<(), Box<dyn Error>> {
-    let target_network = source.v6_network()?;
-    info!("Determined desired IPv6 network to be {}", target_network);
-
-    let current_ranges = pool_conn.v6_ranges().await?;
-    info!(
-        "Found the following Ipv6 ranges in pool {}: {:?}",
-        config.metallb_address_pool, current_ranges
-    );
-    let current_range = find_dynamic_mlb_range(&current_ranges, &config.metallb_host_range);
-
-    let target_range = generate_target_range(&target_network, &config.metallb_host_range)?;
-    info!("Calculated desired MetalLB range: {}", target_range);
+fn log_matching_pools(selector: &str, pools: &[String]) {
+    match pools.is_empty() {
+        true => warn!(
+            "No pools match the selector {}, waiting for one to be created",
+            selector
+        ),
+        false => info!("Pools matching the selector {}: {:?}", selector, pools),
+    }
+}
 
-    match current_range {
-        Some(current_range) => {
-            if current_range == &target_range {
-                info!(
-                    "Target IPv6 range {} already present in MetalLB pool, nothing to do",
-                    target_range
-                );
-                Ok(())
-            } else {
-                info!(
-                    "Range in MetalLB pool ({}) outdated, replacing with new range: {}",
-                    current_range, target_range
-                );
-                if !config.dry_run {
-                    pool_conn.replace(current_range, &target_range).await?;
-                }
-                Ok(())
+/// Completes with the pools matching `selector` once they differ from `current`.
+/// The pools are looked up every `interval`, lookup errors are only logged
+async fn pools_changed(
+    selector: &str,
+    options: &KubeClientOptions,
+    current: &[String],
+    interval: Duration,
+) -> Vec<String> {
+    loop {
+        sleep(interval).await;
+        match KubeClient::matching_pools(selector, options).await {
+            Ok(pools) if pools != current => {
+                log_matching_pools(selector, &pools);
+                return pools;
             }
+            Ok(_) => {}
+            Err(e) => warn!("Unable to look up the pools matching {}: {}", selector, e),
         }
-        None => {
-            info!(
-                "No existing IPv6 range matches address pool {}, adding range {}",
-                config.metallb_address_pool, target_range
-            );
-            if !config.dry_run {
-                pool_conn.insert(&target_range).await?;
+    }
+}
+
+/// Completes with the reloaded config once the `--config` file changes any settings that the reconcilers use.
+/// Invalid files are only logged, the reconcilers keep running with the current config
+async fn config_changed(changes: &Notify, current: &Config) -> Box<Config> {
+    loop {
+        changes.notified().await;
+        sleep(CONFIG_SETTLE_DELAY).await;
+        let new = match config::try_parse() {
+            Ok(Mode::Run(new)) => new,
+            // The command line is the same as at startup, so it can't turn into a subcommand
+            Ok(Mode::Compute(_)) => continue,
+            Err(e) => {
+                warn_ignored_config(&e);
+                continue;
             }
-            info!("Pool updated");
-            Ok(())
+        };
+        match current.reloaded(&new) {
+            Ok((reloaded, restart_required)) => {
+                if restart_required {
+                    warn!(
+                        "Some of the changed settings only take effect after restarting the helper"
+                    );
+                }
+                match reloaded == *current {
+                    true => debug!("The config file changed, but no settings of the reconcilers"),
+                    false => return Box::new(reloaded),
+                }
+            }
+            Err(e) => warn_ignored_config(&e),
         }
     }
 }
 
-fn generate_target_range<'a>(
-    dyn_net: &'a Ipv6Net,
-    mlb_range: &'a Ipv6Net,
-) -> Result<Ipv6Net, PrefixLenError> {
-    let net_sanitized = u128::from(dyn_net.addr()) & IPV6_NETMASK;
-    let range_sanitized = u128::from(mlb_range.addr()) & !IPV6_NETMASK;
-
-    Ipv6Net::new(
-        (net_sanitized | range_sanitized).into(),
-        mlb_range.prefix_len(),
-    )
+fn warn_ignored_config(e: &clap::Error) {
+    // Only the first line of the rendered error carries the problem, the rest is the usage
+    let rendered = e.to_string();
+    let detail = rendered.lines().next().unwrap_or_default();
+    warn!(
+        "Ignoring the changed config file: {}",
+        detail.trim_start_matches("error: ")
+    );
+}
+
+/// Identifies this replica in the leader election lease, the pod name when running in k8s
+fn leader_identity() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| format!("metallb-dynv6-helper-{}", std::process::id()))
 }
 
-fn find_dynamic_mlb_range<'a>(ranges: &'a [Ipv6Net], host_range: &Ipv6Net) -> Option<&'a Ipv6Net> {
-    for r in ranges {
-        let host_part = u128::from(r.addr()) & !IPV6_NETMASK;
-        if Ipv6Addr::from(host_part) == host_range.addr() {
-            return Some(r);
-        }
+/// Random delay between zero and `max`
+fn jitter_delay(max: Duration) -> Duration {
+    max.mul_f64(fastrand::f64())
+}
+
+/// Completes once the process is asked to stop with Ctrl-C
+async fn shutdown_signal() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Unable to listen for Ctrl-C, running until killed: {}", e);
+        futures::future::pending::<()>().await;
     }
-    None
+}
+
+/// Prints the range generated from the prefix in `args`
+fn compute(args: &ComputeArgs) -> Result<(), Box<dyn Error>> {
+    let network = Ipv6Net::new(args.prefix.addr(), args.network_length)?.trunc();
+    let mask = host_mask(args.host_combine, args.network_length);
+    if args.verbose {
+        println!("network: {}", network);
+        println!(
+            "masked network part: {}",
+            Ipv6Addr::from(u128::from(network.addr()) & !mask)
+        );
+        println!(
+            "masked host part: {}",
+            Ipv6Addr::from(u128::from(args.host_range.addr()) & mask)
+        );
+    }
+    println!(
+        "{}",
+        generate_target_range(&network, &args.host_range, mask)?
+    );
+    Ok(())
+}
+
+/// Blocks until the given file exists
+async fn wait_for_file(path: &Path) {
+    while !path.exists() {
+        info!(
+            "Waiting for readiness gate file {} before the first run",
+            path.display()
+        );
+        sleep(GATE_POLL_INTERVAL).await;
+    }
+    debug!("Readiness gate file {} exists", path.display());
 }
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{
+        str::FromStr,
+        sync::{Mutex, MutexGuard},
+        time::Duration,
+    };
 
-    use async_trait::async_trait;
     use ipnet::Ipv6Net;
-    use metallb_v6_prefix_helper::{
-        metallb::{Connector, ConnectorError},
-        prefix::{PrefixSource, SourceError},
-    };
-    use mockall::{mock, predicate};
+    use metallb_v6_prefix_helper::reconcile::{generate_target_range, host_mask, HostCombine};
 
-    use crate::{config::Config, test_run};
+    use clap::Parser;
 
-    fn config(dry_run: bool) -> Config {
-        Config {
-            metallb_address_pool: "my-pool".to_string(),
-            metallb_host_range: Ipv6Net::from_str("::abab:cdcd:0:0/80").unwrap(),
-            iface: "eth0".to_string(),
-            dry_run,
-            ..Default::default()
-        }
-    }
+    use crate::config::{self, ComputeArgs, Config, Mode, Source};
+    use crate::jitter_delay;
 
-    const TARGET_NET: &str = "2001:db8:1111:1111::/64";
-    fn range_other() -> Ipv6Net {
-        Ipv6Net::from_str("fd42:aaaa::/64").unwrap()
-    }
-    fn range_outdated() -> Ipv6Net {
-        Ipv6Net::from_str("2001:db8:0:0:abab:cdcd:0:0/80").unwrap()
-    }
-    fn range_correct() -> Ipv6Net {
-        Ipv6Net::from_str("2001:db8:1111:1111:abab:cdcd:0:0/80").unwrap()
-    }
+    // The configuration is also read from the environment, which is shared by all tests. Tests parsing it hold
+    // this lock, so that they don't see the variables set by another test
+    static ENV: Mutex<()> = Mutex::new(());
 
-    mock! {
-        PrefixSource {}
-        impl PrefixSource for PrefixSource {
-            fn v6_network(&self) -> Result<Ipv6Net, SourceError>;
-        }
+    fn env_lock() -> MutexGuard<'static, ()> {
+        ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
     }
-    mock! {
-        Connector {}
-        #[async_trait]
-        impl Connector for Connector {
-            async fn v6_ranges(&self) -> Result<Vec<Ipv6Net>, ConnectorError>;
-            async fn replace(&self, old: &Ipv6Net, new: &Ipv6Net) -> Result<(), ConnectorError>;
-            async fn insert(&self, range: &Ipv6Net) -> Result<(), ConnectorError>;
-        }
+
+    #[test]
+    fn validates_network_length() {
+        let _env = env_lock();
+        let parse = |length: &str| {
+            Config::try_parse_from([
+                "metallb-dynv6-helper",
+                "pool",
+                "::beef:0:0:0/80",
+                "--network-length",
+                length,
+            ])
+            .map(|c| c.network_length)
+        };
+        assert!(parse("0").is_err());
+        assert_eq!(parse("64").unwrap(), 64);
+        assert_eq!(parse("128").unwrap(), 128);
+        assert!(parse("129").is_err());
     }
 
-    fn mock_source() -> MockPrefixSource {
-        let mut mock = MockPrefixSource::new();
-        mock.expect_v6_network()
-            .returning(|| Ok(Ipv6Net::from_str(TARGET_NET).unwrap()));
-        mock
+    #[test]
+    fn validates_host_range_against_network_length() {
+        let _env = env_lock();
+        let parse = |host_range: &str, combine: &str, network_length: &str| {
+            Config::try_parse_from([
+                "metallb-dynv6-helper",
+                "pool",
+                host_range,
+                "--network-length",
+                network_length,
+                "--host-combine",
+                combine,
+            ])
+            .unwrap()
+            .validate()
+        };
+        assert!(parse("::beef:0:0:0/80", "or", "64").is_ok());
+        assert!(parse("0:0:0:12::/64", "replace", "56").is_ok());
+        // The bits between /48 and /56 belong to the network
+        let err = parse("0:0:0:ab12::/64", "replace", "56").unwrap_err();
+        assert!(
+            err.to_string().contains("Did you mean 0:0:0:12::/64"),
+            "{}",
+            err
+        );
+        // Bits above /64 are never taken from the host range when combining with `or`
+        assert!(parse("0:0:0:12::/64", "or", "64").is_err());
+        // `or` would zero the bits between the network length and /64, the default combine mode is rejected
+        // for other lengths instead
+        for network_length in ["48", "56"] {
+            let err = parse("::beef:0:0:0/80", "or", network_length).unwrap_err();
+            assert!(
+                err.to_string().contains("use --host-combine replace"),
+                "{}",
+                err
+            );
+            assert!(parse("::beef:0:0:0/80", "replace", network_length).is_ok());
+        }
+        let config = Config::try_parse_from([
+            "metallb-dynv6-helper",
+            "pool",
+            "::beef:0:0:0/80",
+            "--network-length",
+            "56",
+        ])
+        .unwrap();
+        assert_eq!(config.host_combine, HostCombine::Or);
+        assert!(config.validate().is_err());
     }
 
     #[test]
-    fn creates_missing_range() {
-        let mock_source = mock_source();
-        let mut mock_connector = MockConnector::new();
-        mock_connector
-            .expect_v6_ranges()
-            .once()
-            .returning(|| Ok(vec![range_other()]));
-        mock_connector
-            .expect_insert()
-            .once()
-            .with(predicate::eq(range_correct()))
-            .returning(|_| Ok(()));
-
-        test_run(
-            Box::new(mock_source).as_ref(),
-            Box::new(mock_connector).as_ref(),
-            &config(false),
-        )
+    fn maps_host_ranges_to_pools() {
+        let _env = env_lock();
+        let config = Config::try_parse_from([
+            "metallb-dynv6-helper",
+            "pool-a,pool-b",
+            "--pool-range",
+            "pool-a=::a:0:0:0/80,pool-b=::b:0:0:0/96",
+        ])
         .unwrap();
+        assert!(config.validate().is_ok());
+        let ranges = |pool| config.reconcile_options(pool).unwrap().host_ranges;
+        assert_eq!(
+            ranges("pool-a"),
+            [Ipv6Net::from_str("::a:0:0:0/80").unwrap()]
+        );
+        assert_eq!(
+            ranges("pool-b"),
+            [Ipv6Net::from_str("::b:0:0:0/96").unwrap()]
+        );
+
+        // Pools without a mapping use the default range, if there is one
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["metallb-dynv6-helper", "pool-a,pool-b"];
+            argv.extend(args);
+            Config::try_parse_from(argv).unwrap().validate()
+        };
+        assert!(parse(&["::beef:0:0:0/80", "--pool-range", "pool-a=::a:0:0:0/80"]).is_ok());
+        let err = parse(&["--pool-range", "pool-a=::a:0:0:0/80"]).unwrap_err();
+        assert!(
+            err.to_string().contains("Pool `pool-b` has no host range"),
+            "{}",
+            err
+        );
+        assert!(parse(&["::beef:0:0:0/80", "--pool-range", "pool-c=::c:0:0:0/80"]).is_err());
+        // Each range is checked against the network length
+        assert!(parse(&["--pool-range", "pool-a=::a:0:0:0/80,pool-b=0:0:0:1::/80"]).is_err());
+        assert!(Config::try_parse_from([
+            "metallb-dynv6-helper",
+            "pool-a",
+            "--pool-range",
+            "pool-a",
+        ])
+        .is_err());
     }
 
     #[test]
-    fn updates_outdated_range() {
-        let mock_source = mock_source();
-        let mut mock_connector = MockConnector::new();
-        mock_connector
-            .expect_v6_ranges()
-            .once()
-            .returning(|| Ok(vec![range_outdated(), range_other()]));
-        mock_connector
-            .expect_replace()
-            .once()
-            .with(
-                predicate::eq(range_outdated()),
-                predicate::eq(range_correct()),
-            )
-            .returning(|_, _| Ok(()));
+    fn accepts_multiple_host_ranges() {
+        let _env = env_lock();
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["metallb-dynv6-helper", "pool-a,pool-b"];
+            argv.extend(args);
+            Config::try_parse_from(argv).and_then(|c| c.validate().map(|_| c))
+        };
 
-        test_run(
-            Box::new(mock_source).as_ref(),
-            Box::new(mock_connector).as_ref(),
-            &config(false),
-        )
+        let config = parse(&["::a:0:0:0/80,::b:0:0:0/80"]).unwrap();
+        assert_eq!(
+            config.reconcile_options("pool-b").unwrap().host_ranges,
+            [net("::a:0:0:0/80"), net("::b:0:0:0/80")]
+        );
+        // Repeating a pool in --pool-range gives it multiple ranges
+        let config = parse(&[
+            "::beef:0:0:0/80",
+            "--pool-range",
+            "pool-a=::a:0:0:0/80,pool-a=::b:0:0:0/96",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.reconcile_options("pool-a").unwrap().host_ranges,
+            [net("::a:0:0:0/80"), net("::b:0:0:0/96")]
+        );
+        assert_eq!(
+            config.reconcile_options("pool-b").unwrap().host_ranges,
+            [net("::beef:0:0:0/80")]
+        );
+
+        // Ranges that only differ in their length would match the same range in the pool
+        let err = parse(&["::a:0:0:0/80,::a:0:0:0/96"]).unwrap_err();
+        assert!(err.to_string().contains("same host bits"), "{}", err);
+        assert!(parse(&[
+            "::beef:0:0:0/80",
+            "--pool-range",
+            "pool-a=::a:0:0:0/80,pool-a=::a:0:0:0/80",
+        ])
+        .is_err());
+
+        // With --pool-selector, the only argument can hold multiple ranges as well
+        let config = Config::try_parse_from([
+            "metallb-dynv6-helper",
+            "--pool-selector",
+            "v6helper.io/managed=true",
+            "::a:0:0:0/80,::b:0:0:0/80",
+        ])
+        .and_then(Config::shift_positionals)
         .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.metallb_host_range,
+            [net("::a:0:0:0/80"), net("::b:0:0:0/80")]
+        );
     }
 
     #[test]
-    fn detects_correct_range() {
-        let mock_source = mock_source();
-        let mut mock_connector = MockConnector::new();
-        mock_connector
-            .expect_v6_ranges()
-            .once()
-            .returning(|| Ok(vec![range_correct(), range_other()]));
-        test_run(
-            Box::new(mock_source).as_ref(),
-            Box::new(mock_connector).as_ref(),
-            &config(false),
-        )
+    fn selects_pools_by_label() {
+        let _env = env_lock();
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["metallb-dynv6-helper"];
+            argv.extend(args);
+            Config::try_parse_from(argv)
+                .and_then(Config::shift_positionals)
+                .and_then(|c| c.validate().map(|_| c))
+        };
+        // The only argument is the host range
+        let config = parse(&[
+            "--pool-selector",
+            "v6helper.io/managed=true",
+            "::beef:0:0:0/80",
+        ])
         .unwrap();
+        assert!(config.metallb_address_pool.is_empty());
+        assert_eq!(
+            config.reconcile_options("any-pool").unwrap().host_ranges,
+            [Ipv6Net::from_str("::beef:0:0:0/80").unwrap()]
+        );
+
+        assert!(parse(&["--pool-selector", "v6helper.io/managed=true"]).is_err());
+        assert!(parse(&[
+            "--pool-selector",
+            "v6helper.io/managed=true",
+            "pool-a",
+            "::beef:0:0:0/80"
+        ])
+        .is_err());
+        assert!(parse(&[
+            "--pool-selector",
+            "v6helper.io/managed=true",
+            "::beef:0:0:0/80",
+            "--pool-uid",
+            "0b6f7a8e-4c1d-4f43-9a51-2f0e0c7d1e55"
+        ])
+        .is_err());
     }
 
     #[test]
-    fn respects_dry_run() {
-        // Part 1, missing range
-        let insert_source = mock_source();
-        let mut insert_connector = MockConnector::new();
-        insert_connector
-            .expect_v6_ranges()
-            .once()
-            .returning(|| Ok(vec![range_other()]));
-        test_run(
-            Box::new(insert_source).as_ref(),
-            Box::new(insert_connector).as_ref(),
-            &config(true),
+    fn loads_config_file() {
+        let _env = env_lock();
+        let dir = std::env::temp_dir().join(format!("v6helper-test-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+metallb-address-pool = ["vlan10", "vlan20"]
+metallb-host-range = "::beef:0:0:0/80"
+source = "kea"
+network-length = 56
+host-combine = "replace"
+interval = 30
+emit_events = true
+prefix-filter = ["2003::/16", "2a02::/16"]
+"#,
         )
         .unwrap();
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["metallb-dynv6-helper", "--config", path.to_str().unwrap()];
+            argv.extend(args);
+            match config::try_parse_from(argv) {
+                Ok(Mode::Run(config)) => Ok(config),
+                Ok(mode) => panic!("Expected run mode, got {:?}", mode),
+                Err(e) => Err(e),
+            }
+        };
 
-        // Part 2, update range
-        let update_source = mock_source();
-        let mut update_connector = MockConnector::new();
-        update_connector
-            .expect_v6_ranges()
-            .once()
-            .returning(|| Ok(vec![range_outdated(), range_other()]));
-        test_run(
-            Box::new(update_source).as_ref(),
-            Box::new(update_connector).as_ref(),
-            &config(true),
-        )
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.metallb_address_pool, vec!["vlan10", "vlan20"]);
+        assert_eq!(
+            config.metallb_host_range,
+            [Ipv6Net::from_str("::beef:0:0:0/80").unwrap()]
+        );
+        assert_eq!(config.source, Source::Kea);
+        assert_eq!(config.network_length, 56);
+        assert_eq!(config.interval, 30);
+        assert!(config.emit_events);
+        assert_eq!(config.prefix_filter.len(), 2);
+        // Settings missing from the file keep their defaults
+        assert_eq!(config.nats_subject, "metallb-v6-helper.reconcile");
+
+        // The command line takes precedence
+        let config = parse(&["pool-a", "--interval", "60", "--network-length", "48"]).unwrap();
+        assert_eq!(config.metallb_address_pool, vec!["pool-a"]);
+        assert_eq!(config.interval, 60);
+        assert_eq!(config.network_length, 48);
+        assert_eq!(config.source, Source::Kea);
+
+        // The merged result is validated, here a range for a pool that isn't managed
+        assert!(parse(&["--pool-range", "pool-c=::c:0:0:0/80"]).is_err());
+
+        std::fs::write(&path, "network-length = 200\n").unwrap();
+        let err = parse(&["pool", "::beef:0:0:0/80"]).unwrap_err();
+        assert!(
+            err.to_string().contains("Invalid `network-length`"),
+            "{}",
+            err
+        );
+        std::fs::write(&path, "no-such-setting = 1\n").unwrap();
+        let err = parse(&["pool", "::beef:0:0:0/80"]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Unknown setting `no-such-setting`"),
+            "{}",
+            err
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_reconciler_settings() {
+        let _env = env_lock();
+        let parse = |args: &[&str]| {
+            let mut argv = vec![
+                "metallb-dynv6-helper",
+                "pool",
+                "--network-length",
+                "56",
+                "--host-combine",
+                "replace",
+            ];
+            argv.extend(args);
+            Config::try_parse_from(argv).unwrap()
+        };
+        let current = parse(&["::beef:0:0:0/80"]);
+        let (reloaded, restart_required) = current
+            .reloaded(&parse(&[
+                "::beef:0:0:0/80",
+                "--interval",
+                "30",
+                "--dry-run",
+            ]))
+            .unwrap();
+        assert_eq!(reloaded.interval, 30);
+        assert!(reloaded.dry_run);
+        assert!(!restart_required);
+
+        // Settings that the reconcilers don't use keep their current value
+        let (reloaded, restart_required) = current
+            .reloaded(&parse(&[
+                "::beef:0:0:0/80",
+                "--interval",
+                "30",
+                "--source",
+                "kea",
+            ]))
+            .unwrap();
+        assert_eq!(reloaded.interval, 30);
+        assert_eq!(reloaded.source, current.source);
+        assert!(restart_required);
+
+        // New host ranges are checked against the network length in use
+        let new = parse(&["0:0:0:ab12::/64"]);
+        assert!(current.reloaded(&new).is_err());
+
+        assert!(Config::try_parse_from([
+            "metallb-dynv6-helper",
+            "pool",
+            "::beef:0:0:0/80",
+            "--reconcile-on-config-change"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn parses_multiple_pools() {
+        let _env = env_lock();
+        let config =
+            Config::try_parse_from(["metallb-dynv6-helper", "vlan10,vlan20", "::beef:0:0:0/80"])
+                .unwrap();
+        assert_eq!(config.metallb_address_pool, vec!["vlan10", "vlan20"]);
+        // A lone host range is taken as the pool name, which then has no host range
+        assert!(
+            Config::try_parse_from(["metallb-dynv6-helper", "::beef:0:0:0/80"])
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(Config::try_parse_from(["metallb-dynv6-helper"]).is_err());
+    }
+
+    #[test]
+    fn parses_source_and_network_length_from_env() {
+        let _env = env_lock();
+        std::env::set_var("V6HELPER_SOURCE", "kea");
+        std::env::set_var("V6HELPER_NETWORK_LENGTH", "56");
+        std::env::set_var("V6HELPER_HOST_COMBINE", "replace");
+        let mode = config::parse_from([
+            "metallb-dynv6-helper",
+            "pool",
+            "::beef:0:0:0/80",
+            "--no-verify",
+        ]);
+        std::env::remove_var("V6HELPER_SOURCE");
+        std::env::remove_var("V6HELPER_NETWORK_LENGTH");
+        std::env::remove_var("V6HELPER_HOST_COMBINE");
+        let Mode::Run(config) = mode else {
+            panic!("Expected run mode, got {:?}", mode);
+        };
+        assert_eq!(config.source, Source::Kea);
+        assert_eq!(config.network_length, 56);
+        assert!(config.no_verify);
+        assert!(!config.once);
+    }
+
+    #[test]
+    fn limits_startup_jitter() {
+        assert_eq!(jitter_delay(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(jitter_delay(Duration::from_secs(30)) <= Duration::from_secs(30));
+        }
+    }
+
+    #[test]
+    fn hides_fritzbox_password() {
+        let _env = env_lock();
+        let config = Config::try_parse_from([
+            "metallb-dynv6-helper",
+            "pool",
+            "::beef:0:0:0/80",
+            "--source",
+            "fritzbox",
+            "--fritzbox-user",
+            "helper",
+            "--fritzbox-password",
+            "hunter2",
+        ])
         .unwrap();
+        assert_eq!(config.fritzbox_password.as_ref().unwrap().0, "hunter2");
+        assert!(!format!("{:?}", config).contains("hunter2"));
+        // Both credentials are required
+        assert!(Config::try_parse_from([
+            "metallb-dynv6-helper",
+            "pool",
+            "::beef:0:0:0/80",
+            "--source",
+            "fritzbox",
+            "--fritzbox-user",
+            "helper",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn parses_compute_subcommand() {
+        let _env = env_lock();
+        let mode = config::parse_from([
+            "metallb-dynv6-helper",
+            "compute",
+            "--prefix",
+            "2003:ee:970c::/48",
+            "--host-range",
+            "::beef:0:0:0/80",
+            "--network-length",
+            "48",
+            "--host-combine",
+            "replace",
+        ]);
+        let Mode::Compute(args) = mode else {
+            panic!("Expected compute mode, got {:?}", mode);
+        };
+        assert_eq!(
+            args,
+            ComputeArgs {
+                prefix: Ipv6Net::from_str("2003:ee:970c::/48").unwrap(),
+                host_range: Ipv6Net::from_str("::beef:0:0:0/80").unwrap(),
+                network_length: 48,
+                host_combine: HostCombine::Replace,
+                verbose: false,
+            }
+        );
+        assert_eq!(
+            generate_target_range(
+                &args.prefix,
+                &args.host_range,
+                host_mask(args.host_combine, args.network_length)
+            )
+            .unwrap(),
+            Ipv6Net::from_str("2003:ee:970c:0:beef::/80").unwrap()
+        );
+        // The default combine mode only splits at /64
+        assert!(config::try_parse_from([
+            "metallb-dynv6-helper",
+            "compute",
+            "--prefix",
+            "2003:ee:970c::/48",
+            "--host-range",
+            "::beef:0:0:0/80",
+            "--network-length",
+            "48",
+        ])
+        .is_err());
     }
 }
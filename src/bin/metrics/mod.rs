@@ -0,0 +1,303 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use log::error;
+use metallb_v6_prefix_helper::reconcile::{
+    OutcomeListener, ReconcileError, ReconcileOutcome, RunStatus,
+};
+use prometheus::{Encoder, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+
+use crate::http;
+
+const NAMESPACE: &str = "metallb_v6_helper";
+
+/// Prometheus metrics of all pools, labeled by pool name
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    reconciles: IntCounterVec,
+    errors: IntCounterVec,
+    prefix_changes: IntCounterVec,
+    last_success: IntGaugeVec,
+    prefix: IntGaugeVec,
+    pending_change: IntGaugeVec,
+    api_requests: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics, prometheus::Error> {
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(NAMESPACE);
+        let metrics = Metrics {
+            registry: Registry::new(),
+            reconciles: IntCounterVec::new(
+                opts("reconciles_total", "Number of finished reconciliations"),
+                &["pool"],
+            )?,
+            errors: IntCounterVec::new(
+                opts("reconcile_errors_total", "Number of failed reconciliations"),
+                &["pool"],
+            )?,
+            prefix_changes: IntCounterVec::new(
+                opts(
+                    "prefix_changes_total",
+                    "Number of times the range in the pool was added or replaced",
+                ),
+                &["pool"],
+            )?,
+            last_success: IntGaugeVec::new(
+                opts(
+                    "last_success_timestamp_seconds",
+                    "Unix time of the last successful reconciliation",
+                ),
+                &["pool"],
+            )?,
+            prefix: IntGaugeVec::new(
+                opts(
+                    "prefix_info",
+                    "Network reported by the source and the range computed from it, always 1",
+                ),
+                &["pool", "network", "range"],
+            )?,
+            pending_change: IntGaugeVec::new(
+                opts(
+                    "pending_change",
+                    "Number of ranges whose change is deferred until the change window opens",
+                ),
+                &["pool"],
+            )?,
+            api_requests: IntCounterVec::new(
+                opts("api_requests_total", "Number of requests to the k8s API"),
+                &["pool", "kind"],
+            )?,
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.reconciles.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.errors.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.prefix_changes.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.last_success.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.prefix.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.pending_change.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.api_requests.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Listener recording the runs of the reconciler of `pool`, which reports its state through `status`
+    pub fn listener(&self, pool: &str, status: Arc<Mutex<RunStatus>>) -> MetricsListener {
+        MetricsListener {
+            metrics: self.clone(),
+            pool: pool.to_string(),
+            status,
+            reported_prefix: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Text exposition of all metrics
+    fn render(&self) -> String {
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            error!("Unable to encode metrics: {}", e);
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+
+    /// Serves the metrics on `/metrics` until the process exits
+    pub fn serve(&self, addr: SocketAddr) -> Result<(), hyper::Error> {
+        let metrics = self.clone();
+        http::serve(addr, "metrics", move |req| metrics.respond(req))
+    }
+
+    fn respond(&self, req: &Request<Body>) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/metrics") => {
+                *response.body_mut() = Body::from(self.render());
+                if let Ok(content_type) = TextEncoder::new().format_type().parse() {
+                    response.headers_mut().insert(CONTENT_TYPE, content_type);
+                }
+            }
+            _ => *response.status_mut() = StatusCode::NOT_FOUND,
+        }
+        response
+    }
+}
+
+/// Records the runs of the reconciler of one pool
+pub struct MetricsListener {
+    metrics: Metrics,
+    pool: String,
+    status: Arc<Mutex<RunStatus>>,
+    /// Labels of the prefix info metrics currently reported for the pool, one per host range
+    reported_prefix: Mutex<Vec<Vec<String>>>,
+}
+
+impl MetricsListener {
+    // Takes the network, ranges and request counts of the run from the status
+    fn record_run(&self) {
+        let (network, targets, pending, requests) = match self.status.lock() {
+            Ok(status) => (
+                status.network,
+                status.targets.clone(),
+                status.pending.len(),
+                status.api_requests,
+            ),
+            Err(poisoned) => {
+                let status = poisoned.into_inner();
+                (
+                    status.network,
+                    status.targets.clone(),
+                    status.pending.len(),
+                    status.api_requests,
+                )
+            }
+        };
+        let pool = self.pool.as_str();
+        self.metrics.reconciles.with_label_values(&[pool]).inc();
+        self.metrics
+            .pending_change
+            .with_label_values(&[pool])
+            .set(pending as i64);
+        self.metrics
+            .api_requests
+            .with_label_values(&[pool, "read"])
+            .inc_by(requests.reads);
+        self.metrics
+            .api_requests
+            .with_label_values(&[pool, "write"])
+            .inc_by(requests.writes);
+        if let Some(network) = network.filter(|_| !targets.is_empty()) {
+            let current: Vec<_> = targets
+                .iter()
+                .map(|target| vec![pool.to_string(), network.to_string(), target.to_string()])
+                .collect();
+            let mut reported = match self.reported_prefix.lock() {
+                Ok(reported) => reported,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            // Only the current prefixes are reported
+            for previous in reported.iter().filter(|p| !current.contains(p)) {
+                let previous: Vec<_> = previous.iter().map(String::as_str).collect();
+                let _ = self.metrics.prefix.remove_label_values(&previous);
+            }
+            for labels in &current {
+                let labels: Vec<_> = labels.iter().map(String::as_str).collect();
+                self.metrics.prefix.with_label_values(&labels).set(1);
+            }
+            *reported = current;
+        }
+    }
+}
+
+#[async_trait]
+impl OutcomeListener for MetricsListener {
+    async fn outcome(&self, outcomes: &[ReconcileOutcome]) {
+        self.record_run();
+        let pool = self.pool.as_str();
+        let changes = outcomes.iter().filter(|o| o.changed()).count();
+        self.metrics
+            .prefix_changes
+            .with_label_values(&[pool])
+            .inc_by(changes as u64);
+        self.metrics
+            .last_success
+            .with_label_values(&[pool])
+            .set(Utc::now().timestamp());
+    }
+
+    async fn error(&self, _error: &ReconcileError) {
+        self.record_run();
+        self.metrics.errors.with_label_values(&[&self.pool]).inc();
+    }
+
+    // Counted as a single failed run, the changes made before the failure are still counted
+    async fn partial_failure(&self, outcomes: &[ReconcileOutcome], _error: &ReconcileError) {
+        self.record_run();
+        let pool = self.pool.as_str();
+        let changes = outcomes.iter().filter(|o| o.changed()).count();
+        self.metrics
+            .prefix_changes
+            .with_label_values(&[pool])
+            .inc_by(changes as u64);
+        self.metrics.errors.with_label_values(&[pool]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        sync::{Arc, Mutex},
+    };
+
+    use ipnet::Ipv6Net;
+    use metallb_v6_prefix_helper::reconcile::{
+        OutcomeListener, ReconcileError, ReconcileOutcome, RunStatus,
+    };
+
+    use super::Metrics;
+
+    #[tokio::test]
+    async fn records_runs() {
+        let metrics = Metrics::new().unwrap();
+        let status = Arc::new(Mutex::new(RunStatus::default()));
+        let listener = metrics.listener("my-pool", status.clone());
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+
+        {
+            let mut status = status.lock().unwrap();
+            status.network = Some(net("2003:ee:970c:80aa::/64"));
+            status.targets = vec![net("2003:ee:970c:80aa:beef::/80")];
+        }
+        listener
+            .outcome(&[ReconcileOutcome::Inserted(net(
+                "2003:ee:970c:80aa:beef::/80",
+            ))])
+            .await;
+        {
+            let mut status = status.lock().unwrap();
+            status.network = Some(net("2003:ee:970c:80bb::/64"));
+            status.targets = vec![net("2003:ee:970c:80bb:beef::/80")];
+            status.pending = vec![net("2003:ee:970c:80bb:beef::/80")];
+        }
+        listener.outcome(&[ReconcileOutcome::NoChange]).await;
+        listener
+            .error(&ReconcileError::Panic("test".to_string()))
+            .await;
+        // A run failing after one of its ranges was changed counts once, along with its change
+        listener
+            .partial_failure(
+                &[ReconcileOutcome::Inserted(net(
+                    "2003:ee:970c:80bb:beef::/80",
+                ))],
+                &ReconcileError::Panic("test".to_string()),
+            )
+            .await;
+
+        let text = metrics.render();
+        assert!(text.contains(r#"metallb_v6_helper_reconciles_total{pool="my-pool"} 4"#));
+        assert!(text.contains(r#"metallb_v6_helper_reconcile_errors_total{pool="my-pool"} 2"#));
+        assert!(text.contains(r#"metallb_v6_helper_prefix_changes_total{pool="my-pool"} 2"#));
+        assert!(text.contains(r#"metallb_v6_helper_pending_change{pool="my-pool"} 1"#));
+        assert!(text.contains(
+            r#"metallb_v6_helper_prefix_info{network="2003:ee:970c:80bb::/64",pool="my-pool",range="2003:ee:970c:80bb:beef::/80"} 1"#
+        ));
+        assert!(!text.contains("80aa"));
+    }
+}
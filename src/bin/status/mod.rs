@@ -0,0 +1,42 @@
+use std::sync::{Arc, Mutex};
+
+use log::{error, info};
+use metallb_v6_prefix_helper::reconcile::RunStatus;
+
+/// Logs the current status of the pool and the resolved config whenever the process receives SIGUSR1.
+/// The dump runs on its own task, so it also works while a run is stuck.
+/// It ends once the reconciler that owns the status is dropped
+#[cfg(unix)]
+pub fn dump_on_signal(pool: String, status: Arc<Mutex<RunStatus>>, config: String) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!(
+                "Unable to listen for SIGUSR1, state dumps are disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    let status = Arc::downgrade(&status);
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            let Some(status) = status.upgrade() else {
+                return;
+            };
+            let summary = match status.lock() {
+                Ok(status) => status.summary(),
+                Err(poisoned) => poisoned.into_inner().summary(),
+            };
+            info!("State dump of {}: {}", pool, summary);
+            info!("State dump: config={}", config);
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn dump_on_signal(_pool: String, _status: Arc<Mutex<RunStatus>>, _config: String) {
+    log::warn!("State dumps on signal are only supported on Unix");
+}
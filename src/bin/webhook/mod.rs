@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::{header, Body, Method, Request, Uri};
+use log::{info, warn};
+use metallb_v6_prefix_helper::{
+    prefix::{http_client, HttpClient},
+    reconcile::{OutcomeListener, ReconcileOutcome},
+};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::time::timeout;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum WebhookError {
+    #[error("Error while calling the webhook: `{0}`")]
+    Request(#[from] hyper::Error),
+    #[error("The webhook returned status {0}")]
+    Status(u16),
+    #[error("Timed out while calling the webhook")]
+    Timeout,
+}
+
+/// POSTs pool changes as JSON to a webhook, e.g. a Slack incoming webhook.
+/// The payload carries a `text` summary besides the structured fields, which is what Slack displays
+pub struct WebhookNotifier {
+    url: Uri,
+    /// Name of the pool included in the notifications
+    pool: String,
+    /// Like the HTTP sources, a system without CA certificates only breaks https:// webhooks
+    client: HttpClient,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &Uri, pool: &str) -> WebhookNotifier {
+        WebhookNotifier {
+            url: url.clone(),
+            pool: pool.to_string(),
+            client: http_client(),
+        }
+    }
+
+    async fn notify(&self, payload: &Value) -> Result<(), WebhookError> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()))
+            .expect("request from a valid URI");
+        let response = timeout(WEBHOOK_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| WebhookError::Timeout)??;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(WebhookError::Status(response.status().as_u16())),
+        }
+    }
+}
+
+#[async_trait]
+impl OutcomeListener for WebhookNotifier {
+    /// Notifies the webhook of each outcome that changed the pool. Errors are logged and otherwise ignored
+    async fn outcome(&self, outcomes: &[ReconcileOutcome]) {
+        for outcome in outcomes {
+            let Some(payload) = webhook_payload(&self.pool, outcome, Utc::now()) else {
+                continue;
+            };
+            match self.notify(&payload).await {
+                Ok(_) => info!("Sent pool change to webhook {}", self.url),
+                Err(e) => warn!("Unable to send pool change to webhook {}: {}", self.url, e),
+            }
+        }
+    }
+}
+
+/// Builds the JSON payload for an outcome, or `None` if the pool was not changed
+fn webhook_payload(
+    pool: &str,
+    outcome: &ReconcileOutcome,
+    timestamp: DateTime<Utc>,
+) -> Option<Value> {
+    let (old, new) = match outcome {
+        ReconcileOutcome::NoChange | ReconcileOutcome::Deferred(_) => return None,
+        ReconcileOutcome::Inserted(range) => (None, range),
+        ReconcileOutcome::Replaced { old, new } => (Some(old), new),
+    };
+    let text = match old {
+        Some(old) => format!("Prefix of pool {} changed: {} -> {}", pool, old, new),
+        None => format!("Added {} to pool {}", new, pool),
+    };
+    Some(json!({
+        "text": text,
+        "pool": pool,
+        "old": old.map(|o| o.to_string()),
+        "new": new.to_string(),
+        "timestamp": timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Utc};
+    use hyper::Uri;
+    use ipnet::Ipv6Net;
+    use metallb_v6_prefix_helper::reconcile::ReconcileOutcome;
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{webhook_payload, WebhookNotifier};
+
+    #[test]
+    fn builds_webhook_payload() {
+        let old = Ipv6Net::from_str("2001:db8:0:0:abab:cdcd:0:0/80").unwrap();
+        let new = Ipv6Net::from_str("2001:db8:1111:1111:abab:cdcd:0:0/80").unwrap();
+        let timestamp = DateTime::parse_from_rfc3339("2024-03-01T04:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert!(webhook_payload("my-pool", &ReconcileOutcome::NoChange, timestamp).is_none());
+        assert!(webhook_payload("my-pool", &ReconcileOutcome::Deferred(new), timestamp).is_none());
+
+        assert_eq!(
+            webhook_payload(
+                "my-pool",
+                &ReconcileOutcome::Replaced { old, new },
+                timestamp
+            )
+            .unwrap(),
+            json!({
+                "text": "Prefix of pool my-pool changed: 2001:db8::abab:cdcd:0:0/80 -> 2001:db8:1111:1111:abab:cdcd::/80",
+                "pool": "my-pool",
+                "old": "2001:db8::abab:cdcd:0:0/80",
+                "new": "2001:db8:1111:1111:abab:cdcd::/80",
+                "timestamp": "2024-03-01T04:30:00Z",
+            })
+        );
+        let inserted =
+            webhook_payload("my-pool", &ReconcileOutcome::Inserted(new), timestamp).unwrap();
+        assert_eq!(inserted["old"], Value::Null);
+        assert_eq!(inserted["new"], "2001:db8:1111:1111:abab:cdcd::/80");
+    }
+
+    #[tokio::test]
+    async fn posts_to_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Uri::from_str(&format!(
+            "http://{}/hooks/prefix",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // The header and the body may arrive separately
+            let mut request = String::new();
+            while !request.ends_with('}') {
+                let mut buf = [0u8; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                assert!(len > 0, "connection closed: {}", request);
+                request.push_str(&String::from_utf8_lossy(&buf[..len]));
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+            request
+        });
+
+        WebhookNotifier::new(&url, "my-pool")
+            .notify(&json!({"pool": "my-pool"}))
+            .await
+            .unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hooks/prefix HTTP/1.1\r\n"));
+        assert!(request.contains("content-type: application/json\r\n"));
+        assert!(request.ends_with(r#"{"pool":"my-pool"}"#));
+    }
+}
@@ -1,6 +1,46 @@
-pub const IPV6_NETMASK: u128 = u128::from_be_bytes([
-    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0,
-]);
+//! Keeps the dynamic range of a MetalLB address pool in sync with a changing IPv6 prefix.
+//!
+//! The building blocks can be used on their own, e.g. to embed the helper into an operator:
+//! a [`prefix::PrefixSource`] reports the current network, a [`metallb::Connector`] reads and updates the pool,
+//! and [`reconcile::reconcile`] or a [`reconcile::Reconciler`] bring the two together.
+//! The range computation itself is available through [`reconcile::generate_target_range`] and
+//! [`reconcile::find_dynamic_mlb_range`].
+
+/// Returns the mask selecting the upper `prefix_len` bits of an address, i.e. its network part.
+/// Lengths above 128 are treated as 128
+pub fn netmask_for(prefix_len: u8) -> u128 {
+    u128::MAX
+        .checked_shl(u32::from(128u8.saturating_sub(prefix_len)))
+        .unwrap_or(0)
+}
+
+/// IPv4 counterpart of [`netmask_for`], lengths above 32 are treated as 32
+pub fn netmask_for_v4(prefix_len: u8) -> u32 {
+    u32::MAX
+        .checked_shl(u32::from(32u8.saturating_sub(prefix_len)))
+        .unwrap_or(0)
+}
 
 pub mod metallb;
 pub mod prefix;
+pub mod reconcile;
+
+#[cfg(test)]
+mod tests {
+    use super::{netmask_for, netmask_for_v4};
+
+    #[test]
+    fn masks_network_part() {
+        assert_eq!(netmask_for(48), 0xffff_ffff_ffff << 80);
+        assert_eq!(netmask_for(56), 0xff_ffff_ffff_ffff << 72);
+        assert_eq!(netmask_for(64), 0xffff_ffff_ffff_ffff << 64);
+        assert_eq!(netmask_for(0), 0);
+        assert_eq!(netmask_for(128), u128::MAX);
+        assert_eq!(netmask_for(200), u128::MAX);
+
+        assert_eq!(netmask_for_v4(24), 0xffff_ff00);
+        assert_eq!(netmask_for_v4(0), 0);
+        assert_eq!(netmask_for_v4(32), u32::MAX);
+        assert_eq!(netmask_for_v4(40), u32::MAX);
+    }
+}
@@ -1,58 +1,164 @@
-use std::str::FromStr;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use ipnet::Ipv6Net;
+use futures::StreamExt;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use k8s_openapi::{
-    apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition,
-    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    api::core::v1::{Event, EventSource, ObjectReference},
+    apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+        CustomResourceDefinition, JSONSchemaProps, JSONSchemaPropsOrArray,
+    },
+    apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time},
+    chrono::{DateTime, SecondsFormat, Utc},
 };
 use kube::{
-    api::{Patch, PatchParams},
+    api::{ApiResource, DynamicObject, ListParams, Patch, PatchParams, PostParams, WatchEvent},
     client::ConfigExt,
-    Api, Client, Config, CustomResource,
+    Api, Client, Config, CustomResource, CustomResourceExt, Resource,
 };
 use log::{debug, info, warn};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use thiserror::Error;
+use tokio::{sync::Notify, task::JoinHandle, time::sleep};
 use tower::ServiceBuilder;
 
-use super::{Connector, ConnectorError};
+use super::{
+    Connector, ConnectorError, ConnectorErrorKind, PatchStrategy, ReplaceStrategy, RequestCounts,
+    UpdateMarker,
+};
 
 const METALLB_IPADDRPOOL_CRD_NAME: &str = "ipaddresspools.metallb.io";
+// Deprecated in MetalLB 0.13 in favour of IPAddressPool and removed in 0.14.
+// MetalLB releases before 0.13 were configured through a ConfigMap and are not supported.
+const METALLB_LEGACY_ADDRPOOL_CRD_NAME: &str = "addresspools.metallb.io";
+const LEGACY_DEFAULT_PROTOCOL: &str = "layer2";
+const ANNOTATION_LAST_UPDATE: &str = "metallb-v6-helper/last-update";
+const ANNOTATION_UPDATE_COUNTER: &str = "metallb-v6-helper/update-counter";
+const ANNOTATION_OBSERVED_GENERATION: &str = "metallb-v6-helper/observed-generation";
+const ANNOTATION_ADDRESS_COMMENTS: &str = "metallb-v6-helper/address-comments";
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(10);
+// Grows linearly with every retry of a failed update
+const PATCH_RETRY_DELAY: Duration = Duration::from_millis(500);
+const EVENT_COMPONENT: &str = "metallb-v6-helper";
+/// Default field manager for server-side apply
+pub const DEFAULT_FIELD_MANAGER: &str = "metallb-v6-helper";
 
 #[derive(Error, Debug)]
 enum K8sError {
-    #[error("Error while accessing the k8s API: `{0}`")]
-    ConnectionError(String),
+    #[error("Error while accessing the k8s API: `{1}`")]
+    ConnectionError(ConnectorErrorKind, String),
     #[error("Could not find MetalLB AddressPool with name `{0}`")]
     PoolNotFound(String),
-    #[error("MetalLB IPAddressPool CRD does not exist, please make sure that it is installed")]
+    #[error("Could not find MetalLB AddressPool with UID `{0}`")]
+    PoolUidNotFound(String),
+    #[error("Neither the MetalLB IPAddressPool nor the legacy AddressPool CRD exist, please make sure that MetalLB 0.13 or later is installed")]
     CRDNotFound,
+    #[error("Version `{1}` of CRD `{0}` is not served, the served versions are {2:?}")]
+    CRDVersionNotServed(String, String, Vec<String>),
+    // Only logged, as the schema check is best effort
+    #[error("Schema of CRD `{0}` version `{1}` differs from what the helper expects, pool updates may misbehave: {2}")]
+    UnexpectedSchema(String, String, String),
     #[error("Could not replace range `{0}` with `{1}` as it does not exist")]
     RangeNotFound(String, String),
+    #[error("Range `{0}` is missing from the pool after adding it, not removing `{1}`")]
+    RangeNotAdded(String, String),
     #[error("Error while updating the ResourcePool: `{0}`")]
     PoolUpdateError(String),
+    #[error("The API server rejected the update of the ResourcePool with status {0}: `{1}`")]
+    PoolUpdateRejected(u16, String),
+    #[error("Invalid pool creation spec: `{0}`")]
+    InvalidPoolSpec(String),
+    #[error("Error while creating the IPAddressPool: `{0}`")]
+    PoolCreationError(String),
+    #[error("IPAddressPool `{0}` is malformed: spec `{1}`: `{2}`")]
+    MalformedPool(String, String, String),
+    #[error("Could not find MetalLB BGPAdvertisement with name `{0}`")]
+    AdvertisementNotFound(String),
+    #[error("Error while updating the BGPAdvertisement `{0}`: `{1}`")]
+    AdvertisementUpdateError(String, String),
+}
+impl K8sError {
+    // Failed updates that are likely to succeed when retried with a freshly read pool
+    fn is_transient(&self) -> bool {
+        match self {
+            K8sError::PoolUpdateError(_) => true,
+            K8sError::PoolUpdateRejected(code, _) => *code == 409 || *code == 429 || *code >= 500,
+            _ => false,
+        }
+    }
+
+    fn kind(&self) -> ConnectorErrorKind {
+        match self {
+            K8sError::ConnectionError(kind, _) => *kind,
+            K8sError::PoolNotFound(_)
+            | K8sError::PoolUidNotFound(_)
+            | K8sError::CRDNotFound
+            | K8sError::RangeNotFound(..)
+            | K8sError::AdvertisementNotFound(_) => ConnectorErrorKind::NotFound,
+            K8sError::PoolUpdateError(_) => ConnectorErrorKind::Network,
+            K8sError::PoolUpdateRejected(code, _) => status_kind(*code),
+            _ => ConnectorErrorKind::Other,
+        }
+    }
+}
+
+fn status_kind(code: u16) -> ConnectorErrorKind {
+    match code {
+        401 | 403 => ConnectorErrorKind::AuthFailed,
+        404 => ConnectorErrorKind::NotFound,
+        409 => ConnectorErrorKind::Conflict,
+        _ => ConnectorErrorKind::Other,
+    }
+}
+
+fn error_kind(e: &kube::Error) -> ConnectorErrorKind {
+    match e {
+        kube::Error::Api(response) => status_kind(response.code),
+        kube::Error::HyperError(_) | kube::Error::Service(_) | kube::Error::ReadEvents(_) => {
+            ConnectorErrorKind::Network
+        }
+        kube::Error::Auth(_) => ConnectorErrorKind::AuthFailed,
+        _ => ConnectorErrorKind::Other,
+    }
+}
+
+fn connection_error(e: kube::Error) -> K8sError {
+    K8sError::ConnectionError(error_kind(&e), e.to_string())
+}
+
+fn update_error(e: kube::Error) -> K8sError {
+    match e {
+        kube::Error::Api(response) => K8sError::PoolUpdateRejected(response.code, response.message),
+        e => K8sError::PoolUpdateError(e.to_string()),
+    }
 }
+
 impl From<K8sError> for ConnectorError {
     fn from(value: K8sError) -> Self {
-        ConnectorError {
-            msg: value.to_string(),
-        }
+        ConnectorError::new(value.kind(), value.to_string())
     }
 }
 impl From<kube::Error> for ConnectorError {
     fn from(value: kube::Error) -> Self {
-        ConnectorError {
-            msg: value.to_string(),
-        }
+        ConnectorError::new(error_kind(&value), value.to_string())
     }
 }
 impl From<kube::config::InferConfigError> for ConnectorError {
     fn from(value: kube::config::InferConfigError) -> Self {
-        ConnectorError {
-            msg: value.to_string(),
-        }
+        ConnectorError::new(ConnectorErrorKind::Other, value.to_string())
     }
 }
 
@@ -68,69 +174,411 @@ impl From<kube::config::InferConfigError> for ConnectorError {
 #[allow(non_snake_case)]
 struct IPAddressPoolSpec {
     addresses: Vec<String>,
+    // Unset fields are left out of merge patches, null would reset the user's settings
+    #[serde(skip_serializing_if = "Option::is_none")]
     autoAssign: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     avoidBuggyIPs: Option<bool>,
+    // Like the settings above, this is never part of a patch, so it is left to whoever manages it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serviceAllocation: Option<ServiceAllocation>,
+}
+
+// Restricts the pool to some namespaces or services, only present in newer MetalLB releases
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, Default, PartialEq)]
+#[allow(non_snake_case)]
+struct ServiceAllocation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespaces: Option<Vec<String>>,
+    // Label selectors are passed through as they are
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespaceSelectors: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serviceSelectors: Option<Vec<Value>>,
+}
+
+// Only the fields that are kept consistent with the pool, the others are left out of patches
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema, Default)]
+#[kube(
+    group = "metallb.io",
+    version = "v1beta1",
+    kind = "BGPAdvertisement",
+    namespaced
+)]
+#[allow(non_snake_case)]
+struct BGPAdvertisementSpec {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aggregationLength: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aggregationLengthV6: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipAddressPools: Option<Vec<String>>,
+}
+
+/// Additional settings for connecting to and managing the pool
+#[derive(Debug, Clone, Default)]
+pub struct KubeClientOptions {
+    /// Don't validate the k8s API server certificates
+    pub no_verify: bool,
+    /// Create the pool if it doesn't exist yet instead of returning an error
+    pub create_pool: bool,
+    /// YAML or JSON IPAddressPool manifest used as the base for newly created pools
+    pub pool_creation_spec: Option<PathBuf>,
+    /// How updates to the pool are recorded in its annotations
+    pub update_marker: UpdateMarker,
+    /// Try to fix pools with malformed addresses instead of returning an error
+    pub repair_pool: bool,
+    /// Carry over comments from the address comments annotation when replacing a range
+    pub preserve_address_comments: bool,
+    /// Find the pool by its UID instead of its name, so that it is still found after being renamed
+    pub pool_uid: Option<String>,
+    /// Send all changes as server-side dry-run requests, which are validated but not persisted
+    pub dry_run: bool,
+    /// Namespace of the pool, the namespace of the kube config or service account is used if not set
+    pub namespace: Option<String>,
+    /// Watch the pool and report changes made by others, so that they are corrected right away
+    pub watch_pool: bool,
+    /// Record a k8s Event on the pool whenever its range is changed
+    pub emit_events: bool,
+    /// Log the added and removed addresses of every patch at info level
+    pub log_diff: bool,
+    /// Version of the pool CRD to use, e.g. `v1beta2`. Detected from the served versions if not set
+    pub crd_version: Option<String>,
+    /// How changes to the pool are sent to the API server
+    pub patch_strategy: PatchStrategy,
+    /// Field manager recorded for changes to the pool, defaults to [`DEFAULT_FIELD_MANAGER`]
+    pub field_manager: Option<String>,
+    /// Number of times a failed change to the pool is retried right away, if the error is likely transient (e.g. a conflict)
+    pub patch_retries: u32,
+    /// Whether a range is replaced in a single patch or by adding the new range before removing the old one
+    pub replace_strategy: ReplaceStrategy,
+    /// Name of a BGPAdvertisement in the namespace of the pool that is kept consistent with the pool after every change
+    pub bgp_advertisement: Option<String>,
 }
 
 pub struct KubeClient<'a> {
     name: &'a str,
+    namespace: String,
     client: Client,
+    create_pool: bool,
+    pool_template: Option<Value>,
+    update_marker: UpdateMarker,
+    repair_pool: bool,
+    preserve_address_comments: bool,
+    pool_uid: Option<String>,
+    dry_run: bool,
+    emit_events: bool,
+    log_diff: bool,
+    patch_strategy: PatchStrategy,
+    field_manager: String,
+    patch_retries: u32,
+    replace_strategy: ReplaceStrategy,
+    bgp_advertisement: Option<String>,
+    changes: Option<Arc<Notify>>,
+    watch_task: Option<JoinHandle<()>>,
+    requests: Arc<RequestCounter>,
+    /// The pool resource served by the cluster, either an IPAddressPool or a legacy AddressPool
+    resource: ApiResource,
+}
+
+// Counts the requests sent to the API server, shared with the client's service stack
+#[derive(Debug, Default)]
+struct RequestCounter {
+    reads: AtomicU64,
+    writes: AtomicU64,
+}
+impl RequestCounter {
+    fn record(&self, method: &hyper::Method) {
+        let counter = match *method {
+            hyper::Method::GET | hyper::Method::HEAD => &self.reads,
+            _ => &self.writes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 impl KubeClient<'_> {
-    /// Connects to the k8s API and looks for a MetalLB IpAddressPool with the given name in the default namespace
+    #[cfg(test)]
+    fn test_new(name: &str, client: Client, requests: Arc<RequestCounter>) -> KubeClient<'_> {
+        KubeClient {
+            name,
+            namespace: "default".to_string(),
+            client,
+            create_pool: false,
+            pool_template: None,
+            update_marker: UpdateMarker::default(),
+            repair_pool: false,
+            preserve_address_comments: false,
+            pool_uid: None,
+            dry_run: false,
+            emit_events: false,
+            log_diff: false,
+            patch_strategy: PatchStrategy::default(),
+            field_manager: DEFAULT_FIELD_MANAGER.to_string(),
+            patch_retries: 0,
+            replace_strategy: ReplaceStrategy::default(),
+            bgp_advertisement: None,
+            changes: None,
+            watch_task: None,
+            requests,
+            resource: ApiResource::erase::<IPAddressPool>(&()),
+        }
+    }
+
+    /// Connects to the k8s API and looks for a MetalLB IpAddressPool with the given name in the configured namespace.
     /// An error is returned if no pool is found.
     pub async fn try_new(
         name: &str,
-        no_verify: bool,
+        options: KubeClientOptions,
     ) -> Result<Box<dyn Connector + '_>, ConnectorError> {
-        let mut cfg = Config::infer().await?;
-        cfg.accept_invalid_certs = no_verify;
-        debug!("Inferred kube config: {:?}", cfg);
-
-        let service = ServiceBuilder::new()
-            .layer(cfg.base_uri_layer())
-            .option_layer(cfg.auth_layer()?)
-            .service(hyper::Client::builder().build(cfg.rustls_https_connector()?));
-        let c = Client::new(service, cfg.default_namespace);
+        let pool_template = match &options.pool_creation_spec {
+            Some(path) => Some(read_pool_template(path)?),
+            None => None,
+        };
 
-        let crds: Api<CustomResourceDefinition> = Api::all(c.clone());
-        let p = crds.get_opt(METALLB_IPADDRPOOL_CRD_NAME).await?;
+        let Connection {
+            client: c,
+            namespace,
+            resource,
+            requests,
+        } = connect(&options).await?;
 
-        if p.is_none() {
-            return Err(K8sError::CRDNotFound.into());
+        let mut kclient = KubeClient {
+            name,
+            namespace,
+            client: c,
+            create_pool: options.create_pool,
+            pool_template,
+            update_marker: options.update_marker,
+            repair_pool: options.repair_pool,
+            preserve_address_comments: options.preserve_address_comments,
+            pool_uid: options.pool_uid,
+            dry_run: options.dry_run,
+            emit_events: options.emit_events,
+            log_diff: options.log_diff,
+            patch_strategy: options.patch_strategy,
+            field_manager: options
+                .field_manager
+                .unwrap_or_else(|| DEFAULT_FIELD_MANAGER.to_string()),
+            patch_retries: options.patch_retries,
+            replace_strategy: options.replace_strategy,
+            bgp_advertisement: options.bgp_advertisement,
+            changes: None,
+            watch_task: None,
+            requests,
+            resource,
+        };
+        if options.watch_pool {
+            let changes = Arc::new(Notify::new());
+            // Pools found by UID may be renamed, so all pools in the namespace are watched
+            let name = match kclient.pool_uid {
+                Some(_) => None,
+                None => Some(name.to_string()),
+            };
+            kclient.watch_task = Some(tokio::spawn(watch_pool(
+                kclient.pools_api(),
+                name,
+                changes.clone(),
+            )));
+            kclient.changes = Some(changes);
         }
 
-        let kclient = KubeClient { name, client: c };
-
         match kclient.find_pool().await {
             Ok(_) => {}
+            Err(K8sError::PoolNotFound(_)) if kclient.create_pool => {
+                info!(
+                    "{} {} does not exist yet, it will be created",
+                    kclient.resource.kind, name
+                )
+            }
+            // A pinned UID that doesn't exist won't appear later on, this is a configuration error
+            Err(e @ K8sError::PoolUidNotFound(_)) => return Err(e.into()),
             Err(e) => {
                 warn!(
-                    "Error encountered when trying to read IPAddressPool, continuing: {}",
-                    e
+                    "Error encountered when trying to read {}, continuing: {}",
+                    kclient.resource.kind, e
                 )
             }
         }
         Ok(Box::new(kclient))
     }
 
+    /// Names of the pools in the configured namespace that match the label selector, e.g. `v6helper.io/managed=true`
+    pub async fn matching_pools(
+        selector: &str,
+        options: &KubeClientOptions,
+    ) -> Result<Vec<String>, ConnectorError> {
+        let conn = connect(options).await?;
+        let pools_api = Api::namespaced_with(conn.client, &conn.namespace, &conn.resource);
+        Ok(list_pool_names(&pools_api, selector).await?)
+    }
+
+    // Pools are accessed untyped, so that the same code handles both pool kinds and
+    // so that we can report the content of pools that don't match the expected schema
+    fn pools_api(&self) -> Api<DynamicObject> {
+        Api::namespaced_with(self.client.clone(), &self.namespace, &self.resource)
+    }
+
+    fn patch_params(&self) -> PatchParams {
+        PatchParams {
+            dry_run: self.dry_run,
+            field_manager: Some(self.field_manager.clone()),
+            ..Default::default()
+        }
+    }
+
+    fn post_params(&self) -> PostParams {
+        PostParams {
+            dry_run: self.dry_run,
+            field_manager: Some(self.field_manager.clone()),
+        }
+    }
+
     async fn find_pool(&self) -> Result<IPAddressPool, K8sError> {
-        let pools_api: Api<IPAddressPool> = Api::default_namespaced(self.client.clone());
+        let pools_api = self.pools_api();
 
-        match pools_api.get_opt(self.name).await {
-            Ok(p) => match p {
-                Some(p) => Ok(p),
-                None => Err(K8sError::PoolNotFound(self.name.to_string())),
+        let raw = match &self.pool_uid {
+            Some(uid) => match pools_api.list(&ListParams::default()).await {
+                Ok(pools) => pool_by_uid(pools.items, uid)
+                    .ok_or_else(|| K8sError::PoolUidNotFound(uid.to_string()))?,
+                Err(e) => return Err(connection_error(e)),
             },
-            Err(e) => Err(K8sError::ConnectionError(e.to_string())),
+            None => match pools_api.get_opt(self.name).await {
+                Ok(Some(p)) => p,
+                Ok(None) => return Err(K8sError::PoolNotFound(self.name.to_string())),
+                Err(e) => return Err(connection_error(e)),
+            },
+        };
+        let name = raw.metadata.name.clone().unwrap_or_default();
+        match parse_pool(&raw) {
+            Err(K8sError::MalformedPool(_, content, _)) if self.repair_pool => {
+                let Some(addresses) = repair_addresses(&raw.data["spec"]["addresses"]) else {
+                    return Err(K8sError::MalformedPool(
+                        name,
+                        content,
+                        "unable to repair".to_string(),
+                    ));
+                };
+                warn!(
+                    "IPAddressPool {} is malformed ({}), repairing addresses to {:?}",
+                    name, content, addresses
+                );
+                let patch = json!({"spec": {"addresses": addresses}});
+                match pools_api
+                    .patch(&name, &self.patch_params(), &Patch::Merge(patch))
+                    .await
+                {
+                    Ok(repaired) => parse_pool(&repaired),
+                    Err(e) => Err(K8sError::PoolUpdateError(e.to_string())),
+                }
+            }
+            r => r,
+        }
+    }
+
+    async fn create(&self, range: &IpNet) -> Result<(), K8sError> {
+        let pools_api = self.pools_api();
+
+        let pool = pool_from_template(
+            self.pool_template.as_ref(),
+            &self.resource,
+            self.name,
+            range,
+        )?;
+        debug!("Generated pool: {}", pool);
+        let pool: DynamicObject =
+            serde_json::from_value(pool).map_err(|e| K8sError::InvalidPoolSpec(e.to_string()))?;
+
+        match pools_api.create(&self.post_params(), &pool).await {
+            Ok(created) => {
+                info!(
+                    "Created {} {} with range {}",
+                    self.resource.kind, self.name, range
+                );
+                self.emit_event(
+                    &created.metadata,
+                    "PoolCreated",
+                    format!("Created pool with range {}", range),
+                )
+                .await;
+                Ok(())
+            }
+            Err(e) => Err(K8sError::PoolCreationError(e.to_string())),
+        }
+    }
+
+    // Makes sure that the BGPAdvertisement still applies to the pool after `range` was added to it:
+    // an explicit list of pools has to include the pool and the aggregation length may not be shorter than the range.
+    // Advertisements that select pools by label or apply to all pools are left as they are
+    async fn sync_advertisement(&self, range: &IpNet) -> Result<(), K8sError> {
+        let Some(name) = &self.bgp_advertisement else {
+            return Ok(());
+        };
+        let pool = match &self.pool_uid {
+            Some(_) => pool_name(&self.find_pool().await?).to_string(),
+            None => self.name.to_string(),
+        };
+        let api: Api<BGPAdvertisement> = Api::namespaced(self.client.clone(), &self.namespace);
+        let advertisement = match api.get_opt(name).await {
+            Ok(Some(a)) => a,
+            Ok(None) => return Err(K8sError::AdvertisementNotFound(name.to_string())),
+            Err(e) => return Err(connection_error(e)),
+        };
+        let Some(patch) = advertisement_patch(&advertisement.spec, &pool, range) else {
+            debug!("BGPAdvertisement {} is consistent with the pool", name);
+            return Ok(());
+        };
+        info!("Updating BGPAdvertisement {}: {}", name, patch);
+        match api
+            .patch(name, &self.patch_params(), &Patch::Merge(patch))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(K8sError::AdvertisementUpdateError(
+                name.to_string(),
+                e.to_string(),
+            )),
+        }
+    }
+
+    // Events are informational, so failing to record one is only logged
+    async fn emit_event(&self, pool: &ObjectMeta, reason: &str, message: String) {
+        if !self.emit_events || self.dry_run {
+            return;
+        }
+        let event = pool_event(&self.resource, pool, reason, message, Utc::now());
+        let events: Api<Event> = Api::namespaced(self.client.clone(), &self.namespace);
+        match events.create(&PostParams::default(), &event).await {
+            Ok(_) => debug!("Recorded {} event", reason),
+            Err(e) => warn!("Unable to record {} event: {}", reason, e),
         }
     }
 
-    fn gen_patch(&self, pool: Vec<String>) -> Patch<IPAddressPool> {
+    fn gen_patch(
+        &self,
+        current: &IPAddressPool,
+        pool: Vec<String>,
+        mut annotations: BTreeMap<String, String>,
+    ) -> Patch<Value> {
+        annotations.extend(update_annotations(self.update_marker, &current.metadata));
+        if self.log_diff {
+            info!(
+                "Patching addresses of {}: {}",
+                pool_name(current),
+                address_diff(&current.spec.addresses, &pool)
+            );
+        }
         let pool = IPAddressPool {
             metadata: ObjectMeta {
-                name: Some(self.name.into()),
+                name: Some(pool_name(current).into()),
+                annotations: (!annotations.is_empty()).then_some(annotations),
+                // Makes the API server reject our patch if another writer modified the pool since we read it
+                resource_version: match self.update_marker {
+                    UpdateMarker::Generation => current.metadata.resource_version.clone(),
+                    _ => None,
+                },
                 ..ObjectMeta::default()
             },
             spec: IPAddressPoolSpec {
@@ -138,114 +586,2282 @@ impl KubeClient<'_> {
                 ..IPAddressPoolSpec::default()
             },
         };
-        debug!(
-            "Generated Patch: {:?}",
-            serde_json::to_string(&pool)
-                .unwrap_or_else(|_| "Error while serializing object".to_string())
-        );
-        Patch::Merge(pool)
+        let mut patch = json!(pool);
+        patch["apiVersion"] = self.resource.api_version.clone().into();
+        patch["kind"] = self.resource.kind.clone().into();
+        debug!("Generated Patch: {}", patch);
+        match self.patch_strategy {
+            PatchStrategy::Merge => Patch::Merge(patch),
+            PatchStrategy::Apply => Patch::Apply(patch),
+        }
     }
 }
 
-#[async_trait]
-impl Connector for KubeClient<'_> {
-    async fn v6_ranges(&self) -> Result<Vec<Ipv6Net>, ConnectorError> {
+impl KubeClient<'_> {
+    // All ranges in the pool, entries that are not a network in CIDR notation are skipped
+    async fn ranges(&self) -> Result<Vec<IpNet>, ConnectorError> {
         let mut ranges = Vec::new();
-        let r = self.find_pool().await?;
+        let r = match self.find_pool().await {
+            Ok(r) => r,
+            // The pool gets created on insert
+            Err(K8sError::PoolNotFound(_)) if self.create_pool => return Ok(ranges),
+            Err(e) => return Err(e.into()),
+        };
 
         for range_str in &r.spec.addresses {
-            match Ipv6Net::from_str(range_str) {
-                Ok(r) => ranges.push(r),
-                Err(e) => {
-                    debug!("Not a V6 range, skipping: {}, {}", range_str, e);
+            match parse_entry(range_str) {
+                Some(r) => ranges.push(r),
+                None => {
+                    debug!(
+                        "Not a CIDR network or address range, skipping: {}",
+                        range_str
+                    );
                     continue;
                 }
             };
         }
-        debug!("Found IPv6 range in pool {}: {:?}", self.name, ranges);
+        debug!("Found ranges in pool {}: {:?}", pool_name(&r), ranges);
         Ok(ranges)
     }
 
-    async fn replace(&self, old: &Ipv6Net, new: &Ipv6Net) -> Result<(), ConnectorError> {
-        let pools_api: Api<IPAddressPool> = Api::default_namespaced(self.client.clone());
+    // Changes are retried on transient errors, such as a conflict with another writer.
+    // The pool is read again before every attempt
+    async fn replace_range(&self, old: &IpNet, new: &IpNet) -> Result<(), ConnectorError> {
+        self.replace_with_strategy(old, new).await?;
+        Ok(self.sync_advertisement(new).await?)
+    }
+
+    async fn replace_with_strategy(&self, old: &IpNet, new: &IpNet) -> Result<(), ConnectorError> {
+        match self.replace_strategy {
+            ReplaceStrategy::Atomic => self.with_retries(|| self.try_replace_range(old, new)).await,
+            // Dry-run patches are not persisted, so the added range could never be confirmed
+            ReplaceStrategy::AddThenRemove if self.dry_run => {
+                self.with_retries(|| self.try_replace_range(old, new)).await
+            }
+            ReplaceStrategy::AddThenRemove => {
+                self.with_retries(|| self.try_add_beside(old, new)).await?;
+                self.with_retries(|| self.try_remove_replaced(old, new))
+                    .await
+            }
+        }
+    }
+
+    async fn insert_range(&self, range: &IpNet) -> Result<(), ConnectorError> {
+        self.with_retries(|| self.try_insert_range(range)).await?;
+        Ok(self.sync_advertisement(range).await?)
+    }
+
+    async fn with_retries<F, Fut>(&self, update: F) -> Result<(), ConnectorError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<(), K8sError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match update().await {
+                Err(e) if e.is_transient() && attempt < self.patch_retries => {
+                    attempt += 1;
+                    warn!("{}, retrying ({}/{})", e, attempt, self.patch_retries);
+                    sleep(PATCH_RETRY_DELAY * attempt).await;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    async fn try_replace_range(&self, old: &IpNet, new: &IpNet) -> Result<(), K8sError> {
+        let pools_api = self.pools_api();
         let pool = self.find_pool().await?;
 
-        // This vec contains all addresses *except* for the old address, we can then add our new range if it makes sense
-        let mut patched_addrs: Vec<String> = pool
-            .spec
-            .addresses
-            .iter()
-            .cloned()
-            .filter(|addr| addr != &old.to_string())
-            .collect();
-        match (
-            net_in_pool(&pool, old).is_some(),
-            net_in_pool(&pool, new).is_some(),
-        ) {
-            (false, false) => {
+        // The new range takes the place of the old one, so that the order of the other addresses is kept
+        let mut patched_addrs = pool.spec.addresses.clone();
+        match (net_in_pool(&pool, old), net_in_pool(&pool, new).is_some()) {
+            (None, false) => {
                 // Neither the old or new address exist, we can't replace anything
-                return Err(K8sError::RangeNotFound(old.to_string(), new.to_string()).into());
+                return Err(K8sError::RangeNotFound(old.to_string(), new.to_string()));
             }
-            (false, true) => {
+            (None, true) => {
                 info!(
                     "New range {} already exists and old range {} is absent, doing nothing",
                     new, old
                 );
                 return Ok(());
             }
-            (true, true) => {
+            (Some(_), true) => {
                 info!("New and old range both exist, deleting old range {}", old);
             }
-            (true, false) => {
+            (Some(pos), false) => {
                 // Normal case, insert our new address
-                patched_addrs.push(new.to_string());
+                patched_addrs[pos] = replacement_entry(&patched_addrs[pos], new);
             }
         };
+        patched_addrs.retain(|addr| !is_entry_for(addr, old));
+
+        if same_addresses(&pool.spec.addresses, &patched_addrs) {
+            info!(
+                "Addresses of {} are unchanged, not patching",
+                pool_name(&pool)
+            );
+            return Ok(());
+        }
+
+        let mut annotations = BTreeMap::new();
+        if self.preserve_address_comments {
+            if let Some(comments) =
+                move_address_comment(&pool.metadata, &old.to_string(), &new.to_string())
+            {
+                annotations.insert(ANNOTATION_ADDRESS_COMMENTS.to_string(), comments);
+            }
+        }
 
         match pools_api
             .patch(
-                self.name,
-                &PatchParams::default(),
-                &self.gen_patch(patched_addrs),
+                pool_name(&pool),
+                &self.patch_params(),
+                &self.gen_patch(&pool, patched_addrs, annotations),
             )
             .await
         {
-            Ok(_) => Ok(()),
-            Err(e) => Err(K8sError::PoolUpdateError(e.to_string()).into()),
+            Ok(_) => {
+                self.emit_event(
+                    &pool.metadata,
+                    "RangeReplaced",
+                    format!("Replaced range {} with {}", old, new),
+                )
+                .await;
+                Ok(())
+            }
+            Err(e) => Err(update_error(e)),
         }
     }
 
-    async fn insert(&self, range: &Ipv6Net) -> Result<(), ConnectorError> {
-        let pools_api: Api<IPAddressPool> = Api::default_namespaced(self.client.clone());
-        let mut pool = self.find_pool().await?;
+    // First step of the add-then-remove replacement, the new range is added right after the old one
+    async fn try_add_beside(&self, old: &IpNet, new: &IpNet) -> Result<(), K8sError> {
+        let pools_api = self.pools_api();
+        let pool = self.find_pool().await?;
+
+        let mut addresses = pool.spec.addresses.clone();
+        match (net_in_pool(&pool, old), net_in_pool(&pool, new).is_some()) {
+            (None, false) => {
+                return Err(K8sError::RangeNotFound(old.to_string(), new.to_string()));
+            }
+            (_, true) => {
+                debug!("New range {} already in pool, not adding", new);
+                return Ok(());
+            }
+            (Some(pos), false) => {
+                let entry = replacement_entry(&addresses[pos], new);
+                addresses.insert(pos + 1, entry)
+            }
+        };
+
+        match pools_api
+            .patch(
+                pool_name(&pool),
+                &self.patch_params(),
+                &self.gen_patch(&pool, addresses, BTreeMap::new()),
+            )
+            .await
+        {
+            Ok(_) => {
+                info!("Added range {}, removing {} next", new, old);
+                Ok(())
+            }
+            Err(e) => Err(update_error(e)),
+        }
+    }
+
+    // Second step of the add-then-remove replacement. The pool is read again, so the old range is only
+    // removed once the new one is confirmed to be in the pool
+    async fn try_remove_replaced(&self, old: &IpNet, new: &IpNet) -> Result<(), K8sError> {
+        let pools_api = self.pools_api();
+        let pool = self.find_pool().await?;
+
+        if net_in_pool(&pool, new).is_none() {
+            return Err(K8sError::RangeNotAdded(new.to_string(), old.to_string()));
+        }
+        let old_str = old.to_string();
+        let mut addresses = pool.spec.addresses.clone();
+        addresses.retain(|addr| !is_entry_for(addr, old));
+        if same_addresses(&pool.spec.addresses, &addresses) {
+            info!("Old range {} already removed, not patching", old);
+            return Ok(());
+        }
+
+        let mut annotations = BTreeMap::new();
+        if self.preserve_address_comments {
+            if let Some(comments) = move_address_comment(&pool.metadata, &old_str, &new.to_string())
+            {
+                annotations.insert(ANNOTATION_ADDRESS_COMMENTS.to_string(), comments);
+            }
+        }
+
+        match pools_api
+            .patch(
+                pool_name(&pool),
+                &self.patch_params(),
+                &self.gen_patch(&pool, addresses, annotations),
+            )
+            .await
+        {
+            Ok(_) => {
+                self.emit_event(
+                    &pool.metadata,
+                    "RangeReplaced",
+                    format!("Replaced range {} with {}", old, new),
+                )
+                .await;
+                Ok(())
+            }
+            Err(e) => Err(update_error(e)),
+        }
+    }
+
+    async fn try_insert_range(&self, range: &IpNet) -> Result<(), K8sError> {
+        let pools_api = self.pools_api();
+        let pool = match self.find_pool().await {
+            Ok(p) => p,
+            Err(K8sError::PoolNotFound(_)) if self.create_pool => return self.create(range).await,
+            Err(e) => return Err(e),
+        };
 
         let None = net_in_pool(&pool, range) else {
             info!("Range {} already in pool, not inserting", range);
             return Ok(());
         };
 
-        pool.spec.addresses.push(range.to_string());
+        let mut addresses = pool.spec.addresses.clone();
+        addresses.push(range.to_string());
+        if same_addresses(&pool.spec.addresses, &addresses) {
+            info!(
+                "Range {} already in pool in a different notation, not inserting",
+                range
+            );
+            return Ok(());
+        }
         match pools_api
             .patch(
-                self.name,
-                &PatchParams::default(),
-                &self.gen_patch(pool.spec.addresses),
+                pool_name(&pool),
+                &self.patch_params(),
+                &self.gen_patch(&pool, addresses, BTreeMap::new()),
             )
             .await
         {
-            Ok(_) => Ok(()),
-            Err(e) => Err(K8sError::PoolUpdateError(e.to_string()).into()),
+            Ok(_) => {
+                self.emit_event(
+                    &pool.metadata,
+                    "RangeAdded",
+                    format!("Added range {}", range),
+                )
+                .await;
+                Ok(())
+            }
+            Err(e) => Err(update_error(e)),
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for KubeClient<'_> {
+    async fn v6_ranges(&self) -> Result<Vec<Ipv6Net>, ConnectorError> {
+        let ranges = self.ranges().await?;
+        Ok(ranges
+            .into_iter()
+            .filter_map(|r| match r {
+                IpNet::V6(r) => Some(r),
+                IpNet::V4(_) => None,
+            })
+            .collect())
+    }
+
+    async fn replace(&self, old: &Ipv6Net, new: &Ipv6Net) -> Result<(), ConnectorError> {
+        self.replace_range(&(*old).into(), &(*new).into()).await
+    }
+
+    async fn insert(&self, range: &Ipv6Net) -> Result<(), ConnectorError> {
+        self.insert_range(&(*range).into()).await
+    }
+
+    async fn v4_ranges(&self) -> Result<Vec<Ipv4Net>, ConnectorError> {
+        let ranges = self.ranges().await?;
+        Ok(ranges
+            .into_iter()
+            .filter_map(|r| match r {
+                IpNet::V4(r) => Some(r),
+                IpNet::V6(_) => None,
+            })
+            .collect())
+    }
+
+    async fn replace_v4(&self, old: &Ipv4Net, new: &Ipv4Net) -> Result<(), ConnectorError> {
+        self.replace_range(&(*old).into(), &(*new).into()).await
+    }
+
+    async fn insert_v4(&self, range: &Ipv4Net) -> Result<(), ConnectorError> {
+        self.insert_range(&(*range).into()).await
+    }
+
+    fn describe(&self) -> String {
+        match &self.pool_uid {
+            Some(uid) => format!(
+                "kube {} with UID '{}' in namespace '{}'",
+                self.resource.kind, uid, self.namespace
+            ),
+            None => format!(
+                "kube {} '{}' in namespace '{}'",
+                self.resource.kind, self.name, self.namespace
+            ),
+        }
+    }
+
+    fn request_counts(&self) -> RequestCounts {
+        RequestCounts {
+            reads: self.requests.reads.load(Ordering::Relaxed),
+            writes: self.requests.writes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn changes(&self) -> Option<Arc<Notify>> {
+        self.changes.clone()
+    }
+}
+
+impl Drop for KubeClient<'_> {
+    fn drop(&mut self) {
+        if let Some(task) = &self.watch_task {
+            task.abort();
+        }
+    }
+}
+
+// Sorted names of the pools matching the label selector
+async fn list_pool_names(
+    pools_api: &Api<DynamicObject>,
+    selector: &str,
+) -> Result<Vec<String>, K8sError> {
+    let pools = pools_api
+        .list(&ListParams::default().labels(selector))
+        .await
+        .map_err(connection_error)?;
+    let mut names: Vec<_> = pools
+        .items
+        .into_iter()
+        .filter_map(|p| p.metadata.name)
+        .collect();
+    names.sort();
+    debug!("Pools matching selector {}: {:?}", selector, names);
+    Ok(names)
+}
+
+// Notifies about every event of the watched pool, or of all pools if no name is given.
+// Our own updates are reported as well, which only causes an extra run.
+// The API server ends watches after a while, they are resumed from the last seen version
+async fn watch_pool(api: Api<DynamicObject>, name: Option<String>, changes: Arc<Notify>) {
+    let params = match &name {
+        Some(name) => ListParams::default().fields(&format!("metadata.name={}", name)),
+        None => ListParams::default(),
+    };
+    let mut version = "0".to_string();
+    loop {
+        let mut stream = match api.watch(&params, &version).await {
+            Ok(stream) => stream.boxed(),
+            Err(e) => {
+                warn!("Unable to watch the pool, retrying: {}", e);
+                sleep(WATCH_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        while let Some(event) = stream.next().await {
+            match event {
+                Ok(WatchEvent::Added(pool))
+                | Ok(WatchEvent::Modified(pool))
+                | Ok(WatchEvent::Deleted(pool)) => {
+                    debug!("Pool {:?} changed", pool.metadata.name);
+                    if let Some(v) = pool.metadata.resource_version {
+                        version = v;
+                    }
+                    changes.notify_waiters();
+                }
+                Ok(WatchEvent::Bookmark(bookmark)) => version = bookmark.metadata.resource_version,
+                Ok(WatchEvent::Error(e)) => {
+                    // The version is too old, start over with the current state
+                    if e.code == 410 {
+                        version = "0".to_string();
+                    }
+                    warn!("Error while watching the pool, restarting: {}", e.message);
+                    sleep(WATCH_RETRY_DELAY).await;
+                    break;
+                }
+                Err(e) => {
+                    warn!("Error while watching the pool, restarting: {}", e);
+                    sleep(WATCH_RETRY_DELAY).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Merge patch that makes the advertisement apply to the pool containing `range`, `None` if it already does
+fn advertisement_patch(spec: &BGPAdvertisementSpec, pool: &str, range: &IpNet) -> Option<Value> {
+    let mut patch = serde_json::Map::new();
+    // An empty list applies the advertisement to all pools, adding ours would exclude the others
+    let pools = spec.ipAddressPools.as_deref().unwrap_or_default();
+    if !pools.is_empty() && !pools.iter().any(|p| p == pool) {
+        let mut pools = pools.to_vec();
+        pools.push(pool.to_string());
+        patch.insert("ipAddressPools".to_string(), json!(pools));
+    }
+    // MetalLB rejects aggregation lengths that are shorter than a range of the pool
+    let (field, length) = match range {
+        IpNet::V4(_) => ("aggregationLength", spec.aggregationLength),
+        IpNet::V6(_) => ("aggregationLengthV6", spec.aggregationLengthV6),
+    };
+    if let Some(length) = length {
+        if length < i32::from(range.prefix_len()) {
+            patch.insert(field.to_string(), json!(range.prefix_len()));
+        }
+    }
+    (!patch.is_empty()).then(|| json!({ "spec": patch }))
+}
+
+// The current name of the pool, which may differ from the configured one when the pool is found by UID
+fn pool_name(pool: &IPAddressPool) -> &str {
+    pool.metadata.name.as_deref().unwrap_or_default()
+}
+
+fn pool_by_uid(pools: Vec<DynamicObject>, uid: &str) -> Option<DynamicObject> {
+    pools
+        .into_iter()
+        .find(|p| p.metadata.uid.as_deref() == Some(uid))
+}
+
+// Normalizes an address entry of the pool, so that different notations of the same network compare equal
+fn canonical_address(address: &str) -> String {
+    if let Some((start, end)) = parse_range(address) {
+        let net = range_net(start, end);
+        // Ranges spanning exactly one network are the same as its CIDR notation
+        if net.network() != start || net.broadcast() != end {
+            return format!("{}-{}", start, end);
+        }
+    }
+    match parse_entry(address) {
+        Some(net) => net.to_string(),
+        None => address.trim().to_string(),
+    }
+}
+
+// Parses an entry of the pool in `start-end` form, e.g. `2001:db8::1-2001:db8::ff`
+fn parse_range(entry: &str) -> Option<(IpAddr, IpAddr)> {
+    let (start, end) = entry.trim().split_once('-')?;
+    let (start, end) = (
+        IpAddr::from_str(start.trim()).ok()?,
+        IpAddr::from_str(end.trim()).ok()?,
+    );
+    match start.is_ipv6() == end.is_ipv6() && start <= end {
+        true => Some((start, end)),
+        false => None,
+    }
+}
+
+// Smallest network containing the range, with the start of the range as its address.
+// This is how ranges are handed to the reconcile, which only looks at the host part of the address
+fn range_net(start: IpAddr, end: IpAddr) -> IpNet {
+    let common = match (start, end) {
+        (IpAddr::V6(start), IpAddr::V6(end)) => {
+            (u128::from(start) ^ u128::from(end)).leading_zeros()
         }
+        (IpAddr::V4(start), IpAddr::V4(end)) => (u32::from(start) ^ u32::from(end)).leading_zeros(),
+        _ => 0,
+    };
+    IpNet::new(start, common as u8).expect("common prefix within the address length")
+}
+
+// Parses a pool entry, either in CIDR notation or in `start-end` form
+fn parse_entry(entry: &str) -> Option<IpNet> {
+    match IpNet::from_str(entry.trim()) {
+        Ok(net) => Some(net),
+        Err(_) => parse_range(entry).map(|(start, end)| range_net(start, end)),
     }
 }
 
-// Checks whether the address exists in the IPAddressPool, returns the index as an option if found
-fn net_in_pool(pool: &IPAddressPool, addr: &Ipv6Net) -> Option<usize> {
-    let mut pos = None;
-    for (i, a) in pool.spec.addresses.iter().enumerate() {
-        if a == &addr.to_string() {
-            pos = Some(i);
+// The entry taking the place of `entry` when its range is replaced with `new`.
+// Ranges in `start-end` form keep that form and their size, only the network part of both ends changes
+fn replacement_entry(entry: &str, new: &IpNet) -> String {
+    let Some((start, end)) = parse_range(entry) else {
+        return new.to_string();
+    };
+    let end = match (start, end, new.addr()) {
+        (IpAddr::V6(start), IpAddr::V6(end), IpAddr::V6(new)) => {
+            let size = u128::from(end) - u128::from(start);
+            u128::from(new)
+                .checked_add(size)
+                .map(|end| IpAddr::V6(end.into()))
         }
+        (IpAddr::V4(start), IpAddr::V4(end), IpAddr::V4(new)) => {
+            let size = u32::from(end) - u32::from(start);
+            u32::from(new)
+                .checked_add(size)
+                .map(|end| IpAddr::V4(end.into()))
+        }
+        _ => None,
+    };
+    match end {
+        Some(end) => format!("{}-{}", new.addr(), end),
+        None => new.to_string(),
+    }
+}
+
+// Whether the pool entry denotes the network, regardless of its notation.
+// Entries that can't be parsed are compared as strings
+fn is_entry_for(entry: &str, net: &IpNet) -> bool {
+    match parse_entry(entry) {
+        Some(parsed) => parsed == *net,
+        None => entry.trim() == net.to_string(),
+    }
+}
+
+// Checks whether two address lists contain the same networks, ignoring notation, order and duplicates.
+// Patching a pool with an equivalent list would only bump its resourceVersion and wake up other controllers
+fn same_addresses(current: &[String], new: &[String]) -> bool {
+    let canonical = |addresses: &[String]| -> BTreeSet<String> {
+        addresses.iter().map(|a| canonical_address(a)).collect()
+    };
+    canonical(current) == canonical(new)
+}
+
+// Checks whether the address exists in the IPAddressPool in any notation, returns the index as an option if found
+fn net_in_pool(pool: &IPAddressPool, addr: &IpNet) -> Option<usize> {
+    pool.spec
+        .addresses
+        .iter()
+        .rposition(|a| is_entry_for(a, addr))
+}
+
+fn parse_pool(raw: &DynamicObject) -> Result<IPAddressPool, K8sError> {
+    let name = raw.metadata.name.clone().unwrap_or_default();
+    serde_json::to_value(raw)
+        .and_then(serde_json::from_value)
+        .map_err(|e| K8sError::MalformedPool(name, raw.data["spec"].to_string(), e.to_string()))
+}
+
+// Lists the removed and added addresses, e.g. `-2001:db8::/80 +2001:db8:1::/80`
+fn address_diff(old: &[String], new: &[String]) -> String {
+    let removed = old
+        .iter()
+        .filter(|a| !new.contains(a))
+        .map(|a| format!("-{}", a));
+    let added = new
+        .iter()
+        .filter(|a| !old.contains(a))
+        .map(|a| format!("+{}", a));
+    let diff: Vec<_> = removed.chain(added).collect();
+    match diff.is_empty() {
+        true => "no changes".to_string(),
+        false => diff.join(" "),
+    }
+}
+
+// Tries to turn a malformed addresses field into a list of addresses.
+// Handles the address list being written as a single (possibly comma-separated) string
+fn repair_addresses(addresses: &Value) -> Option<Vec<String>> {
+    match addresses {
+        Value::String(s) => Some(
+            s.split(',')
+                .map(str::trim)
+                .filter(|a| !a.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ),
+        Value::Array(a) => a.iter().map(|v| v.as_str().map(str::to_string)).collect(),
+        _ => None,
+    }
+}
+
+// Moves the comment of the old range to the new one in the address comments annotation.
+// The annotation is a JSON object mapping pool addresses to comments.
+// Returns the new annotation value, or None if there is nothing to move
+fn move_address_comment(current: &ObjectMeta, old: &str, new: &str) -> Option<String> {
+    let raw = current
+        .annotations
+        .as_ref()?
+        .get(ANNOTATION_ADDRESS_COMMENTS)?;
+    let mut comments: BTreeMap<String, String> = match serde_json::from_str(raw) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!(
+                "Ignoring invalid {} annotation: {}",
+                ANNOTATION_ADDRESS_COMMENTS, e
+            );
+            return None;
+        }
+    };
+    let comment = comments.remove(old)?;
+    comments.insert(new.to_string(), comment);
+    serde_json::to_string(&comments).ok()
+}
+
+// Generates the annotations recording an update of the pool with the given metadata
+fn update_annotations(marker: UpdateMarker, current: &ObjectMeta) -> BTreeMap<String, String> {
+    let mut annotations = BTreeMap::new();
+    match marker {
+        UpdateMarker::None => {}
+        UpdateMarker::Timestamp => {
+            annotations.insert(
+                ANNOTATION_LAST_UPDATE.to_string(),
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            );
+        }
+        UpdateMarker::Generation => {
+            // A counter doesn't depend on synchronized clocks, unlike a timestamp
+            let counter = current
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get(ANNOTATION_UPDATE_COUNTER))
+                .and_then(|c| c.parse::<u64>().ok())
+                .unwrap_or(0);
+            annotations.insert(
+                ANNOTATION_UPDATE_COUNTER.to_string(),
+                (counter + 1).to_string(),
+            );
+            if let Some(generation) = current.generation {
+                annotations.insert(
+                    ANNOTATION_OBSERVED_GENERATION.to_string(),
+                    generation.to_string(),
+                );
+            }
+        }
+    }
+    annotations
+}
+
+fn read_pool_template(path: &PathBuf) -> Result<Value, K8sError> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| K8sError::InvalidPoolSpec(format!("{}: {}", path.display(), e)))?;
+    // YAML is a superset of JSON, so this handles both formats
+    let template: Value = serde_yaml::from_str(&content)
+        .map_err(|e| K8sError::InvalidPoolSpec(format!("{}: {}", path.display(), e)))?;
+    if !template.is_object() {
+        return Err(K8sError::InvalidPoolSpec(format!(
+            "{}: not a mapping",
+            path.display()
+        )));
+    }
+    Ok(template)
+}
+
+// Builds the pool resource from its CRD, using the requested version if it is served.
+// Otherwise the preferred version is used if it is served, falling back to the version the pools are stored in
+fn crd_resource(
+    crd: &CustomResourceDefinition,
+    requested: Option<&str>,
+    preferred: Option<&str>,
+) -> Result<ApiResource, K8sError> {
+    let crd_name = crd.metadata.name.clone().unwrap_or_default();
+    let served: Vec<_> = crd.spec.versions.iter().filter(|v| v.served).collect();
+    let version = match requested {
+        Some(requested) => served.iter().find(|v| v.name == requested),
+        None => served
+            .iter()
+            .find(|v| Some(v.name.as_str()) == preferred)
+            .or_else(|| served.iter().max_by_key(|v| v.storage)),
+    };
+    let Some(version) = version else {
+        return Err(K8sError::CRDVersionNotServed(
+            crd_name,
+            requested.or(preferred).unwrap_or("any").to_string(),
+            served.iter().map(|v| v.name.clone()).collect(),
+        ));
+    };
+    if let (None, Some(preferred)) = (requested, preferred) {
+        if preferred != version.name {
+            warn!(
+                "Version {} of CRD {} is not served, using {}",
+                preferred, crd_name, version.name
+            );
+        }
+    }
+    Ok(ApiResource {
+        group: crd.spec.group.clone(),
+        version: version.name.clone(),
+        api_version: format!("{}/{}", crd.spec.group, version.name),
+        kind: crd.spec.names.kind.clone(),
+        plural: crd.spec.names.plural.clone(),
+    })
+}
+
+// Compares the served schema of the pool spec with `IPAddressPoolSpec` and logs any surprises
+fn check_schema(crd: &CustomResourceDefinition, version: &str) {
+    for problem in schema_problems(crd, version) {
+        warn!(
+            "{}",
+            K8sError::UnexpectedSchema(
+                crd.metadata.name.clone().unwrap_or_default(),
+                version.to_string(),
+                problem
+            )
+        );
+    }
+}
+
+fn spec_schema(crd: &CustomResourceDefinition, version: &str) -> Option<JSONSchemaProps> {
+    crd.spec
+        .versions
+        .iter()
+        .find(|v| v.name == version)?
+        .schema
+        .as_ref()?
+        .open_api_v3_schema
+        .as_ref()?
+        .properties
+        .as_ref()?
+        .get("spec")
+        .cloned()
+}
+
+fn schema_problems(crd: &CustomResourceDefinition, version: &str) -> Vec<String> {
+    let served = crd.spec.versions.iter().find(|v| v.name == version);
+    if served.and_then(|v| v.schema.as_ref()).is_none() {
+        debug!("CRD version {} has no schema, not checking it", version);
+        return Vec::new();
+    }
+    let Some(spec) = spec_schema(crd, version) else {
+        return vec!["there is no spec".to_string()];
+    };
+    let properties = spec.properties.unwrap_or_default();
+    let mut problems = Vec::new();
+    match properties.get("addresses") {
+        None => problems.push("spec.addresses is missing".to_string()),
+        Some(addresses) => {
+            let items = match &addresses.items {
+                Some(JSONSchemaPropsOrArray::Schema(items)) => items.type_.as_deref(),
+                _ => None,
+            };
+            if addresses.type_.as_deref() != Some("array") || items != Some("string") {
+                problems.push(format!(
+                    "spec.addresses is of type {} with items of type {}, not an array of strings",
+                    addresses.type_.as_deref().unwrap_or("unknown"),
+                    items.unwrap_or("unknown")
+                ));
+            }
+        }
+    }
+
+    let known = spec_schema(&IPAddressPool::crd(), &IPAddressPool::version(&()))
+        .and_then(|s| s.properties)
+        .unwrap_or_default();
+    let unknown_required: Vec<_> = spec
+        .required
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|f| !known.contains_key(f))
+        .collect();
+    if !unknown_required.is_empty() {
+        problems.push(format!(
+            "unknown fields {:?} are required, creating pools may fail",
+            unknown_required
+        ));
+    }
+    let unknown: Vec<_> = properties
+        .keys()
+        .filter(|f| !known.contains_key(*f))
+        .collect();
+    if !unknown.is_empty() {
+        problems.push(format!(
+            "unknown fields {:?} are left untouched by updates",
+            unknown
+        ));
+    }
+    problems
+}
+
+// Merges the pool name and the range into the template, keeping all other fields as-is
+fn pool_from_template(
+    template: Option<&Value>,
+    resource: &ApiResource,
+    name: &str,
+    range: &IpNet,
+) -> Result<Value, K8sError> {
+    let mut pool = template.cloned().unwrap_or_else(|| json!({}));
+    pool["apiVersion"] = resource.api_version.clone().into();
+    pool["kind"] = resource.kind.clone().into();
+    if !pool["metadata"].is_object() {
+        pool["metadata"] = json!({});
+    }
+    pool["metadata"]["name"] = name.into();
+    if !pool["spec"].is_object() {
+        pool["spec"] = json!({});
+    }
+    // Legacy AddressPools also select the announcement protocol, which is required
+    if resource.kind != IPAddressPool::kind(&()) && pool["spec"]["protocol"].is_null() {
+        pool["spec"]["protocol"] = LEGACY_DEFAULT_PROTOCOL.into();
+    }
+    let addresses = &mut pool["spec"]["addresses"];
+    if addresses.is_null() {
+        *addresses = json!([]);
+    }
+    match addresses.as_array_mut() {
+        Some(addrs) => addrs.push(range.to_string().into()),
+        None => {
+            return Err(K8sError::InvalidPoolSpec(
+                "spec.addresses is not a list".to_string(),
+            ))
+        }
+    }
+    Ok(pool)
+}
+
+// Builds a Normal event about the pool, named like the events of kubectl and the controllers
+fn pool_event(
+    resource: &ApiResource,
+    pool: &ObjectMeta,
+    reason: &str,
+    message: String,
+    now: DateTime<Utc>,
+) -> Event {
+    let name = pool.name.clone().unwrap_or_default();
+    Event {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}.", name)),
+            namespace: pool.namespace.clone(),
+            ..ObjectMeta::default()
+        },
+        involved_object: ObjectReference {
+            api_version: Some(resource.api_version.clone()),
+            kind: Some(resource.kind.clone()),
+            name: Some(name),
+            namespace: pool.namespace.clone(),
+            uid: pool.uid.clone(),
+            resource_version: pool.resource_version.clone(),
+            ..ObjectReference::default()
+        },
+        reason: Some(reason.to_string()),
+        message: Some(message),
+        type_: Some("Normal".to_string()),
+        count: Some(1),
+        first_timestamp: Some(Time(now)),
+        last_timestamp: Some(Time(now)),
+        source: Some(EventSource {
+            component: Some(EVENT_COMPONENT.to_string()),
+            host: None,
+        }),
+        reporting_component: Some(EVENT_COMPONENT.to_string()),
+        ..Event::default()
+    }
+}
+
+// Connection to the API server and the pool resource it serves
+struct Connection {
+    client: Client,
+    namespace: String,
+    resource: ApiResource,
+    requests: Arc<RequestCounter>,
+}
+
+async fn connect(options: &KubeClientOptions) -> Result<Connection, ConnectorError> {
+    let cfg = client_config(Config::infer().await?, options);
+    debug!("Inferred kube config: {:?}", cfg);
+
+    let requests = Arc::new(RequestCounter::default());
+    let counter = requests.clone();
+    let service = ServiceBuilder::new()
+        .layer(cfg.base_uri_layer())
+        .option_layer(cfg.auth_layer()?)
+        .map_request(move |req: hyper::Request<hyper::Body>| {
+            counter.record(req.method());
+            req
+        })
+        .service(hyper::Client::builder().build(cfg.rustls_https_connector()?));
+    let namespace = options
+        .namespace
+        .clone()
+        .unwrap_or_else(|| cfg.default_namespace.clone());
+    let client = Client::new(service, cfg.default_namespace);
+
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    let crd_version = options.crd_version.as_deref();
+    let resource = match crds.get_opt(METALLB_IPADDRPOOL_CRD_NAME).await? {
+        Some(crd) => {
+            let resource = crd_resource(&crd, crd_version, Some(&IPAddressPool::version(&())))?;
+            check_schema(&crd, &resource.version);
+            debug!("Using {} {}", resource.api_version, resource.kind);
+            resource
+        }
+        None => match crds.get_opt(METALLB_LEGACY_ADDRPOOL_CRD_NAME).await? {
+            Some(crd) => {
+                let resource = crd_resource(&crd, crd_version, None)?;
+                warn!(
+                    "IPAddressPool CRD not found, using the deprecated {}",
+                    resource.api_version
+                );
+                resource
+            }
+            None => return Err(K8sError::CRDNotFound.into()),
+        },
+    };
+    Ok(Connection {
+        client,
+        namespace,
+        resource,
+        requests,
+    })
+}
+
+// Applies the connection settings to the inferred config.
+// The rustls connector built from it skips certificate validation if `accept_invalid_certs` is set
+pub(super) fn client_config(mut cfg: Config, options: &KubeClientOptions) -> Config {
+    cfg.accept_invalid_certs = options.no_verify;
+    cfg
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use std::collections::BTreeMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Method, Request, Response, Server, StatusCode,
+    };
+    use ipnet::{IpNet, Ipv6Net};
+    use k8s_openapi::{
+        apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time},
+        chrono::Utc,
+    };
+    use serde_json::{json, Value};
+
+    use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+    use kube::{
+        api::{ApiResource, DynamicObject},
+        client::ConfigExt,
+        Api, Client, Config,
+    };
+
+    use super::{
+        address_diff, advertisement_patch, canonical_address, client_config, crd_resource,
+        list_pool_names, move_address_comment, net_in_pool, parse_entry, parse_pool, pool_by_uid,
+        pool_event, pool_from_template, repair_addresses, replacement_entry, schema_problems,
+        update_annotations, BGPAdvertisementSpec, IPAddressPool, IPAddressPoolSpec, K8sError,
+        KubeClient, KubeClientOptions, PatchStrategy, ReplaceStrategy, RequestCounter,
+        ANNOTATION_ADDRESS_COMMENTS, ANNOTATION_UPDATE_COUNTER,
+    };
+    use crate::metallb::{
+        Connector, ConnectorError, ConnectorErrorKind, RequestCounts, UpdateMarker,
+    };
+
+    #[test]
+    fn builds_pool_event() {
+        let pool = ObjectMeta {
+            name: Some("my-pool".to_string()),
+            namespace: Some("metallb-system".to_string()),
+            uid: Some("1234".to_string()),
+            ..ObjectMeta::default()
+        };
+        let now = Utc::now();
+        let event = pool_event(
+            &ApiResource::erase::<IPAddressPool>(&()),
+            &pool,
+            "RangeReplaced",
+            "Replaced range 2001:db8::/80 with 2001:db8:1::/80".to_string(),
+            now,
+        );
+        assert_eq!(event.metadata.generate_name.as_deref(), Some("my-pool."));
+        assert_eq!(event.metadata.namespace.as_deref(), Some("metallb-system"));
+        assert_eq!(event.involved_object.kind.as_deref(), Some("IPAddressPool"));
+        assert_eq!(
+            event.involved_object.api_version.as_deref(),
+            Some("metallb.io/v1beta1")
+        );
+        assert_eq!(event.involved_object.uid.as_deref(), Some("1234"));
+        assert_eq!(event.reason.as_deref(), Some("RangeReplaced"));
+        assert_eq!(event.type_.as_deref(), Some("Normal"));
+        assert_eq!(event.last_timestamp, Some(Time(now)));
+    }
+
+    #[test]
+    fn applies_no_verify() {
+        let cfg = || Config::new("https://127.0.0.1:6443".parse().unwrap());
+        let options = |no_verify| KubeClientOptions {
+            no_verify,
+            ..Default::default()
+        };
+        assert!(!client_config(cfg(), &options(false)).accept_invalid_certs);
+        let cfg = client_config(cfg(), &options(true));
+        assert!(cfg.accept_invalid_certs);
+        assert!(cfg.rustls_https_connector().is_ok());
+    }
+
+    #[test]
+    fn finds_pool_by_uid() {
+        let pool = |name: &str, uid: &str| -> DynamicObject {
+            serde_json::from_value(json!({
+                "apiVersion": "metallb.io/v1beta1",
+                "kind": "IPAddressPool",
+                "metadata": {"name": name, "uid": uid},
+                "spec": {"addresses": []},
+            }))
+            .unwrap()
+        };
+        let pools = vec![
+            pool("other-pool", "0b9f2d44-6f4e-4a35-9f0e-0d5c1b7e8a21"),
+            pool("renamed-pool", "5e1c9a3b-2f7d-4c8e-a6b0-7d4f3e2a1c90"),
+        ];
+
+        let found = pool_by_uid(pools.clone(), "5e1c9a3b-2f7d-4c8e-a6b0-7d4f3e2a1c90").unwrap();
+        assert_eq!(found.metadata.name.as_deref(), Some("renamed-pool"));
+        assert!(pool_by_uid(pools, "a0000000-0000-0000-0000-000000000000").is_none());
+    }
+
+    #[test]
+    fn detects_malformed_pool() {
+        let raw: DynamicObject = serde_json::from_value(json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool"},
+            "spec": {"addresses": "192.0.2.0/24, 2001:db8::abab:cdcd:0:0/80"},
+        }))
+        .unwrap();
+
+        let Err(K8sError::MalformedPool(name, content, _)) = parse_pool(&raw) else {
+            panic!("Malformed pool was not detected");
+        };
+        assert_eq!(name, "my-pool");
+        assert!(content.contains("192.0.2.0/24, 2001:db8::abab:cdcd:0:0/80"));
+
+        assert_eq!(
+            repair_addresses(&raw.data["spec"]["addresses"]).unwrap(),
+            vec!["192.0.2.0/24", "2001:db8::abab:cdcd:0:0/80"]
+        );
+        assert!(repair_addresses(&json!({"foo": "bar"})).is_none());
+    }
+
+    #[test]
+    fn keeps_service_allocation() {
+        let manifest = r#"
+apiVersion: metallb.io/v1beta1
+kind: IPAddressPool
+metadata:
+  name: my-pool
+spec:
+  addresses:
+    - 2001:db8::abab:cdcd:0:0/80
+  serviceAllocation:
+    priority: 50
+    namespaces:
+      - web
+    namespaceSelectors:
+      - matchLabels:
+          team: web
+    serviceSelectors:
+      - matchExpressions:
+          - key: exposed
+            operator: In
+            values: ["true"]
+"#;
+        let raw: DynamicObject = serde_yaml::from_str(manifest).unwrap();
+        let pool = parse_pool(&raw).unwrap();
+        let allocation = pool.spec.serviceAllocation.as_ref().unwrap();
+        assert_eq!(allocation.priority, Some(50));
+        assert_eq!(allocation.namespaces, Some(vec!["web".to_string()]));
+        assert_eq!(
+            serde_json::to_value(&pool.spec).unwrap(),
+            raw.data["spec"],
+            "spec does not survive a round trip"
+        );
+    }
+
+    #[test]
+    fn parses_range_entries() {
+        let net = |s: &str| IpNet::from_str(s).unwrap();
+        assert_eq!(
+            parse_entry("2001:db8::abab:cdcd:0:0/80"),
+            Some(net("2001:db8::abab:cdcd:0:0/80"))
+        );
+        // Ranges are represented by their start and the smallest network containing them
+        assert_eq!(
+            parse_entry("2001:db8::abab:cdcd:0:1-2001:db8::abab:cdcd:0:ff"),
+            Some(net("2001:db8::abab:cdcd:0:1/120"))
+        );
+        assert_eq!(
+            parse_entry(" 2001:db8::abab:0:0:0 - 2001:db8::abab:ffff:ffff:ffff "),
+            Some(net("2001:db8::abab:0:0:0/80"))
+        );
+        assert_eq!(
+            parse_entry("192.0.2.10-192.0.2.20"),
+            Some(net("192.0.2.10/27"))
+        );
+        for entry in [
+            "2001:db8::ff-2001:db8::1",
+            "192.0.2.1-2001:db8::1",
+            "2001:db8::1-",
+            "2001:db8::1-2001:db8::2-2001:db8::3",
+        ] {
+            assert_eq!(parse_entry(entry), None, "{}", entry);
+        }
+
+        assert_eq!(
+            canonical_address("2001:DB8::1-2001:db8:0::ff"),
+            "2001:db8::1-2001:db8::ff"
+        );
+        assert_eq!(
+            canonical_address("2001:db8::-2001:db8::ff"),
+            "2001:db8::/120"
+        );
+
+        // Replacements keep the form of the entry and the size of the range
+        assert_eq!(
+            replacement_entry(
+                "2001:db8::abab:cdcd:0:1-2001:db8::abab:cdcd:0:ff",
+                &net("2001:db8:1::abab:cdcd:0:1/120")
+            ),
+            "2001:db8:1:0:abab:cdcd:0:1-2001:db8:1:0:abab:cdcd:0:ff"
+        );
+        assert_eq!(
+            replacement_entry("192.0.2.10-192.0.2.20", &net("198.51.100.10/27")),
+            "198.51.100.10-198.51.100.20"
+        );
+        assert_eq!(
+            replacement_entry(
+                "2001:DB8::abab:cdcd:0:0/80",
+                &net("2001:db8:1::abab:cdcd:0:0/80")
+            ),
+            "2001:db8:1:0:abab:cdcd::/80"
+        );
+    }
+
+    #[tokio::test]
+    async fn replaces_range_in_start_end_form() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": [
+                "192.0.2.0/24",
+                "2001:db8::abab:cdcd:0:1-2001:db8::abab:cdcd:0:ff",
+            ]},
+        });
+        let api = FakeApi::with_pool(pool);
+        let client = api.kube_client("my-pool");
+
+        let ranges = client.v6_ranges().await.unwrap();
+        assert_eq!(
+            ranges,
+            vec![Ipv6Net::from_str("2001:db8::abab:cdcd:0:1/120").unwrap()]
+        );
+        let target = Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:1/120").unwrap();
+        client.replace(&ranges[0], &target).await.unwrap();
+        assert_eq!(
+            api.patches()[0]["spec"]["addresses"],
+            json!([
+                "192.0.2.0/24",
+                "2001:db8:1:0:abab:cdcd:0:1-2001:db8:1:0:abab:cdcd:0:ff",
+            ])
+        );
+        // Once replaced, the pool reports the target range, so the next run sees it as up to date
+        assert_eq!(client.v6_ranges().await.unwrap(), vec![target]);
+    }
+
+    #[test]
+    fn finds_equivalent_pool_entries() {
+        let pool = |addresses: &[&str]| IPAddressPool {
+            metadata: ObjectMeta::default(),
+            spec: IPAddressPoolSpec {
+                addresses: addresses.iter().map(|a| a.to_string()).collect(),
+                ..IPAddressPoolSpec::default()
+            },
+        };
+        let range = IpNet::from_str("2001:db8::abab:cdcd:0:0/80").unwrap();
+
+        for entry in [
+            "2001:db8::abab:cdcd:0:0/80",
+            "2001:DB8::ABAB:CDCD:0:0/80",
+            "2001:0db8:0000::abab:cdcd:0:0/80",
+            " 2001:db8:0:0:abab:cdcd::/80 ",
+        ] {
+            assert_eq!(
+                net_in_pool(&pool(&["fd00::/64", entry]), &range),
+                Some(1),
+                "{}",
+                entry
+            );
+        }
+        // Different host bits, a range starting elsewhere and garbage
+        for entry in [
+            "2001:db8::abab:cdcd:0:1/80",
+            "2001:db8::abab:0:0:0-2001:db8::abab:ffff:ffff:ffff",
+            "2001:db8::abab:cdcd:0:0/80/80",
+        ] {
+            assert_eq!(net_in_pool(&pool(&[entry]), &range), None, "{}", entry);
+        }
+
+        // Ranges match if they span exactly the network
+        let network = IpNet::from_str("2001:db8::abab:0:0:0/80").unwrap();
+        assert_eq!(
+            net_in_pool(
+                &pool(&["2001:db8::abab:0:0:0-2001:db8::abab:ffff:ffff:ffff"]),
+                &network
+            ),
+            Some(0)
+        );
+        assert_eq!(
+            net_in_pool(
+                &pool(&["2001:db8::abab:0:0:0-2001:db8::abac:ffff:ffff:ffff"]),
+                &network
+            ),
+            None
+        );
+        assert_eq!(
+            net_in_pool(
+                &pool(&["192.0.2.0 - 192.0.2.255"]),
+                &IpNet::from_str("192.0.2.0/24").unwrap()
+            ),
+            Some(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn replaces_non_canonical_range() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": [
+                "2001:DB8:0:0:ABAB:CDCD:0:0/80",
+                "fd00:1::0/64",
+                "2001:db8:1::abab:0:0:0-2001:db8:1::abab:ffff:ffff:ffff",
+            ]},
+        });
+        let api = FakeApi::with_pool(pool);
+        let client = api.kube_client("my-pool");
+
+        client
+            .replace(
+                &Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap(),
+                &Ipv6Net::from_str("2001:db8:2::abab:cdcd:0:0/80").unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            api.patches()[0]["spec"]["addresses"],
+            json!([
+                "2001:db8:2:0:abab:cdcd::/80",
+                "fd00:1::0/64",
+                "2001:db8:1::abab:0:0:0-2001:db8:1::abab:ffff:ffff:ffff",
+            ])
+        );
+
+        // Both ranges are in the pool in another notation, only the old one is removed
+        client
+            .replace(
+                &Ipv6Net::from_str("2001:db8:2::abab:cdcd:0:0/80").unwrap(),
+                &Ipv6Net::from_str("2001:db8:1::abab:0:0:0/80").unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            api.patches()[1]["spec"]["addresses"],
+            json!([
+                "fd00:1::0/64",
+                "2001:db8:1::abab:0:0:0-2001:db8:1::abab:ffff:ffff:ffff",
+            ])
+        );
+        // The new range is present in range notation, so it isn't inserted again
+        client
+            .insert(&Ipv6Net::from_str("2001:db8:1::abab:0:0:0/80").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(api.patches().len(), 2);
+    }
+
+    #[test]
+    fn lists_address_diff() {
+        let addrs = |a: &[&str]| a.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            address_diff(
+                &addrs(&["192.0.2.0/24", "2001:db8::abab:cdcd:0:0/80"]),
+                &addrs(&["192.0.2.0/24", "2001:db8:1:0:abab:cdcd::/80"])
+            ),
+            "-2001:db8::abab:cdcd:0:0/80 +2001:db8:1:0:abab:cdcd::/80"
+        );
+        assert_eq!(
+            address_diff(&addrs(&[]), &addrs(&["2001:db8::/80"])),
+            "+2001:db8::/80"
+        );
+        assert_eq!(
+            address_diff(&addrs(&["2001:db8::/80"]), &addrs(&["2001:db8::/80"])),
+            "no changes"
+        );
+    }
+
+    #[test]
+    fn moves_address_comment() {
+        let meta = ObjectMeta {
+            annotations: Some(BTreeMap::from([(
+                ANNOTATION_ADDRESS_COMMENTS.to_string(),
+                json!({
+                    "192.0.2.0/24": "static v4",
+                    "2001:db8::abab:cdcd:0:0/80": "dynamic v6",
+                })
+                .to_string(),
+            )])),
+            ..ObjectMeta::default()
+        };
+
+        let moved = move_address_comment(
+            &meta,
+            "2001:db8::abab:cdcd:0:0/80",
+            "2001:db8:1111:1111:abab:cdcd::/80",
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&moved).unwrap(),
+            json!({
+                "192.0.2.0/24": "static v4",
+                "2001:db8:1111:1111:abab:cdcd::/80": "dynamic v6",
+            })
+        );
+
+        assert!(move_address_comment(&meta, "2001:db8:ffff::/80", "2001:db8::/80").is_none());
+        assert!(move_address_comment(&ObjectMeta::default(), "a", "b").is_none());
+    }
+
+    #[test]
+    fn increments_update_counter() {
+        let meta = ObjectMeta {
+            generation: Some(7),
+            annotations: Some(BTreeMap::from([(
+                ANNOTATION_UPDATE_COUNTER.to_string(),
+                "41".to_string(),
+            )])),
+            ..ObjectMeta::default()
+        };
+        let annotations = update_annotations(UpdateMarker::Generation, &meta);
+        assert_eq!(annotations[ANNOTATION_UPDATE_COUNTER], "42");
+        assert_eq!(annotations["metallb-v6-helper/observed-generation"], "7");
+
+        let first = update_annotations(UpdateMarker::Generation, &ObjectMeta::default());
+        assert_eq!(first[ANNOTATION_UPDATE_COUNTER], "1");
+
+        assert!(update_annotations(UpdateMarker::None, &meta).is_empty());
+        assert!(update_annotations(UpdateMarker::Timestamp, &meta)
+            .contains_key("metallb-v6-helper/last-update"));
+    }
+
+    #[test]
+    fn merges_pool_template() {
+        let range = IpNet::from_str("2001:db8:1111:1111:abab:cdcd::/80").unwrap();
+        let template = json!({
+            "metadata": {
+                "name": "ignored",
+                "labels": {"app": "metallb"},
+            },
+            "spec": {
+                "addresses": ["192.0.2.0/24"],
+                "autoAssign": false,
+            },
+        });
+
+        let resource = ApiResource::erase::<IPAddressPool>(&());
+        let pool = pool_from_template(Some(&template), &resource, "my-pool", &range).unwrap();
+        assert_eq!(pool["apiVersion"], "metallb.io/v1beta1");
+        assert_eq!(pool["kind"], "IPAddressPool");
+        assert_eq!(pool["metadata"]["name"], "my-pool");
+        assert_eq!(pool["metadata"]["labels"]["app"], "metallb");
+        assert_eq!(pool["spec"]["autoAssign"], false);
+        assert_eq!(
+            pool["spec"]["addresses"],
+            json!(["192.0.2.0/24", "2001:db8:1111:1111:abab:cdcd::/80"])
+        );
+
+        let bare = pool_from_template(None, &resource, "my-pool", &range).unwrap();
+        assert_eq!(
+            bare["spec"]["addresses"],
+            json!(["2001:db8:1111:1111:abab:cdcd::/80"])
+        );
+        assert!(bare["spec"]["protocol"].is_null());
+    }
+
+    #[test]
+    fn uses_legacy_address_pool() {
+        let crd: CustomResourceDefinition = serde_json::from_value(json!({
+            "apiVersion": "apiextensions.k8s.io/v1",
+            "kind": "CustomResourceDefinition",
+            "metadata": {"name": "addresspools.metallb.io"},
+            "spec": {
+                "group": "metallb.io",
+                "names": {"kind": "AddressPool", "plural": "addresspools"},
+                "scope": "Namespaced",
+                "versions": [
+                    {"name": "v1alpha1", "served": true, "storage": false},
+                    {"name": "v1beta1", "served": true, "storage": true},
+                ],
+            },
+        }))
+        .unwrap();
+        let resource = crd_resource(&crd, None, None).unwrap();
+        assert_eq!(resource.api_version, "metallb.io/v1beta1");
+        assert_eq!(resource.kind, "AddressPool");
+        assert_eq!(resource.plural, "addresspools");
+
+        let range = IpNet::from_str("2001:db8:1111:1111:abab:cdcd::/80").unwrap();
+        let pool = pool_from_template(None, &resource, "my-pool", &range).unwrap();
+        assert_eq!(pool["apiVersion"], "metallb.io/v1beta1");
+        assert_eq!(pool["kind"], "AddressPool");
+        assert_eq!(pool["spec"]["protocol"], "layer2");
+
+        let template = json!({"spec": {"protocol": "bgp"}});
+        let pool = pool_from_template(Some(&template), &resource, "my-pool", &range).unwrap();
+        assert_eq!(pool["spec"]["protocol"], "bgp");
+    }
+
+    #[test]
+    fn selects_crd_version() {
+        let crd = |versions: Value| -> CustomResourceDefinition {
+            serde_json::from_value(json!({
+                "apiVersion": "apiextensions.k8s.io/v1",
+                "kind": "CustomResourceDefinition",
+                "metadata": {"name": "ipaddresspools.metallb.io"},
+                "spec": {
+                    "group": "metallb.io",
+                    "names": {"kind": "IPAddressPool", "plural": "ipaddresspools"},
+                    "scope": "Namespaced",
+                    "versions": versions,
+                },
+            }))
+            .unwrap()
+        };
+        let both = crd(json!([
+            {"name": "v1beta1", "served": true, "storage": false},
+            {"name": "v1beta2", "served": true, "storage": true},
+        ]));
+        let v1beta2 = crd(json!([
+            {"name": "v1beta1", "served": false, "storage": false},
+            {"name": "v1beta2", "served": true, "storage": true},
+        ]));
+
+        // The version the pool spec was written for is preferred while it is served
+        let resource = crd_resource(&both, None, Some("v1beta1")).unwrap();
+        assert_eq!(resource.api_version, "metallb.io/v1beta1");
+        assert_eq!(resource.kind, "IPAddressPool");
+        let resource = crd_resource(&v1beta2, None, Some("v1beta1")).unwrap();
+        assert_eq!(resource.api_version, "metallb.io/v1beta2");
+        let resource = crd_resource(&both, Some("v1beta2"), Some("v1beta1")).unwrap();
+        assert_eq!(resource.api_version, "metallb.io/v1beta2");
+
+        let err = crd_resource(&v1beta2, Some("v1beta1"), Some("v1beta1")).unwrap_err();
+        assert!(matches!(err, K8sError::CRDVersionNotServed(..)));
+        assert_eq!(
+            err.to_string(),
+            r#"Version `v1beta1` of CRD `ipaddresspools.metallb.io` is not served, the served versions are ["v1beta2"]"#
+        );
+    }
+
+    #[test]
+    fn checks_crd_schema() {
+        let crd = |spec: Value| -> CustomResourceDefinition {
+            serde_json::from_value(json!({
+                "apiVersion": "apiextensions.k8s.io/v1",
+                "kind": "CustomResourceDefinition",
+                "metadata": {"name": "ipaddresspools.metallb.io"},
+                "spec": {
+                    "group": "metallb.io",
+                    "names": {"kind": "IPAddressPool", "plural": "ipaddresspools"},
+                    "scope": "Namespaced",
+                    "versions": [{
+                        "name": "v1beta1",
+                        "served": true,
+                        "storage": true,
+                        "schema": {"openAPIV3Schema": {
+                            "type": "object",
+                            "properties": {"spec": spec},
+                        }},
+                    }],
+                },
+            }))
+            .unwrap()
+        };
+        // Abbreviated schema of MetalLB 0.14
+        let current = crd(json!({
+            "type": "object",
+            "required": ["addresses"],
+            "properties": {
+                "addresses": {"type": "array", "items": {"type": "string"}},
+                "autoAssign": {"type": "boolean"},
+                "avoidBuggyIPs": {"type": "boolean"},
+                "serviceAllocation": {"type": "object"},
+            },
+        }));
+        assert!(schema_problems(&current, "v1beta1").is_empty());
+        // Versions without a schema are not checked
+        assert!(schema_problems(&current, "v1beta2").is_empty());
+
+        let changed = crd(json!({
+            "type": "object",
+            "required": ["addresses", "family"],
+            "properties": {
+                "addresses": {"type": "array", "items": {"type": "object"}},
+                "family": {"type": "string"},
+            },
+        }));
+        let problems = schema_problems(&changed, "v1beta1");
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].contains("items of type object"));
+        assert!(problems[1].contains(r#"["family"] are required"#));
+        assert!(problems[2].contains(r#"["family"] are left untouched"#));
+
+        let no_addresses = crd(json!({"type": "object", "properties": {}}));
+        assert_eq!(
+            schema_problems(&no_addresses, "v1beta1"),
+            vec!["spec.addresses is missing"]
+        );
+    }
+
+    // A request received by the fake API server
+    #[derive(Debug, Clone)]
+    struct FakeRequest {
+        method: Method,
+        path: String,
+        query: String,
+        content_type: String,
+        body: Option<Value>,
+    }
+
+    // Fails the latest of the requests received so far with the returned status and message
+    type FailFn = dyn Fn(&[FakeRequest]) -> Option<(StatusCode, &'static str)> + Send + Sync;
+
+    // Fake API server holding objects by their path. It serves them, lists them by the path of their collection
+    // and applies merge patches to them. All requests are recorded and counted like the client's service stack does
+    #[derive(Clone, Default)]
+    struct FakeApi {
+        objects: Arc<Mutex<Vec<(String, Value)>>>,
+        requests: Arc<Mutex<Vec<FakeRequest>>>,
+        counter: Arc<RequestCounter>,
+        // Records patches without applying them
+        frozen: bool,
+        fail: Option<Arc<FailFn>>,
+    }
+
+    impl FakeApi {
+        fn with_pool(pool: Value) -> FakeApi {
+            FakeApi::default().object(pool)
+        }
+
+        // Adds an object at the path derived from its apiVersion, kind, namespace and name
+        fn object(self, object: Value) -> FakeApi {
+            let plural = format!("{}s", object["kind"].as_str().unwrap().to_lowercase());
+            let namespace = match object["metadata"]["namespace"].as_str() {
+                Some(namespace) => format!("/namespaces/{}", namespace),
+                None => String::new(),
+            };
+            let path = format!(
+                "/apis/{}{}/{}/{}",
+                object["apiVersion"].as_str().unwrap(),
+                namespace,
+                plural,
+                object["metadata"]["name"].as_str().unwrap()
+            );
+            self.objects.lock().unwrap().push((path, object));
+            self
+        }
+
+        fn frozen(mut self) -> FakeApi {
+            self.frozen = true;
+            self
+        }
+
+        fn failing(
+            mut self,
+            fail: impl Fn(&[FakeRequest]) -> Option<(StatusCode, &'static str)> + Send + Sync + 'static,
+        ) -> FakeApi {
+            self.fail = Some(Arc::new(fail));
+            self
+        }
+
+        fn client(&self) -> Client {
+            let api = self.clone();
+            let service = tower::service_fn(move |req: Request<Body>| {
+                let api = api.clone();
+                async move { Ok::<_, Infallible>(api.respond(req).await) }
+            });
+            Client::new(service, "default")
+        }
+
+        fn kube_client<'a>(&self, name: &'a str) -> KubeClient<'a> {
+            KubeClient::test_new(name, self.client(), self.counter.clone())
+        }
+
+        // Serves the objects on a local port and points the kube config to it
+        fn serve(&self, namespace: &str) -> PathBuf {
+            let api = self.clone();
+            let make_service = make_service_fn(move |_| {
+                let api = api.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let api = api.clone();
+                        async move { Ok::<_, Infallible>(api.respond(req).await) }
+                    }))
+                }
+            });
+            let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+            let addr = server.local_addr();
+            tokio::spawn(server);
+
+            let path =
+                std::env::temp_dir().join(format!("v6helper-test-kubeconfig-{}.yaml", addr.port()));
+            let kubeconfig = format!(
+                r#"apiVersion: v1
+kind: Config
+clusters:
+- name: fake
+  cluster:
+    server: http://{}
+users:
+- name: fake
+  user:
+    token: fake-token
+contexts:
+- name: fake
+  context:
+    cluster: fake
+    user: fake
+    namespace: {}
+current-context: fake
+"#,
+                addr, namespace
+            );
+            std::fs::write(&path, kubeconfig).unwrap();
+            path
+        }
+
+        async fn respond(&self, req: Request<Body>) -> Response<Body> {
+            self.counter.record(req.method());
+            let (parts, body) = req.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap();
+            let request = FakeRequest {
+                method: parts.method,
+                path: parts.uri.path().to_string(),
+                query: parts.uri.query().unwrap_or_default().to_string(),
+                content_type: parts
+                    .headers
+                    .get(hyper::header::CONTENT_TYPE)
+                    .and_then(|c| c.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string(),
+                body: serde_json::from_slice(&body).ok(),
+            };
+            let failure = {
+                let mut requests = self.requests.lock().unwrap();
+                requests.push(request.clone());
+                self.fail.as_ref().and_then(|fail| fail(&requests))
+            };
+            if let Some((status, message)) = failure {
+                return status_response(status, message);
+            }
+
+            let mut objects = self.objects.lock().unwrap();
+            let Some(object) = objects
+                .iter_mut()
+                .find(|(path, _)| *path == request.path)
+                .map(|(_, object)| object)
+            else {
+                let collection = format!("{}/", request.path);
+                let items: Vec<_> = objects
+                    .iter()
+                    .filter(|(path, _)| {
+                        path.starts_with(&collection) && !path[collection.len()..].contains('/')
+                    })
+                    .map(|(_, object)| object.clone())
+                    .collect();
+                return match (request.method, items.first()) {
+                    (Method::GET, Some(item)) => json_response(&json!({
+                        "apiVersion": item["apiVersion"],
+                        "kind": format!("{}List", item["kind"].as_str().unwrap()),
+                        "metadata": {},
+                        "items": items,
+                    })),
+                    _ => status_response(StatusCode::NOT_FOUND, "not found"),
+                };
+            };
+            if request.method == Method::PATCH && !self.frozen {
+                if let Some(patch @ Value::Object(_)) = &request.body {
+                    merge_patch(object, patch);
+                }
+            }
+            json_response(object)
+        }
+
+        fn requests(&self) -> Vec<FakeRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn patches(&self) -> Vec<Value> {
+            self.requests()
+                .into_iter()
+                .filter(|r| r.method == Method::PATCH)
+                .filter_map(|r| r.body)
+                .collect()
+        }
+
+        // The first object, which is the pool in all tests
+        fn pool(&self) -> Value {
+            self.objects.lock().unwrap()[0].1.clone()
+        }
+
+        fn addresses(&self) -> Value {
+            self.pool()["spec"]["addresses"].clone()
+        }
+    }
+
+    fn json_response(body: &Value) -> Response<Body> {
+        Response::new(Body::from(body.to_string()))
+    }
+
+    fn status_response(status: StatusCode, message: &str) -> Response<Body> {
+        let body = json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "status": "Failure",
+            "message": message,
+            "reason": status.canonical_reason().unwrap_or_default().replace(' ', ""),
+            "code": status.as_u16(),
+        });
+        let mut response = json_response(&body);
+        *response.status_mut() = status;
+        response
+    }
+
+    // Applies a JSON merge patch (RFC 7386) like the API server does
+    fn merge_patch(target: &mut Value, patch: &Value) {
+        let Value::Object(patch) = patch else {
+            *target = patch.clone();
+            return;
+        };
+        if !target.is_object() {
+            *target = json!({});
+        }
+        let target = target.as_object_mut().unwrap();
+        for (key, value) in patch {
+            match value {
+                Value::Null => {
+                    target.remove(key);
+                }
+                value => merge_patch(target.entry(key.clone()).or_insert(Value::Null), value),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn preserves_pool_settings() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {
+                "addresses": ["2001:db8::abab:cdcd:0:0/80"],
+                "autoAssign": false,
+                "avoidBuggyIPs": true,
+                "serviceAllocation": {"namespaces": ["web"]},
+            },
+        });
+        let api = FakeApi::with_pool(pool);
+        let client = api.kube_client("my-pool");
+
+        client
+            .replace(
+                &Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap(),
+                &Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap(),
+            )
+            .await
+            .unwrap();
+        // Only the addresses are patched, the other settings are left as they are
+        assert_eq!(
+            api.patches(),
+            vec![json!({
+                "apiVersion": "metallb.io/v1beta1",
+                "kind": "IPAddressPool",
+                "metadata": {"name": "my-pool"},
+                "spec": {"addresses": ["2001:db8:1:0:abab:cdcd::/80"]},
+            })]
+        );
+        let pool = api.pool();
+        assert_eq!(pool["spec"]["autoAssign"], false);
+        assert_eq!(
+            pool["spec"]["serviceAllocation"],
+            json!({"namespaces": ["web"]})
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_address_order() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {
+                "addresses": ["192.0.2.0/24", "2001:db8::abab:cdcd:0:0/80", "fd00:1::/64"],
+            },
+        });
+        let api = FakeApi::with_pool(pool);
+        let client = api.kube_client("my-pool");
+
+        client
+            .replace(
+                &Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap(),
+                &Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            api.addresses(),
+            json!(["192.0.2.0/24", "2001:db8:1:0:abab:cdcd::/80", "fd00:1::/64"])
+        );
+    }
+
+    #[tokio::test]
+    async fn inserts_range() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": ["192.0.2.0/24"]},
+        });
+        let api = FakeApi::with_pool(pool);
+        let client = api.kube_client("my-pool");
+
+        assert!(client.v6_ranges().await.unwrap().is_empty());
+        client
+            .insert(&Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            api.addresses(),
+            json!(["192.0.2.0/24", "2001:db8::abab:cdcd:0:0/80"])
+        );
+    }
+
+    #[tokio::test]
+    async fn handles_present_and_missing_ranges() {
+        let pool = |addresses: Value| {
+            json!({
+                "apiVersion": "metallb.io/v1beta1",
+                "kind": "IPAddressPool",
+                "metadata": {"name": "my-pool", "namespace": "default"},
+                "spec": {"addresses": addresses},
+            })
+        };
+        let old = Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap();
+        let new = Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap();
+
+        // Only the new range is in the pool, so it is already up to date
+        let api = FakeApi::with_pool(pool(json!(["2001:db8:1:0:abab:cdcd::/80"])));
+        api.kube_client("my-pool")
+            .replace(&old, &new)
+            .await
+            .unwrap();
+        assert!(api.patches().is_empty());
+
+        // Both ranges are in the pool, the old one is removed
+        let api = FakeApi::with_pool(pool(json!([
+            "2001:db8::abab:cdcd:0:0/80",
+            "2001:db8:1:0:abab:cdcd::/80"
+        ])));
+        api.kube_client("my-pool")
+            .replace(&old, &new)
+            .await
+            .unwrap();
+        assert_eq!(api.addresses(), json!(["2001:db8:1:0:abab:cdcd::/80"]));
+
+        // Neither range is in the pool, there is nothing to replace
+        let api = FakeApi::with_pool(pool(json!(["fd00:1::/64"])));
+        let err = api
+            .kube_client("my-pool")
+            .replace(&old, &new)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ConnectorErrorKind::NotFound);
+        assert!(api.patches().is_empty());
+    }
+
+    #[tokio::test]
+    async fn replaces_range_with_strategy() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": ["2001:db8::abab:cdcd:0:0/80", "fd00:1::/64"]},
+        });
+        let old = Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap();
+        let new = Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap();
+        let patched_addresses = |api: &FakeApi| -> Vec<Value> {
+            api.patches()
+                .iter()
+                .map(|p| p["spec"]["addresses"].clone())
+                .collect()
+        };
+
+        let api = FakeApi::with_pool(pool.clone());
+        api.kube_client("my-pool")
+            .replace(&old, &new)
+            .await
+            .unwrap();
+        assert_eq!(
+            patched_addresses(&api),
+            vec![json!(["2001:db8:1:0:abab:cdcd::/80", "fd00:1::/64"])]
+        );
+
+        // The new range is added next to the old one first, the pool never lacks a range
+        let api = FakeApi::with_pool(pool);
+        let mut client = api.kube_client("my-pool");
+        client.replace_strategy = ReplaceStrategy::AddThenRemove;
+        client.replace(&old, &new).await.unwrap();
+        assert_eq!(
+            patched_addresses(&api),
+            vec![
+                json!([
+                    "2001:db8::abab:cdcd:0:0/80",
+                    "2001:db8:1:0:abab:cdcd::/80",
+                    "fd00:1::/64"
+                ]),
+                json!(["2001:db8:1:0:abab:cdcd::/80", "fd00:1::/64"]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_old_range_if_new_one_is_missing() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": ["2001:db8::abab:cdcd:0:0/80"]},
+        });
+        // The fake API server accepts the patch, but the pool stays unchanged
+        let api = FakeApi::with_pool(pool).frozen();
+        let mut client = api.kube_client("my-pool");
+        client.replace_strategy = ReplaceStrategy::AddThenRemove;
+
+        let err = client
+            .replace(
+                &Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap(),
+                &Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap(),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is missing from the pool"));
+        assert_eq!(api.patches().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn uses_server_side_apply() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": ["2001:db8::abab:cdcd:0:0/80"]},
+        });
+        let api = FakeApi::with_pool(pool);
+        let mut client = api.kube_client("my-pool");
+        client.patch_strategy = PatchStrategy::Apply;
+
+        client
+            .insert(&Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap())
+            .await
+            .unwrap();
+        let patches: Vec<_> = api
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == Method::PATCH)
+            .collect();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].content_type, "application/apply-patch+yaml");
+        assert!(patches[0].query.contains("fieldManager=metallb-v6-helper"));
+    }
+
+    #[tokio::test]
+    async fn retries_conflicting_updates() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": ["2001:db8::abab:cdcd:0:0/80"]},
+        });
+        // The first update conflicts with another writer
+        let first_update_conflicts = |requests: &[FakeRequest]| {
+            let patches = requests
+                .iter()
+                .filter(|r| r.method == Method::PATCH)
+                .count();
+            match requests.last().map(|r| &r.method) {
+                Some(&Method::PATCH) if patches == 1 => {
+                    Some((StatusCode::CONFLICT, "the object has been modified"))
+                }
+                _ => None,
+            }
+        };
+        let range = Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap();
+
+        let api = FakeApi::with_pool(pool.clone()).failing(first_update_conflicts);
+        let err = api.kube_client("my-pool").insert(&range).await.unwrap_err();
+        assert_eq!(err.kind(), ConnectorErrorKind::Conflict);
+        assert_eq!(api.patches().len(), 1);
+
+        let api = FakeApi::with_pool(pool).failing(first_update_conflicts);
+        let mut client = api.kube_client("my-pool");
+        client.patch_retries = 1;
+        client.insert(&range).await.unwrap();
+        assert_eq!(api.patches().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reports_error_kind() {
+        let api = FakeApi::default().failing(|_| {
+            Some((
+                StatusCode::FORBIDDEN,
+                "ipaddresspools.metallb.io \"my-pool\" is forbidden",
+            ))
+        });
+        let client = api.kube_client("my-pool");
+
+        let err = client.v6_ranges().await.unwrap_err();
+        assert_eq!(err.kind(), ConnectorErrorKind::AuthFailed);
+        // The message is unchanged
+        assert!(err.to_string().starts_with(
+            "Error while accessing the k8s API: `ApiError: ipaddresspools.metallb.io"
+        ));
+
+        let err: ConnectorError = K8sError::PoolNotFound("my-pool".to_string()).into();
+        assert_eq!(err.kind(), ConnectorErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            "Could not find MetalLB AddressPool with name `my-pool`"
+        );
+        let err: ConnectorError = K8sError::PoolUpdateError("connection reset".to_string()).into();
+        assert_eq!(err.kind(), ConnectorErrorKind::Network);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_pool() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": []},
+        });
+        let api = FakeApi::with_pool(pool);
+
+        let err = api.kube_client("other-pool").v6_ranges().await.unwrap_err();
+        assert_eq!(err.kind(), ConnectorErrorKind::NotFound);
+        assert!(api.patches().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lists_pools_by_selector() {
+        let pool = |name: &str| {
+            json!({
+                "apiVersion": "metallb.io/v1beta1",
+                "kind": "IPAddressPool",
+                "metadata": {"name": name, "namespace": "default"},
+                "spec": {"addresses": []},
+            })
+        };
+        let api = FakeApi::default()
+            .object(pool("pool-b"))
+            .object(pool("pool-a"));
+        let pools_api: Api<DynamicObject> = Api::namespaced_with(
+            api.client(),
+            "default",
+            &ApiResource::erase::<IPAddressPool>(&()),
+        );
+
+        assert_eq!(
+            list_pool_names(&pools_api, "v6helper.io/managed=true")
+                .await
+                .unwrap(),
+            vec!["pool-a", "pool-b"]
+        );
+        let queries: Vec<_> = api.requests().into_iter().map(|r| r.query).collect();
+        assert_eq!(queries, vec!["&labelSelector=v6helper.io%2Fmanaged%3Dtrue"]);
+    }
+
+    #[test]
+    fn builds_advertisement_patch() {
+        let range = IpNet::from_str("2001:db8::abab:cdcd:0:0/80").unwrap();
+        let spec = |pools: &[&str], length| BGPAdvertisementSpec {
+            aggregationLength: Some(24),
+            aggregationLengthV6: length,
+            ipAddressPools: Some(pools.iter().map(|p| p.to_string()).collect()),
+        };
+
+        assert_eq!(
+            advertisement_patch(&spec(&["my-pool"], Some(128)), "my-pool", &range),
+            None
+        );
+        // Applies to all pools
+        assert_eq!(
+            advertisement_patch(&spec(&[], None), "my-pool", &range),
+            None
+        );
+        assert_eq!(
+            advertisement_patch(&BGPAdvertisementSpec::default(), "my-pool", &range),
+            None
+        );
+        assert_eq!(
+            advertisement_patch(&spec(&["other-pool"], Some(64)), "my-pool", &range),
+            Some(json!({"spec": {
+                "ipAddressPools": ["other-pool", "my-pool"],
+                "aggregationLengthV6": 80,
+            }}))
+        );
+        // IPv4 ranges only affect the IPv4 aggregation length
+        assert_eq!(
+            advertisement_patch(
+                &spec(&["my-pool"], Some(64)),
+                "my-pool",
+                &IpNet::from_str("192.0.2.0/25").unwrap()
+            ),
+            Some(json!({"spec": {"aggregationLength": 25}}))
+        );
+    }
+
+    #[tokio::test]
+    async fn syncs_bgp_advertisement() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": ["2001:db8::abab:cdcd:0:0/80"]},
+        });
+        let advertisement = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "BGPAdvertisement",
+            "metadata": {"name": "my-adv", "namespace": "default"},
+            "spec": {"ipAddressPools": ["other-pool"], "aggregationLengthV6": 64, "localPref": 50},
+        });
+        let api = FakeApi::with_pool(pool).object(advertisement);
+        let mut client = api.kube_client("my-pool");
+        client.bgp_advertisement = Some("my-adv".to_string());
+
+        client
+            .replace(
+                &Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap(),
+                &Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap(),
+            )
+            .await
+            .unwrap();
+        let patches: Vec<_> = api
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == Method::PATCH)
+            .map(|r| (r.path, r.body.unwrap()))
+            .collect();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(
+            patches[1],
+            (
+                "/apis/metallb.io/v1beta1/namespaces/default/bgpadvertisements/my-adv".to_string(),
+                json!({"spec": {"ipAddressPools": ["other-pool", "my-pool"], "aggregationLengthV6": 80}})
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn uses_configured_namespace() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "metallb-system"},
+            "spec": {"addresses": ["2001:db8::abab:cdcd:0:0/80"]},
+        });
+        let api = FakeApi::with_pool(pool);
+        let mut client = api.kube_client("my-pool");
+        client.namespace = "metallb-system".to_string();
+
+        client.v6_ranges().await.unwrap();
+        let paths: Vec<_> = api.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            vec!["/apis/metallb.io/v1beta1/namespaces/metallb-system/ipaddresspools/my-pool"]
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_unchanged_patches() {
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "default"},
+            "spec": {"addresses": ["192.0.2.0/24", "2001:0db8:0:0:abab:cdcd:0:0/80"]},
+        });
+        let api = FakeApi::with_pool(pool);
+        let client = api.kube_client("my-pool");
+
+        // Same network as in the pool, only written differently
+        client
+            .insert(&Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.request_counts(),
+            RequestCounts {
+                reads: 1,
+                writes: 0
+            }
+        );
+
+        client
+            .insert(&Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.request_counts(),
+            RequestCounts {
+                reads: 2,
+                writes: 1
+            }
+        );
+    }
+
+    // Goes through the kube config and the CRD lookup, which the other tests skip
+    #[tokio::test]
+    async fn connects_through_kubeconfig() {
+        let pool_crd = json!({
+            "apiVersion": "apiextensions.k8s.io/v1",
+            "kind": "CustomResourceDefinition",
+            "metadata": {"name": "ipaddresspools.metallb.io"},
+            "spec": {
+                "group": "metallb.io",
+                "names": {"kind": "IPAddressPool", "plural": "ipaddresspools"},
+                "scope": "Namespaced",
+                "versions": [{"name": "v1beta1", "served": true, "storage": true}],
+            },
+        });
+        let pool = json!({
+            "apiVersion": "metallb.io/v1beta1",
+            "kind": "IPAddressPool",
+            "metadata": {"name": "my-pool", "namespace": "metallb-system"},
+            "spec": {"addresses": ["2001:db8::abab:cdcd:0:0/80"]},
+        });
+        let api = FakeApi::with_pool(pool).object(pool_crd);
+        let kubeconfig = api.serve("metallb-system");
+        std::env::set_var("KUBECONFIG", &kubeconfig);
+        let client = KubeClient::try_new("my-pool", KubeClientOptions::default()).await;
+        std::env::remove_var("KUBECONFIG");
+        std::fs::remove_file(kubeconfig).unwrap();
+        let client = client.unwrap();
+
+        client
+            .replace(
+                &Ipv6Net::from_str("2001:db8::abab:cdcd:0:0/80").unwrap(),
+                &Ipv6Net::from_str("2001:db8:1::abab:cdcd:0:0/80").unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(api.addresses(), json!(["2001:db8:1:0:abab:cdcd::/80"]));
+        assert_eq!(client.request_counts().writes, 1);
     }
-    pos
 }
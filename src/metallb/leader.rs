@@ -0,0 +1,367 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+    chrono::Utc,
+};
+use kube::{api::PostParams, Api, Client, Config};
+use log::{debug, info, warn};
+use tokio::time::sleep;
+
+use super::{k8s::client_config, ConnectorError, KubeClientOptions};
+
+/// Default time after which a lease that is no longer renewed can be taken over
+pub const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(15);
+
+/// Elects a single replica to reconcile the pools through a `coordination.k8s.io/v1` Lease,
+/// so that replicas of an HA deployment don't race each other.
+/// Expiry is judged by how long the lease has been unchanged since this replica first saw it,
+/// so the clocks of the replicas don't need to be in sync.
+///
+/// The service account needs access to the lease in its own namespace, e.g. through
+///
+/// ```yaml
+/// apiVersion: rbac.authorization.k8s.io/v1
+/// kind: Role
+/// metadata:
+///   name: metallb-dynv6-helper-leader-election
+/// rules:
+///   - apiGroups: ["coordination.k8s.io"]
+///     resources: ["leases"]
+///     verbs: ["get", "create", "update"]
+/// ```
+///
+/// bound to it with a RoleBinding
+pub struct LeaderElector {
+    api: Api<Lease>,
+    namespace: String,
+    lease_name: String,
+    identity: String,
+    lease_duration: Duration,
+    /// Resource version of the lease when it was last seen to change, and when that was
+    observed: Mutex<Option<(String, Instant)>>,
+}
+
+impl LeaderElector {
+    /// Elects with the lease `lease_name` in the default namespace of the client, i.e. the namespace of the pod
+    pub async fn try_new(
+        lease_name: &str,
+        identity: String,
+        lease_duration: Duration,
+        options: &KubeClientOptions,
+    ) -> Result<LeaderElector, ConnectorError> {
+        let cfg = client_config(Config::infer().await?, options);
+        let namespace = cfg.default_namespace.clone();
+        let client = Client::try_from(cfg)?;
+        Ok(LeaderElector::new(
+            client,
+            namespace,
+            lease_name,
+            identity,
+            lease_duration,
+        ))
+    }
+
+    fn new(
+        client: Client,
+        namespace: String,
+        lease_name: &str,
+        identity: String,
+        lease_duration: Duration,
+    ) -> LeaderElector {
+        LeaderElector {
+            api: Api::namespaced(client, &namespace),
+            namespace,
+            lease_name: lease_name.to_string(),
+            identity,
+            lease_duration,
+            observed: Mutex::new(None),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "leader election with lease {}/{} as {}",
+            self.namespace, self.lease_name, self.identity
+        )
+    }
+
+    // Renewing three times per lease duration leaves room for two failed attempts
+    fn retry_interval(&self) -> Duration {
+        self.lease_duration / 3
+    }
+
+    /// Completes once this replica holds the lease, retrying until then
+    pub async fn acquire(&self) {
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => {
+                    info!("Acquired lease {}, now leading", self.lease_name);
+                    return;
+                }
+                Ok(false) => debug!("Lease {} is held by another replica", self.lease_name),
+                Err(e) => warn!("Unable to acquire lease {}: {}", self.lease_name, e),
+            }
+            sleep(self.retry_interval()).await;
+        }
+    }
+
+    /// Keeps renewing the lease and completes once it is lost, either to another replica or because
+    /// it could not be renewed in time. Reconciling has to stop then, as another replica may take over
+    pub async fn hold(&self) {
+        // Step down before the lease expires, so that two replicas never lead at the same time
+        let renew_deadline = self.lease_duration * 2 / 3;
+        let mut last_renewal = Instant::now();
+        loop {
+            sleep(self.retry_interval()).await;
+            match self.try_acquire_or_renew().await {
+                Ok(true) => last_renewal = Instant::now(),
+                Ok(false) => {
+                    warn!(
+                        "Lease {} was taken over by another replica",
+                        self.lease_name
+                    );
+                    return;
+                }
+                Err(e) if last_renewal.elapsed() >= renew_deadline => {
+                    warn!(
+                        "Unable to renew lease {} in time, stepping down: {}",
+                        self.lease_name, e
+                    );
+                    return;
+                }
+                Err(e) => warn!("Unable to renew lease {}: {}", self.lease_name, e),
+            }
+        }
+    }
+
+    /// Gives up the lease if this replica holds it, so that another one can take over right away
+    pub async fn release(&self) {
+        let result = async {
+            let Some(mut lease) = self.api.get_opt(&self.lease_name).await? else {
+                return Ok(());
+            };
+            let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+            if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+                return Ok(());
+            }
+            spec.holder_identity = None;
+            self.api
+                .replace(&self.lease_name, &PostParams::default(), &lease)
+                .await?;
+            info!("Released lease {}", self.lease_name);
+            Ok::<_, ConnectorError>(())
+        };
+        if let Err(e) = result.await {
+            warn!("Unable to release lease {}: {}", self.lease_name, e);
+        }
+    }
+
+    /// Takes the lease if it is free or expired, or renews it if this replica holds it.
+    /// Returns whether this replica holds the lease afterwards
+    async fn try_acquire_or_renew(&self) -> Result<bool, ConnectorError> {
+        let now = MicroTime(Utc::now());
+        let Some(mut lease) = self.api.get_opt(&self.lease_name).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(self.lease_name.clone()),
+                    ..ObjectMeta::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(self.identity.clone()),
+                    lease_duration_seconds: Some(self.lease_duration_seconds()),
+                    acquire_time: Some(now.clone()),
+                    renew_time: Some(now),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return self.update(self.api.create(&PostParams::default(), &lease).await);
+        };
+
+        let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+        let held = spec.holder_identity.as_deref() == Some(self.identity.as_str());
+        if !held {
+            let free = spec
+                .holder_identity
+                .as_deref()
+                .unwrap_or_default()
+                .is_empty();
+            if !free && !self.expired(&lease) {
+                return Ok(false);
+            }
+            let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+            info!(
+                "Taking over lease {} from {}",
+                self.lease_name,
+                spec.holder_identity.as_deref().unwrap_or("nobody")
+            );
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(now.clone());
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+        }
+        let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+        spec.renew_time = Some(now);
+        spec.lease_duration_seconds = Some(self.lease_duration_seconds());
+        // The resource version is kept, so the update fails if another replica got there first
+        self.update(
+            self.api
+                .replace(&self.lease_name, &PostParams::default(), &lease)
+                .await,
+        )
+    }
+
+    // Records the written lease. Conflicts mean another replica won the race
+    fn update(&self, result: Result<Lease, kube::Error>) -> Result<bool, ConnectorError> {
+        match result {
+            Ok(lease) => {
+                self.expired(&lease);
+                Ok(true)
+            }
+            Err(kube::Error::Api(response)) if response.code == 409 => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Whether the lease has not changed for a whole lease duration since it was first seen in its current version
+    fn expired(&self, lease: &Lease) -> bool {
+        let version = lease.metadata.resource_version.clone().unwrap_or_default();
+        let mut observed = self.observed.lock().unwrap();
+        match &*observed {
+            Some((seen, at)) if *seen == version => at.elapsed() >= self.lease_duration,
+            _ => {
+                *observed = Some((version, Instant::now()));
+                false
+            }
+        }
+    }
+
+    fn lease_duration_seconds(&self) -> i32 {
+        i32::try_from(self.lease_duration.as_secs().max(1)).unwrap_or(i32::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use kube::Client;
+    use serde_json::{json, Value};
+
+    use super::LeaderElector;
+
+    // Serves a single Lease with optimistic concurrency like the API server
+    fn lease_client(lease: Arc<Mutex<Option<Value>>>) -> Client {
+        let service = tower::service_fn(move |req: Request<Body>| {
+            let lease = lease.clone();
+            async move {
+                let method = req.method().clone();
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let mut stored = lease.lock().unwrap();
+                let status = match (method, stored.as_ref()) {
+                    (Method::GET, Some(_)) => StatusCode::OK,
+                    (Method::GET, None) => StatusCode::NOT_FOUND,
+                    (Method::POST, Some(_)) => StatusCode::CONFLICT,
+                    (Method::PUT, None) => StatusCode::NOT_FOUND,
+                    (Method::POST | Method::PUT, current) => {
+                        let mut new: Value = serde_json::from_slice(&body).unwrap();
+                        let version = current
+                            .map(|c| {
+                                c["metadata"]["resourceVersion"]
+                                    .as_str()
+                                    .unwrap()
+                                    .to_string()
+                            })
+                            .unwrap_or_default();
+                        match new["metadata"]["resourceVersion"]
+                            .as_str()
+                            .unwrap_or_default()
+                            == version
+                        {
+                            true => {
+                                let next = version.parse::<u64>().unwrap_or_default() + 1;
+                                new["metadata"]["resourceVersion"] = json!(next.to_string());
+                                *stored = Some(new);
+                                StatusCode::OK
+                            }
+                            false => StatusCode::CONFLICT,
+                        }
+                    }
+                    _ => StatusCode::METHOD_NOT_ALLOWED,
+                };
+                let body = match status {
+                    StatusCode::OK => stored.as_ref().unwrap().to_string(),
+                    status => json!({
+                        "kind": "Status",
+                        "apiVersion": "v1",
+                        "status": "Failure",
+                        "message": "lease request failed",
+                        // Reason as the API server reports it, kube relies on it to detect missing objects
+                        "reason": status.canonical_reason().unwrap_or_default().replace(' ', ""),
+                        "code": status.as_u16(),
+                    })
+                    .to_string(),
+                };
+                let mut response = Response::new(Body::from(body));
+                *response.status_mut() = status;
+                Ok::<_, Infallible>(response)
+            }
+        });
+        Client::new(service, "default")
+    }
+
+    fn elector(lease: &Arc<Mutex<Option<Value>>>, identity: &str) -> LeaderElector {
+        LeaderElector::new(
+            lease_client(lease.clone()),
+            "default".to_string(),
+            "v6helper",
+            identity.to_string(),
+            Duration::from_millis(100),
+        )
+    }
+
+    fn holder(lease: &Arc<Mutex<Option<Value>>>) -> Value {
+        lease.lock().unwrap().as_ref().unwrap()["spec"]["holderIdentity"].clone()
+    }
+
+    #[tokio::test]
+    async fn elects_single_leader() {
+        let lease = Arc::new(Mutex::new(None));
+        let a = elector(&lease, "replica-a");
+        let b = elector(&lease, "replica-b");
+
+        assert!(a.try_acquire_or_renew().await.unwrap());
+        assert_eq!(holder(&lease), "replica-a");
+        assert!(!b.try_acquire_or_renew().await.unwrap());
+        assert!(a.try_acquire_or_renew().await.unwrap());
+        // Every renewal is a new version, so b keeps waiting
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(a.try_acquire_or_renew().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(!b.try_acquire_or_renew().await.unwrap());
+
+        // a stops renewing, b takes over once the lease expires
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(b.try_acquire_or_renew().await.unwrap());
+        assert_eq!(holder(&lease), "replica-b");
+        assert_eq!(
+            lease.lock().unwrap().as_ref().unwrap()["spec"]["leaseTransitions"],
+            1
+        );
+        assert!(!a.try_acquire_or_renew().await.unwrap());
+
+        // A released lease is free right away
+        b.release().await;
+        assert_eq!(holder(&lease), Value::Null);
+        assert!(a.try_acquire_or_renew().await.unwrap());
+        assert_eq!(holder(&lease), "replica-a");
+    }
+}
@@ -1,29 +1,131 @@
 mod k8s;
+mod leader;
 
-use std::fmt::Display;
+use std::{fmt::Display, ops::Sub, sync::Arc};
 
 use async_trait::async_trait;
-pub use k8s::KubeClient;
+pub use k8s::{KubeClient, KubeClientOptions, DEFAULT_FIELD_MANAGER};
+pub use leader::{LeaderElector, DEFAULT_LEASE_DURATION};
 
-use ipnet::Ipv6Net;
+use ipnet::{Ipv4Net, Ipv6Net};
 #[cfg(test)]
 use mockall::automock;
 use thiserror::Error;
+use tokio::sync::Notify;
+
+/// Broad category of a [`ConnectorError`], e.g. for deciding whether retrying is worthwhile
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ConnectorErrorKind {
+    /// The pool, its CRD or a range in it does not exist
+    NotFound,
+    /// The credentials were rejected or lack the required permissions
+    AuthFailed,
+    /// The pool was changed concurrently by another writer
+    Conflict,
+    /// The API server could not be reached or the request failed in transit
+    Network,
+    Other,
+}
 
 #[derive(Error, Debug)]
 pub struct ConnectorError {
+    kind: ConnectorErrorKind,
     msg: String,
 }
+impl ConnectorError {
+    pub fn new(kind: ConnectorErrorKind, msg: impl Into<String>) -> Self {
+        ConnectorError {
+            kind,
+            msg: msg.into(),
+        }
+    }
+
+    pub fn kind(&self) -> ConnectorErrorKind {
+        self.kind
+    }
+}
 impl Display for ConnectorError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.msg)
     }
 }
 
+/// How the helper records its updates in the pool annotations
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+pub enum UpdateMarker {
+    /// Don't annotate the pool
+    #[default]
+    None,
+    /// Record the wall-clock time of the last update.
+    /// Unreliable for ordering updates from multiple writers if their clocks are skewed
+    Timestamp,
+    /// Record a monotonically increasing update counter and the observed object generation.
+    /// Patches are made conditional on the pool's resourceVersion, so concurrent writers conflict instead of overwriting each other
+    Generation,
+}
+
+/// How changes to the pool are sent to the API server
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+pub enum PatchStrategy {
+    /// JSON merge patch, which overwrites the addresses regardless of who manages them
+    #[default]
+    Merge,
+    /// Server-side apply with a dedicated field manager.
+    /// Ownership of the addresses is tracked by the API server, conflicts with other managers are reported as errors
+    Apply,
+}
+
+/// How a range is replaced with another one
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+pub enum ReplaceStrategy {
+    /// Swap the ranges in a single patch, the pool never contains both of them
+    #[default]
+    Atomic,
+    /// Add the new range next to the old one, confirm that it is in the pool and only then remove the old range in a second patch.
+    /// The pool is never without a range, at the cost of briefly containing both
+    AddThenRemove,
+}
+
+/// Number of API requests issued by a connector
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct RequestCounts {
+    pub reads: u64,
+    pub writes: u64,
+}
+impl Sub for RequestCounts {
+    type Output = RequestCounts;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        RequestCounts {
+            reads: self.reads.saturating_sub(rhs.reads),
+            writes: self.writes.saturating_sub(rhs.writes),
+        }
+    }
+}
+impl Display for RequestCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reads={} writes={}", self.reads, self.writes)
+    }
+}
+
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait Connector {
     async fn v6_ranges(&self) -> Result<Vec<Ipv6Net>, ConnectorError>;
     async fn replace(&self, old: &Ipv6Net, new: &Ipv6Net) -> Result<(), ConnectorError>;
     async fn insert(&self, range: &Ipv6Net) -> Result<(), ConnectorError>;
+    /// IPv4 counterparts of the methods above, only used if IPv4 ranges are reconciled as well
+    async fn v4_ranges(&self) -> Result<Vec<Ipv4Net>, ConnectorError>;
+    async fn replace_v4(&self, old: &Ipv4Net, new: &Ipv4Net) -> Result<(), ConnectorError>;
+    async fn insert_v4(&self, range: &Ipv4Net) -> Result<(), ConnectorError>;
+    /// Human-readable description of the managed pool, for use in logs
+    fn describe(&self) -> String;
+    /// Total number of API requests issued so far
+    fn request_counts(&self) -> RequestCounts {
+        RequestCounts::default()
+    }
+    /// Notified (through `notify_waiters`) when the pool may have been changed by someone else
+    fn changes(&self) -> Option<Arc<Notify>> {
+        None
+    }
 }
@@ -0,0 +1,235 @@
+use std::{
+    net::Ipv6Addr,
+    process::{Command, ExitStatus, Stdio},
+    str::FromStr,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Child,
+};
+
+use super::{mask_network, PrefixSource, SourceError};
+
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("Unable to run `{0}`: `{1}`")]
+    SpawnError(String, String),
+    #[error("`{0}` was killed after running for {1:?}")]
+    Timeout(String, Duration),
+    #[error("`{0}` failed with {1}: `{2}`")]
+    Failed(String, ExitStatus, String),
+    #[error("`{0}` returned `{1}`, which is not an IPv6 prefix (stderr: `{2}`)")]
+    InvalidOutput(String, String, String),
+}
+
+impl From<CommandError> for SourceError {
+    fn from(e: CommandError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Runs a shell command that prints the prefix in CIDR notation (or an address from it) to stdout
+pub struct CommandSource {
+    command: String,
+    timeout: Duration,
+    network_length: u8,
+}
+
+impl CommandSource {
+    pub async fn try_new(
+        command: String,
+        timeout: Duration,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, CommandError> {
+        let source = CommandSource {
+            command,
+            timeout,
+            network_length,
+        };
+        match source.run().await {
+            Err(e @ CommandError::SpawnError(..)) => return Err(e),
+            Err(e) => warn!("{} while creating source, continuing", e),
+            Ok(_) => {}
+        }
+        Ok(Box::new(source))
+    }
+
+    async fn run(&self) -> Result<Ipv6Net, CommandError> {
+        let mut command = Command::new("sh");
+        // The command runs in its own process group, so that everything it started can be killed on timeout,
+        // not only the shell
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = tokio::process::Command::from(command)
+            .spawn()
+            .map_err(|e| CommandError::SpawnError(self.command.clone(), e.to_string()))?;
+
+        // Both pipes are read while waiting, so that a chatty command can't block on a full pipe
+        let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+        let output = async {
+            let (stdout, stderr, status) =
+                tokio::join!(read_pipe(stdout), read_pipe(stderr), child.wait());
+            status.map(|status| (stdout, stderr, status))
+        };
+        let (stdout, stderr, status) = match tokio::time::timeout(self.timeout, output).await {
+            Ok(output) => {
+                output.map_err(|e| CommandError::SpawnError(self.command.clone(), e.to_string()))?
+            }
+            Err(_) => {
+                kill(&mut child);
+                let _ = child.wait().await;
+                return Err(CommandError::Timeout(self.command.clone(), self.timeout));
+            }
+        };
+        debug!(
+            "`{}` exited with {}, stdout: `{}`, stderr: `{}`",
+            self.command,
+            status,
+            stdout.trim(),
+            stderr.trim()
+        );
+        parse_output(&self.command, status, &stdout, &stderr)
+    }
+}
+
+async fn read_pipe(pipe: Option<impl AsyncRead + Unpin>) -> String {
+    let mut out = String::new();
+    if let Some(mut pipe) = pipe {
+        let _ = pipe.read_to_string(&mut out).await;
+    }
+    out
+}
+
+// Kills the child along with the processes it started
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    if let Some(Ok(pid)) = child.id().map(libc::pid_t::try_from) {
+        // SAFETY: only sends a signal, the process group was created for the child by `run`
+        if unsafe { libc::kill(-pid, libc::SIGKILL) } == 0 {
+            return;
+        }
+    }
+    let _ = child.start_kill();
+}
+
+fn parse_output(
+    command: &str,
+    status: ExitStatus,
+    stdout: &str,
+    stderr: &str,
+) -> Result<Ipv6Net, CommandError> {
+    if !status.success() {
+        return Err(CommandError::Failed(
+            command.to_string(),
+            status,
+            stderr.trim().to_string(),
+        ));
+    }
+    let out = stdout.trim();
+    Ipv6Net::from_str(out)
+        .or_else(|_| Ipv6Addr::from_str(out).map(Ipv6Net::from))
+        .map_err(|_| {
+            CommandError::InvalidOutput(
+                command.to_string(),
+                out.to_string(),
+                stderr.trim().to_string(),
+            )
+        })
+}
+
+#[async_trait]
+impl PrefixSource for CommandSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.run().await?;
+        mask_network(prefix.addr(), self.network_length).ok_or_else(|| {
+            CommandError::InvalidOutput(self.command.clone(), prefix.to_string(), String::new())
+                .into()
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "command source running `{}`, network-length {}",
+            self.command, self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use ipnet::Ipv6Net;
+
+    use super::{CommandError, CommandSource};
+
+    async fn run(command: &str) -> Result<Ipv6Net, CommandError> {
+        CommandSource {
+            command: command.to_string(),
+            timeout: Duration::from_secs(1),
+            network_length: 56,
+        }
+        .run()
+        .await
+    }
+
+    #[tokio::test]
+    async fn runs_command() {
+        assert_eq!(
+            run("echo ' 2003:ee:970c:8000::/56'").await.unwrap(),
+            Ipv6Net::from_str("2003:ee:970c:8000::/56").unwrap()
+        );
+        let err = run("echo 'no prefix yet' >&2; exit 3").await.unwrap_err();
+        assert!(matches!(err, CommandError::Failed(..)));
+        assert!(err.to_string().contains("no prefix yet"), "{}", err);
+        assert!(matches!(
+            run("echo 192.0.2.0/24").await,
+            Err(CommandError::InvalidOutput(..))
+        ));
+        assert!(matches!(
+            run("sleep 5").await,
+            Err(CommandError::Timeout(..))
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn kills_started_processes_on_timeout() {
+        let pid_file =
+            std::env::temp_dir().join(format!("v6helper-test-command-{}", std::process::id()));
+        let result = run(&format!(
+            "sleep 30 & echo $! > {}; wait",
+            pid_file.display()
+        ))
+        .await;
+        let pid = std::fs::read_to_string(&pid_file);
+        let _ = std::fs::remove_file(&pid_file);
+        assert!(matches!(result, Err(CommandError::Timeout(..))));
+
+        // The background process is gone, or a zombie waiting to be reaped
+        let stat = format!("/proc/{}/stat", pid.unwrap().trim());
+        let running = || match std::fs::read_to_string(&stat) {
+            Ok(stat) => !stat.contains(") Z "),
+            Err(_) => false,
+        };
+        for _ in 0..50 {
+            if !running() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} is still running", stat);
+    }
+}
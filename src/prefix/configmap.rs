@@ -0,0 +1,261 @@
+use std::{
+    collections::BTreeMap,
+    net::Ipv6Addr,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use ipnet::Ipv6Net;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    api::{ListParams, WatchEvent},
+    Api, Client, Config,
+};
+use log::{debug, warn};
+use thiserror::Error;
+use tokio::{sync::Notify, task::JoinHandle, time::sleep};
+
+use super::{mask_network, PrefixSource, SourceError};
+
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum ConfigMapError {
+    #[error("Error while accessing the k8s API: `{0}`")]
+    ConnectionError(String),
+    #[error("ConfigMap `{0}` could not be found")]
+    NotFound(String),
+    #[error("ConfigMap `{0}` has no key `{1}`")]
+    MissingKey(String, String),
+    #[error("Key `{1}` of ConfigMap `{0}` contains `{2}`, which is not an IPv6 prefix")]
+    InvalidValue(String, String, String),
+}
+
+impl From<ConfigMapError> for SourceError {
+    fn from(e: ConfigMapError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+// Data of the ConfigMap, `None` while it doesn't exist
+type Data = Arc<Mutex<Option<BTreeMap<String, String>>>>;
+
+/// Reads the prefix in CIDR notation (or an address from it) from a key of a ConfigMap maintained by something else.
+// The ConfigMap is watched in the background and the source only reads the latest data, so no API call is made per run.
+// This also reports changes right away
+pub struct ConfigMapSource {
+    namespace: String,
+    name: String,
+    key: String,
+    network_length: u8,
+    data: Data,
+    changes: Arc<Notify>,
+    watch_task: JoinHandle<()>,
+}
+
+impl ConfigMapSource {
+    pub async fn try_new(
+        namespace: Option<String>,
+        name: String,
+        key: String,
+        no_verify: bool,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, ConfigMapError> {
+        let conn_err = |e: String| ConfigMapError::ConnectionError(e);
+        let mut cfg = Config::infer().await.map_err(|e| conn_err(e.to_string()))?;
+        cfg.accept_invalid_certs = no_verify;
+        let namespace = namespace.unwrap_or_else(|| cfg.default_namespace.clone());
+        let client = Client::try_from(cfg).map_err(|e| conn_err(e.to_string()))?;
+        let api: Api<ConfigMap> = Api::namespaced(client, &namespace);
+
+        let data = Data::default();
+        // The ConfigMap may not have been created yet
+        match api.get_opt(&name).await {
+            Ok(configmap) => *lock(&data) = configmap.map(|c| c.data.unwrap_or_default()),
+            Err(e) => warn!(
+                "{} while creating source, continuing",
+                conn_err(e.to_string())
+            ),
+        }
+        let changes = Arc::new(Notify::new());
+        let watch_task = tokio::spawn(watch_configmap(
+            api,
+            name.clone(),
+            data.clone(),
+            changes.clone(),
+        ));
+        let source = ConfigMapSource {
+            namespace,
+            name,
+            key,
+            network_length,
+            data,
+            changes,
+            watch_task,
+        };
+        if let Err(e) = source.read() {
+            warn!("{} while creating source, continuing", e);
+        }
+        Ok(Box::new(source))
+    }
+
+    fn read(&self) -> Result<Ipv6Net, ConfigMapError> {
+        let configmap = format!("{}/{}", self.namespace, self.name);
+        parse_data(lock(&self.data).as_ref(), &configmap, &self.key)
+    }
+}
+
+impl Drop for ConfigMapSource {
+    fn drop(&mut self) {
+        self.watch_task.abort();
+    }
+}
+
+fn lock(data: &Data) -> MutexGuard<'_, Option<BTreeMap<String, String>>> {
+    match data.lock() {
+        Ok(data) => data,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Keeps `data` up to date with the ConfigMap
+async fn watch_configmap(api: Api<ConfigMap>, name: String, data: Data, changes: Arc<Notify>) {
+    let params = ListParams::default().fields(&format!("metadata.name={}", name));
+    let mut version = "0".to_string();
+    loop {
+        let mut stream = match api.watch(&params, &version).await {
+            Ok(stream) => stream.boxed(),
+            Err(e) => {
+                warn!("Unable to watch ConfigMap {}, retrying: {}", name, e);
+                sleep(WATCH_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        while let Some(event) = stream.next().await {
+            match event {
+                Ok(WatchEvent::Added(configmap)) | Ok(WatchEvent::Modified(configmap)) => {
+                    debug!("ConfigMap {} changed", name);
+                    if let Some(v) = configmap.metadata.resource_version {
+                        version = v;
+                    }
+                    *lock(&data) = Some(configmap.data.unwrap_or_default());
+                    changes.notify_waiters();
+                }
+                Ok(WatchEvent::Deleted(configmap)) => {
+                    debug!("ConfigMap {} deleted", name);
+                    if let Some(v) = configmap.metadata.resource_version {
+                        version = v;
+                    }
+                    *lock(&data) = None;
+                    changes.notify_waiters();
+                }
+                Ok(WatchEvent::Bookmark(bookmark)) => version = bookmark.metadata.resource_version,
+                Ok(WatchEvent::Error(e)) => {
+                    // The version is too old, start over with the current state
+                    if e.code == 410 {
+                        version = "0".to_string();
+                    }
+                    warn!(
+                        "Error while watching ConfigMap {}, restarting: {}",
+                        name, e.message
+                    );
+                    sleep(WATCH_RETRY_DELAY).await;
+                    break;
+                }
+                Err(e) => {
+                    warn!("Error while watching ConfigMap {}, restarting: {}", name, e);
+                    sleep(WATCH_RETRY_DELAY).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn parse_data(
+    data: Option<&BTreeMap<String, String>>,
+    configmap: &str,
+    key: &str,
+) -> Result<Ipv6Net, ConfigMapError> {
+    let data = data.ok_or_else(|| ConfigMapError::NotFound(configmap.to_string()))?;
+    let value = data
+        .get(key)
+        .ok_or_else(|| ConfigMapError::MissingKey(configmap.to_string(), key.to_string()))?
+        .trim();
+    Ipv6Net::from_str(value)
+        .or_else(|_| Ipv6Addr::from_str(value).map(Ipv6Net::from))
+        .map_err(|_| {
+            ConfigMapError::InvalidValue(configmap.to_string(), key.to_string(), value.to_string())
+        })
+}
+
+#[async_trait]
+impl PrefixSource for ConfigMapSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.read()?;
+        mask_network(prefix.addr(), self.network_length).ok_or_else(|| {
+            ConfigMapError::InvalidValue(
+                format!("{}/{}", self.namespace, self.name),
+                self.key.clone(),
+                prefix.to_string(),
+            )
+            .into()
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "configmap source on {}/{} key {}, network-length {}",
+            self.namespace, self.name, self.key, self.network_length
+        )
+    }
+
+    fn changes(&self) -> Option<Arc<Notify>> {
+        Some(self.changes.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, str::FromStr};
+
+    use ipnet::Ipv6Net;
+
+    use super::{parse_data, ConfigMapError};
+
+    #[test]
+    fn parses_configmap_data() {
+        let data: BTreeMap<_, _> = [
+            ("prefix".to_string(), "2003:ee:970c:8000::/56\n".to_string()),
+            ("address".to_string(), "2003:ee:970c:80aa::199".to_string()),
+            ("broken".to_string(), "192.0.2.0/24".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let parse = |key| parse_data(Some(&data), "default/prefix", key);
+
+        assert_eq!(
+            parse("prefix").unwrap(),
+            Ipv6Net::from_str("2003:ee:970c:8000::/56").unwrap()
+        );
+        assert_eq!(
+            parse("address").unwrap(),
+            Ipv6Net::from_str("2003:ee:970c:80aa::199/128").unwrap()
+        );
+        assert!(matches!(
+            parse("broken"),
+            Err(ConfigMapError::InvalidValue(..))
+        ));
+        assert!(matches!(
+            parse("other"),
+            Err(ConfigMapError::MissingKey(..))
+        ));
+        assert!(matches!(
+            parse_data(None, "default/prefix", "prefix"),
+            Err(ConfigMapError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    fs,
+    net::Ipv6Addr,
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use thiserror::Error;
+
+use super::{mask_network, PrefixSource, SourceError};
+
+#[derive(Error, Debug)]
+pub enum DhcpPdError {
+    #[error("Unable to read lease file `{0}`: `{1}`")]
+    ReadError(String, String),
+    #[error("Lease file `{0}` contains no active prefix delegation")]
+    NoDelegation(String),
+}
+
+impl From<DhcpPdError> for SourceError {
+    fn from(e: DhcpPdError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Format of the lease file read by [`DhcpPdSource`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+pub enum LeaseFormat {
+    /// ISC dhclient (`dhclient -6 -P`) lease file, using the `iaprefix` of the newest active lease
+    #[default]
+    Isc,
+    /// dnsmasq lease file, using the IPv6 lease that expires last
+    Dnsmasq,
+    /// systemd-networkd style `KEY=value` state file, using the first global IPv6 network (`address/length`) in any value
+    Networkd,
+}
+
+/// Reads the delegated prefix from the lease file of a DHCPv6 client.
+/// The delegated prefix is usually not assigned to any interface as a whole, so it has to be taken from the lease itself
+pub struct DhcpPdSource {
+    lease_file: PathBuf,
+    format: LeaseFormat,
+    network_length: u8,
+}
+
+impl DhcpPdSource {
+    pub fn try_new(
+        lease_file: PathBuf,
+        format: LeaseFormat,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, DhcpPdError> {
+        let source = DhcpPdSource {
+            lease_file,
+            format,
+            network_length,
+        };
+        // An unreadable file is fatal, the client may just not have received a delegation yet
+        match source.delegated_prefix() {
+            Err(e @ DhcpPdError::ReadError(..)) => return Err(e),
+            Err(e) => warn!("{} while creating source, continuing", e),
+            Ok(_) => {}
+        }
+        Ok(Box::new(source))
+    }
+
+    fn delegated_prefix(&self) -> Result<Ipv6Net, DhcpPdError> {
+        let path = self.lease_file.display().to_string();
+        let content = fs::read_to_string(&self.lease_file)
+            .map_err(|e| DhcpPdError::ReadError(path.clone(), e.to_string()))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let prefix = match self.format {
+            LeaseFormat::Isc => parse_isc(&content, now),
+            LeaseFormat::Dnsmasq => parse_dnsmasq(&content, now),
+            LeaseFormat::Networkd => parse_networkd(&content),
+        };
+        debug!("Delegated prefix in {}: {:?}", path, prefix);
+        prefix.ok_or(DhcpPdError::NoDelegation(path))
+    }
+}
+
+// Finds the newest `iaprefix` that has not yet reached its max-life.
+// dhclient appends renewed leases, so for equal start times the last one wins
+fn parse_isc(content: &str, now: u64) -> Option<Ipv6Net> {
+    let mut best: Option<(u64, Ipv6Net)> = None;
+    let mut current: Option<(Ipv6Net, u64, Option<u64>)> = None;
+    for line in content.lines() {
+        let line = line.trim().trim_end_matches(';');
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("iaprefix"), Some(net)) => {
+                current = Ipv6Net::from_str(net).ok().map(|net| (net, 0, None))
+            }
+            (Some("starts"), Some(starts)) => {
+                if let Some((_, s, _)) = &mut current {
+                    *s = starts.parse().unwrap_or(0);
+                }
+            }
+            (Some("max-life"), Some(life)) => {
+                if let Some((_, _, l)) = &mut current {
+                    *l = life.parse().ok();
+                }
+            }
+            (Some("}"), _) => {
+                if let Some((net, starts, life)) = current.take() {
+                    let active = match life {
+                        Some(life) => starts.saturating_add(life) > now,
+                        None => true,
+                    };
+                    if active && !matches!(best, Some((s, _)) if s > starts) {
+                        best = Some((starts, net));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    best.map(|(_, net)| net)
+}
+
+// IPv6 leases follow the `duid` line and have the form `<expiry> <iaid> <address> <hostname> <client id>`.
+// An expiry of 0 means that the lease never expires
+fn parse_dnsmasq(content: &str, now: u64) -> Option<Ipv6Net> {
+    content
+        .lines()
+        .skip_while(|l| !l.starts_with("duid "))
+        .skip(1)
+        .filter_map(|l| {
+            let fields: Vec<_> = l.split_whitespace().collect();
+            let expiry = fields.first()?.parse::<u64>().ok()?;
+            let addr = fields.get(2)?;
+            let net = match addr.split_once('/') {
+                Some(_) => Ipv6Net::from_str(addr).ok()?,
+                None => Ipv6Net::new(Ipv6Addr::from_str(addr).ok()?, 128).ok()?,
+            };
+            let expiry = match expiry {
+                0 => u64::MAX,
+                e => e,
+            };
+            Some((expiry, net))
+        })
+        .filter(|(expiry, _)| *expiry > now)
+        .max_by_key(|(expiry, _)| *expiry)
+        .map(|(_, net)| net)
+}
+
+fn parse_networkd(content: &str) -> Option<Ipv6Net> {
+    content
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .flat_map(|(_, value)| value.split_whitespace())
+        .filter_map(|v| Ipv6Net::from_str(v).ok())
+        .find(|net| ip_rfc::global_v6(&net.addr()))
+}
+
+#[async_trait]
+impl PrefixSource for DhcpPdSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.delegated_prefix()?;
+        mask_network(prefix.addr(), self.network_length)
+            .ok_or_else(|| DhcpPdError::NoDelegation(self.lease_file.display().to_string()).into())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "dhcp-pd source on {} ({:?} format), network-length {}",
+            self.lease_file.display(),
+            self.format,
+            self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ipnet::Ipv6Net;
+
+    use super::{parse_dnsmasq, parse_isc, parse_networkd};
+
+    const NOW: u64 = 1700003600;
+
+    fn net(s: &str) -> Option<Ipv6Net> {
+        Some(Ipv6Net::from_str(s).unwrap())
+    }
+
+    #[test]
+    fn parses_isc_lease() {
+        let lease = |prefix: &str, starts: u64| {
+            format!(
+                r#"lease6 {{
+  interface "wan0";
+  ia-pd 1a:2b:3c:4d {{
+    starts {starts};
+    renew 1800;
+    rebind 2880;
+    iaprefix {prefix} {{
+      starts {starts};
+      preferred-life 3600;
+      max-life 7200;
+    }}
+  }}
+  option dhcp6.client-id 0:1:0:1:2a:2b:2c:2d:0:11:22:33:44:55;
+}}
+"#
+            )
+        };
+        let content =
+            lease("2001:db8:aa00::/56", 1700000000) + &lease("2001:db8:bb00::/56", 1700001000);
+        assert_eq!(parse_isc(&content, NOW), net("2001:db8:bb00::/56"));
+        // The newer lease wins regardless of its position in the file
+        let content =
+            lease("2001:db8:bb00::/56", 1700001000) + &lease("2001:db8:aa00::/56", 1700000000);
+        assert_eq!(parse_isc(&content, NOW), net("2001:db8:bb00::/56"));
+        // Both delegations have expired
+        assert_eq!(parse_isc(&content, 1700010000), None);
+        assert_eq!(parse_isc("", NOW), None);
+    }
+
+    #[test]
+    fn parses_dnsmasq_lease() {
+        let content = "\
+1700005000 00:11:22:33:44:55 192.168.1.10 host1 01:00:11:22:33:44:55
+duid 00:01:00:01:2a:2b:2c:2d:00:11:22:33:44:55
+1700009000 1234 2001:db8:cc00::1 host1 00:03:00:01:aa:bb:cc:dd:ee:ff
+1700007000 1235 2001:db8:dd00::/56 * 00:03:00:01:aa:bb:cc:dd:ee:ff
+1700000000 1236 2001:db8:ee00::1 host2 00:03:00:01:11:22:33:44:55:66
+";
+        assert_eq!(parse_dnsmasq(content, NOW), net("2001:db8:cc00::1/128"));
+        let without_newest: String = content
+            .lines()
+            .filter(|l| !l.contains("cc00"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        assert_eq!(
+            parse_dnsmasq(&without_newest, NOW),
+            net("2001:db8:dd00::/56")
+        );
+        assert_eq!(parse_dnsmasq(content, 1700009000), None);
+        // IPv4 leases before the duid line are ignored
+        assert_eq!(
+            parse_dnsmasq("1700005000 00:11:22:33:44:55 192.168.1.10 host1 *", NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_networkd_state() {
+        let content = "\
+# This is private data. Do not parse.
+ADMIN_STATE=configured
+ADDRESSES=fe80::1/64 fd00::1/64
+DHCP6_PREFIXES=2003:ee:ab00::/56
+";
+        assert_eq!(parse_networkd(content), net("2003:ee:ab00::/56"));
+        assert_eq!(parse_networkd("ADDRESSES=fd00::1/64"), None);
+    }
+}
@@ -0,0 +1,137 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use async_trait::async_trait;
+use ipnet::{Ipv4Net, Ipv6Net};
+use log::{debug, warn};
+use thiserror::Error;
+
+use super::{mask_network, PrefixSource, SourceError};
+
+#[derive(Error, Debug)]
+pub enum DnsError {
+    #[error("Unable to resolve `{0}`: `{1}`")]
+    ResolveError(String, String),
+    #[error("`{0}` has no global AAAA record")]
+    NoAaaaRecord(String),
+    #[error("`{0}` has no global A record")]
+    NoARecord(String),
+}
+
+impl From<DnsError> for SourceError {
+    fn from(e: DnsError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Derives the network from the AAAA record of a host name, e.g. a dynamic DNS name updated by the router.
+/// Names are resolved through the system resolver, so results may be cached according to the record TTL
+pub struct DnsSource {
+    hostname: String,
+    network_length: u8,
+}
+
+impl DnsSource {
+    pub async fn try_new(
+        hostname: String,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, DnsError> {
+        let source = DnsSource {
+            hostname,
+            network_length,
+        };
+        // The record may not have been published yet
+        if let Err(e) = source.resolve().await {
+            warn!("{} while creating source, continuing", e);
+        }
+        Ok(Box::new(source))
+    }
+
+    async fn resolve(&self) -> Result<Vec<IpAddr>, DnsError> {
+        let addrs: Vec<_> = tokio::net::lookup_host((self.hostname.as_str(), 0))
+            .await
+            .map_err(|e| DnsError::ResolveError(self.hostname.clone(), e.to_string()))?
+            .map(|a| a.ip())
+            .collect();
+        debug!("Resolved {} to {:?}", self.hostname, addrs);
+        Ok(addrs)
+    }
+}
+
+// Returns the first global IPv6 address
+fn first_global_v6(addrs: &[IpAddr]) -> Option<Ipv6Addr> {
+    addrs.iter().find_map(|a| match a {
+        IpAddr::V6(v6) if ip_rfc::global_v6(v6) => Some(*v6),
+        _ => None,
+    })
+}
+
+// Returns the first global IPv4 address
+fn first_global_v4(addrs: &[IpAddr]) -> Option<Ipv4Addr> {
+    addrs.iter().find_map(|a| match a {
+        IpAddr::V4(v4) if ip_rfc::global_v4(v4) => Some(*v4),
+        _ => None,
+    })
+}
+
+#[async_trait]
+impl PrefixSource for DnsSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let addrs = self.resolve().await?;
+        first_global_v6(&addrs)
+            .and_then(|addr| mask_network(addr, self.network_length))
+            .ok_or_else(|| DnsError::NoAaaaRecord(self.hostname.clone()).into())
+    }
+
+    async fn v4_network(&self) -> Result<Ipv4Net, SourceError> {
+        let addrs = self.resolve().await?;
+        first_global_v4(&addrs)
+            .map(Ipv4Net::from)
+            .ok_or_else(|| DnsError::NoARecord(self.hostname.clone()).into())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "dns source for {}, network-length {}",
+            self.hostname, self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, str::FromStr};
+
+    use ipnet::Ipv6Net;
+
+    use super::{first_global_v4, first_global_v6};
+    use crate::prefix::mask_network;
+
+    #[test]
+    fn masks_resolved_address() {
+        let addrs: Vec<_> = [
+            "192.0.2.1",
+            "fd00::1",
+            "2003:ee:970c:80aa::199",
+            "2a02:8070::1",
+        ]
+        .iter()
+        .map(|a| IpAddr::from_str(a).unwrap())
+        .collect();
+        let addr = first_global_v6(&addrs).unwrap();
+        assert_eq!(
+            mask_network(addr, 56),
+            Some(Ipv6Net::from_str("2003:ee:970c:8000::/56").unwrap())
+        );
+        assert_eq!(first_global_v6(&addrs[..2]), None);
+        // 192.0.2.0/24 is reserved for documentation
+        assert_eq!(first_global_v4(&addrs), None);
+        let addrs = [
+            IpAddr::from_str("198.51.100.7").unwrap(),
+            IpAddr::from_str("93.184.216.34").unwrap(),
+        ];
+        assert_eq!(
+            first_global_v4(&addrs),
+            Some("93.184.216.34".parse().unwrap())
+        );
+    }
+}
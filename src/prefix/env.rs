@@ -0,0 +1,114 @@
+use std::{env, net::Ipv6Addr, str::FromStr};
+
+use async_trait::async_trait;
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use thiserror::Error;
+
+use super::{mask_network, PrefixSource, SourceError};
+
+#[derive(Error, Debug)]
+pub enum EnvError {
+    #[error("Environment variable `{0}` is not set")]
+    NotSet(String),
+    #[error("Environment variable `{0}` contains `{1}`, which is not an IPv6 prefix")]
+    InvalidValue(String, String),
+}
+
+impl From<EnvError> for SourceError {
+    fn from(e: EnvError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Reads the prefix in CIDR notation (or an address from it) from an environment variable of the process.
+/// The variable is read again on every run, so changes made at runtime are picked up without a restart
+pub struct EnvSource {
+    var: String,
+    network_length: u8,
+}
+
+impl EnvSource {
+    pub fn try_new(var: String, network_length: u8) -> Result<Box<dyn PrefixSource>, EnvError> {
+        let source = EnvSource {
+            var,
+            network_length,
+        };
+        // The variable may be set later on
+        if let Err(e) = source.read() {
+            warn!("{} while creating source, continuing", e);
+        }
+        Ok(Box::new(source))
+    }
+
+    fn read(&self) -> Result<Ipv6Net, EnvError> {
+        let value = env::var(&self.var).map_err(|_| EnvError::NotSet(self.var.clone()))?;
+        debug!("Value of {}: {}", self.var, value);
+        let trimmed = value.trim();
+        Ipv6Net::from_str(trimmed)
+            .or_else(|_| Ipv6Addr::from_str(trimmed).map(Ipv6Net::from))
+            .map_err(|_| EnvError::InvalidValue(self.var.clone(), value))
+    }
+}
+
+#[async_trait]
+impl PrefixSource for EnvSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.read()?;
+        mask_network(prefix.addr(), self.network_length)
+            .ok_or_else(|| EnvError::InvalidValue(self.var.clone(), prefix.to_string()).into())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "env source on {}, network-length {}",
+            self.var, self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, str::FromStr};
+
+    use ipnet::Ipv6Net;
+
+    use super::EnvSource;
+
+    #[tokio::test]
+    async fn reads_prefix_from_env() {
+        // Unique to this test, as the environment is shared by all tests
+        let var = "V6HELPER_TEST_ENV_SOURCE_PREFIX";
+        env::remove_var(var);
+        let source = EnvSource::try_new(var.to_string(), 64).unwrap();
+        assert!(source
+            .v6_network()
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("is not set"));
+
+        env::set_var(var, "2003:ee:970c:8000::/56\n");
+        assert_eq!(
+            source.v6_network().await.unwrap(),
+            Ipv6Net::from_str("2003:ee:970c:8000::/64").unwrap()
+        );
+        // Changes are picked up without recreating the source
+        env::set_var(var, "2003:ee:970c:80aa::199");
+        assert_eq!(
+            source.v6_network().await.unwrap(),
+            Ipv6Net::from_str("2003:ee:970c:80aa::/64").unwrap()
+        );
+
+        env::set_var(var, "192.0.2.0/24");
+        assert!(source
+            .v6_network()
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("not an IPv6 prefix"));
+
+        env::remove_var(var);
+        assert!(source.v6_network().await.is_err());
+    }
+}
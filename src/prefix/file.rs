@@ -0,0 +1,192 @@
+use std::{
+    fs,
+    net::Ipv6Addr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use super::{mask_network, PrefixSource, SourceError};
+
+#[derive(Error, Debug)]
+pub enum FileError {
+    #[error("Unable to read `{0}`: `{1}`")]
+    ReadError(String, String),
+    #[error("`{0}` contains `{1}`, which is not an IPv6 prefix")]
+    InvalidContent(String, String),
+    #[error("Unable to watch `{0}`: `{1}`")]
+    WatchError(String, String),
+}
+
+impl From<FileError> for SourceError {
+    fn from(e: FileError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Reads the prefix in CIDR notation (or an address from it) from a file maintained by something else.
+/// Optionally watches the file, so that changes are picked up right away instead of at the next run
+pub struct FileSource {
+    path: PathBuf,
+    network_length: u8,
+    changes: Option<Arc<Notify>>,
+    // Stops watching when dropped
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl FileSource {
+    pub fn try_new(
+        path: PathBuf,
+        watch: bool,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, FileError> {
+        let (changes, watcher) = match watch {
+            true => {
+                let changes = Arc::new(Notify::new());
+                let watcher = watch_file(&path, changes.clone())?;
+                (Some(changes), Some(watcher))
+            }
+            false => (None, None),
+        };
+        let source = FileSource {
+            path,
+            network_length,
+            changes,
+            _watcher: watcher,
+        };
+        // The file may not have been written yet
+        if let Err(e) = source.read() {
+            warn!("{} while creating source, continuing", e);
+        }
+        Ok(Box::new(source))
+    }
+
+    fn read(&self) -> Result<Ipv6Net, FileError> {
+        let path = self.path.display().to_string();
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| FileError::ReadError(path.clone(), e.to_string()))?;
+        debug!("Content of {}: {}", path, content.trim());
+        parse_prefix(&content)
+            .ok_or_else(|| FileError::InvalidContent(path, content.trim().to_string()))
+    }
+}
+
+/// Notifies `changes` whenever the file at `path` is written, replaced or removed. Watching stops when the
+/// returned watcher is dropped.
+// Watches the parent directory, as the file is usually replaced rather than written in place
+pub fn watch_file(path: &Path, changes: Arc<Notify>) -> Result<RecommendedWatcher, FileError> {
+    let watch_err =
+        |e: notify::Error| FileError::WatchError(path.display().to_string(), e.to_string());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path.file_name().map(|n| n.to_os_string());
+    let display = path.display().to_string();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                if event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == file_name.as_deref())
+                {
+                    debug!("{} changed: {:?}", display, event.kind);
+                    changes.notify_waiters();
+                }
+            }
+            Err(e) => warn!("Error while watching {}: {}", display, e),
+        })
+        .map_err(watch_err)?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(watch_err)?;
+    Ok(watcher)
+}
+
+fn parse_prefix(content: &str) -> Option<Ipv6Net> {
+    let content = content.trim();
+    Ipv6Net::from_str(content)
+        .or_else(|_| Ipv6Addr::from_str(content).map(Ipv6Net::from))
+        .ok()
+}
+
+#[async_trait]
+impl PrefixSource for FileSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.read()?;
+        mask_network(prefix.addr(), self.network_length).ok_or_else(|| {
+            FileError::InvalidContent(self.path.display().to_string(), prefix.to_string()).into()
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "file source on {}{}, network-length {}",
+            self.path.display(),
+            if self.changes.is_some() {
+                " (watched)"
+            } else {
+                ""
+            },
+            self.network_length
+        )
+    }
+
+    fn changes(&self) -> Option<Arc<Notify>> {
+        self.changes.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, str::FromStr, time::Duration};
+
+    use ipnet::Ipv6Net;
+
+    use super::FileSource;
+
+    #[tokio::test]
+    async fn reads_and_watches_file() {
+        let dir = std::env::temp_dir().join(format!("v6helper-test-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("prefix");
+        let _ = fs::remove_file(&path);
+
+        let source = FileSource::try_new(path.clone(), true, 64).unwrap();
+        assert!(source
+            .v6_network()
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("Unable to read"));
+
+        let changes = source.changes().unwrap();
+        let notified = changes.notified();
+        fs::write(&path, "2003:ee:970c:80aa::/64\n").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), notified)
+            .await
+            .expect("no change notification");
+        assert_eq!(
+            source.v6_network().await.unwrap(),
+            Ipv6Net::from_str("2003:ee:970c:80aa::/64").unwrap()
+        );
+
+        fs::write(&path, "not a prefix").unwrap();
+        assert!(source
+            .v6_network()
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("not an IPv6 prefix"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+use std::{net::Ipv6Addr, str::FromStr, time::Duration};
+
+use async_trait::async_trait;
+use hyper::{header, Body, HeaderMap, Request, StatusCode, Uri};
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use thiserror::Error;
+
+use super::{
+    http::{self, host_header, HttpClient, HttpResponse},
+    mask_network, PrefixSource, SourceError,
+};
+
+/// Default port of the TR-064 API
+pub const FRITZBOX_DEFAULT_PORT: u16 = 49000;
+
+const CONTROL_URL: &str = "/upnp/control/wanipconnection1";
+const SERVICE: &str = "urn:dslforum-org:service:WANIPConnection:1";
+const ACTION: &str = "X_AVM_DE_GetIPv6Prefix";
+
+#[derive(Error, Debug)]
+pub enum FritzboxError {
+    #[error("Error while connecting to `{0}`: `{1}`")]
+    ConnectionError(String, String),
+    #[error("`{0}` rejected the credentials of user `{1}`")]
+    AuthFailed(String, String),
+    #[error("Unexpected response from `{0}`: `{1}`")]
+    InvalidResponse(String, String),
+    #[error("`{0}` did not report an IPv6 prefix, the connection may be down")]
+    NoPrefix(String),
+}
+
+impl From<FritzboxError> for SourceError {
+    fn from(e: FritzboxError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Asks a FRITZ!Box for the prefix delegated by the ISP through its TR-064 SOAP API (`X_AVM_DE_GetIPv6Prefix`).
+/// TR-064 has to be enabled on the box ("Allow access for applications"), and the user needs the
+/// "FRITZ!Box settings" permission.
+// Requests are authenticated with HTTP digest authentication, which needs a fresh nonce from a 401 response first
+pub struct FritzboxSource {
+    host: String,
+    port: u16,
+    control_url: Uri,
+    user: String,
+    password: String,
+    timeout: Duration,
+    network_length: u8,
+    client: HttpClient,
+}
+
+impl FritzboxSource {
+    pub async fn try_new(
+        host: String,
+        port: u16,
+        user: String,
+        password: String,
+        timeout: Duration,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, FritzboxError> {
+        let address = format!("{}:{}", host_header(&host), port);
+        let control_url = format!("http://{}{}", address, CONTROL_URL)
+            .parse()
+            .map_err(|e: hyper::http::uri::InvalidUri| {
+                FritzboxError::ConnectionError(address, e.to_string())
+            })?;
+        let source = FritzboxSource {
+            host,
+            port,
+            control_url,
+            user,
+            password,
+            timeout,
+            network_length,
+            client: http::client(),
+        };
+        // Wrong credentials won't fix themselves, but the box may just be rebooting
+        match source.fetch().await {
+            Err(e @ FritzboxError::AuthFailed(..)) => return Err(e),
+            Err(e) => warn!("{} while creating source, continuing", e),
+            Ok(_) => {}
+        }
+        Ok(Box::new(source))
+    }
+
+    async fn fetch(&self) -> Result<Ipv6Net, FritzboxError> {
+        let mut response = self.call(None).await?;
+        if response.status == StatusCode::UNAUTHORIZED {
+            let challenge = digest_challenge(&response.headers)
+                .ok_or_else(|| self.invalid("401 without a digest challenge".to_string()))?;
+            response = self.call(Some(&challenge)).await?;
+            if response.status == StatusCode::UNAUTHORIZED {
+                return Err(FritzboxError::AuthFailed(self.address(), self.user.clone()));
+            }
+        }
+        debug!("Response from {}: {}", self.address(), response.body);
+        if let Some(fault) = soap_fault(&response.body) {
+            return Err(self.invalid(fault));
+        }
+        if !response.status.is_success() {
+            return Err(self.invalid(format!("status {}", response.status.as_u16())));
+        }
+        parse_prefix(&response.body)
+            .map_err(|e| self.invalid(e))?
+            .ok_or_else(|| FritzboxError::NoPrefix(self.address()))
+    }
+
+    // Sends the SOAP request, with an authorization header answering `challenge` if given
+    async fn call(&self, challenge: Option<&Challenge>) -> Result<HttpResponse, FritzboxError> {
+        let authorization =
+            challenge.map(|c| c.authorization(&self.user, &self.password, "POST", CONTROL_URL));
+        let request = soap_request(&self.control_url, SERVICE, ACTION, authorization);
+        http::send(&self.client, request, self.timeout)
+            .await
+            .map_err(|e| FritzboxError::ConnectionError(self.address(), e))
+    }
+
+    fn address(&self) -> String {
+        format!("{}:{}", host_header(&self.host), self.port)
+    }
+
+    fn invalid(&self, msg: String) -> FritzboxError {
+        FritzboxError::InvalidResponse(self.address(), msg)
+    }
+}
+
+// Request for a SOAP action without arguments, along with an `Authorization` header if given
+pub(super) fn soap_request(
+    control_url: &Uri,
+    service: &str,
+    action: &str,
+    authorization: Option<String>,
+) -> Request<Body> {
+    let body = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><u:{action} xmlns:u="{service}"/></s:Body>
+</s:Envelope>"#,
+        action = action,
+        service = service
+    );
+    let mut request = Request::post(control_url)
+        .header(header::CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{}#{}\"", service, action));
+    if let Some(authorization) = authorization {
+        request = request.header(header::AUTHORIZATION, authorization);
+    }
+    request
+        .body(Body::from(body))
+        .expect("request from a valid URI")
+}
+
+// Parameters of a `WWW-Authenticate: Digest ...` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Challenge {
+    realm: String,
+    nonce: String,
+    qop: Option<String>,
+}
+
+impl Challenge {
+    fn authorization(&self, user: &str, password: &str, method: &str, uri: &str) -> String {
+        let ha1 = md5::compute(format!("{}:{}:{}", user, self.realm, password));
+        let ha2 = md5::compute(format!("{}:{}", method, uri));
+        match &self.qop {
+            Some(qop) => {
+                let cnonce = format!("{:016x}", fastrand::u64(..));
+                let nc = "00000001";
+                let response = md5::compute(format!(
+                    "{:x}:{}:{}:{}:{}:{:x}",
+                    ha1, self.nonce, nc, cnonce, qop, ha2
+                ));
+                format!(
+                    r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm=MD5, response="{:x}", qop={}, nc={}, cnonce="{}""#,
+                    user, self.realm, self.nonce, uri, response, qop, nc, cnonce
+                )
+            }
+            None => {
+                let response = md5::compute(format!("{:x}:{}:{:x}", ha1, self.nonce, ha2));
+                format!(
+                    r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm=MD5, response="{:x}""#,
+                    user, self.realm, self.nonce, uri, response
+                )
+            }
+        }
+    }
+}
+
+// Finds the digest challenge in the response headers
+fn digest_challenge(headers: &HeaderMap) -> Option<Challenge> {
+    let params = headers
+        .get_all(header::WWW_AUTHENTICATE)
+        .iter()
+        .find_map(|value| value.to_str().ok()?.trim().strip_prefix("Digest "))?;
+    let param = |key: &str| {
+        params.split(',').find_map(|p| {
+            let (k, v) = p.trim().split_once('=')?;
+            (k.trim() == key).then(|| v.trim().trim_matches('"').to_string())
+        })
+    };
+    Some(Challenge {
+        realm: param("realm")?,
+        nonce: param("nonce")?,
+        // Only `auth` is supported, which the box always offers
+        qop: param("qop").map(|_| "auth".to_string()),
+    })
+}
+
+// Text content of the first element with the given name, ignoring namespace prefixes
+pub(super) fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        let tag_name = tag.split_whitespace().next().unwrap_or_default();
+        let local_name = tag_name.rsplit(':').next().unwrap_or_default();
+        if local_name == name && !tag.ends_with('/') {
+            let content = &rest[end + 1..];
+            return Some(&content[..content.find('<')?]);
+        }
+    }
+    None
+}
+
+// Extracts the error of a SOAP fault, e.g. for missing permissions
+pub(super) fn soap_fault(xml: &str) -> Option<String> {
+    element(xml, "Fault")?;
+    let code = element(xml, "errorCode").unwrap_or_default();
+    let description = element(xml, "errorDescription")
+        .or_else(|| element(xml, "faultstring"))
+        .unwrap_or_default();
+    Some(format!("SOAP fault {} {}", code, description.trim()))
+}
+
+// Parses the prefix from the action response. The box reports an empty prefix while it has none
+pub(super) fn parse_prefix(xml: &str) -> Result<Option<Ipv6Net>, String> {
+    let prefix = element(xml, "NewIPv6Prefix")
+        .ok_or("missing NewIPv6Prefix")?
+        .trim();
+    let length = element(xml, "NewPrefixLength")
+        .ok_or("missing NewPrefixLength")?
+        .trim();
+    if prefix.is_empty() || prefix == "::" {
+        return Ok(None);
+    }
+    let addr =
+        Ipv6Addr::from_str(prefix).map_err(|_| format!("`{}` is not an IPv6 prefix", prefix))?;
+    let length = u8::from_str(length).map_err(|_| format!("invalid prefix length `{}`", length))?;
+    Ipv6Net::new(addr, length)
+        .map(Some)
+        .map_err(|_| format!("invalid prefix length `{}`", length))
+}
+
+#[async_trait]
+impl PrefixSource for FritzboxSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.fetch().await?;
+        mask_network(prefix.addr(), self.network_length)
+            .ok_or_else(|| self.invalid(prefix.to_string()).into())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "fritzbox source on {} as {}, network-length {}",
+            self.address(),
+            self.user,
+            self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use hyper::{header, HeaderMap};
+    use ipnet::Ipv6Net;
+
+    use super::{digest_challenge, parse_prefix, soap_fault, Challenge};
+
+    // Response of FRITZ!OS to X_AVM_DE_GetIPv6Prefix
+    const RESPONSE: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:X_AVM_DE_GetIPv6PrefixResponse xmlns:u="urn:dslforum-org:service:WANIPConnection:1">
+<NewIPv6Prefix>2003:ee:970c:8000::</NewIPv6Prefix>
+<NewPrefixLength>56</NewPrefixLength>
+<NewValidLifetime>14394</NewValidLifetime>
+<NewPreferedLifetime>3594</NewPreferedLifetime>
+</u:X_AVM_DE_GetIPv6PrefixResponse>
+</s:Body>
+</s:Envelope>"#;
+
+    const FAULT: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<s:Fault>
+<faultcode>s:Client</faultcode>
+<faultstring>UPnPError</faultstring>
+<detail>
+<UPnPError xmlns="urn:dslforum-org:control-1-0">
+<errorCode>606</errorCode>
+<errorDescription>Action Not Authorized</errorDescription>
+</UPnPError>
+</detail>
+</s:Fault>
+</s:Body>
+</s:Envelope>"#;
+
+    #[test]
+    fn parses_soap_response() {
+        assert_eq!(
+            parse_prefix(RESPONSE).unwrap(),
+            Some(Ipv6Net::from_str("2003:ee:970c:8000::/56").unwrap())
+        );
+        assert_eq!(soap_fault(RESPONSE), None);
+
+        let disconnected = RESPONSE
+            .replace("2003:ee:970c:8000::", "")
+            .replace(">56<", ">0<");
+        assert_eq!(parse_prefix(&disconnected).unwrap(), None);
+        assert!(parse_prefix(&RESPONSE.replace("2003:ee:970c:8000::", "192.0.2.0")).is_err());
+        assert!(parse_prefix("<s:Envelope></s:Envelope>").is_err());
+
+        assert_eq!(
+            soap_fault(FAULT).unwrap(),
+            "SOAP fault 606 Action Not Authorized"
+        );
+    }
+
+    #[test]
+    fn answers_digest_challenge() {
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, "0".parse().unwrap());
+            headers.insert(header::WWW_AUTHENTICATE, value.parse().unwrap());
+            headers
+        };
+        let challenge = digest_challenge(&headers(
+            r#"Digest realm="F!Box SOAP-Auth", nonce="5B8D8A46C1F2D85B", algorithm=MD5, qop="auth""#,
+        ))
+        .unwrap();
+        assert_eq!(
+            challenge,
+            Challenge {
+                realm: "F!Box SOAP-Auth".to_string(),
+                nonce: "5B8D8A46C1F2D85B".to_string(),
+                qop: Some("auth".to_string()),
+            }
+        );
+        assert!(digest_challenge(&headers("Basic")).is_none());
+
+        // Credentials from the example in RFC 2617, section 3.5, without qop
+        let challenge = Challenge {
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            qop: None,
+        };
+        let authorization =
+            challenge.authorization("Mufasa", "Circle Of Life", "GET", "/dir/index.html");
+        assert!(
+            authorization.contains(r#"response="670fd8c2df070c60b045671b8b24ff02""#),
+            "{}",
+            authorization
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::{net::Ipv6Addr, str::FromStr, time::Duration};
+
+use async_trait::async_trait;
+use hyper::{client::HttpConnector, header, Body, Client, HeaderMap, Request, StatusCode, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use rustls::{Certificate, ClientConfig, RootCertStore};
+use serde_json::Value;
+use thiserror::Error;
+
+use super::{mask_network, PrefixSource, SourceError};
+
+// Redirects followed before giving up, to break loops
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Error, Debug)]
+pub enum HttpError {
+    #[error("Invalid URL `{0}`, only http:// and https:// URLs are supported")]
+    InvalidUrl(String),
+    #[error("Error while fetching `{0}`: `{1}`")]
+    ConnectionError(String, String),
+    #[error("`{0}` returned status {1}")]
+    Status(String, u16),
+    #[error("`{0}` redirected more than {1} times")]
+    TooManyRedirects(String, usize),
+    #[error("Unexpected response from `{0}`: `{1}`")]
+    InvalidResponse(String, String),
+}
+
+impl From<HttpError> for SourceError {
+    fn from(e: HttpError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Client of the sources that talk HTTP, https:// URLs are verified against the CA certificates of the system
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+pub fn client() -> HttpClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config())
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+// Unlike `with_native_roots` of hyper-rustls, a system without CA certificates only breaks https:// instead of
+// panicking, most routers are reached over plain HTTP
+fn tls_config() -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            for cert in certs {
+                if let Err(e) = roots.add(&Certificate(cert.0)) {
+                    debug!("Skipping invalid CA certificate: {}", e);
+                }
+            }
+        }
+        Err(e) => warn!("Unable to load the CA certificates of the system: {}", e),
+    }
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// A response with its body read completely
+pub(super) struct HttpResponse {
+    pub(super) status: StatusCode,
+    pub(super) headers: HeaderMap,
+    pub(super) body: String,
+}
+
+/// Sends a request and reads the response, giving up after `timeout`
+pub(super) async fn send(
+    client: &HttpClient,
+    request: Request<Body>,
+    timeout: Duration,
+) -> Result<HttpResponse, String> {
+    let exchange = async {
+        let response = client.request(request).await.map_err(|e| e.to_string())?;
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|e| e.to_string())?;
+        let body = String::from_utf8(body.to_vec()).map_err(|_| "body is not UTF-8".to_string())?;
+        Ok(HttpResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        })
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| format!("no response within {:?}", timeout))?
+}
+
+/// Fetches the prefix from an HTTP endpoint, e.g. a script on the router.
+/// The body is either the prefix in CIDR notation as plain text, or a JSON document containing it.
+/// Redirects are followed, https:// URLs need the CA certificates of the system
+pub struct HttpSource {
+    url: Uri,
+    /// JSON pointer (RFC 6901) to the prefix within the body, e.g. `/wan/prefix`. The body is plain text if not set
+    json_pointer: Option<String>,
+    timeout: Duration,
+    network_length: u8,
+    client: HttpClient,
+}
+
+impl HttpSource {
+    pub async fn try_new(
+        url: String,
+        json_pointer: Option<String>,
+        timeout: Duration,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, HttpError> {
+        let url = parse_url(&url).ok_or(HttpError::InvalidUrl(url))?;
+        let source = HttpSource {
+            url,
+            json_pointer,
+            timeout,
+            network_length,
+            client: client(),
+        };
+        // The endpoint may not be up yet, so only log errors here
+        if let Err(e) = source.fetch().await {
+            warn!("{} while creating source, continuing", e);
+        }
+        Ok(Box::new(source))
+    }
+
+    async fn fetch(&self) -> Result<Ipv6Net, HttpError> {
+        let mut url = self.url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let request = Request::get(&url)
+                .header(header::ACCEPT, "*/*")
+                .body(Body::empty())
+                .expect("request from a valid URI");
+            let response = send(&self.client, request, self.timeout)
+                .await
+                .map_err(|e| HttpError::ConnectionError(url.to_string(), e))?;
+            if is_redirect(response.status) {
+                let location = response
+                    .headers
+                    .get(header::LOCATION)
+                    .and_then(|l| l.to_str().ok())
+                    .unwrap_or_default();
+                let target = resolve_url(&url, location).ok_or_else(|| {
+                    HttpError::InvalidResponse(
+                        url.to_string(),
+                        format!("unsupported redirect to `{}`", location),
+                    )
+                })?;
+                debug!("{} redirected to {}", url, target);
+                url = target;
+                continue;
+            }
+            if !response.status.is_success() {
+                return Err(HttpError::Status(url.to_string(), response.status.as_u16()));
+            }
+            debug!("Response from {}: {}", url, response.body);
+            return parse_body(
+                &url.to_string(),
+                &response.body,
+                self.json_pointer.as_deref(),
+            );
+        }
+        Err(HttpError::TooManyRedirects(
+            self.url.to_string(),
+            MAX_REDIRECTS,
+        ))
+    }
+}
+
+// Value of the Host header, IPv6 literals have to be enclosed in brackets
+pub(super) fn host_header(host: &str) -> String {
+    match host.contains(':') {
+        true => format!("[{}]", host),
+        false => host.to_string(),
+    }
+}
+
+// Parses an http:// or https:// URL
+pub(super) fn parse_url(url: &str) -> Option<Uri> {
+    let uri = Uri::from_str(url).ok()?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => return None,
+    }
+    match uri.host() {
+        Some(host) if !host.is_empty() => Some(uri),
+        _ => None,
+    }
+}
+
+fn is_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+// Resolves a reference to another resource, e.g. the location of a redirect, which is either an absolute URL
+// or a path on the same server as `url`
+pub(super) fn resolve_url(url: &Uri, location: &str) -> Option<Uri> {
+    if !location.starts_with('/') || location.starts_with("//") {
+        return parse_url(location);
+    }
+    let mut parts = url.clone().into_parts();
+    parts.path_and_query = Some(location.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+// Extracts the prefix from the body. A plain address is treated as a single host
+fn parse_body(url: &str, body: &str, json_pointer: Option<&str>) -> Result<Ipv6Net, HttpError> {
+    let invalid = |msg: String| HttpError::InvalidResponse(url.to_string(), msg);
+    let text = match json_pointer {
+        Some(pointer) => {
+            let json: Value = serde_json::from_str(body).map_err(|e| invalid(e.to_string()))?;
+            json.pointer(pointer)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| invalid(format!("no string at {}", pointer)))?
+                .to_string()
+        }
+        None => body.trim().to_string(),
+    };
+    Ipv6Net::from_str(&text)
+        .or_else(|_| Ipv6Addr::from_str(&text).map(Ipv6Net::from))
+        .map_err(|_| invalid(format!("`{}` is not an IPv6 prefix", text)))
+}
+
+#[async_trait]
+impl PrefixSource for HttpSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.fetch().await?;
+        mask_network(prefix.addr(), self.network_length).ok_or_else(|| {
+            HttpError::InvalidResponse(self.url.to_string(), prefix.to_string()).into()
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "http source on {}{}, network-length {}",
+            self.url,
+            self.json_pointer
+                .as_ref()
+                .map(|p| format!(" at {}", p))
+                .unwrap_or_default(),
+            self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        str::FromStr,
+        thread,
+        time::Duration,
+    };
+
+    use ipnet::Ipv6Net;
+
+    use super::{parse_body, parse_url, resolve_url, HttpError, HttpSource};
+
+    fn net(s: &str) -> Ipv6Net {
+        Ipv6Net::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parses_url() {
+        let url = parse_url("http://router.lan/prefix").unwrap();
+        assert_eq!(url.host(), Some("router.lan"));
+        assert_eq!(url.path(), "/prefix");
+        let url = parse_url("https://[fd00::1]:8443").unwrap();
+        assert_eq!(url.host(), Some("[fd00::1]"));
+        assert_eq!(url.port_u16(), Some(8443));
+        assert!(parse_url("ftp://router.lan/prefix").is_none());
+        assert!(parse_url("router.lan/prefix").is_none());
+        assert!(parse_url("http:///prefix").is_none());
+    }
+
+    #[test]
+    fn resolves_redirects() {
+        let url = parse_url("http://router.lan:8080/prefix?iface=wan").unwrap();
+        assert_eq!(
+            resolve_url(&url, "/cgi-bin/prefix").unwrap(),
+            "http://router.lan:8080/cgi-bin/prefix"
+        );
+        assert_eq!(
+            resolve_url(&url, "https://router.lan/prefix").unwrap(),
+            "https://router.lan/prefix"
+        );
+        assert!(resolve_url(&url, "").is_none());
+        assert!(resolve_url(&url, "//other.lan/prefix").is_none());
+    }
+
+    #[test]
+    fn parses_body() {
+        assert_eq!(
+            parse_body("u", " 2001:db8:ab00::/56\n", None).unwrap(),
+            net("2001:db8:ab00::/56")
+        );
+        assert_eq!(
+            parse_body("u", "2001:db8:ab00::1", None).unwrap(),
+            net("2001:db8:ab00::1/128")
+        );
+        assert_eq!(
+            parse_body(
+                "u",
+                r#"{"wan": {"prefix": "2001:db8:ab00::/56"}}"#,
+                Some("/wan/prefix")
+            )
+            .unwrap(),
+            net("2001:db8:ab00::/56")
+        );
+        assert!(parse_body("u", r#"{"wan": {}}"#, Some("/wan/prefix")).is_err());
+        assert!(parse_body("u", "192.0.2.0/24", None).is_err());
+    }
+
+    // Answers each connection with the next response and returns the request lines
+    fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 1024];
+                let len = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..len]).to_string();
+                requests.push(request.lines().next().unwrap_or_default().to_string());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn fetches_prefix() {
+        let (url, server) = serve(vec![
+            "HTTP/1.0 200 OK\r\n\r\n2003:ee:970c:8000::/56\n",
+            // The endpoint moved, which is followed
+            "HTTP/1.0 302 Found\r\nLocation: /moved\r\n\r\n",
+            "HTTP/1.0 200 OK\r\n\r\n2003:ee:970c:8000::/56\n",
+            "HTTP/1.0 404 Not Found\r\n\r\n",
+        ]);
+
+        let source =
+            HttpSource::try_new(format!("{}/prefix", url), None, Duration::from_secs(5), 64)
+                .await
+                .unwrap();
+        assert_eq!(
+            source.v6_network().await.unwrap(),
+            net("2003:ee:970c:8000::/64")
+        );
+        let err = source.v6_network().await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&HttpError::Status(format!("{}/prefix", url), 404).to_string()),
+            "{}",
+            err
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                "GET /prefix HTTP/1.1",
+                "GET /prefix HTTP/1.1",
+                "GET /moved HTTP/1.1",
+                "GET /prefix HTTP/1.1"
+            ]
+        );
+    }
+}
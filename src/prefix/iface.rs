@@ -1,14 +1,25 @@
-use std::net::Ipv6Addr;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use ipnet::Ipv6Net;
-use log::{debug, error, warn};
+use async_trait::async_trait;
+use ipnet::{Ipv4Net, Ipv6Net};
+use log::{debug, info, warn};
 use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
 use thiserror::Error;
+use tokio::sync::Notify;
 
 #[cfg(test)]
 use mockall::automock;
 
-use super::{PrefixSource, SourceError};
+#[cfg(target_os = "linux")]
+use super::netlink;
+use super::{mask_network, PrefixSource, SourceError};
 
 #[derive(Error, Debug)]
 pub enum IfaceError {
@@ -16,8 +27,14 @@ pub enum IfaceError {
     NotFound(String),
     #[error("Interface `{0}` does not have a suitable IPv6 address assigned")]
     NoIpv6Prefix(String),
+    #[error("Interface `{0}` is down")]
+    InterfaceDown(String),
+    #[error("Interface `{0}` does not have a global IPv4 address assigned")]
+    NoIpv4Address(String),
     #[error("Error while looking up interfaces: `{0}`")]
     LookupError(String),
+    #[error("Invalid network length {0}, must be between 1 and 128")]
+    InvalidNetworkLength(u8),
 }
 
 impl From<IfaceError> for SourceError {
@@ -26,116 +43,696 @@ impl From<IfaceError> for SourceError {
     }
 }
 
+/// Which addresses on the interface are considered
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+pub enum AddressScope {
+    /// Global unicast addresses
+    #[default]
+    Global,
+    /// Unique local addresses (fc00::/7)
+    Ula,
+    /// Both global unicast and unique local addresses
+    Any,
+}
+
+impl AddressScope {
+    fn contains(&self, addr: &Ipv6Addr) -> bool {
+        // fc00::/7
+        let ula = addr.segments()[0] & 0xfe00 == 0xfc00;
+        match self {
+            AddressScope::Global => ip_rfc::global_v6(addr),
+            AddressScope::Ula => ula,
+            AddressScope::Any => ula || ip_rfc::global_v6(addr),
+        }
+    }
+}
+
+/// Leading hex digits an address has to start with, e.g. `2003` for the addresses of one ISP.
+/// Lighter than a prefix filter when only the beginning of the ISP's prefix is known
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AddressHint(String);
+
+impl AddressHint {
+    fn matches(&self, addr: &Ipv6Addr) -> bool {
+        format!("{:032x}", u128::from(*addr)).starts_with(&self.0)
+    }
+}
+
+impl FromStr for AddressHint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > 32 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "`{}` is not a hint of up to 32 hex digits without colons",
+                s
+            ));
+        }
+        Ok(AddressHint(s.to_ascii_lowercase()))
+    }
+}
+
+/// Additional settings for selecting the address on the interface
+#[derive(Debug, Clone, Default)]
+pub struct IfaceOptions {
+    /// If multiple addresses qualify, prefer the one covered by the route with the lowest metric (Linux only)
+    pub prefer_route_metric: bool,
+    /// Skip temporary (privacy extension) addresses, which are only valid for a short time (Linux only)
+    pub exclude_temporary: bool,
+    /// Skip deprecated addresses, i.e. those of a prefix that is being phased out during a rotation (Linux only)
+    pub exclude_deprecated: bool,
+    /// If multiple addresses qualify, prefer the one with the longest remaining preferred lifetime.
+    /// Deprecated addresses are only used if nothing else is available (Linux only)
+    pub prefer_lifetime: bool,
+    /// Only trust addresses that were assigned or refreshed within this duration.
+    /// Tentative addresses are never trusted, manually configured ones are exempt from the age check (Linux only)
+    pub max_address_age: Option<Duration>,
+    /// If any stable address qualifies, ignore temporary and deprecated ones.
+    /// Unlike `exclude_temporary`, those are still used if nothing else is available (Linux only)
+    pub prefer_stable: bool,
+    /// Report address and route changes, so that they are picked up right away (Linux only)
+    pub watch: bool,
+    /// Which addresses are considered at all
+    pub address_scope: AddressScope,
+    /// Only consider addresses within one of these networks, e.g. the supernet of one ISP on a multihomed host.
+    /// All addresses in scope are considered if empty
+    pub prefix_filter: Vec<Ipv6Net>,
+    /// Ignore addresses whose on-link prefix is longer than this, e.g. a /128 handed out while the link comes up.
+    /// Addresses without a known prefix length are kept
+    pub min_prefix_len: Option<u8>,
+    /// Only consider addresses starting with these hex digits
+    pub addr_hint: Option<AddressHint>,
+    /// Take the network length from the on-link prefix of the selected address instead of the configured one.
+    /// The configured length is still used for addresses without a known prefix length
+    pub use_iface_prefixlen: bool,
+}
+
+// `IFA_F_*` flags from linux/if_addr.h
+const IFA_F_TEMPORARY: u32 = 0x01;
+const IFA_F_DEPRECATED: u32 = 0x20;
+const IFA_F_TENTATIVE: u32 = 0x40;
+const IFA_F_PERMANENT: u32 = 0x80;
+
+// Kernel state of an address that `network-interface` doesn't expose
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct AddressState {
+    flags: u32,
+    /// Remaining preferred lifetime in seconds, `u32::MAX` if infinite
+    preferred_lft: u32,
+    /// Time since the address was assigned or last refreshed
+    age: Option<Duration>,
+}
+
+impl AddressState {
+    fn is_preferred(&self) -> bool {
+        self.flags & IFA_F_DEPRECATED == 0 && self.preferred_lft > 0
+    }
+
+    /// Whether the address is neither temporary nor deprecated, i.e. derived from EUI-64, a stable privacy
+    /// interface identifier or configured manually
+    fn is_stable(&self) -> bool {
+        self.flags & (IFA_F_TEMPORARY | IFA_F_DEPRECATED) == 0
+    }
+}
+
+/// Takes the network from the addresses of an interface.
+/// Several interfaces can be given in order of preference, the first one that yields an address is used
 pub struct IfaceSource {
-    iface_name: String,
+    iface_names: Vec<String>,
     network_length: u8,
+    options: IfaceOptions,
+    changes: Option<Arc<Notify>>,
+    used_iface: UsedIface,
+}
+
+/// Interface the last network was taken from, to log only when it changes
+#[derive(Default)]
+pub(super) struct UsedIface(Mutex<Option<String>>);
+
+impl UsedIface {
+    /// Logs `iface_name` if it differs from the last one used and more than one interface is configured
+    pub(super) fn log(&self, iface_name: &str, iface_names: &[String]) {
+        let mut used = self.0.lock().unwrap();
+        if used.as_deref() != Some(iface_name) {
+            if iface_names.len() > 1 {
+                info!("Using addresses on interface {}", iface_name);
+            }
+            *used = Some(iface_name.to_string());
+        }
+    }
 }
 
 impl IfaceSource {
     #[cfg(test)]
     pub fn test_new(iface_name: String, network_length: u8) -> IfaceSource {
         IfaceSource {
-            iface_name,
+            iface_names: vec![iface_name],
             network_length,
+            options: IfaceOptions::default(),
+            changes: None,
+            used_iface: UsedIface::default(),
         }
     }
 
     pub fn try_new(
-        iface_name: String,
+        iface_names: Vec<String>,
         network_length: u8,
+        options: IfaceOptions,
     ) -> Result<Box<dyn PrefixSource>, IfaceError> {
+        if !(1..=128).contains(&network_length) {
+            return Err(IfaceError::InvalidNetworkLength(network_length));
+        }
+        let changes = match options.watch {
+            true => watch_changes(),
+            false => None,
+        };
         let source = IfaceSource {
-            iface_name,
+            iface_names,
             network_length,
+            options,
+            changes,
+            used_iface: UsedIface::default(),
         };
-        // Try to resolve iface addresses once, just to make sure its there
-        match source.addrs() {
-            Err(e) => match e {
-                IfaceError::NotFound(_) => return Err(e),
-                IfaceError::LookupError(_) => return Err(e),
-                _ => unreachable!(),
-            },
-            Ok(addrs) => match source.find_v6_net(&addrs) {
-                Some(_) => {}
-                None => {
-                    warn!(
-                        "No Ipv6 address on interface {:?} while creating source, continuing",
-                        source.iface_name
-                    );
-                }
-            },
+        // Try to resolve iface addresses once, just to make sure at least one of them is there
+        match source.first_match(|name, addrs| source.find_v6_net(name, addrs)) {
+            Err(e @ (IfaceError::NotFound(_) | IfaceError::LookupError(_))) => return Err(e),
+            Err(e @ IfaceError::InterfaceDown(_)) => {
+                warn!("{} while creating source, continuing", e)
+            }
+            Err(_) => {
+                warn!(
+                    "No Ipv6 address on interface {} while creating source, continuing",
+                    source.iface_names.join(", ")
+                );
+            }
+            Ok(_) => {}
         };
         Ok(Box::new(source))
     }
-    fn addrs(&self) -> Result<Vec<Addr>, IfaceError> {
+
+    // Returns the name of the interface that `selector` refers to, along with its addresses
+    fn addrs(&self, selector: &str) -> Result<(String, Vec<Addr>), IfaceError> {
         let ifs = NetworkInterface::show().map_err(|e| IfaceError::LookupError(e.to_string()))?;
-        let ifaces: Vec<_> = ifs.iter().filter(|i| i.name == self.iface_name).collect();
+        let ifaces = select_iface(&ifs, selector, index_name);
 
-        match ifaces.len() {
-            0 => Err(IfaceError::NotFound(self.iface_name.to_string())),
-            _ => Ok({
+        match ifaces.first() {
+            None => Err(IfaceError::NotFound(selector.to_string())),
+            Some(iface) => {
                 let addrs = ifaces.iter().filter_map(|i| i.addr).collect();
-                debug!(
-                    "Found addresses on interface {}: {:?}",
-                    self.iface_name, addrs
-                );
-                addrs
-            }),
+                debug!("Found addresses on interface {}: {:?}", iface.name, addrs);
+                Ok((iface.name.clone(), addrs))
+            }
         }
     }
 
-    fn find_v6_net(&self, addrs: &[Addr]) -> Option<Ipv6Net> {
-        let mut v6_addrs: Vec<_> = addrs
+    // Tries the interfaces in order and returns the first result of `find` along with the interface.
+    // Fails with `NotFound` if none of the interfaces exist, with `InterfaceDown` if all existing ones are down,
+    // and with `NoIpv6Prefix` if none yields a result
+    fn first_match<T>(
+        &self,
+        find: impl Fn(&str, &[Addr]) -> Option<T>,
+    ) -> Result<(T, &str), IfaceError> {
+        let mut found = 0;
+        let mut down = Vec::new();
+        for name in &self.iface_names {
+            match self.addrs(name) {
+                Ok((iface, addrs)) => {
+                    found += 1;
+                    match find(&iface, &addrs) {
+                        Some(result) => return Ok((result, name)),
+                        None if link_down(&iface) => {
+                            debug!("Interface {} is down", iface);
+                            down.push(name.as_str());
+                        }
+                        None => debug!("No suitable address on interface {}", iface),
+                    }
+                }
+                Err(IfaceError::NotFound(_)) => debug!("Interface {} could not be found", name),
+                Err(e) => return Err(e),
+            }
+        }
+        let names = self.iface_names.join(", ");
+        match found {
+            0 => Err(IfaceError::NotFound(names)),
+            n if n == down.len() => Err(IfaceError::InterfaceDown(down.join(", "))),
+            _ => Err(IfaceError::NoIpv6Prefix(names)),
+        }
+    }
+
+    fn find_v6_net(&self, iface_name: &str, addrs: &[Addr]) -> Option<Ipv6Net> {
+        let v6_addrs: Vec<_> = addrs
             .iter()
             .filter_map(|a| match a {
                 Addr::V4(_) => None,
-                Addr::V6(v6a) => {
-                    if ip_rfc::global_v6(&v6a.ip) {
-                        Some(v6a.ip)
-                    } else {
-                        debug!("Ignoring address {:?} because it is not global", v6a.ip);
-                        None
-                    }
-                }
+                // The netmask is contiguous for IPv6, so its leading ones are the prefix length
+                Addr::V6(v6a) => Some((
+                    v6a.ip,
+                    v6a.netmask.map(|m| u128::from(m).leading_ones() as u8),
+                )),
             })
+            .filter(|(ip, prefix_len)| prefix_len_allowed(ip, *prefix_len, &self.options))
             .collect();
+        let prefix_lens = v6_addrs
+            .iter()
+            .filter_map(|(ip, prefix_len)| Some((*ip, (*prefix_len)?)))
+            .collect();
+        select_network(
+            v6_addrs.into_iter().map(|(ip, _)| ip).collect(),
+            &prefix_lens,
+            &self.options,
+            self.network_length,
+            || address_states(iface_name),
+        )
+    }
 
-        let Some(addr) = v6_addrs.pop() else {
-            return None;
-        };
-        if !v6_addrs.is_empty() {
+    // Takes the lowest global IPv4 address, so that the choice doesn't depend on the enumeration order
+    fn find_v4_addr(&self, addrs: &[Addr]) -> Option<Ipv4Addr> {
+        addrs
+            .iter()
+            .filter_map(|a| match a {
+                Addr::V4(v4a) if ip_rfc::global_v4(&v4a.ip) => Some(v4a.ip),
+                _ => None,
+            })
+            .min()
+    }
+}
+
+// Whether the on-link prefix of an address is short enough to be trusted with `min_prefix_len`
+pub(super) fn prefix_len_allowed(
+    addr: &Ipv6Addr,
+    prefix_len: Option<u8>,
+    options: &IfaceOptions,
+) -> bool {
+    match (prefix_len, options.min_prefix_len) {
+        (Some(len), Some(min)) if len > min => {
+            debug!(
+                "Ignoring address {:?} because its prefix /{} is longer than /{}",
+                addr, len, min
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+// Picks the network to use among the IPv6 addresses of an interface according to the options.
+// `prefix_lens` holds the on-link prefix lengths reported for the candidates, as far as known.
+// `states` is only called if an option needs the flags and lifetimes of the addresses
+pub(super) fn select_network(
+    candidates: Vec<Ipv6Addr>,
+    prefix_lens: &HashMap<Ipv6Addr, u8>,
+    options: &IfaceOptions,
+    network_length: u8,
+    states: impl FnOnce() -> Option<HashMap<Ipv6Addr, AddressState>>,
+) -> Option<Ipv6Net> {
+    let v6_addrs: Vec<_> = candidates
+        .into_iter()
+        .filter(|a| {
+            let in_scope = options.address_scope.contains(a);
+            if !in_scope {
+                debug!(
+                    "Ignoring address {:?} because it is not in scope {:?}",
+                    a, options.address_scope
+                );
+            }
+            in_scope
+        })
+        .filter(|a| {
+            let matches = options.prefix_filter.is_empty()
+                || options.prefix_filter.iter().any(|f| f.contains(a));
+            if !matches {
+                debug!(
+                    "Ignoring address {:?} because it is not in the prefix filter",
+                    a
+                );
+            }
+            matches
+        })
+        .filter(|a| match &options.addr_hint {
+            Some(hint) if !hint.matches(a) => {
+                debug!(
+                    "Ignoring address {:?} because it does not start with {}",
+                    a, hint.0
+                );
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    let states = match options.exclude_temporary
+        || options.exclude_deprecated
+        || options.prefer_lifetime
+        || options.max_address_age.is_some()
+        || options.prefer_stable
+    {
+        true => states(),
+        false => None,
+    };
+    let v6_addrs = match (options.exclude_temporary, &states) {
+        (true, Some(states)) => drop_temporary(v6_addrs, states),
+        _ => v6_addrs,
+    };
+    let v6_addrs = match (options.exclude_deprecated, &states) {
+        (true, Some(states)) => drop_deprecated(v6_addrs, states),
+        _ => v6_addrs,
+    };
+    let v6_addrs = match (options.max_address_age, &states) {
+        (Some(max_age), Some(states)) => drop_stale(v6_addrs, states, max_age),
+        _ => v6_addrs,
+    };
+    let v6_addrs = match (options.prefer_stable, &states) {
+        (true, Some(states)) => prefer_stable(v6_addrs, states),
+        _ => v6_addrs,
+    };
+
+    let metrics = match options.prefer_route_metric {
+        true => route_metrics(&v6_addrs),
+        false => None,
+    };
+    let states = states.filter(|_| options.prefer_lifetime);
+    let addr = select_address(v6_addrs, metrics.as_ref(), states.as_ref())?;
+    let network_length = match (options.use_iface_prefixlen, prefix_lens.get(&addr)) {
+        (true, Some(prefix_len)) => *prefix_len,
+        (true, None) => {
+            debug!(
+                "No prefix length reported for {:?}, using network length {}",
+                addr, network_length
+            );
+            network_length
+        }
+        (false, _) => network_length,
+    };
+    mask_network(addr, network_length)
+}
+
+// Picks the address to derive the network from.
+// Candidates are sorted to make the choice independent of the enumeration order,
+// then ordered by their route metric if available. Addresses without a known metric come last.
+// If address states are given, they take precedence: preferred addresses come before deprecated ones,
+// and among them the longest remaining preferred lifetime wins. Addresses without a known state come last.
+fn select_address(
+    mut candidates: Vec<Ipv6Addr>,
+    metrics: Option<&HashMap<Ipv6Addr, u32>>,
+    states: Option<&HashMap<Ipv6Addr, AddressState>>,
+) -> Option<Ipv6Addr> {
+    candidates.sort();
+    candidates.dedup();
+    if let Some(metrics) = metrics {
+        candidates.sort_by_key(|a| metrics.get(a).copied().unwrap_or(u32::MAX));
+    }
+    if let Some(states) = states {
+        candidates.sort_by_key(|a| match states.get(a) {
+            Some(s) if s.is_preferred() => (0, Reverse(s.preferred_lft)),
+            Some(_) => (1, Reverse(0)),
+            None => (2, Reverse(0)),
+        });
+    }
+    let addr = *candidates.first()?;
+    if candidates.len() > 1 {
+        warn!(
+            "Multiple global IPv6 addresses in address list, selecting: {:?}",
+            addr
+        );
+    }
+    Some(addr)
+}
+
+/// Parses an interface selector of the form `if<index>`, e.g. `if3`
+pub(super) fn parse_index(selector: &str) -> Option<u32> {
+    let index = selector.strip_prefix("if")?;
+    match !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) {
+        true => index.parse().ok(),
+        false => None,
+    }
+}
+
+// Keeps the entries of the interface that `selector` refers to, either by name or by index in the form `if<index>`.
+// A name takes precedence, in case an interface is literally called e.g. `if3`.
+// `index_name` resolves an index to the name of the interface
+fn select_iface<'a>(
+    ifs: &'a [NetworkInterface],
+    selector: &str,
+    index_name: impl Fn(u32) -> Option<String>,
+) -> Vec<&'a NetworkInterface> {
+    let by_name: Vec<_> = ifs.iter().filter(|i| i.name == selector).collect();
+    if !by_name.is_empty() {
+        return by_name;
+    }
+    match parse_index(selector).and_then(index_name) {
+        Some(name) => ifs.iter().filter(|i| i.name == name).collect(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(unix)]
+fn index_name(index: u32) -> Option<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let name = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+    if name.is_null() {
+        return None;
+    }
+    unsafe { std::ffi::CStr::from_ptr(name) }
+        .to_str()
+        .ok()
+        .map(str::to_string)
+}
+
+#[cfg(not(unix))]
+fn index_name(_index: u32) -> Option<String> {
+    None
+}
+
+// Whether the interface is known to be down, e.g. without a carrier. Interfaces whose state can't be read count as up
+#[cfg(target_os = "linux")]
+fn link_down(iface_name: &str) -> bool {
+    match netlink::ifindex(iface_name).and_then(netlink::link) {
+        Ok(Some(link)) => !link.is_up(),
+        Ok(None) => false,
+        Err(e) => {
+            debug!(
+                "Unable to read the state of interface {}: {}",
+                iface_name, e
+            );
+            false
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn link_down(_iface_name: &str) -> bool {
+    false
+}
+
+// Looks up the metric of the most preferred (non-default) route covering each address
+#[cfg(target_os = "linux")]
+fn route_metrics(addrs: &[Ipv6Addr]) -> Option<HashMap<Ipv6Addr, u32>> {
+    let routes = match netlink::routes() {
+        Ok(r) => r,
+        Err(e) => {
             warn!(
-                "Multiple global IPv6 addresses in address list, selecting: {:?}",
-                addr
+                "Unable to read route metrics, falling back to default order: {}",
+                e
             );
+            return None;
         }
+    };
+    let metrics = addrs
+        .iter()
+        .filter_map(|addr| {
+            routes
+                .iter()
+                .filter(|r| r.dst.prefix_len() > 0 && r.dst.contains(addr))
+                .map(|r| r.metric)
+                .min()
+                .map(|metric| (*addr, metric))
+        })
+        .collect();
+    debug!("Route metrics of candidate addresses: {:?}", metrics);
+    Some(metrics)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn route_metrics(_addrs: &[Ipv6Addr]) -> Option<HashMap<Ipv6Addr, u32>> {
+    warn!("Route metrics are only available on Linux, falling back to default order");
+    None
+}
+
+// Removes all addresses flagged as temporary
+fn drop_temporary(addrs: Vec<Ipv6Addr>, states: &HashMap<Ipv6Addr, AddressState>) -> Vec<Ipv6Addr> {
+    addrs
+        .into_iter()
+        .filter(|a| {
+            let temporary = matches!(states.get(a), Some(s) if s.flags & IFA_F_TEMPORARY != 0);
+            if temporary {
+                debug!("Ignoring address {:?} because it is temporary", a);
+            }
+            !temporary
+        })
+        .collect()
+}
 
-        let netmask: u128 = !(u128::MAX >> self.network_length);
-        let network_part = Ipv6Addr::from(u128::from(addr) & netmask);
+// Removes all addresses that are flagged as deprecated or have run out of preferred lifetime.
+// Addresses without a known state are kept
+fn drop_deprecated(
+    addrs: Vec<Ipv6Addr>,
+    states: &HashMap<Ipv6Addr, AddressState>,
+) -> Vec<Ipv6Addr> {
+    addrs
+        .into_iter()
+        .filter(|a| {
+            let deprecated = matches!(states.get(a), Some(s) if !s.is_preferred());
+            if deprecated {
+                debug!("Ignoring address {:?} because it is deprecated", a);
+            }
+            !deprecated
+        })
+        .collect()
+}
 
-        match Ipv6Net::new(network_part, self.network_length) {
-            Ok(net) => Some(net),
-            Err(e) => {
-                warn!("Unable to construct Ipv6 prefix: {}", e.to_string());
-                None
+// Keeps only the stable addresses, unless there are none.
+// Addresses without a known state are considered stable
+fn prefer_stable(addrs: Vec<Ipv6Addr>, states: &HashMap<Ipv6Addr, AddressState>) -> Vec<Ipv6Addr> {
+    let is_stable = |a: &Ipv6Addr| states.get(a).map(AddressState::is_stable).unwrap_or(true);
+    if !addrs.iter().any(is_stable) {
+        debug!("No stable address among {:?}, keeping all", addrs);
+        return addrs;
+    }
+    addrs
+        .into_iter()
+        .filter(|a| {
+            let stable = is_stable(a);
+            if !stable {
+                debug!("Ignoring address {:?} in favor of a stable one", a);
             }
+            stable
+        })
+        .collect()
+}
+
+// Removes tentative addresses and dynamic addresses that were not refreshed within `max_age`.
+// Addresses without a known state are kept
+fn drop_stale(
+    addrs: Vec<Ipv6Addr>,
+    states: &HashMap<Ipv6Addr, AddressState>,
+    max_age: Duration,
+) -> Vec<Ipv6Addr> {
+    addrs
+        .into_iter()
+        .filter(|a| match states.get(a) {
+            Some(s) if s.flags & IFA_F_TENTATIVE != 0 => {
+                debug!("Ignoring address {:?} because it is tentative", a);
+                false
+            }
+            Some(s)
+                if s.flags & IFA_F_PERMANENT == 0
+                    && matches!(s.age, Some(age) if age > max_age) =>
+            {
+                debug!(
+                    "Ignoring address {:?} because it was last updated {:?} ago",
+                    a,
+                    s.age.unwrap_or_default()
+                );
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+// Subscribes to address and route changes on all interfaces, changes on other interfaces only cause an extra run
+#[cfg(target_os = "linux")]
+pub(super) fn watch_changes() -> Option<Arc<Notify>> {
+    let changes = Arc::new(Notify::new());
+    let notify = changes.clone();
+    match netlink::watch_changes(move || notify.notify_waiters()) {
+        Ok(()) => Some(changes),
+        Err(e) => {
+            warn!("Unable to watch for address changes, only polling: {}", e);
+            None
         }
     }
 }
 
+#[cfg(not(target_os = "linux"))]
+fn watch_changes() -> Option<Arc<Notify>> {
+    warn!("Watching for address changes is only supported on Linux, only polling");
+    None
+}
+
+// Looks up the flags and lifetimes of the addresses on the interface
+#[cfg(target_os = "linux")]
+fn address_states(iface_name: &str) -> Option<HashMap<Ipv6Addr, AddressState>> {
+    match netlink::addresses(iface_name) {
+        Ok(addrs) => {
+            let states = states_of(&addrs);
+            debug!("Address states on {}: {:?}", iface_name, states);
+            Some(states)
+        }
+        Err(e) => {
+            warn!(
+                "Unable to read address flags and lifetimes of {}, falling back to default selection: {}",
+                iface_name, e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(super) fn states_of(addrs: &[netlink::Address]) -> HashMap<Ipv6Addr, AddressState> {
+    let now = netlink::monotonic_now()
+        .map_err(|e| warn!("Unable to read the monotonic clock: {}", e))
+        .ok();
+    addrs
+        .iter()
+        .map(|a| {
+            let state = AddressState {
+                flags: a.flags,
+                preferred_lft: a.preferred_lft,
+                age: now.zip(a.updated).map(|(now, u)| now.saturating_sub(u)),
+            };
+            (a.addr, state)
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn address_states(_iface_name: &str) -> Option<HashMap<Ipv6Addr, AddressState>> {
+    warn!("Address flags and lifetimes are only available on Linux, falling back to default selection");
+    None
+}
+
 #[cfg_attr(test, automock)]
+#[async_trait]
 impl PrefixSource for IfaceSource {
-    fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
-        let addrs = match self.addrs() {
-            Ok(a) => a,
-            Err(e) => return Err(e.into()),
-        };
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let (net, iface_name) = self.first_match(|name, addrs| self.find_v6_net(name, addrs))?;
+        self.used_iface.log(iface_name, &self.iface_names);
+        Ok(net)
+    }
 
-        match self.find_v6_net(&addrs) {
-            Some(net) => Ok(net),
-            None => Err(IfaceError::NoIpv6Prefix(self.iface_name.to_string()).into()),
+    async fn v4_network(&self) -> Result<Ipv4Net, SourceError> {
+        match self.first_match(|_, addrs| self.find_v4_addr(addrs)) {
+            Ok((addr, iface_name)) => {
+                self.used_iface.log(iface_name, &self.iface_names);
+                Ok(addr.into())
+            }
+            Err(IfaceError::NoIpv6Prefix(names)) => Err(IfaceError::NoIpv4Address(names).into()),
+            Err(e) => Err(e.into()),
         }
     }
+
+    fn describe(&self) -> String {
+        format!(
+            "iface source on {}, network-length {}",
+            self.iface_names.join(","),
+            self.network_length
+        )
+    }
+
+    fn changes(&self) -> Option<Arc<Notify>> {
+        self.changes.clone()
+    }
 }
 
 #[cfg(test)]
@@ -143,37 +740,514 @@ mod tests {
     use std::{
         net::{Ipv4Addr, Ipv6Addr},
         str::FromStr,
+        time::Duration,
     };
 
+    use std::collections::HashMap;
+
     use ipnet::Ipv6Net;
-    use network_interface::{Addr, V4IfAddr, V6IfAddr};
+    use network_interface::{Addr, NetworkInterface, V4IfAddr, V6IfAddr};
+
+    use super::{
+        drop_deprecated, drop_stale, drop_temporary, prefer_stable, select_address, AddressHint,
+        AddressScope, AddressState, IfaceError, IfaceOptions, IfaceSource, IFA_F_DEPRECATED,
+        IFA_F_PERMANENT, IFA_F_TEMPORARY, IFA_F_TENTATIVE,
+    };
+    use super::{parse_index, select_iface};
+    use crate::prefix::PrefixSource;
 
-    use super::IfaceSource;
+    #[test]
+    fn describes_source() {
+        assert_eq!(
+            IfaceSource::test_new("eth0".to_string(), 64).describe(),
+            "iface source on eth0, network-length 64"
+        );
+    }
+
+    #[test]
+    fn selects_address_by_metric() {
+        let a = Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap();
+        let b = Ipv6Addr::from_str("2a02:8070:1:2::5").unwrap();
+
+        // Without metrics, the selection only depends on the addresses themselves
+        assert_eq!(select_address(vec![b, a], None, None), Some(a));
+        assert_eq!(select_address(vec![a, b], None, None), Some(a));
+
+        let metrics = HashMap::from([(a, 1024), (b, 100)]);
+        assert_eq!(select_address(vec![a, b], Some(&metrics), None), Some(b));
+
+        let metrics = HashMap::from([(a, 100), (b, 1024)]);
+        assert_eq!(select_address(vec![b, a], Some(&metrics), None), Some(a));
+
+        // Addresses with a known metric are preferred over ones without
+        let metrics = HashMap::from([(b, 1024)]);
+        assert_eq!(select_address(vec![a, b], Some(&metrics), None), Some(b));
+
+        assert_eq!(select_address(vec![], None, None), None);
+    }
+
+    #[test]
+    fn drops_temporary_addresses() {
+        let stable = Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap();
+        let temporary = Ipv6Addr::from_str("2003:ee:970c:80aa:8d1e:5f2a:c3b4:1e07").unwrap();
+        let unknown = Ipv6Addr::from_str("2003:ee:970c:80aa::1").unwrap();
+
+        let state = |flags| AddressState {
+            flags,
+            preferred_lft: u32::MAX,
+            age: None,
+        };
+        let states = HashMap::from([
+            (stable, state(0x80)),
+            (temporary, state(IFA_F_TEMPORARY | 0x80)),
+        ]);
+        assert_eq!(
+            drop_temporary(vec![stable, temporary, unknown], &states),
+            vec![stable, unknown]
+        );
+        assert_eq!(
+            drop_temporary(vec![temporary], &states),
+            Vec::<Ipv6Addr>::new()
+        );
+    }
+
+    #[test]
+    fn drops_deprecated_addresses() {
+        let new = Ipv6Addr::from_str("2003:ee:970c:80bb::199").unwrap();
+        let old = Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap();
+        let expired = Ipv6Addr::from_str("2003:ee:970c:80cc::199").unwrap();
+        let unknown = Ipv6Addr::from_str("2003:ee:970c:80dd::1").unwrap();
+
+        let state = |flags, preferred_lft| AddressState {
+            flags,
+            preferred_lft,
+            age: None,
+        };
+        let states = HashMap::from([
+            (new, state(0, 3600)),
+            (old, state(IFA_F_DEPRECATED, 0)),
+            // Deprecated by its lifetime, before the kernel has set the flag
+            (expired, state(0, 0)),
+        ]);
+        assert_eq!(
+            drop_deprecated(vec![old, new, expired, unknown], &states),
+            vec![new, unknown]
+        );
+        assert_eq!(
+            drop_deprecated(vec![old, expired], &states),
+            Vec::<Ipv6Addr>::new()
+        );
+    }
+
+    #[test]
+    fn prefers_stable_addresses() {
+        let eui64 = Ipv6Addr::from_str("2003:ee:970c:80aa:bc4d:ffff:fe13:47ce").unwrap();
+        let temporary = Ipv6Addr::from_str("2003:ee:970c:80aa:8d1e:5f2a:c3b4:1e07").unwrap();
+        let deprecated = Ipv6Addr::from_str("2003:ee:970c:80bb::199").unwrap();
+        let state = |flags| AddressState {
+            flags,
+            preferred_lft: u32::MAX,
+            age: None,
+        };
+
+        let states = HashMap::from([
+            (eui64, state(0)),
+            (temporary, state(IFA_F_TEMPORARY)),
+            (deprecated, state(IFA_F_DEPRECATED)),
+        ]);
+        assert_eq!(
+            prefer_stable(vec![temporary, eui64, deprecated], &states),
+            vec![eui64]
+        );
+        // Without a stable address, the others are still used
+        assert_eq!(
+            prefer_stable(vec![temporary, deprecated], &states),
+            vec![temporary, deprecated]
+        );
+    }
+
+    #[test]
+    fn drops_stale_addresses() {
+        let fresh = Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap();
+        let stale = Ipv6Addr::from_str("2003:ee:970c:80bb::199").unwrap();
+        let tentative = Ipv6Addr::from_str("2003:ee:970c:80cc::199").unwrap();
+        let manual = Ipv6Addr::from_str("2a02:8070:1:2::5").unwrap();
+        let unknown = Ipv6Addr::from_str("2a02:8070:1:2::6").unwrap();
+        let state = |flags, age| AddressState {
+            flags,
+            preferred_lft: u32::MAX,
+            age: Some(Duration::from_secs(age)),
+        };
+
+        let states = HashMap::from([
+            (fresh, state(0, 60)),
+            (stale, state(0, 86400)),
+            (tentative, state(IFA_F_TENTATIVE, 1)),
+            (manual, state(IFA_F_PERMANENT, 86400)),
+        ]);
+        assert_eq!(
+            drop_stale(
+                vec![fresh, stale, tentative, manual, unknown],
+                &states,
+                Duration::from_secs(3600)
+            ),
+            vec![fresh, manual, unknown]
+        );
+        assert_eq!(
+            drop_stale(vec![stale], &states, Duration::from_secs(86400)),
+            vec![stale]
+        );
+    }
+
+    #[test]
+    fn selects_address_by_lifetime() {
+        let old = Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap();
+        let new = Ipv6Addr::from_str("2003:ee:970c:80bb::199").unwrap();
+        let static_addr = Ipv6Addr::from_str("2a02:8070:1:2::5").unwrap();
+        let state = |flags, preferred_lft| AddressState {
+            flags,
+            preferred_lft,
+            age: None,
+        };
+
+        // During a renumbering the old prefix is deprecated while the new one is preferred
+        let states = HashMap::from([(old, state(0, 0)), (new, state(0, 3600))]);
+        assert_eq!(
+            select_address(vec![old, new], None, Some(&states)),
+            Some(new)
+        );
+        let states = HashMap::from([(old, state(IFA_F_DEPRECATED, 3600)), (new, state(0, 60))]);
+        assert_eq!(
+            select_address(vec![old, new], None, Some(&states)),
+            Some(new)
+        );
+
+        // Among preferred addresses the longest lifetime wins, infinite lifetimes included
+        let states = HashMap::from([
+            (old, state(0, 1800)),
+            (new, state(0, 3600)),
+            (static_addr, state(0, u32::MAX)),
+        ]);
+        assert_eq!(
+            select_address(vec![old, new, static_addr], None, Some(&states)),
+            Some(static_addr)
+        );
+
+        // Addresses with unknown state come last, even after deprecated ones
+        let states = HashMap::from([(new, state(0, 0))]);
+        assert_eq!(
+            select_address(vec![old, new], None, Some(&states)),
+            Some(new)
+        );
+
+        // Lifetimes take precedence over route metrics, which break ties
+        let states = HashMap::from([(old, state(0, 3600)), (new, state(0, 3600))]);
+        let metrics = HashMap::from([(old, 1024), (new, 100)]);
+        assert_eq!(
+            select_address(vec![old, new], Some(&metrics), Some(&states)),
+            Some(new)
+        );
+    }
+
+    #[test]
+    fn handles_network_length_bounds() {
+        let addrs = [Addr::V6(V6IfAddr {
+            ip: Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap(),
+            broadcast: None,
+            netmask: None,
+        })];
+        let net = |s| Some(Ipv6Net::from_str(s).unwrap());
+        assert_eq!(
+            IfaceSource::test_new("test0".to_string(), 64).find_v6_net("test0", &addrs),
+            net("2003:ee:970c:80aa::/64")
+        );
+        assert_eq!(
+            IfaceSource::test_new("test0".to_string(), 128).find_v6_net("test0", &addrs),
+            net("2003:ee:970c:80aa::199/128")
+        );
+        assert!(matches!(
+            IfaceSource::try_new(vec!["test0".to_string()], 0, IfaceOptions::default()),
+            Err(IfaceError::InvalidNetworkLength(0))
+        ));
+    }
 
     #[test]
     fn finds_correct_net() {
         let s = IfaceSource::test_new("test0".to_string(), 48);
-        let r = s.find_v6_net(&[
+        let r = s.find_v6_net(
+            "test0",
+            &[
+                Addr::V6(V6IfAddr {
+                    ip: Ipv6Addr::from_str("fe80::bc4d:ffff:fe13:47ce").unwrap(),
+                    broadcast: None,
+                    netmask: None,
+                }),
+                Addr::V4(V4IfAddr {
+                    ip: Ipv4Addr::from_str("10.10.10.2").unwrap(),
+                    broadcast: None,
+                    netmask: None,
+                }),
+                Addr::V6(V6IfAddr {
+                    ip: Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap(),
+                    broadcast: None,
+                    netmask: None,
+                }),
+            ],
+        );
+        assert!(r.is_some());
+        assert_eq!(
+            Ipv6Net::new(Ipv6Addr::from_str("2003:ee:970c::0").unwrap(), 48).unwrap(),
+            r.unwrap()
+        );
+    }
+
+    #[test]
+    fn selects_addresses_by_scope() {
+        let v6 = |ip: &str| {
             Addr::V6(V6IfAddr {
-                ip: Ipv6Addr::from_str("fe80::bc4d:ffff:fe13:47ce").unwrap(),
+                ip: Ipv6Addr::from_str(ip).unwrap(),
                 broadcast: None,
                 netmask: None,
-            }),
-            Addr::V4(V4IfAddr {
-                ip: Ipv4Addr::from_str("10.10.10.2").unwrap(),
+            })
+        };
+        let with_scope = |address_scope| IfaceSource {
+            options: IfaceOptions {
+                address_scope,
+                ..IfaceOptions::default()
+            },
+            ..IfaceSource::test_new("test0".to_string(), 64)
+        };
+        let net = |s| Some(Ipv6Net::from_str(s).unwrap());
+        let ula_only = [v6("fe80::1"), v6("fd12:3456:789a:1::10")];
+        let mixed = [
+            v6("fe80::1"),
+            v6("fd12:3456:789a:1::10"),
+            v6("2003:ee:970c:80aa::199"),
+        ];
+
+        assert_eq!(
+            with_scope(AddressScope::Global).find_v6_net("test0", &ula_only),
+            None
+        );
+        assert_eq!(
+            with_scope(AddressScope::Global).find_v6_net("test0", &mixed),
+            net("2003:ee:970c:80aa::/64")
+        );
+        assert_eq!(
+            with_scope(AddressScope::Ula).find_v6_net("test0", &ula_only),
+            net("fd12:3456:789a:1::/64")
+        );
+        assert_eq!(
+            with_scope(AddressScope::Ula).find_v6_net("test0", &mixed),
+            net("fd12:3456:789a:1::/64")
+        );
+        assert_eq!(
+            with_scope(AddressScope::Any).find_v6_net("test0", &ula_only),
+            net("fd12:3456:789a:1::/64")
+        );
+        assert!(with_scope(AddressScope::Any)
+            .find_v6_net("test0", &mixed)
+            .is_some());
+    }
+
+    #[test]
+    fn applies_prefix_filter() {
+        let v6 = |ip: &str| {
+            Addr::V6(V6IfAddr {
+                ip: Ipv6Addr::from_str(ip).unwrap(),
                 broadcast: None,
                 netmask: None,
-            }),
+            })
+        };
+        let addrs = [v6("2003:ee:970c:80aa::199"), v6("2a02:8070:1:2::5")];
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let mut source = IfaceSource::test_new("test0".to_string(), 64);
+        source.options.prefix_filter = vec![net("2a02:8070::/32")];
+        assert_eq!(
+            source.find_v6_net("test0", &addrs),
+            Some(net("2a02:8070:1:2::/64"))
+        );
+        source.options.prefix_filter = vec![net("2001:db8::/32")];
+        assert_eq!(source.find_v6_net("test0", &addrs), None);
+    }
+
+    #[test]
+    fn applies_addr_hint() {
+        let v6 = |ip: &str| {
             Addr::V6(V6IfAddr {
-                ip: Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap(),
+                ip: Ipv6Addr::from_str(ip).unwrap(),
                 broadcast: None,
                 netmask: None,
-            }),
-        ]);
-        assert!(r.is_some());
+            })
+        };
+        let addrs = [v6("2003:ee:970c:80aa::199"), v6("2a02:8070:1:2::5")];
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let mut source = IfaceSource::test_new("test0".to_string(), 64);
+        source.options.addr_hint = Some(AddressHint::from_str("2003").unwrap());
         assert_eq!(
-            Ipv6Net::new(Ipv6Addr::from_str("2003:ee:970c::0").unwrap(), 48).unwrap(),
-            r.unwrap()
+            source.find_v6_net("test0", &addrs),
+            Some(net("2003:ee:970c:80aa::/64"))
+        );
+        // Digits beyond the first group and upper case are fine
+        source.options.addr_hint = Some(AddressHint::from_str("2A02807").unwrap());
+        assert_eq!(
+            source.find_v6_net("test0", &addrs),
+            Some(net("2a02:8070:1:2::/64"))
+        );
+        source.options.addr_hint = Some(AddressHint::from_str("2001").unwrap());
+        assert_eq!(source.find_v6_net("test0", &addrs), None);
+
+        assert!(AddressHint::from_str("").is_err());
+        assert!(AddressHint::from_str("2003:ee").is_err());
+        assert!(AddressHint::from_str(&"0".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn uses_iface_prefix_length() {
+        let v6 = |ip: &str, prefix_len: Option<u8>| {
+            Addr::V6(V6IfAddr {
+                ip: Ipv6Addr::from_str(ip).unwrap(),
+                broadcast: None,
+                netmask: prefix_len.map(|len| Ipv6Addr::from(u128::MAX << (128 - len))),
+            })
+        };
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let mut source = IfaceSource::test_new("test0".to_string(), 64);
+        let addrs = [v6("2003:ee:970c:80ab::199", Some(62))];
+        // The reported netmask is ignored unless asked for
+        assert_eq!(
+            source.find_v6_net("test0", &addrs),
+            Some(net("2003:ee:970c:80ab::/64"))
+        );
+        source.options.use_iface_prefixlen = true;
+        assert_eq!(
+            source.find_v6_net("test0", &addrs),
+            Some(net("2003:ee:970c:80a8::/62"))
+        );
+        // The network length is the fallback for addresses without a netmask
+        assert_eq!(
+            source.find_v6_net("test0", &[v6("2003:ee:970c:80ab::199", None)]),
+            Some(net("2003:ee:970c:80ab::/64"))
+        );
+    }
+
+    #[test]
+    fn rejects_long_prefixes() {
+        let v6 = |ip: &str, prefix_len: u8| {
+            Addr::V6(V6IfAddr {
+                ip: Ipv6Addr::from_str(ip).unwrap(),
+                broadcast: None,
+                netmask: Some(Ipv6Addr::from(u128::MAX << (128 - prefix_len))),
+            })
+        };
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let mut source = IfaceSource::test_new("test0".to_string(), 64);
+        source.options.min_prefix_len = Some(64);
+        assert_eq!(
+            source.find_v6_net("test0", &[v6("2003:ee:970c:80aa::199", 64)]),
+            Some(net("2003:ee:970c:80aa::/64"))
+        );
+        assert_eq!(
+            source.find_v6_net("test0", &[v6("2003:ee:970c:80aa::199", 65)]),
+            None
+        );
+        assert_eq!(
+            source.find_v6_net(
+                "test0",
+                &[
+                    v6("2003:ee:970c:80bb::1", 128),
+                    v6("2003:ee:970c:80aa::199", 64)
+                ]
+            ),
+            Some(net("2003:ee:970c:80aa::/64"))
+        );
+        // Addresses without a netmask are kept
+        let no_mask = Addr::V6(V6IfAddr {
+            ip: Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap(),
+            broadcast: None,
+            netmask: None,
+        });
+        assert_eq!(
+            source.find_v6_net("test0", &[no_mask]),
+            Some(net("2003:ee:970c:80aa::/64"))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_next_interface() {
+        let mut source = IfaceSource::test_new("does-not-exist0".to_string(), 64);
+        source.iface_names.push("lo".to_string());
+        assert_eq!(
+            source.first_match(|name, _| Some(name.len())).unwrap(),
+            (2, "lo")
+        );
+        assert!(matches!(
+            source.first_match(|_, _| None::<()>),
+            Err(IfaceError::NoIpv6Prefix(names)) if names == "does-not-exist0, lo"
+        ));
+        source.iface_names = vec!["does-not-exist0".to_string()];
+        assert!(matches!(
+            source.first_match(|_, _| Some(())),
+            Err(IfaceError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn selects_interface_by_index() {
+        assert_eq!(parse_index("if3"), Some(3));
+        assert_eq!(parse_index("if"), None);
+        assert_eq!(parse_index("if+3"), None);
+        assert_eq!(parse_index("eth0"), None);
+
+        let iface = |name: &str, ip: &str| NetworkInterface {
+            name: name.to_string(),
+            addr: Some(Addr::V6(V6IfAddr {
+                ip: Ipv6Addr::from_str(ip).unwrap(),
+                broadcast: None,
+                netmask: None,
+            })),
+            mac_addr: None,
+        };
+        let ifs = [
+            iface("lo", "::1"),
+            iface("eth0", "2003:ee:970c:80aa::199"),
+            iface("eth0", "fe80::1"),
+            iface("if2", "2003:ee:970c:80bb::1"),
+        ];
+        let index_name = |index| match index {
+            1 => Some("lo".to_string()),
+            2 => Some("eth0".to_string()),
+            _ => None,
+        };
+        let names = |selector| {
+            select_iface(&ifs, selector, index_name)
+                .iter()
+                .map(|i| i.addr.unwrap().ip().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names("eth0"), ["2003:ee:970c:80aa::199", "fe80::1"]);
+        assert_eq!(names("if1"), ["::1"]);
+        // An interface literally named like an index selector takes precedence
+        assert_eq!(names("if2"), ["2003:ee:970c:80bb::1"]);
+        assert!(names("if3").is_empty());
+        assert!(names("eth1").is_empty());
+    }
+
+    #[test]
+    fn finds_global_v4_address() {
+        let s = IfaceSource::test_new("test0".to_string(), 64);
+        let v4 = |ip: &str| {
+            Addr::V4(V4IfAddr {
+                ip: Ipv4Addr::from_str(ip).unwrap(),
+                broadcast: None,
+                netmask: None,
+            })
+        };
+        assert_eq!(
+            s.find_v4_addr(&[v4("10.10.10.2"), v4("93.184.216.34"), v4("85.10.1.5")]),
+            Some(Ipv4Addr::from_str("85.10.1.5").unwrap())
         );
+        assert_eq!(s.find_v4_addr(&[v4("10.10.10.2"), v4("100.64.0.1")]), None);
     }
 }
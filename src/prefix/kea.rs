@@ -0,0 +1,356 @@
+use std::{
+    net::Ipv6Addr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use hyper::{header, Body, Request, Uri};
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+
+use super::{
+    http::{self, parse_url, HttpClient},
+    mask_network, PrefixSource, SourceError,
+};
+
+const KEA_TIMEOUT: Duration = Duration::from_secs(10);
+// Kea result codes, see the Kea management API documentation
+const KEA_RESULT_SUCCESS: i64 = 0;
+const KEA_RESULT_EMPTY: i64 = 3;
+
+#[derive(Error, Debug)]
+pub enum KeaError {
+    #[error("Invalid control agent URL `{0}`, only http:// and https:// URLs are supported")]
+    InvalidUrl(String),
+    #[error("Error while talking to Kea at `{0}`: `{1}`")]
+    ConnectionError(String, String),
+    #[error("Kea returned an error: `{0}`")]
+    CommandError(String),
+    #[error("Unexpected response from Kea: `{0}`")]
+    InvalidResponse(String),
+    #[error("Kea has no active prefix delegation lease")]
+    NoLease,
+}
+
+impl From<KeaError> for SourceError {
+    fn from(e: KeaError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Where the commands for Kea are sent to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeaEndpoint {
+    /// Control socket of the DHCPv6 server, only reachable on the same host
+    Socket(PathBuf),
+    /// URL of the Kea control agent, which forwards the commands to the DHCPv6 server
+    ControlAgent(String),
+}
+
+// Connection to the endpoint
+enum Transport {
+    Socket(PathBuf),
+    ControlAgent { url: Uri, client: HttpClient },
+}
+
+impl Transport {
+    fn describe(&self) -> String {
+        match self {
+            Transport::Socket(path) => path.display().to_string(),
+            Transport::ControlAgent { url, .. } => url.to_string(),
+        }
+    }
+}
+
+/// Reads the delegated prefix from the leases of a Kea DHCPv6 server.
+/// Requires the `lease_cmds` hook to be loaded in Kea.
+pub struct KeaSource {
+    transport: Transport,
+    duid: Option<String>,
+    network_length: u8,
+}
+
+impl KeaSource {
+    pub async fn try_new(
+        endpoint: KeaEndpoint,
+        duid: Option<String>,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, KeaError> {
+        let transport = match endpoint {
+            KeaEndpoint::Socket(path) => Transport::Socket(path),
+            KeaEndpoint::ControlAgent(url) => Transport::ControlAgent {
+                url: parse_url(&url).ok_or(KeaError::InvalidUrl(url))?,
+                client: http::client(),
+            },
+        };
+        let source = KeaSource {
+            transport,
+            duid,
+            network_length,
+        };
+        // Query once to make sure that the endpoint is reachable
+        match source.query().await {
+            Err(e @ KeaError::ConnectionError(..)) => return Err(e),
+            Err(e) => warn!(
+                "Unable to read lease from Kea while creating source, continuing: {}",
+                e
+            ),
+            Ok(_) => {}
+        }
+        Ok(Box::new(source))
+    }
+
+    fn command(&self) -> Value {
+        match &self.duid {
+            Some(duid) => json!({"command": "lease6-get-by-duid", "arguments": {"duid": duid}}),
+            None => json!({"command": "lease6-get-all"}),
+        }
+    }
+
+    async fn query(&self) -> Result<Ipv6Net, KeaError> {
+        let response = match &self.transport {
+            Transport::Socket(path) => {
+                match tokio::time::timeout(KEA_TIMEOUT, self.exchange(path)).await {
+                    Ok(response) => response.map_err(|e| e.to_string()),
+                    Err(_) => Err(format!("no response within {:?}", KEA_TIMEOUT)),
+                }
+            }
+            Transport::ControlAgent { url, client } => self.post(url, client).await,
+        }
+        .map_err(|e| KeaError::ConnectionError(self.transport.describe(), e))?;
+        debug!("Kea response: {}", response);
+
+        let response: Value = serde_json::from_str(&response)
+            .map_err(|e| KeaError::InvalidResponse(e.to_string()))?;
+        find_pd_lease(&response, self.duid.as_deref())
+    }
+
+    // Sends the command to the control socket and reads the response, Kea closes the connection once the full
+    // response has been sent
+    async fn exchange(&self, path: &Path) -> std::io::Result<String> {
+        let mut stream = UnixStream::connect(path).await?;
+        stream
+            .write_all(self.command().to_string().as_bytes())
+            .await?;
+        stream.shutdown().await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    // Sends the command to the control agent, which needs to be told which server it is meant for
+    async fn post(&self, url: &Uri, client: &HttpClient) -> Result<String, String> {
+        let mut command = self.command();
+        command["service"] = json!(["dhcp6"]);
+        let request = Request::post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(command.to_string()))
+            .expect("request from a valid URI");
+        let response = http::send(client, request, KEA_TIMEOUT).await?;
+        match response.status.is_success() {
+            true => Ok(response.body),
+            false => Err(format!("status {}", response.status.as_u16())),
+        }
+    }
+}
+
+// Extracts the most recently renewed active prefix delegation from a lease6-get-* response
+fn find_pd_lease(response: &Value, duid: Option<&str>) -> Result<Ipv6Net, KeaError> {
+    // Responses relayed through the Kea control agent are wrapped in a list
+    let response = match response {
+        Value::Array(responses) => responses.first().unwrap_or(&Value::Null),
+        r => r,
+    };
+    match response["result"].as_i64() {
+        Some(KEA_RESULT_SUCCESS) => {}
+        Some(KEA_RESULT_EMPTY) => return Err(KeaError::NoLease),
+        Some(_) => {
+            return Err(KeaError::CommandError(
+                response["text"].as_str().unwrap_or_default().to_string(),
+            ))
+        }
+        None => return Err(KeaError::InvalidResponse(response.to_string())),
+    }
+
+    let leases = response["arguments"]["leases"]
+        .as_array()
+        .ok_or_else(|| KeaError::InvalidResponse("no leases in response".to_string()))?;
+    leases
+        .iter()
+        .filter(|l| l["type"] == "IA_PD")
+        // State 0 is the default state, other states are declined or expired leases
+        .filter(|l| l["state"].as_i64().unwrap_or(0) == 0)
+        .filter(|l| duid.is_none() || l["duid"].as_str() == duid)
+        .filter_map(|l| {
+            let addr = Ipv6Addr::from_str(l["ip-address"].as_str()?).ok()?;
+            let len = u8::try_from(l["prefix-len"].as_u64()?).ok()?;
+            let net = Ipv6Net::new(addr, len).ok()?;
+            Some((l["cltt"].as_i64().unwrap_or(0), net))
+        })
+        .max_by_key(|(cltt, _)| *cltt)
+        .map(|(_, net)| net)
+        .ok_or(KeaError::NoLease)
+}
+
+#[async_trait]
+impl PrefixSource for KeaSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let lease = self.query().await?;
+        debug!("Found delegated prefix {} in Kea", lease);
+        mask_network(lease.addr(), self.network_length)
+            .ok_or_else(|| KeaError::InvalidResponse(lease.to_string()).into())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "kea source on {}{}, network-length {}",
+            self.transport.describe(),
+            self.duid
+                .as_ref()
+                .map(|d| format!(" for duid {}", d))
+                .unwrap_or_default(),
+            self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        str::FromStr,
+        thread,
+    };
+
+    use ipnet::Ipv6Net;
+    use serde_json::{json, Value};
+
+    use super::{find_pd_lease, KeaEndpoint, KeaError, KeaSource};
+
+    fn sample_response() -> serde_json::Value {
+        json!({
+            "result": 0,
+            "text": "3 IPv6 lease(s) found.",
+            "arguments": {
+                "leases": [
+                    {
+                        "type": "IA_NA",
+                        "ip-address": "2001:db8:1::10",
+                        "prefix-len": 128,
+                        "duid": "00:03:00:01:aa:bb:cc:dd:ee:ff",
+                        "cltt": 1700000500,
+                        "state": 0
+                    },
+                    {
+                        "type": "IA_PD",
+                        "ip-address": "2001:db8:aa00::",
+                        "prefix-len": 56,
+                        "duid": "00:03:00:01:aa:bb:cc:dd:ee:ff",
+                        "cltt": 1700000000,
+                        "state": 0
+                    },
+                    {
+                        "type": "IA_PD",
+                        "ip-address": "2001:db8:bb00::",
+                        "prefix-len": 56,
+                        "duid": "00:03:00:01:11:22:33:44:55:66",
+                        "cltt": 1700000100,
+                        "state": 0
+                    },
+                    {
+                        "type": "IA_PD",
+                        "ip-address": "2001:db8:cc00::",
+                        "prefix-len": 56,
+                        "duid": "00:03:00:01:11:22:33:44:55:66",
+                        "cltt": 1700000200,
+                        "state": 2
+                    }
+                ]
+            }
+        })
+    }
+
+    #[test]
+    fn finds_pd_lease() {
+        assert_eq!(
+            find_pd_lease(&sample_response(), None).unwrap(),
+            Ipv6Net::from_str("2001:db8:bb00::/56").unwrap()
+        );
+        assert_eq!(
+            find_pd_lease(
+                &json!([sample_response()]),
+                Some("00:03:00:01:aa:bb:cc:dd:ee:ff")
+            )
+            .unwrap(),
+            Ipv6Net::from_str("2001:db8:aa00::/56").unwrap()
+        );
+        assert!(matches!(
+            find_pd_lease(&sample_response(), Some("00:00")),
+            Err(KeaError::NoLease)
+        ));
+    }
+
+    #[test]
+    fn handles_kea_errors() {
+        assert!(matches!(
+            find_pd_lease(
+                &json!({"result": 3, "text": "0 IPv6 lease(s) found."}),
+                None
+            ),
+            Err(KeaError::NoLease)
+        ));
+        assert!(matches!(
+            find_pd_lease(&json!({"result": 2, "text": "unknown command"}), None),
+            Err(KeaError::CommandError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn queries_control_agent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let response = json!([sample_response()]).to_string();
+        let server = thread::spawn(move || {
+            let mut commands = Vec::new();
+            // One query from try_new and one from v6_network
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = String::new();
+                while !request.ends_with('}') {
+                    let mut buf = [0u8; 4096];
+                    let len = stream.read(&mut buf).unwrap();
+                    assert!(len > 0, "connection closed: {}", request);
+                    request.push_str(&String::from_utf8_lossy(&buf[..len]));
+                }
+                stream
+                    .write_all(format!("HTTP/1.0 200 OK\r\n\r\n{}", response).as_bytes())
+                    .unwrap();
+                let body = request.split_once("\r\n\r\n").unwrap().1;
+                commands.push(serde_json::from_str::<Value>(body).unwrap());
+            }
+            commands
+        });
+
+        let source = KeaSource::try_new(KeaEndpoint::ControlAgent(url), None, 64)
+            .await
+            .unwrap();
+        assert_eq!(
+            source.v6_network().await.unwrap(),
+            Ipv6Net::from_str("2001:db8:bb00::/64").unwrap()
+        );
+        let commands = server.join().unwrap();
+        assert_eq!(
+            commands[1],
+            json!({"command": "lease6-get-all", "service": ["dhcp6"]})
+        );
+    }
+}
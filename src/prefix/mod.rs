@@ -1,24 +1,121 @@
+mod command;
+mod configmap;
+mod dhcp_pd;
+mod dns;
+mod env;
+mod file;
+mod fritzbox;
+mod http;
 mod iface;
-pub use iface::IfaceSource;
+mod kea;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(all(target_os = "linux", feature = "netlink"))]
+mod netlink_iface;
+mod openwrt;
+mod ppp;
+mod ra;
+mod static_prefix;
+mod upnp;
+pub use command::CommandSource;
+pub use configmap::ConfigMapSource;
+pub use dhcp_pd::{DhcpPdSource, LeaseFormat};
+pub use dns::DnsSource;
+pub use env::EnvSource;
+pub use file::{watch_file, FileSource};
+pub use fritzbox::{FritzboxSource, FRITZBOX_DEFAULT_PORT};
+pub use http::{client as http_client, HttpClient, HttpSource};
+pub use iface::{AddressHint, AddressScope, IfaceOptions, IfaceSource};
+pub use kea::{KeaEndpoint, KeaSource};
+#[cfg(all(target_os = "linux", feature = "netlink"))]
+pub use netlink_iface::NetlinkSource;
+pub use openwrt::OpenwrtSource;
+pub use ppp::PppSource;
+pub use ra::RaSource;
+pub use static_prefix::StaticSource;
+pub use upnp::UpnpSource;
 
-use std::fmt::Display;
+use std::{fmt::Display, net::Ipv6Addr, sync::Arc};
 
-use ipnet::Ipv6Net;
+use async_trait::async_trait;
+use ipnet::{Ipv4Net, Ipv6Net};
+use log::warn;
 #[cfg(test)]
 use mockall::automock;
 use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::netmask_for;
 
 #[derive(Error, Debug)]
 pub struct SourceError {
     msg: String,
 }
+impl SourceError {
+    #[cfg(test)]
+    pub(crate) fn test_new(msg: &str) -> SourceError {
+        SourceError {
+            msg: msg.to_string(),
+        }
+    }
+}
 impl Display for SourceError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.msg)
     }
 }
 
+/// Provides the dynamic network. Sources that need network requests or other I/O to determine it
+/// can do so asynchronously, cheap local sources simply return right away
 #[cfg_attr(test, automock)]
-pub trait PrefixSource {
-    fn v6_network(&self) -> Result<Ipv6Net, SourceError>;
+#[async_trait]
+pub trait PrefixSource: Send + Sync {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError>;
+    /// Human-readable description of the source and its configuration, for use in logs
+    fn describe(&self) -> String;
+    /// Public IPv4 address, as a single-address network. Only used if IPv4 ranges are reconciled as well,
+    /// the network length is then applied by the reconciler
+    async fn v4_network(&self) -> Result<Ipv4Net, SourceError> {
+        Err(SourceError {
+            msg: format!("{} does not provide an IPv4 address", self.describe()),
+        })
+    }
+    /// Notified (through `notify_waiters`) when the network may have changed,
+    /// so that it is picked up right away instead of at the next run
+    fn changes(&self) -> Option<Arc<Notify>> {
+        None
+    }
+}
+
+/// Allows one source to be shared, e.g. by the reconcilers of multiple pools
+#[async_trait]
+impl<T: PrefixSource + ?Sized> PrefixSource for Arc<T> {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        (**self).v6_network().await
+    }
+
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+
+    async fn v4_network(&self) -> Result<Ipv4Net, SourceError> {
+        (**self).v4_network().await
+    }
+
+    fn changes(&self) -> Option<Arc<Notify>> {
+        (**self).changes()
+    }
+}
+
+// Derives the network of the given length that contains the address
+fn mask_network(addr: Ipv6Addr, network_length: u8) -> Option<Ipv6Net> {
+    let network_part = Ipv6Addr::from(u128::from(addr) & netmask_for(network_length));
+
+    match Ipv6Net::new(network_part, network_length) {
+        Ok(net) => Some(net),
+        Err(e) => {
+            warn!("Unable to construct Ipv6 prefix: {}", e.to_string());
+            None
+        }
+    }
 }
@@ -0,0 +1,523 @@
+// Minimal rtnetlink client for the routing information that `network-interface` doesn't expose.
+// Only the few message types needed by the sources are implemented.
+use std::{ffi::CString, io, mem, net::Ipv6Addr, thread, time::Duration};
+
+use ipnet::Ipv6Net;
+
+const NLMSG_HDR_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const IFADDRMSG_LEN: usize = 8;
+const IFINFOMSG_LEN: usize = 16;
+// Not exported by libc for all targets (e.g. musl)
+const IFA_FLAGS: u16 = 8;
+const IFLA_OPERSTATE: u16 = 16;
+// Operational states (RFC 2863) from linux/if.h
+const IF_OPER_DOWN: u8 = 2;
+const IF_OPER_LOWERLAYERDOWN: u8 = 5;
+const SOL_NETLINK: libc::c_int = 270;
+const NETLINK_GET_STRICT_CHK: libc::c_int = 12;
+const RECV_TIMEOUT_SECS: libc::time_t = 5;
+
+/// A unicast route from the main IPv6 routing table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub dst: Ipv6Net,
+    pub ifindex: u32,
+    pub metric: u32,
+}
+
+/// Returns all unicast routes in the main IPv6 routing table
+pub fn routes() -> io::Result<Vec<Route>> {
+    let mut request = [0u8; RTMSG_LEN];
+    request[0] = libc::AF_INET6 as u8;
+    Ok(dump(libc::RTM_GETROUTE, &request)?
+        .iter()
+        .filter_map(|msg| parse_route(msg))
+        .collect())
+}
+
+/// An IPv6 address assigned to an interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub addr: Ipv6Addr,
+    pub prefix_len: u8,
+    pub ifindex: u32,
+    /// `IFA_F_*` flags of the address
+    pub flags: u32,
+    /// Remaining preferred lifetime in seconds, `u32::MAX` if infinite
+    pub preferred_lft: u32,
+    /// Remaining valid lifetime in seconds, `u32::MAX` if infinite
+    pub valid_lft: u32,
+    /// When the address was last updated, e.g. by a router advertisement, relative to [`monotonic_now`]
+    pub updated: Option<Duration>,
+}
+
+/// Time since boot on the clock that the kernel uses for address timestamps
+pub fn monotonic_now() -> io::Result<Duration> {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// Returns all IPv6 addresses assigned to the interface with the given name
+pub fn addresses(iface_name: &str) -> io::Result<Vec<Address>> {
+    addresses_on(ifindex(iface_name)?)
+}
+
+/// Looks up the index of the interface with the given name
+pub fn ifindex(iface_name: &str) -> io::Result<u32> {
+    let name =
+        CString::new(iface_name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        ifindex => Ok(ifindex),
+    }
+}
+
+/// Returns all IPv6 addresses assigned to the interface with the given index.
+/// Kernels with strict checking (4.20+) only dump the addresses of that interface
+pub fn addresses_on(ifindex: u32) -> io::Result<Vec<Address>> {
+    let mut request = [0u8; IFADDRMSG_LEN];
+    request[0] = libc::AF_INET6 as u8;
+    request[4..8].copy_from_slice(&ifindex.to_ne_bytes());
+    Ok(dump(libc::RTM_GETADDR, &request)?
+        .iter()
+        .filter_map(|msg| parse_address(msg))
+        .filter(|a| a.ifindex == ifindex)
+        .collect())
+}
+
+/// State of a network interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Link {
+    pub ifindex: u32,
+    /// `IFF_*` flags of the interface
+    pub flags: u32,
+    /// Operational state, missing for drivers that don't report one
+    pub operstate: Option<u8>,
+}
+
+impl Link {
+    /// Whether the interface is administratively up and not known to be without a carrier.
+    /// Interfaces that don't report an operational state, like loopback, count as up
+    pub fn is_up(&self) -> bool {
+        self.flags & libc::IFF_UP as u32 != 0
+            && !matches!(self.operstate, Some(IF_OPER_DOWN | IF_OPER_LOWERLAYERDOWN))
+    }
+}
+
+/// Returns the state of the interface with the given index, `None` if it doesn't exist
+pub fn link(ifindex: u32) -> io::Result<Option<Link>> {
+    // Link dumps can't be filtered by index, the kernel rejects that with strict checking
+    let mut request = [0u8; IFINFOMSG_LEN];
+    request[0] = libc::AF_UNSPEC as u8;
+    Ok(dump(libc::RTM_GETLINK, &request)?
+        .iter()
+        .filter_map(|msg| parse_link(msg))
+        .find(|l| l.ifindex == ifindex))
+}
+
+fn parse_link(msg: &[u8]) -> Option<Link> {
+    if msg.len() < IFINFOMSG_LEN {
+        return None;
+    }
+    let mut operstate = None;
+    for (kind, data) in attributes(&msg[IFINFOMSG_LEN..]) {
+        if kind == IFLA_OPERSTATE {
+            operstate = data.first().copied();
+        }
+    }
+    Some(Link {
+        ifindex: read_u32(&msg[4..])?,
+        flags: read_u32(&msg[8..])?,
+        operstate,
+    })
+}
+
+fn parse_address(msg: &[u8]) -> Option<Address> {
+    if msg.len() < IFADDRMSG_LEN || msg[0] != libc::AF_INET6 as u8 {
+        return None;
+    }
+    let prefix_len = msg[1];
+    // The legacy 8 bit flags are superseded by the IFA_FLAGS attribute if present
+    let mut flags = u32::from(msg[2]);
+    let ifindex = read_u32(&msg[4..])?;
+    let mut addr = None;
+    // Addresses without cache info are permanent
+    let mut preferred_lft = u32::MAX;
+    let mut valid_lft = u32::MAX;
+    let mut updated = None;
+    for (kind, data) in attributes(&msg[IFADDRMSG_LEN..]) {
+        match kind {
+            libc::IFA_ADDRESS => addr = Some(<[u8; 16]>::try_from(data).ok()?.into()),
+            IFA_FLAGS => flags = read_u32(data)?,
+            libc::IFA_CACHEINFO => (preferred_lft, valid_lft, updated) = parse_cache_info(data)?,
+            _ => {}
+        }
+    }
+    Some(Address {
+        addr: addr?,
+        prefix_len,
+        ifindex,
+        flags,
+        preferred_lft,
+        valid_lft,
+        updated,
+    })
+}
+
+/// Parses an `IFA_CACHEINFO` attribute into the preferred and valid lifetimes and the update timestamp
+pub fn parse_cache_info(data: &[u8]) -> Option<(u32, u32, Option<Duration>)> {
+    // struct ifa_cacheinfo: preferred and valid lifetime in seconds,
+    // then the created and updated timestamps in hundredths of seconds since boot
+    let preferred_lft = read_u32(data)?;
+    let valid_lft = read_u32(data.get(4..)?)?;
+    let tstamp = read_u32(data.get(12..)?)?;
+    Some((
+        preferred_lft,
+        valid_lft,
+        Some(Duration::from_millis(u64::from(tstamp) * 10)),
+    ))
+}
+
+fn parse_route(msg: &[u8]) -> Option<Route> {
+    if msg.len() < RTMSG_LEN || msg[7] != libc::RTN_UNICAST {
+        return None;
+    }
+    let dst_len = msg[1];
+    let mut table = u32::from(msg[4]);
+    let mut dst = Ipv6Addr::UNSPECIFIED;
+    let mut ifindex = 0;
+    let mut metric = 0;
+    for (kind, data) in attributes(&msg[RTMSG_LEN..]) {
+        match kind {
+            libc::RTA_DST => dst = <[u8; 16]>::try_from(data).ok()?.into(),
+            libc::RTA_OIF => ifindex = read_u32(data)?,
+            libc::RTA_PRIORITY => metric = read_u32(data)?,
+            libc::RTA_TABLE => table = read_u32(data)?,
+            _ => {}
+        }
+    }
+    if table != u32::from(libc::RT_TABLE_MAIN) {
+        return None;
+    }
+    Some(Route {
+        dst: Ipv6Net::new(dst, dst_len).ok()?,
+        ifindex,
+        metric,
+    })
+}
+
+// Splits a buffer into its netlink attributes (type, payload)
+fn attributes(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while buf.len() >= 4 {
+        let len = usize::from(u16::from_ne_bytes([buf[0], buf[1]]));
+        // The upper bits are used for nesting/byte order flags
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]) & 0x3fff;
+        if len < 4 || len > buf.len() {
+            break;
+        }
+        attrs.push((kind, &buf[4..len]));
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    attrs
+}
+
+fn read_u32(data: &[u8]) -> Option<u32> {
+    Some(u32::from_ne_bytes(data.get(..4)?.try_into().ok()?))
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+struct Socket(libc::c_int);
+impl Socket {
+    fn open() -> io::Result<Socket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Socket(fd))
+    }
+}
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Calls `on_change` from a background thread whenever an IPv6 address or route is added or removed on any interface.
+/// The thread runs for the rest of the process lifetime, only setup errors are returned
+pub fn watch_changes(mut on_change: impl FnMut() + Send + 'static) -> io::Result<()> {
+    let socket = Socket::open()?;
+    let mut local: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    local.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    local.nl_groups = (libc::RTMGRP_IPV6_IFADDR | libc::RTMGRP_IPV6_ROUTE) as u32;
+    let res = unsafe {
+        libc::bind(
+            socket.0,
+            &local as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    thread::spawn(move || {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let received = unsafe {
+                libc::recv(
+                    socket.0,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if received < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    // Dropped notifications mean that something changed as well
+                    Some(libc::ENOBUFS) => {}
+                    _ => {
+                        log::warn!("Stopped watching for address changes: {}", e);
+                        return;
+                    }
+                }
+            }
+            on_change();
+        }
+    });
+    Ok(())
+}
+
+// Sends a dump request of the given type and returns the payloads of all response messages
+fn dump(msg_type: u16, request: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let socket = Socket::open()?;
+
+    let timeout = libc::timeval {
+        tv_sec: RECV_TIMEOUT_SECS,
+        tv_usec: 0,
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            socket.0,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Lets the kernel apply the filters in the request, older kernels ignore them and dump everything
+    let strict: libc::c_int = 1;
+    unsafe {
+        libc::setsockopt(
+            socket.0,
+            SOL_NETLINK,
+            NETLINK_GET_STRICT_CHK,
+            &strict as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    let mut msg = Vec::with_capacity(NLMSG_HDR_LEN + request.len());
+    msg.extend_from_slice(&((NLMSG_HDR_LEN + request.len()) as u32).to_ne_bytes());
+    msg.extend_from_slice(&msg_type.to_ne_bytes());
+    msg.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    msg.extend_from_slice(&1u32.to_ne_bytes()); // sequence number
+    msg.extend_from_slice(&0u32.to_ne_bytes()); // port id, assigned by the kernel
+    msg.extend_from_slice(request);
+
+    let mut kernel: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    kernel.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    let sent = unsafe {
+        libc::sendto(
+            socket.0,
+            msg.as_ptr() as *const libc::c_void,
+            msg.len(),
+            0,
+            &kernel as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut payloads = Vec::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let received = unsafe {
+            libc::recv(
+                socket.0,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut data = &buf[..received as usize];
+        while data.len() >= NLMSG_HDR_LEN {
+            let len = read_u32(data).unwrap_or(0) as usize;
+            let kind = u16::from_ne_bytes([data[4], data[5]]);
+            if len < NLMSG_HDR_LEN || len > data.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated netlink message",
+                ));
+            }
+            let payload = &data[NLMSG_HDR_LEN..len];
+            if kind == libc::NLMSG_DONE as u16 {
+                return Ok(payloads);
+            } else if kind == libc::NLMSG_ERROR as u16 {
+                let code = payload
+                    .get(..4)
+                    .map(|c| i32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+                    .unwrap_or(0);
+                if code != 0 {
+                    return Err(io::Error::from_raw_os_error(-code));
+                }
+            } else {
+                payloads.push(payload.to_vec());
+            }
+            data = &data[align(len).min(data.len())..];
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::str::FromStr;
+
+    use ipnet::Ipv6Net;
+
+    use std::{net::Ipv6Addr, time::Duration};
+
+    use super::{
+        parse_address, parse_link, parse_route, Address, Link, Route, IFADDRMSG_LEN, IFA_FLAGS,
+        IFINFOMSG_LEN, IFLA_OPERSTATE, IF_OPER_DOWN, RTMSG_LEN,
+    };
+
+    /// Encodes a netlink attribute, including padding
+    pub fn attr(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(data);
+        buf.resize((buf.len() + 3) & !3, 0);
+        buf
+    }
+
+    #[test]
+    fn parses_route() {
+        let dst = Ipv6Net::from_str("2003:ee:970c:80aa::/64").unwrap();
+        let mut msg = vec![0u8; RTMSG_LEN];
+        msg[0] = libc::AF_INET6 as u8;
+        msg[1] = 64;
+        msg[4] = libc::RT_TABLE_MAIN;
+        msg[7] = libc::RTN_UNICAST;
+        msg.extend(attr(libc::RTA_DST, &dst.addr().octets()));
+        msg.extend(attr(libc::RTA_OIF, &3u32.to_ne_bytes()));
+        msg.extend(attr(libc::RTA_PRIORITY, &1024u32.to_ne_bytes()));
+
+        assert_eq!(
+            parse_route(&msg),
+            Some(Route {
+                dst,
+                ifindex: 3,
+                metric: 1024
+            })
+        );
+
+        // Routes from other tables are ignored
+        msg[4] = 255;
+        assert_eq!(parse_route(&msg), None);
+    }
+
+    #[test]
+    fn parses_link() {
+        const IF_OPER_UP: u8 = 6;
+        let mut msg = vec![0u8; IFINFOMSG_LEN];
+        msg[4..8].copy_from_slice(&3u32.to_ne_bytes());
+        msg[8..12].copy_from_slice(&(libc::IFF_UP as u32).to_ne_bytes());
+        let up = msg.clone();
+        msg.extend(attr(IFLA_OPERSTATE, &[IF_OPER_UP]));
+
+        let link = parse_link(&msg).unwrap();
+        assert_eq!(
+            link,
+            Link {
+                ifindex: 3,
+                flags: libc::IFF_UP as u32,
+                operstate: Some(IF_OPER_UP)
+            }
+        );
+        assert!(link.is_up());
+        // Without an operational state only the flags count
+        assert!(parse_link(&up).unwrap().is_up());
+
+        // No carrier
+        let mut down = up.clone();
+        down.extend(attr(IFLA_OPERSTATE, &[IF_OPER_DOWN]));
+        assert!(!parse_link(&down).unwrap().is_up());
+        // Administratively down
+        let mut disabled = up;
+        disabled[8..12].copy_from_slice(&0u32.to_ne_bytes());
+        assert!(!parse_link(&disabled).unwrap().is_up());
+    }
+
+    #[test]
+    fn parses_address() {
+        let addr = Ipv6Addr::from_str("2003:ee:970c:80aa::199").unwrap();
+        let mut msg = vec![0u8; IFADDRMSG_LEN];
+        msg[0] = libc::AF_INET6 as u8;
+        msg[1] = 64;
+        msg[2] = libc::IFA_F_TEMPORARY as u8;
+        msg[4..8].copy_from_slice(&2u32.to_ne_bytes());
+        msg.extend(attr(libc::IFA_ADDRESS, &addr.octets()));
+
+        let mut expected = Address {
+            addr,
+            prefix_len: 64,
+            ifindex: 2,
+            flags: libc::IFA_F_TEMPORARY,
+            preferred_lft: u32::MAX,
+            valid_lft: u32::MAX,
+            updated: None,
+        };
+        assert_eq!(parse_address(&msg), Some(expected));
+
+        // The extended flags take precedence
+        let flags = libc::IFA_F_DEPRECATED | 0x100;
+        msg.extend(attr(IFA_FLAGS, &flags.to_ne_bytes()));
+        expected.flags = flags;
+        assert_eq!(parse_address(&msg), Some(expected));
+
+        let mut cacheinfo = Vec::new();
+        for field in [1800u32, 7200, 100, 200] {
+            cacheinfo.extend_from_slice(&field.to_ne_bytes());
+        }
+        msg.extend(attr(libc::IFA_CACHEINFO, &cacheinfo));
+        expected.preferred_lft = 1800;
+        expected.valid_lft = 7200;
+        expected.updated = Some(Duration::from_secs(2));
+        assert_eq!(parse_address(&msg), Some(expected));
+    }
+}
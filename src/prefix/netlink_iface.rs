@@ -0,0 +1,278 @@
+use std::{net::Ipv6Addr, sync::Arc};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use rtnetlink::{
+    packet::{address::Nla, AddressMessage},
+    Handle,
+};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use super::{
+    iface::{
+        parse_index, prefix_len_allowed, select_network, states_of, watch_changes, IfaceOptions,
+        UsedIface,
+    },
+    netlink, PrefixSource, SourceError,
+};
+
+#[derive(Error, Debug)]
+pub enum NetlinkError {
+    #[error("Interface `{0}` could not be found")]
+    NotFound(String),
+    #[error("Interface `{0}` does not have a suitable IPv6 address assigned")]
+    NoIpv6Prefix(String),
+    #[error("Error while querying the addresses of `{0}`: `{1}`")]
+    QueryError(String, String),
+    #[error("Invalid network length {0}, must be between 1 and 128")]
+    InvalidNetworkLength(u8),
+    #[error("Unable to open a netlink connection: `{0}`")]
+    ConnectionError(String),
+}
+
+impl From<NetlinkError> for SourceError {
+    fn from(e: NetlinkError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Like [`super::IfaceSource`], but asks the kernel for the addresses of just the configured interfaces
+/// over rtnetlink instead of enumerating all interfaces on every run.
+/// The address flags and lifetimes come with the same query, so the selection options need no extra lookup
+pub struct NetlinkSource {
+    iface_names: Vec<String>,
+    network_length: u8,
+    options: IfaceOptions,
+    changes: Option<Arc<Notify>>,
+    handle: Handle,
+    used_iface: UsedIface,
+}
+
+impl NetlinkSource {
+    pub async fn try_new(
+        iface_names: Vec<String>,
+        network_length: u8,
+        options: IfaceOptions,
+    ) -> Result<Box<dyn PrefixSource>, NetlinkError> {
+        if !(1..=128).contains(&network_length) {
+            return Err(NetlinkError::InvalidNetworkLength(network_length));
+        }
+        let (connection, handle, _) = rtnetlink::new_connection()
+            .map_err(|e| NetlinkError::ConnectionError(e.to_string()))?;
+        tokio::spawn(connection);
+        let changes = match options.watch {
+            true => watch_changes(),
+            false => None,
+        };
+        let source = NetlinkSource {
+            iface_names,
+            network_length,
+            options,
+            changes,
+            handle,
+            used_iface: UsedIface::default(),
+        };
+        match source.find_v6_net().await {
+            Err(e @ NetlinkError::NotFound(_)) => return Err(e),
+            Err(e) => warn!("{} while creating source, continuing", e),
+            Ok(_) => {}
+        }
+        Ok(Box::new(source))
+    }
+
+    // Looks up the index of an interface by name, then as an `if<index>` selector.
+    // `None` if neither matches an existing interface
+    async fn index_of(&self, selector: &str) -> Result<Option<u32>, NetlinkError> {
+        let by_name = self.handle.link().get().match_name(selector.to_string());
+        match by_name.execute().try_next().await {
+            Ok(link) => return Ok(link.map(|l| l.header.index)),
+            Err(e) if !is_no_device(&e) => return Err(query_error(selector, e)),
+            Err(_) => {}
+        }
+        let Some(ifindex) = parse_index(selector) else {
+            return Ok(None);
+        };
+        let by_index = self.handle.link().get().match_index(ifindex);
+        match by_index.execute().try_next().await {
+            Ok(link) => Ok(link.map(|l| l.header.index)),
+            Err(e) if is_no_device(&e) => Ok(None),
+            Err(e) => Err(query_error(selector, e)),
+        }
+    }
+
+    // Dumps the IPv6 addresses of a single interface
+    async fn addresses_on(&self, ifindex: u32) -> Result<Vec<netlink::Address>, rtnetlink::Error> {
+        let mut request = self.handle.address().get().set_link_index_filter(ifindex);
+        request.message_mut().header.family = libc::AF_INET6 as u8;
+        let messages: Vec<AddressMessage> = request.execute().try_collect().await?;
+        Ok(messages.into_iter().filter_map(to_address).collect())
+    }
+
+    // Tries the interfaces in order, like the iface source
+    async fn find_v6_net(&self) -> Result<Ipv6Net, NetlinkError> {
+        let mut found_any = false;
+        for name in &self.iface_names {
+            let Some(ifindex) = self.index_of(name).await? else {
+                debug!("Interface {} could not be found", name);
+                continue;
+            };
+            found_any = true;
+            let addrs = self
+                .addresses_on(ifindex)
+                .await
+                .map_err(|e| query_error(name, e))?;
+            debug!("Found addresses on interface {}: {:?}", name, addrs);
+            let candidates = addrs
+                .iter()
+                .filter(|a| prefix_len_allowed(&a.addr, Some(a.prefix_len), &self.options))
+                .map(|a| a.addr)
+                .collect();
+            let prefix_lens = addrs.iter().map(|a| (a.addr, a.prefix_len)).collect();
+            match select_network(
+                candidates,
+                &prefix_lens,
+                &self.options,
+                self.network_length,
+                || Some(states_of(&addrs)),
+            ) {
+                Some(net) => {
+                    self.used_iface.log(name, &self.iface_names);
+                    return Ok(net);
+                }
+                None => debug!("No suitable address on interface {}", name),
+            }
+        }
+        let names = self.iface_names.join(", ");
+        match found_any {
+            true => Err(NetlinkError::NoIpv6Prefix(names)),
+            false => Err(NetlinkError::NotFound(names)),
+        }
+    }
+}
+
+fn query_error(iface_name: &str, e: rtnetlink::Error) -> NetlinkError {
+    NetlinkError::QueryError(iface_name.to_string(), e.to_string())
+}
+
+fn is_no_device(e: &rtnetlink::Error) -> bool {
+    matches!(e, rtnetlink::Error::NetlinkError(msg) if msg.code == -libc::ENODEV)
+}
+
+// Converts an rtnetlink address message into the address type that the selection options work on
+fn to_address(msg: AddressMessage) -> Option<netlink::Address> {
+    if msg.header.family != libc::AF_INET6 as u8 {
+        return None;
+    }
+    // The legacy 8 bit flags are superseded by the IFA_FLAGS attribute if present
+    let mut flags = u32::from(msg.header.flags);
+    let mut addr = None;
+    // Addresses without cache info are permanent
+    let mut lifetimes = (u32::MAX, u32::MAX, None);
+    for nla in msg.nlas {
+        match nla {
+            Nla::Address(bytes) => {
+                addr = Some(Ipv6Addr::from(<[u8; 16]>::try_from(bytes.as_slice()).ok()?))
+            }
+            Nla::Flags(f) => flags = f,
+            Nla::CacheInfo(data) => lifetimes = netlink::parse_cache_info(&data)?,
+            _ => {}
+        }
+    }
+    let (preferred_lft, valid_lft, updated) = lifetimes;
+    Some(netlink::Address {
+        addr: addr?,
+        prefix_len: msg.header.prefix_len,
+        ifindex: msg.header.index,
+        flags,
+        preferred_lft,
+        valid_lft,
+        updated,
+    })
+}
+
+#[async_trait]
+impl PrefixSource for NetlinkSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        Ok(self.find_v6_net().await?)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "netlink source on {}, network-length {}",
+            self.iface_names.join(","),
+            self.network_length
+        )
+    }
+
+    fn changes(&self) -> Option<Arc<Notify>> {
+        self.changes.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv6Addr, time::Duration};
+
+    use rtnetlink::packet::{address::Nla, AddressMessage};
+
+    use crate::prefix::IfaceOptions;
+
+    use super::{to_address, NetlinkError, NetlinkSource};
+
+    #[test]
+    fn converts_address_messages() {
+        let mut msg = AddressMessage::default();
+        msg.header.family = libc::AF_INET6 as u8;
+        msg.header.prefix_len = 64;
+        msg.header.flags = 0x01;
+        msg.header.index = 2;
+        let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        msg.nlas.push(Nla::Address(addr.octets().to_vec()));
+        msg.nlas.push(Nla::Flags(0x100));
+        let cache_info = [3600u32, 7200, 100, 250]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+        msg.nlas.push(Nla::CacheInfo(cache_info));
+
+        let converted = to_address(msg.clone()).unwrap();
+        assert_eq!(converted.addr, addr);
+        assert_eq!(converted.prefix_len, 64);
+        assert_eq!(converted.ifindex, 2);
+        assert_eq!(converted.flags, 0x100);
+        assert_eq!(converted.preferred_lft, 3600);
+        assert_eq!(converted.valid_lft, 7200);
+        assert_eq!(converted.updated, Some(Duration::from_millis(2500)));
+
+        msg.header.family = libc::AF_INET as u8;
+        assert_eq!(to_address(msg), None);
+    }
+
+    #[tokio::test]
+    async fn requires_existing_interface() {
+        assert!(matches!(
+            NetlinkSource::try_new(
+                vec!["does-not-exist0".to_string()],
+                64,
+                IfaceOptions::default()
+            )
+            .await,
+            Err(NetlinkError::NotFound(_))
+        ));
+        assert!(matches!(
+            NetlinkSource::try_new(vec!["lo".to_string()], 0, IfaceOptions::default()).await,
+            Err(NetlinkError::InvalidNetworkLength(0))
+        ));
+        // The loopback address is not global, so the source only warns
+        let source = NetlinkSource::try_new(vec!["lo".to_string()], 64, IfaceOptions::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            source.v6_network().await,
+            Err(e) if e.to_string().contains("does not have a suitable IPv6 address")
+        ));
+    }
+}
@@ -0,0 +1,369 @@
+use std::{net::Ipv6Addr, str::FromStr, time::Duration};
+
+use async_trait::async_trait;
+use hyper::{header, Body, Request, Uri};
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use super::{
+    http::{self, parse_url, HttpClient},
+    mask_network, PrefixSource, SourceError,
+};
+
+/// Session id used for the login call, before there is a session
+const NULL_SESSION: &str = "00000000000000000000000000000000";
+/// ubus status code for denied access, e.g. because of wrong credentials or a missing ACL
+const UBUS_STATUS_PERMISSION_DENIED: i64 = 6;
+
+#[derive(Error, Debug)]
+pub enum OpenwrtError {
+    #[error("Invalid URL `{0}`, only http:// and https:// URLs are supported")]
+    InvalidUrl(String),
+    #[error("Error while connecting to `{0}`: `{1}`")]
+    ConnectionError(String, String),
+    #[error("`{0}` rejected the credentials of user `{1}`")]
+    AuthFailed(String, String),
+    #[error("Unexpected response from `{0}`: `{1}`")]
+    InvalidResponse(String, String),
+    #[error("Interface `{1}` on `{0}` did not report a delegated IPv6 prefix, it may be down")]
+    NoPrefix(String, String),
+}
+
+impl From<OpenwrtError> for SourceError {
+    fn from(e: OpenwrtError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Asks an OpenWrt router for the prefix delegated to one of its interfaces, through the ubus JSON-RPC
+/// interface of its web server (`uhttpd-mod-ubus`, installed along with LuCI).
+/// The user needs read access to `network.interface.<interface>` in its rpcd ACL, which `root` has.
+// Sessions expire after a few minutes of inactivity, so every fetch logs in again
+pub struct OpenwrtSource {
+    url: Uri,
+    user: String,
+    password: String,
+    /// Logical interface in the OpenWrt network config, e.g. `wan6`
+    interface: String,
+    timeout: Duration,
+    network_length: u8,
+    client: HttpClient,
+}
+
+impl OpenwrtSource {
+    pub async fn try_new(
+        url: String,
+        user: String,
+        password: String,
+        interface: String,
+        timeout: Duration,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, OpenwrtError> {
+        let url = parse_url(&url).ok_or(OpenwrtError::InvalidUrl(url))?;
+        let source = OpenwrtSource {
+            url,
+            user,
+            password,
+            interface,
+            timeout,
+            network_length,
+            client: http::client(),
+        };
+        // Wrong credentials won't fix themselves, but the router may just be rebooting
+        match source.fetch().await {
+            Err(e @ OpenwrtError::AuthFailed(..)) => return Err(e),
+            Err(e) => warn!("{} while creating source, continuing", e),
+            Ok(_) => {}
+        }
+        Ok(Box::new(source))
+    }
+
+    async fn fetch(&self) -> Result<Ipv6Net, OpenwrtError> {
+        let login = self
+            .call(
+                NULL_SESSION,
+                "session",
+                "login",
+                json!({"username": self.user, "password": self.password}),
+            )
+            .await;
+        let session = match login {
+            Err(UbusFailure::Status(UBUS_STATUS_PERMISSION_DENIED)) => {
+                return Err(OpenwrtError::AuthFailed(
+                    self.url.to_string(),
+                    self.user.clone(),
+                ))
+            }
+            result => result.map_err(|e| self.failure(e))?,
+        };
+        let session = session
+            .as_ref()
+            .and_then(|s| s["ubus_rpc_session"].as_str())
+            .ok_or_else(|| self.invalid("login returned no session".to_string()))?;
+
+        let object = format!("network.interface.{}", self.interface);
+        let status = self
+            .call(session, &object, "status", json!({}))
+            .await
+            .map_err(|e| self.failure(e))?
+            .ok_or_else(|| self.invalid(format!("{} status returned no data", object)))?;
+        parse_prefix(&status)
+            .map_err(|e| self.invalid(e))?
+            .ok_or_else(|| OpenwrtError::NoPrefix(self.url.to_string(), self.interface.clone()))
+    }
+
+    // Calls a ubus method and returns the data of the result, if there is any
+    async fn call(
+        &self,
+        session: &str,
+        object: &str,
+        method: &str,
+        args: Value,
+    ) -> Result<Option<Value>, UbusFailure> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "call",
+            "params": [session, object, method, args],
+        })
+        .to_string();
+        let request = Request::post(&self.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .expect("request from a valid URI");
+        let response = http::send(&self.client, request, self.timeout)
+            .await
+            .map_err(UbusFailure::Connection)?;
+        if !response.status.is_success() {
+            return Err(UbusFailure::Invalid(format!(
+                "status {}",
+                response.status.as_u16()
+            )));
+        }
+        debug!(
+            "Response from {} to {} {}: {}",
+            self.url, object, method, response.body
+        );
+        ubus_result(&response.body)
+    }
+
+    fn failure(&self, failure: UbusFailure) -> OpenwrtError {
+        match failure {
+            UbusFailure::Connection(msg) => {
+                OpenwrtError::ConnectionError(self.url.to_string(), msg)
+            }
+            UbusFailure::Status(code) => self.invalid(format!("ubus status {}", code)),
+            UbusFailure::Invalid(msg) => self.invalid(msg),
+        }
+    }
+
+    fn invalid(&self, msg: String) -> OpenwrtError {
+        OpenwrtError::InvalidResponse(self.url.to_string(), msg)
+    }
+}
+
+// Why a ubus call failed
+#[derive(Debug, PartialEq, Eq)]
+enum UbusFailure {
+    Connection(String),
+    /// Non-zero ubus status code
+    Status(i64),
+    Invalid(String),
+}
+
+// Unpacks a JSON-RPC response. The result is an array of the ubus status code and the data, if there is any
+fn ubus_result(body: &str) -> Result<Option<Value>, UbusFailure> {
+    let response: Value =
+        serde_json::from_str(body).map_err(|e| UbusFailure::Invalid(e.to_string()))?;
+    if let Some(error) = response.get("error") {
+        // rpcd answers calls with an expired or unknown session with "Access denied"
+        return match error["code"].as_i64() {
+            Some(-32002) => Err(UbusFailure::Status(UBUS_STATUS_PERMISSION_DENIED)),
+            _ => Err(UbusFailure::Invalid(format!("error {}", error))),
+        };
+    }
+    let result = response["result"]
+        .as_array()
+        .ok_or_else(|| UbusFailure::Invalid("missing result".to_string()))?;
+    match result.first().and_then(Value::as_i64) {
+        Some(0) => Ok(result.get(1).cloned()),
+        Some(code) => Err(UbusFailure::Status(code)),
+        None => Err(UbusFailure::Invalid("missing status code".to_string())),
+    }
+}
+
+// Takes the first prefix delegated to the interface from its status
+fn parse_prefix(status: &Value) -> Result<Option<Ipv6Net>, String> {
+    let prefixes = match status.get("ipv6-prefix") {
+        Some(prefixes) => prefixes.as_array().ok_or("ipv6-prefix is not a list")?,
+        None => return Ok(None),
+    };
+    let Some(prefix) = prefixes.first() else {
+        return Ok(None);
+    };
+    let address = prefix["address"]
+        .as_str()
+        .ok_or("prefix without an address")?;
+    let addr =
+        Ipv6Addr::from_str(address).map_err(|_| format!("`{}` is not an IPv6 prefix", address))?;
+    let mask = prefix["mask"]
+        .as_u64()
+        .and_then(|m| u8::try_from(m).ok())
+        .ok_or("prefix without a valid mask")?;
+    Ipv6Net::new(addr, mask)
+        .map(Some)
+        .map_err(|_| format!("invalid prefix length `{}`", mask))
+}
+
+#[async_trait]
+impl PrefixSource for OpenwrtSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.fetch().await?;
+        mask_network(prefix.addr(), self.network_length)
+            .ok_or_else(|| self.invalid(prefix.to_string()).into())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "openwrt source on {} interface {} as {}, network-length {}",
+            self.url, self.interface, self.user, self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        str::FromStr,
+        thread,
+        time::Duration,
+    };
+
+    use ipnet::Ipv6Net;
+    use serde_json::Value;
+
+    use super::{parse_prefix, ubus_result, OpenwrtError, OpenwrtSource, UbusFailure};
+
+    // Response of OpenWrt 23.05 to `network.interface.wan6 status` on a PPPoE uplink
+    const STATUS: &str = r#"{"jsonrpc":"2.0","id":1,"result":[0,{"up":true,"pending":false,"available":true,"autostart":true,"dynamic":false,"uptime":86012,"l3_device":"pppoe-wan","proto":"dhcpv6","device":"pppoe-wan","updated":["addresses","routes","prefixes"],"metric":0,"dns_metric":0,"delegation":true,"ipv4-address":[],"ipv6-address":[{"address":"2003:ee:9700:1a2b:3c4d:5e6f:7a8b:9c0d","mask":64,"preferred":6830,"valid":13830}],"ipv6-prefix":[{"address":"2003:ee:970c:8000::","mask":56,"preferred":6830,"valid":13830,"class":"wan6","assigned":{"lan":{"address":"2003:ee:970c:8000::","mask":60}}}],"ipv6-prefix-assignment":[],"route":[{"target":"::","mask":0,"nexthop":"fe80::1","metric":512,"valid":1630,"source":"2003:ee:970c:8000::/56"}],"dns-server":["2003:180:2:7000::53"],"dns-search":[],"neighbors":[],"inactive":{"ipv4-address":[],"ipv6-address":[],"route":[],"dns-server":[],"dns-search":[],"neighbors":[]},"data":{}}]}"#;
+
+    const LOGIN: &str = r#"{"jsonrpc":"2.0","id":1,"result":[0,{"ubus_rpc_session":"c1ed6c7b025d0caca723a816fa61b668","timeout":300,"expires":299,"acls":{},"data":{"username":"root"}}]}"#;
+
+    fn status() -> Value {
+        ubus_result(STATUS).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parses_ubus_response() {
+        assert_eq!(
+            parse_prefix(&status()).unwrap(),
+            Some(Ipv6Net::from_str("2003:ee:970c:8000::/56").unwrap())
+        );
+
+        let mut down = status();
+        down["ipv6-prefix"] = Value::Array(Vec::new());
+        assert_eq!(parse_prefix(&down).unwrap(), None);
+        let mut invalid = status();
+        invalid["ipv6-prefix"][0]["address"] = "192.0.2.0".into();
+        assert!(parse_prefix(&invalid).is_err());
+
+        // Wrong credentials and missing objects are reported through the status code
+        assert_eq!(
+            ubus_result(r#"{"jsonrpc":"2.0","id":1,"result":[6]}"#),
+            Err(UbusFailure::Status(6))
+        );
+        assert_eq!(
+            ubus_result(r#"{"jsonrpc":"2.0","id":1,"result":[4]}"#),
+            Err(UbusFailure::Status(4))
+        );
+        assert_eq!(
+            ubus_result(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32002,"message":"Access denied"}}"#
+            ),
+            Err(UbusFailure::Status(6))
+        );
+        assert!(matches!(
+            ubus_result("<html></html>"),
+            Err(UbusFailure::Invalid(_))
+        ));
+    }
+
+    // Answers each request with the next response and returns the request bodies
+    fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ubus", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = String::new();
+                while !request.ends_with('}') {
+                    let mut buf = [0u8; 4096];
+                    let len = stream.read(&mut buf).unwrap();
+                    assert!(len > 0, "connection closed: {}", request);
+                    request.push_str(&String::from_utf8_lossy(&buf[..len]));
+                }
+                stream
+                    .write_all(format!("HTTP/1.0 200 OK\r\n\r\n{}", response).as_bytes())
+                    .unwrap();
+                requests.push(request.split_once("\r\n\r\n").unwrap().1.to_string());
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn fetches_prefix() {
+        // One login and status call each from try_new and v6_network
+        let (url, server) = serve(vec![LOGIN, STATUS, LOGIN, STATUS]);
+        let source = OpenwrtSource::try_new(
+            url,
+            "root".to_string(),
+            "hunter2".to_string(),
+            "wan6".to_string(),
+            Duration::from_secs(5),
+            64,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            source.v6_network().await.unwrap(),
+            Ipv6Net::from_str("2003:ee:970c:8000::/64").unwrap()
+        );
+
+        let requests: Vec<Value> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|r| serde_json::from_str(r).unwrap())
+            .collect();
+        assert_eq!(requests[0]["params"][1], "session");
+        assert_eq!(requests[0]["params"][3]["password"], "hunter2");
+        assert_eq!(requests[1]["params"][0], "c1ed6c7b025d0caca723a816fa61b668");
+        assert_eq!(requests[1]["params"][1], "network.interface.wan6");
+        assert_eq!(requests[1]["params"][2], "status");
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_credentials() {
+        let (url, server) = serve(vec![r#"{"jsonrpc":"2.0","id":1,"result":[6]}"#]);
+        assert!(matches!(
+            OpenwrtSource::try_new(
+                url,
+                "root".to_string(),
+                "wrong".to_string(),
+                "wan6".to_string(),
+                Duration::from_secs(5),
+                64,
+            )
+            .await,
+            Err(OpenwrtError::AuthFailed(..))
+        ));
+        server.join().unwrap();
+    }
+}
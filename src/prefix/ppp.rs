@@ -0,0 +1,182 @@
+use std::net::Ipv6Addr;
+
+use async_trait::async_trait;
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
+use thiserror::Error;
+
+use super::{mask_network, PrefixSource, SourceError};
+
+#[derive(Error, Debug)]
+pub enum PppError {
+    #[error("PPP interface `{0}` could not be found")]
+    NotFound(String),
+    #[error("PPP interface `{0}` has no link-local address, IPv6CP has not been negotiated yet")]
+    NotNegotiated(String),
+    #[error("No downstream interface of `{0}` has an address from the delegated prefix")]
+    NoDelegatedPrefix(String),
+    #[error("Error while looking up interfaces: `{0}`")]
+    LookupError(String),
+}
+
+impl From<PppError> for SourceError {
+    fn from(e: PppError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Finds the delegated prefix on PPPoE uplinks.
+/// The PPP interface itself usually only has a link-local address built from the IPv6CP interface identifier,
+/// the prefix delegated by the ISP ends up on the downstream interfaces.
+pub struct PppSource {
+    ppp_iface: String,
+    /// Interfaces to search for the delegated prefix, all interfaces except the PPP one if empty
+    downstream: Vec<String>,
+    network_length: u8,
+}
+
+impl PppSource {
+    pub fn try_new(
+        ppp_iface: String,
+        downstream: Vec<String>,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, PppError> {
+        let source = PppSource {
+            ppp_iface,
+            downstream,
+            network_length,
+        };
+        // Only a missing interface is fatal, the link may still be negotiating
+        match source.delegated_address() {
+            Err(e @ (PppError::NotFound(_) | PppError::LookupError(_))) => return Err(e),
+            Err(e) => warn!("{} while creating source, continuing", e),
+            Ok(_) => {}
+        }
+        Ok(Box::new(source))
+    }
+
+    fn delegated_address(&self) -> Result<Ipv6Addr, PppError> {
+        let ifaces = NetworkInterface::show().map_err(|e| PppError::LookupError(e.to_string()))?;
+        find_delegated_address(&ifaces, &self.ppp_iface, &self.downstream)
+    }
+}
+
+fn v6_addrs<'a>(
+    ifaces: &'a [NetworkInterface],
+    name: &'a str,
+) -> impl Iterator<Item = Ipv6Addr> + 'a {
+    ifaces
+        .iter()
+        .filter(move |i| i.name == name)
+        .filter_map(|i| match i.addr {
+            Some(Addr::V6(a)) => Some(a.ip),
+            _ => None,
+        })
+}
+
+// Looks for a global address on the downstream interfaces, once the PPP link has been negotiated
+fn find_delegated_address(
+    ifaces: &[NetworkInterface],
+    ppp_iface: &str,
+    downstream: &[String],
+) -> Result<Ipv6Addr, PppError> {
+    if !ifaces.iter().any(|i| i.name == ppp_iface) {
+        return Err(PppError::NotFound(ppp_iface.to_string()));
+    }
+    let link_local = v6_addrs(ifaces, ppp_iface)
+        .find(|a| (u128::from(*a) >> 118) == 0x3fa)
+        .ok_or_else(|| PppError::NotNegotiated(ppp_iface.to_string()))?;
+    debug!(
+        "IPv6CP interface identifier of {}: {}",
+        ppp_iface,
+        Ipv6Addr::from(u128::from(link_local) & u128::from(u64::MAX))
+    );
+
+    let mut candidates: Vec<_> = ifaces
+        .iter()
+        .filter(|i| match downstream.is_empty() {
+            true => i.name != ppp_iface,
+            false => downstream.contains(&i.name),
+        })
+        .filter_map(|i| match i.addr {
+            Some(Addr::V6(a)) if ip_rfc::global_v6(&a.ip) => Some(a.ip),
+            _ => None,
+        })
+        .collect();
+    debug!(
+        "Global addresses on the downstream interfaces of {}: {:?}",
+        ppp_iface, candidates
+    );
+    candidates.sort();
+    candidates
+        .first()
+        .copied()
+        .ok_or_else(|| PppError::NoDelegatedPrefix(ppp_iface.to_string()))
+}
+
+#[async_trait]
+impl PrefixSource for PppSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let addr = self.delegated_address()?;
+        mask_network(addr, self.network_length)
+            .ok_or_else(|| PppError::NoDelegatedPrefix(self.ppp_iface.to_string()).into())
+    }
+
+    fn describe(&self) -> String {
+        let downstream = match self.downstream.is_empty() {
+            true => "all interfaces".to_string(),
+            false => self.downstream.join(", "),
+        };
+        format!(
+            "ppp source on {} (downstream: {}), network-length {}",
+            self.ppp_iface, downstream, self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv6Addr, str::FromStr};
+
+    use network_interface::NetworkInterface;
+
+    use super::{find_delegated_address, PppError};
+
+    fn iface(name: &str, addr: &str) -> NetworkInterface {
+        NetworkInterface::new_afinet6(name, Ipv6Addr::from_str(addr).unwrap(), None, None)
+    }
+
+    #[test]
+    fn finds_delegated_address() {
+        let mut ifaces = vec![
+            iface("ppp0", "2003:e0:1:2:3:4:5:6"),
+            iface("lan0", "fe80::1"),
+            iface("lan0", "2003:ee:970c:80aa::1"),
+            iface("lan1", "2003:ee:970c:80ab::1"),
+            iface("lan1", "fd00::1"),
+        ];
+        assert!(matches!(
+            find_delegated_address(&ifaces, "ppp0", &[]),
+            Err(PppError::NotNegotiated(_))
+        ));
+
+        ifaces.push(iface("ppp0", "fe80::a1b2:c3d4:e5f6:1"));
+        assert_eq!(
+            find_delegated_address(&ifaces, "ppp0", &[]).unwrap(),
+            Ipv6Addr::from_str("2003:ee:970c:80aa::1").unwrap()
+        );
+        assert_eq!(
+            find_delegated_address(&ifaces, "ppp0", &["lan1".to_string()]).unwrap(),
+            Ipv6Addr::from_str("2003:ee:970c:80ab::1").unwrap()
+        );
+        assert!(matches!(
+            find_delegated_address(&ifaces, "ppp0", &["lan2".to_string()]),
+            Err(PppError::NoDelegatedPrefix(_))
+        ));
+        assert!(matches!(
+            find_delegated_address(&ifaces, "ppp1", &[]),
+            Err(PppError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,427 @@
+use std::{net::Ipv6Addr, time::Duration};
+
+use async_trait::async_trait;
+use ipnet::Ipv6Net;
+use log::{debug, warn};
+use thiserror::Error;
+
+use super::{mask_network, PrefixSource, SourceError};
+
+const ND_ROUTER_SOLICIT: u8 = 133;
+const ND_ROUTER_ADVERT: u8 = 134;
+const RA_HDR_LEN: usize = 16;
+const ND_OPT_PREFIX_INFORMATION: u8 = 3;
+const PIO_LEN: usize = 32;
+// Autonomous address-configuration flag of the prefix information option
+const PIO_FLAG_AUTONOMOUS: u8 = 0x40;
+// Neighbor discovery messages are sent with the maximum hop limit, so that forwarded ones can be detected
+const ND_HOP_LIMIT: u8 = 255;
+
+#[derive(Error, Debug)]
+pub enum RaError {
+    #[error("Interface `{0}` could not be found")]
+    NotFound(String),
+    #[error("Error while listening for router advertisements on `{0}`: `{1}`")]
+    SocketError(String, String),
+    #[error("No router advertisement with an autonomous prefix received on `{0}` within {1:?}")]
+    Timeout(String, Duration),
+    #[error("The router advertisement source is only supported on Linux")]
+    Unsupported,
+}
+
+impl From<RaError> for SourceError {
+    fn from(e: RaError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Takes the prefix from the router advertisements received on an interface.
+/// A router solicitation is sent first, so that routers answer right away instead of at their next periodic advertisement.
+/// Requires the `CAP_NET_RAW` capability to open the ICMPv6 socket
+pub struct RaSource {
+    iface_name: String,
+    timeout: Duration,
+    network_length: u8,
+}
+
+impl RaSource {
+    pub async fn try_new(
+        iface_name: String,
+        timeout: Duration,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, RaError> {
+        let source = RaSource {
+            iface_name,
+            timeout,
+            network_length,
+        };
+        // Make sure that we are allowed to open the socket, advertisements may just be delayed
+        match source.listen().await {
+            Err(e @ RaError::Timeout(..)) => warn!("{} while creating source, continuing", e),
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+        Ok(Box::new(source))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn listen(&self) -> Result<Ipv6Net, RaError> {
+        let socket = socket::IcmpSocket::open(&self.iface_name)?;
+        socket.get_ref().solicit()?;
+        tokio::time::timeout(self.timeout, self.receive_advert(&socket))
+            .await
+            .map_err(|_| RaError::Timeout(self.iface_name.clone(), self.timeout))?
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn receive_advert(
+        &self,
+        socket: &tokio::io::unix::AsyncFd<socket::IcmpSocket>,
+    ) -> Result<Ipv6Net, RaError> {
+        loop {
+            let mut ready = socket
+                .readable()
+                .await
+                .map_err(|e| RaError::SocketError(self.iface_name.clone(), e.to_string()))?;
+            let received = match ready.try_io(|socket| socket.get_ref().recv()) {
+                Ok(received) => received
+                    .map_err(|e| RaError::SocketError(self.iface_name.clone(), e.to_string()))?,
+                Err(_would_block) => continue,
+            };
+            if !is_on_link(&received) {
+                debug!(
+                    "Ignoring ICMPv6 message from {} with hop limit {:?} on {}, it may have been forwarded",
+                    received.source, received.hop_limit, self.iface_name
+                );
+                continue;
+            }
+            match parse_router_advert(&received.msg) {
+                Some(prefix) => {
+                    debug!(
+                        "Received router advertisement on {} with prefix {}",
+                        self.iface_name, prefix
+                    );
+                    return Ok(prefix);
+                }
+                None => debug!(
+                    "Ignoring ICMPv6 message without a usable prefix on {}",
+                    self.iface_name
+                ),
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn listen(&self) -> Result<Ipv6Net, RaError> {
+        Err(RaError::Unsupported)
+    }
+}
+
+/// An ICMPv6 message along with what the IPv6 header said about it
+struct Received {
+    msg: Vec<u8>,
+    source: Ipv6Addr,
+    /// Hop limit of the packet, `None` if the kernel didn't report it
+    hop_limit: Option<u8>,
+}
+
+// Router advertisements have to come from a link-local address with the maximum hop limit, anything else may
+// have been forwarded from another link (RFC 4861, section 6.1.2)
+fn is_on_link(received: &Received) -> bool {
+    received.hop_limit == Some(ND_HOP_LIMIT) && (received.source.segments()[0] & 0xffc0) == 0xfe80
+}
+
+// Returns the first global prefix with the autonomous flag from a router advertisement.
+// `msg` starts at the ICMPv6 header, as delivered by raw ICMPv6 sockets
+fn parse_router_advert(msg: &[u8]) -> Option<Ipv6Net> {
+    if msg.len() < RA_HDR_LEN || msg[0] != ND_ROUTER_ADVERT || msg[1] != 0 {
+        return None;
+    }
+    let mut options = &msg[RA_HDR_LEN..];
+    while options.len() >= 2 {
+        // Option lengths are given in units of 8 bytes
+        let len = usize::from(options[1]) * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        let (option, rest) = options.split_at(len);
+        options = rest;
+        if option[0] != ND_OPT_PREFIX_INFORMATION || option.len() < PIO_LEN {
+            continue;
+        }
+        let prefix_len = option[2];
+        let flags = option[3];
+        let valid_lft = u32::from_be_bytes(option[4..8].try_into().ok()?);
+        let addr: [u8; 16] = option[16..32].try_into().ok()?;
+        let prefix = match Ipv6Net::new(Ipv6Addr::from(addr), prefix_len) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        if flags & PIO_FLAG_AUTONOMOUS == 0 || valid_lft == 0 {
+            debug!("Skipping prefix {} that is not usable for SLAAC", prefix);
+            continue;
+        }
+        if ip_rfc::global_v6(&prefix.addr()) {
+            return Some(prefix);
+        }
+    }
+    None
+}
+
+#[cfg(target_os = "linux")]
+mod socket {
+    use std::{
+        ffi::CString,
+        io, mem,
+        net::Ipv6Addr,
+        os::unix::io::{AsRawFd, RawFd},
+    };
+
+    use tokio::io::unix::AsyncFd;
+
+    use super::{RaError, Received, ND_HOP_LIMIT, ND_ROUTER_SOLICIT};
+
+    const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+    pub(super) struct IcmpSocket {
+        fd: libc::c_int,
+        iface_name: String,
+        ifindex: u32,
+    }
+
+    impl Drop for IcmpSocket {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+
+    impl AsRawFd for IcmpSocket {
+        fn as_raw_fd(&self) -> RawFd {
+            self.fd
+        }
+    }
+
+    impl IcmpSocket {
+        /// Opens a non-blocking socket bound to the interface, registered with the tokio reactor
+        pub(super) fn open(iface_name: &str) -> Result<AsyncFd<IcmpSocket>, RaError> {
+            let name =
+                CString::new(iface_name).map_err(|_| RaError::NotFound(iface_name.to_string()))?;
+            let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+            if ifindex == 0 {
+                return Err(RaError::NotFound(iface_name.to_string()));
+            }
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_INET6,
+                    libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                    libc::IPPROTO_ICMPV6,
+                )
+            };
+            if fd < 0 {
+                let e = io::Error::last_os_error();
+                return Err(RaError::SocketError(iface_name.to_string(), e.to_string()));
+            }
+            let socket = IcmpSocket {
+                fd,
+                iface_name: iface_name.to_string(),
+                ifindex,
+            };
+            let name = name.as_bytes_with_nul();
+            socket.set_option(libc::SOL_SOCKET, libc::SO_BINDTODEVICE, name)?;
+            // Routers drop neighbor discovery messages that may have been forwarded
+            socket.set_option(
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MULTICAST_HOPS,
+                &libc::c_int::from(ND_HOP_LIMIT).to_ne_bytes(),
+            )?;
+            socket.set_option(
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MULTICAST_IF,
+                &(ifindex as libc::c_int).to_ne_bytes(),
+            )?;
+            // The hop limit of received packets is needed to validate them
+            socket.set_option(
+                libc::IPPROTO_IPV6,
+                libc::IPV6_RECVHOPLIMIT,
+                &(1 as libc::c_int).to_ne_bytes(),
+            )?;
+            AsyncFd::new(socket)
+                .map_err(|e| RaError::SocketError(iface_name.to_string(), e.to_string()))
+        }
+
+        fn error(&self, e: io::Error) -> RaError {
+            RaError::SocketError(self.iface_name.clone(), e.to_string())
+        }
+
+        fn set_option(
+            &self,
+            level: libc::c_int,
+            name: libc::c_int,
+            value: &[u8],
+        ) -> Result<(), RaError> {
+            let res = unsafe {
+                libc::setsockopt(
+                    self.fd,
+                    level,
+                    name,
+                    value.as_ptr() as *const libc::c_void,
+                    value.len() as libc::socklen_t,
+                )
+            };
+            match res {
+                0 => Ok(()),
+                _ => Err(self.error(io::Error::last_os_error())),
+            }
+        }
+
+        /// Sends a router solicitation to all routers on the link
+        pub(super) fn solicit(&self) -> Result<(), RaError> {
+            // Type, code, checksum (filled in by the kernel) and reserved bytes
+            let msg = [ND_ROUTER_SOLICIT, 0, 0, 0, 0, 0, 0, 0];
+            let mut dst: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            dst.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            dst.sin6_addr.s6_addr = ALL_ROUTERS.octets();
+            dst.sin6_scope_id = self.ifindex;
+            let sent = unsafe {
+                libc::sendto(
+                    self.fd,
+                    msg.as_ptr() as *const libc::c_void,
+                    msg.len(),
+                    0,
+                    &dst as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            };
+            if sent < 0 {
+                return Err(self.error(io::Error::last_os_error()));
+            }
+            Ok(())
+        }
+
+        /// Receives the next ICMPv6 message, fails with `WouldBlock` if there is none
+        pub(super) fn recv(&self) -> io::Result<Received> {
+            let mut buf = vec![0u8; 1500];
+            let mut source: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            // Aligned for the control message headers
+            let mut control = [0u64; 8];
+            let mut iov = libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = &mut source as *mut libc::sockaddr_in6 as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            hdr.msg_iov = &mut iov;
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = mem::size_of_val(&control) as _;
+
+            let len = unsafe { libc::recvmsg(self.fd, &mut hdr, 0) };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            buf.truncate(len as usize);
+
+            let mut hop_limit = None;
+            let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+            while !cmsg.is_null() {
+                let header = unsafe { &*cmsg };
+                if header.cmsg_level == libc::IPPROTO_IPV6
+                    && header.cmsg_type == libc::IPV6_HOPLIMIT
+                {
+                    let value = unsafe {
+                        std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int)
+                    };
+                    hop_limit = u8::try_from(value).ok();
+                }
+                cmsg = unsafe { libc::CMSG_NXTHDR(&hdr, cmsg) };
+            }
+            Ok(Received {
+                msg: buf,
+                source: Ipv6Addr::from(source.sin6_addr.s6_addr),
+                hop_limit,
+            })
+        }
+    }
+}
+
+#[async_trait]
+impl PrefixSource for RaSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.listen().await?;
+        mask_network(prefix.addr(), self.network_length)
+            .ok_or_else(|| RaError::Timeout(self.iface_name.clone(), self.timeout).into())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "router advertisement source on {}, network-length {}",
+            self.iface_name, self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ipnet::Ipv6Net;
+
+    use super::{is_on_link, parse_router_advert, Received};
+
+    // Router advertisement as sent by radvd, with a source link-layer address, an MTU and two prefix options
+    fn sample_advert(flags: u8) -> Vec<u8> {
+        let mut msg = vec![
+            134, 0, 0x4b, 0x1a, // type, code, checksum
+            64, 0, 0x07, 0x08, // hop limit, flags, router lifetime
+            0, 0, 0, 0, 0, 0, 0, 0, // reachable time, retransmission timer
+            1, 1, 0x52, 0x54, 0, 0x12, 0x34, 0x56, // source link-layer address
+            5, 1, 0, 0, 0, 0, 0x05, 0xd4, // MTU 1492
+        ];
+        // ULA prefix, which is skipped
+        msg.extend_from_slice(&[
+            3, 4, 64, 0xc0, 0, 0, 0x0e, 0x10, 0, 0, 0x07, 0x08, 0, 0, 0, 0,
+        ]);
+        msg.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // Global prefix 2003:ee:970c:80aa::/64
+        msg.extend_from_slice(&[
+            3, 4, 64, flags, 0, 0, 0x0e, 0x10, 0, 0, 0x07, 0x08, 0, 0, 0, 0,
+        ]);
+        msg.extend_from_slice(&[
+            0x20, 0x03, 0, 0xee, 0x97, 0x0c, 0x80, 0xaa, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        msg
+    }
+
+    #[test]
+    fn parses_router_advert() {
+        assert_eq!(
+            parse_router_advert(&sample_advert(0xc0)),
+            Some(Ipv6Net::from_str("2003:ee:970c:80aa::/64").unwrap())
+        );
+        // On-link only prefixes are not used for address configuration
+        assert_eq!(parse_router_advert(&sample_advert(0x80)), None);
+
+        // Router solicitations and truncated messages are ignored
+        assert_eq!(parse_router_advert(&[133, 0, 0, 0, 0, 0, 0, 0]), None);
+        let advert = sample_advert(0xc0);
+        assert_eq!(parse_router_advert(&advert[..advert.len() - 8]), None);
+    }
+
+    #[test]
+    fn rejects_forwarded_adverts() {
+        let received = |source: &str, hop_limit| Received {
+            msg: sample_advert(0xc0),
+            source: source.parse().unwrap(),
+            hop_limit,
+        };
+        assert!(is_on_link(&received("fe80::1", Some(255))));
+        // Forwarded by a router, or the hop limit is unknown
+        assert!(!is_on_link(&received("fe80::1", Some(254))));
+        assert!(!is_on_link(&received("fe80::1", Some(64))));
+        assert!(!is_on_link(&received("fe80::1", None)));
+        // Not sent from a link-local address
+        assert!(!is_on_link(&received("2003:ee:970c:80aa::1", Some(255))));
+        assert!(!is_on_link(&received("fec0::1", Some(255))));
+    }
+}
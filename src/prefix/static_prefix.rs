@@ -0,0 +1,80 @@
+use async_trait::async_trait;
+use ipnet::Ipv6Net;
+use thiserror::Error;
+
+use super::{PrefixSource, SourceError};
+
+#[derive(Error, Debug)]
+pub enum StaticError {
+    #[error("Static prefix {0} does not match the network length /{1}")]
+    LengthMismatch(Ipv6Net, u8),
+}
+
+impl From<StaticError> for SourceError {
+    fn from(e: StaticError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+/// Always returns the same, configured network. Useful for testing and for manually overriding the prefix
+pub struct StaticSource {
+    network: Ipv6Net,
+}
+
+impl StaticSource {
+    pub fn try_new(
+        network: Ipv6Net,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, StaticError> {
+        Ok(Box::new(StaticSource::new_checked(
+            network,
+            network_length,
+        )?))
+    }
+
+    fn new_checked(network: Ipv6Net, network_length: u8) -> Result<StaticSource, StaticError> {
+        if network.prefix_len() != network_length {
+            return Err(StaticError::LengthMismatch(network, network_length));
+        }
+        Ok(StaticSource {
+            network: network.trunc(),
+        })
+    }
+}
+
+#[async_trait]
+impl PrefixSource for StaticSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        Ok(self.network)
+    }
+
+    fn describe(&self) -> String {
+        format!("static source with network {}", self.network)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ipnet::Ipv6Net;
+
+    use super::{StaticError, StaticSource};
+    use crate::prefix::PrefixSource;
+
+    #[tokio::test]
+    async fn returns_static_network() {
+        let source =
+            StaticSource::new_checked(Ipv6Net::from_str("2001:db8:1:2::1/64").unwrap(), 64)
+                .unwrap();
+        assert_eq!(
+            source.v6_network().await.unwrap(),
+            Ipv6Net::from_str("2001:db8:1:2::/64").unwrap()
+        );
+
+        assert!(matches!(
+            StaticSource::new_checked(Ipv6Net::from_str("2001:db8::/56").unwrap(), 64),
+            Err(StaticError::LengthMismatch(_, 64))
+        ));
+    }
+}
@@ -0,0 +1,400 @@
+use std::{io, net::Ipv6Addr, str::FromStr, sync::Mutex, time::Duration};
+
+use async_trait::async_trait;
+use hyper::{Body, Request, Uri};
+use ipnet::Ipv6Net;
+use log::{debug, info, warn};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+use super::{
+    fritzbox::{element, parse_prefix, soap_fault, soap_request},
+    http::{self, parse_url, resolve_url, HttpClient},
+    mask_network, PrefixSource, SourceError,
+};
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+// IGDv2 devices answer searches for version 1 as well
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+// Services of the WAN connection, in order of preference
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+// Not part of the IGD standard, but offered by FRITZ!Box and compatible gateways
+const PREFIX_ACTION: &str = "X_AVM_DE_GetIPv6Prefix";
+const ADDRESS_ACTION: &str = "GetExternalIPAddress";
+
+#[derive(Error, Debug)]
+pub enum UpnpError {
+    #[error("No Internet Gateway Device answered the SSDP search within {0}s")]
+    NotDiscovered(u64),
+    #[error("Error during SSDP discovery: `{0}`")]
+    DiscoveryError(String),
+    #[error("`{0}` does not offer a WAN connection service")]
+    NoService(String),
+    #[error("Error while connecting to `{0}`: `{1}`")]
+    ConnectionError(String, String),
+    #[error("Unexpected response from `{0}`: `{1}`")]
+    InvalidResponse(String, String),
+    #[error("`{0}` did not report an IPv6 prefix, the connection may be down or the gateway lacks IPv6 support")]
+    NoPrefix(String),
+}
+
+impl From<UpnpError> for SourceError {
+    fn from(e: UpnpError) -> Self {
+        SourceError { msg: e.to_string() }
+    }
+}
+
+// Control endpoint of the WAN connection service of the gateway
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Control {
+    url: Uri,
+    service: String,
+}
+
+impl Control {
+    fn address(&self) -> String {
+        self.url
+            .authority()
+            .map(|a| a.to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Asks the UPnP Internet Gateway Device on the LAN for the prefix, for routers without TR-064.
+/// The gateway is discovered through SSDP on the first run, its control URL is then reused until it stops answering.
+/// Gateways that don't offer `X_AVM_DE_GetIPv6Prefix` are asked for their external address instead,
+/// which only works if they report an IPv6 address there
+pub struct UpnpSource {
+    timeout: Duration,
+    network_length: u8,
+    control: Mutex<Option<Control>>,
+    client: HttpClient,
+}
+
+impl UpnpSource {
+    pub async fn try_new(
+        timeout: Duration,
+        network_length: u8,
+    ) -> Result<Box<dyn PrefixSource>, UpnpError> {
+        let source = UpnpSource {
+            timeout,
+            network_length,
+            control: Mutex::new(None),
+            client: http::client(),
+        };
+        // The gateway may still be booting, so only log errors here
+        if let Err(e) = source.fetch().await {
+            warn!("{} while creating source, continuing", e);
+        }
+        Ok(Box::new(source))
+    }
+
+    async fn fetch(&self) -> Result<Ipv6Net, UpnpError> {
+        let cached = self.control.lock().unwrap().clone();
+        let control = match cached {
+            Some(control) => control,
+            None => {
+                let control = self.discover().await?;
+                info!(
+                    "Using {} of the gateway at {}",
+                    control.service,
+                    control.address()
+                );
+                *self.control.lock().unwrap() = Some(control.clone());
+                control
+            }
+        };
+        let result = self.query(&control).await;
+        if let Err(UpnpError::ConnectionError(..)) = result {
+            // The gateway may have restarted on a different port
+            *self.control.lock().unwrap() = None;
+        }
+        result
+    }
+
+    // Finds the gateway and the control URL of its WAN connection service
+    async fn discover(&self) -> Result<Control, UpnpError> {
+        let location = search(self.timeout).await?;
+        debug!("Found Internet Gateway Device at {}", location);
+        let invalid = |msg: &str| UpnpError::InvalidResponse(location.clone(), msg.to_string());
+        let description_url =
+            parse_url(&location).ok_or_else(|| invalid("unsupported description URL"))?;
+
+        let request = Request::get(&description_url)
+            .body(Body::empty())
+            .expect("request from a valid URI");
+        let response = http::send(&self.client, request, self.timeout)
+            .await
+            .map_err(|e| UpnpError::ConnectionError(location.clone(), e))?;
+        if !response.status.is_success() {
+            return Err(invalid(&format!("status {}", response.status.as_u16())));
+        }
+        let (service, control_url) =
+            wan_service(&response.body).ok_or_else(|| UpnpError::NoService(location.clone()))?;
+
+        // Control URLs are usually relative to the description
+        let url = match parse_url(&control_url) {
+            Some(url) => url,
+            None => resolve_url(
+                &description_url,
+                &format!("/{}", control_url.trim_start_matches('/')),
+            )
+            .ok_or_else(|| invalid("unsupported control URL"))?,
+        };
+        Ok(Control { url, service })
+    }
+
+    async fn query(&self, control: &Control) -> Result<Ipv6Net, UpnpError> {
+        let invalid = |msg: String| UpnpError::InvalidResponse(control.address(), msg);
+        let body = self.call(control, PREFIX_ACTION).await?;
+        match soap_fault(&body) {
+            None => {
+                return parse_prefix(&body)
+                    .map_err(invalid)?
+                    .ok_or_else(|| UpnpError::NoPrefix(control.address()))
+            }
+            Some(fault) => debug!(
+                "{} is not supported by the gateway ({}), asking for the external address",
+                PREFIX_ACTION, fault
+            ),
+        }
+
+        let body = self.call(control, ADDRESS_ACTION).await?;
+        if let Some(fault) = soap_fault(&body) {
+            return Err(invalid(fault));
+        }
+        let address = element(&body, "NewExternalIPAddress")
+            .ok_or_else(|| invalid("missing NewExternalIPAddress".to_string()))?
+            .trim();
+        // Most gateways only report their IPv4 address here
+        Ipv6Addr::from_str(address)
+            .map(Ipv6Net::from)
+            .map_err(|_| UpnpError::NoPrefix(control.address()))
+    }
+
+    // Sends a SOAP action and returns the response body, which may be a SOAP fault
+    async fn call(&self, control: &Control, action: &str) -> Result<String, UpnpError> {
+        let request = soap_request(&control.url, &control.service, action, None);
+        let response = http::send(&self.client, request, self.timeout)
+            .await
+            .map_err(|e| UpnpError::ConnectionError(control.address(), e))?;
+        debug!(
+            "Response to {} from {}: {}",
+            action,
+            control.address(),
+            response.body
+        );
+        // Faults are reported with status 500
+        if !response.status.is_success() && soap_fault(&response.body).is_none() {
+            return Err(UpnpError::InvalidResponse(
+                control.address(),
+                format!("status {}", response.status.as_u16()),
+            ));
+        }
+        Ok(response.body)
+    }
+}
+
+// Sends an SSDP search for gateways and returns the description URL of the first one that answers
+async fn search(timeout: Duration) -> Result<String, UpnpError> {
+    let discovery_error = |e: io::Error| UpnpError::DiscoveryError(e.to_string());
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(discovery_error)?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+        SSDP_ADDR, SEARCH_TARGET
+    );
+    socket
+        .send_to(request.as_bytes(), SSDP_ADDR)
+        .await
+        .map_err(discovery_error)?;
+
+    let answer = async {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await?;
+            match parse_search_response(&buf[..len]) {
+                Some(location) => return Ok(location),
+                None => debug!("Ignoring SSDP response from {}", from),
+            }
+        }
+    };
+    tokio::time::timeout(timeout, answer)
+        .await
+        .map_err(|_| UpnpError::NotDiscovered(timeout.as_secs()))?
+        .map_err(discovery_error)
+}
+
+// Returns the description URL of a successful search response for a gateway
+fn parse_search_response(response: &[u8]) -> Option<String> {
+    let response = std::str::from_utf8(response).ok()?;
+    let mut lines = response.lines();
+    if lines.next()?.split_whitespace().nth(1)? != "200" {
+        return None;
+    }
+    let mut location = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "st" if value != SEARCH_TARGET => return None,
+            "location" => location = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    location
+}
+
+// Finds the most preferred WAN connection service in the device description and returns its type and control URL
+fn wan_service(description: &str) -> Option<(String, String)> {
+    let services: Vec<_> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|s| {
+            Some((
+                element(s, "serviceType")?.trim(),
+                element(s, "controlURL")?.trim(),
+            ))
+        })
+        .collect();
+    WAN_SERVICES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|(service, _)| service == wanted)
+            .map(|(service, url)| (service.to_string(), url.to_string()))
+    })
+}
+
+#[async_trait]
+impl PrefixSource for UpnpSource {
+    async fn v6_network(&self) -> Result<Ipv6Net, SourceError> {
+        let prefix = self.fetch().await?;
+        mask_network(prefix.addr(), self.network_length).ok_or_else(|| {
+            UpnpError::InvalidResponse("gateway".to_string(), prefix.to_string()).into()
+        })
+    }
+
+    fn describe(&self) -> String {
+        let gateway = match &*self.control.lock().unwrap() {
+            Some(control) => control.address(),
+            None => "undiscovered gateway".to_string(),
+        };
+        format!(
+            "upnp source on {}, network-length {}",
+            gateway, self.network_length
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        str::FromStr,
+        thread,
+        time::Duration,
+    };
+
+    use ipnet::Ipv6Net;
+
+    use super::{http, parse_search_response, wan_service, Control, UpnpSource};
+
+    // Abbreviated description of a gateway offering both IGDv1 and IGDv2 services
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:2</deviceType>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:2</deviceType>
+<serviceList><service>
+<serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType>
+<controlURL>/ctl/CmnIfCfg</controlURL>
+</service></serviceList>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:2</deviceType>
+<serviceList><service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<controlURL>/ctl/IPConn1</controlURL>
+</service><service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>
+<controlURL>/ctl/IPConn</controlURL>
+</service></serviceList>
+</device></deviceList>
+</device></deviceList>
+</device>
+</root>"#;
+
+    #[test]
+    fn parses_discovery() {
+        let response = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nUSN: uuid:1234::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            parse_search_response(response).unwrap(),
+            "http://192.168.1.1:5000/rootDesc.xml"
+        );
+        let printer = b"HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:Printer:1\r\nLOCATION: http://192.168.1.20/desc.xml\r\n\r\n";
+        assert_eq!(parse_search_response(printer), None);
+        assert_eq!(parse_search_response(b"NOTIFY * HTTP/1.1\r\n\r\n"), None);
+
+        assert_eq!(
+            wan_service(DESCRIPTION).unwrap(),
+            (
+                "urn:schemas-upnp-org:service:WANIPConnection:2".to_string(),
+                "/ctl/IPConn".to_string()
+            )
+        );
+        assert_eq!(
+            wan_service(&DESCRIPTION.replace("WANIPConnection", "WANIPv6FirewallControl")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_external_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let responses = [
+                "HTTP/1.1 500 Internal Server Error\r\n\r\n<s:Envelope><s:Body><s:Fault><faultstring>UPnPError</faultstring><detail><UPnPError><errorCode>401</errorCode><errorDescription>Invalid Action</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+                "HTTP/1.1 200 OK\r\n\r\n<s:Envelope><s:Body><u:GetExternalIPAddressResponse><NewExternalIPAddress>2003:ee:970c:8000::1</NewExternalIPAddress></u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+            ];
+            for (action, response) in ["X_AVM_DE_GetIPv6Prefix", "GetExternalIPAddress"]
+                .iter()
+                .zip(responses)
+            {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 2048];
+                let len = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..len]);
+                assert!(request.starts_with("POST /ctl/IPConn HTTP/1.1\r\n"));
+                assert!(request.contains(&format!("#{}\"", action)), "{}", request);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let source = UpnpSource {
+            timeout: Duration::from_secs(5),
+            network_length: 64,
+            control: Default::default(),
+            client: http::client(),
+        };
+        let control = Control {
+            url: format!("http://127.0.0.1:{}/ctl/IPConn", port)
+                .parse()
+                .unwrap(),
+            service: "urn:schemas-upnp-org:service:WANIPConnection:2".to_string(),
+        };
+        assert_eq!(
+            source.query(&control).await.unwrap(),
+            Ipv6Net::from_str("2003:ee:970c:8000::1/128").unwrap()
+        );
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+/// Delay between runs after consecutive errors.
+/// Starts at `initial` and doubles with every error up to `max`. Up to 25% jitter is applied in
+/// both directions, so that multiple instances failing at the same time don't retry in lockstep
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    errors: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial: initial.min(max),
+            max,
+            errors: 0,
+        }
+    }
+
+    /// Starts over after a successful run
+    pub(crate) fn reset(&mut self) {
+        self.errors = 0;
+    }
+
+    /// Records an error and returns the delay before the next attempt
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let factor = 1u32.checked_shl(self.errors).unwrap_or(u32::MAX);
+        let base = self.initial.saturating_mul(factor).min(self.max);
+        self.errors = self.errors.saturating_add(1);
+        base.mul_f64(0.75 + fastrand::f64() * 0.5).min(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    #[test]
+    fn backs_off_exponentially() {
+        let secs = Duration::from_secs;
+        let mut backoff = Backoff::new(secs(5), secs(60));
+        let within = |delay: Duration, base: u64| {
+            delay >= secs(base).mul_f64(0.75) && delay <= secs(base).mul_f64(1.25).min(secs(60))
+        };
+        assert!(within(backoff.next_delay(), 5));
+        assert!(within(backoff.next_delay(), 10));
+        assert!(within(backoff.next_delay(), 20));
+        assert!(within(backoff.next_delay(), 40));
+        for _ in 0..100 {
+            assert!(within(backoff.next_delay(), 60));
+        }
+
+        backoff.reset();
+        assert!(within(backoff.next_delay(), 5));
+
+        // The initial delay never exceeds the cap
+        assert!(Backoff::new(secs(5), secs(1)).next_delay() <= secs(1));
+    }
+}
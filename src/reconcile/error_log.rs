@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+/// Decides which errors of consecutive runs are logged, so that a persistent error doesn't flood the log.
+/// The first occurrence of an error is logged, repeats of the same error at most once per `interval`
+#[derive(Debug)]
+pub(crate) struct ErrorLog {
+    interval: Duration,
+    /// The error failing the runs, when it was last logged and how many repeats were suppressed since
+    current: Option<(String, Instant, u32)>,
+}
+
+impl ErrorLog {
+    pub(crate) fn new(interval: Duration) -> ErrorLog {
+        ErrorLog {
+            interval,
+            current: None,
+        }
+    }
+
+    /// Records a failed run. Returns the number of repeats suppressed since the error was last logged
+    /// if it should be logged now, `None` otherwise
+    pub(crate) fn failed(&mut self, error: &str) -> Option<u32> {
+        match &mut self.current {
+            Some((current, logged, suppressed)) if current == error => {
+                if logged.elapsed() < self.interval {
+                    *suppressed += 1;
+                    return None;
+                }
+                let repeats = *suppressed;
+                *logged = Instant::now();
+                *suppressed = 0;
+                Some(repeats)
+            }
+            _ => {
+                self.current = Some((error.to_string(), Instant::now(), 0));
+                Some(0)
+            }
+        }
+    }
+
+    /// Records a successful run and returns the error it recovered from, if any
+    pub(crate) fn succeeded(&mut self) -> Option<String> {
+        self.current.take().map(|(error, _, _)| error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::ErrorLog;
+
+    #[test]
+    fn suppresses_repeated_errors() {
+        let mut log = ErrorLog::new(Duration::from_secs(60));
+        assert_eq!(log.failed("interface gone"), Some(0));
+        assert_eq!(log.failed("interface gone"), None);
+        assert_eq!(log.failed("interface gone"), None);
+        // A different error is logged right away
+        assert_eq!(log.failed("connection refused"), Some(0));
+        assert_eq!(log.failed("connection refused"), None);
+
+        // Once the interval has passed, the error is logged again along with the number of repeats
+        if let Some((_, logged, _)) = &mut log.current {
+            *logged = Instant::now().checked_sub(Duration::from_secs(61)).unwrap();
+        }
+        assert_eq!(log.failed("connection refused"), Some(1));
+        assert_eq!(log.failed("connection refused"), None);
+
+        assert_eq!(log.succeeded(), Some("connection refused".to_string()));
+        assert_eq!(log.succeeded(), None);
+        assert_eq!(log.failed("connection refused"), Some(0));
+
+        // Without an interval every error is logged
+        let mut log = ErrorLog::new(Duration::ZERO);
+        assert_eq!(log.failed("interface gone"), Some(0));
+        assert_eq!(log.failed("interface gone"), Some(0));
+    }
+}
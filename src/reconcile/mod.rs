@@ -0,0 +1,2032 @@
+mod backoff;
+mod error_log;
+mod status;
+mod transform;
+mod window;
+pub use status::RunStatus;
+pub use transform::{PrefixTransform, TransformError, TransformStep};
+pub use window::ChangeWindow;
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chrono::Local;
+use futures::FutureExt;
+use ipnet::{Ipv4Net, Ipv6Net, PrefixLenError};
+use log::{debug, error, info, log, warn, Level};
+use thiserror::Error;
+use tokio::{sync::Notify, time::sleep};
+
+use crate::{
+    metallb::{Connector, ConnectorError},
+    netmask_for, netmask_for_v4,
+    prefix::{PrefixSource, SourceError},
+};
+use backoff::Backoff;
+use error_log::ErrorLog;
+
+/// First delay after an error, unless the interval is shorter
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const VERIFY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const CANARY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum ReconcileError {
+    #[error("{0}: {1}")]
+    Source(String, SourceError),
+    #[error(transparent)]
+    Transform(#[from] TransformError),
+    #[error(transparent)]
+    Connector(#[from] ConnectorError),
+    #[error("Unable to generate the target range: {0}")]
+    TargetRange(#[from] PrefixLenError),
+    #[error("Multiple ranges in the pool match the host range: {0:?}, remove all but one")]
+    AmbiguousRange(Vec<String>),
+    #[error("Range {0} was not found in the pool {1:?} after the change, it may have been rejected silently")]
+    NotPropagated(String, Duration),
+    #[error("Run panicked: {0}")]
+    Panic(String),
+}
+
+/// Describes what a single reconciliation changed in the pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconcileOutcome {
+    /// The pool was left untouched, either because it is up to date or because the change was skipped
+    NoChange,
+    /// The range was added to the pool
+    Inserted(Ipv6Net),
+    /// An outdated range was replaced
+    Replaced { old: Ipv6Net, new: Ipv6Net },
+    /// A change to the given range is pending until its cooldown is over, the change window opens or the apply interval has passed
+    Deferred(Ipv6Net),
+}
+
+impl ReconcileOutcome {
+    /// Whether the pool was changed
+    pub fn changed(&self) -> bool {
+        matches!(
+            self,
+            ReconcileOutcome::Inserted(_) | ReconcileOutcome::Replaced { .. }
+        )
+    }
+}
+
+/// How the dynamic network and the host range are combined into the MetalLB range
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+pub enum HostCombine {
+    /// The upper 64 bits are taken from the network, the lower 64 bits from the host range.
+    /// The two parts never overlap: network bits below /64 and host range bits above /64 are dropped.
+    /// Only meaningful with a network length of 64, other lengths need [`HostCombine::Replace`]
+    #[default]
+    Or,
+    /// Everything below the network length is taken from the host range.
+    /// This allows the host range to pick the subnet of a delegation shorter than /64
+    Replace,
+}
+
+/// Settings for reconciling an IPv4 range in addition to the IPv6 range
+#[derive(Debug, Clone, Copy)]
+pub struct V4Options {
+    /// Host range to combine with the network derived from the IPv4 address of the source. Its network part is ignored
+    pub host_range: Ipv4Net,
+    /// Length of the network around the IPv4 address of the source, everything below is taken from the host range
+    pub network_length: u8,
+}
+
+/// Settings that control how the range is computed and when changes are applied
+#[derive(Debug, Clone)]
+pub struct ReconcileOptions {
+    /// Host ranges to combine with the network from the source, each one is matched against the pool and
+    /// updated independently. Their network part is ignored
+    pub host_ranges: Vec<Ipv6Net>,
+    pub host_combine: HostCombine,
+    /// Rules applied to the network from the source before it is combined with the host range
+    pub prefix_transform: Option<PrefixTransform>,
+    /// Prefix lengths outside of this range are used, but logged as a likely detection error
+    pub expected_prefix_min: u8,
+    pub expected_prefix_max: u8,
+    /// Number of consecutive runs in which the same network has to be observed before it is published for the first time
+    pub stabilize_count: u32,
+    /// Only apply changes within this daily time window
+    pub change_window: Option<ChangeWindow>,
+    /// Minimum time between two changes to the pool
+    pub apply_interval: Duration,
+    /// Time a new range has to be calculated in every run before it replaces the range in the pool.
+    /// It is only checked when a run happens, so the change is applied in the first run after the cooldown.
+    /// A different range in between, including the current one, restarts it
+    pub change_cooldown: Duration,
+    /// Reuse the last network reported by the source for up to this long when the source fails,
+    /// instead of failing the run
+    pub last_known_max_age: Option<Duration>,
+    /// Time to wait between detecting a change and applying it, during which it can be aborted
+    pub canary_delay: Duration,
+    /// Environment variable that aborts a pending change during the canary delay when set.
+    /// Only the environment of the process is checked, so it can't be set from outside once running
+    pub abort_env: Option<String>,
+    /// File whose existence aborts a pending change during the canary delay
+    pub abort_file: Option<PathBuf>,
+    /// Only log the changes that would be made
+    pub dry_run: bool,
+    /// In dry-run mode, still pass the changes to the connector, which is expected to only validate them
+    /// (e.g. a k8s server-side dry-run)
+    pub validate_dry_run: bool,
+    /// After a change, re-read the pool and fail if the new range doesn't show up within this time,
+    /// e.g. because a webhook dropped it. Unlike `validate_dry_run`, this checks the persisted pool
+    pub verify_propagation: Option<Duration>,
+    /// Time to wait between two runs of [`Reconciler::run_loop`]
+    pub interval: Duration,
+    /// Longest time to wait after consecutive errors, which are retried with an exponentially growing delay
+    pub max_backoff: Duration,
+    /// In [`Reconciler::run_loop`], log an error that keeps failing the runs at most once in this interval.
+    /// A different error is logged right away, `Duration::ZERO` logs every error
+    pub error_log_interval: Duration,
+    /// Also reconcile an IPv4 range after the IPv6 range.
+    /// Only the change window and dry-run settings apply to it, and it is not reported to listeners
+    pub v4: Option<V4Options>,
+}
+
+impl Default for ReconcileOptions {
+    fn default() -> Self {
+        ReconcileOptions {
+            host_ranges: vec![Ipv6Net::default()],
+            host_combine: HostCombine::default(),
+            prefix_transform: None,
+            expected_prefix_min: 48,
+            expected_prefix_max: 64,
+            stabilize_count: 1,
+            change_window: None,
+            apply_interval: Duration::ZERO,
+            change_cooldown: Duration::ZERO,
+            last_known_max_age: None,
+            canary_delay: Duration::ZERO,
+            abort_env: None,
+            abort_file: None,
+            dry_run: false,
+            validate_dry_run: false,
+            verify_propagation: None,
+            interval: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(600),
+            error_log_interval: Duration::ZERO,
+            v4: None,
+        }
+    }
+}
+
+/// Gets notified of the outcome of every successful reconciliation, e.g. to publish pool changes
+#[async_trait]
+pub trait OutcomeListener: Send + Sync {
+    /// Receives one outcome per host range, in the order of [`ReconcileOptions::host_ranges`]
+    async fn outcome(&self, outcomes: &[ReconcileOutcome]);
+    /// Gets notified of every failed reconciliation
+    async fn error(&self, _error: &ReconcileError) {}
+    /// Gets notified of a reconciliation that failed after it had already changed the pool, with the outcomes
+    /// of the host ranges handled before the failure. By default both the outcomes and the error are reported
+    async fn partial_failure(&self, outcomes: &[ReconcileOutcome], error: &ReconcileError) {
+        self.outcome(outcomes).await;
+        self.error(error).await;
+    }
+}
+
+/// State carried over between runs
+#[derive(Debug, Default)]
+struct LoopState {
+    /// The network observed in the previous runs and how many runs in a row it was seen
+    candidate: Option<(Ipv6Net, u32)>,
+    /// Whether a network has been observed often enough to be published
+    stabilized: bool,
+    /// When the pool was last changed
+    last_apply: Option<Instant>,
+    /// The range that is waiting for the change cooldown and since when it has been calculated, by host range
+    pending: HashMap<Ipv6Net, (Ipv6Net, Instant)>,
+    /// The last network reported by the source and when
+    last_known: Option<(Ipv6Net, Instant)>,
+}
+
+impl LoopState {
+    /// Records an observation of `network` and returns whether it may be published.
+    /// Before the first publish, the same network has to be seen in `required` consecutive runs;
+    /// afterwards every observation is accepted.
+    fn observe(&mut self, network: Ipv6Net, required: u32) -> bool {
+        if self.stabilized {
+            return true;
+        }
+        let count = match self.candidate {
+            Some((candidate, count)) if candidate == network => count + 1,
+            _ => 1,
+        };
+        self.candidate = Some((network, count));
+        self.stabilized = count >= required;
+        debug!(
+            "Observed network {} in {} consecutive runs, {} required before the first publish",
+            network, count, required
+        );
+        self.stabilized
+    }
+
+    /// The last network reported by the source, if it is not older than `max_age`
+    fn last_known(&self, max_age: Option<Duration>) -> Option<(Ipv6Net, Duration)> {
+        let (network, since) = self.last_known?;
+        let age = since.elapsed();
+        (age <= max_age?).then_some((network, age))
+    }
+
+    /// Records `target_range` as the range to change the range of `host_range` to and returns whether
+    /// it has been calculated in every run for at least `cooldown`
+    fn cooled_down(
+        &mut self,
+        host_range: Ipv6Net,
+        target_range: Ipv6Net,
+        cooldown: Duration,
+    ) -> bool {
+        let since = match self.pending.get(&host_range) {
+            Some((pending, since)) if *pending == target_range => *since,
+            _ => {
+                let now = Instant::now();
+                self.pending.insert(host_range, (target_range, now));
+                now
+            }
+        };
+        let elapsed = since.elapsed();
+        if elapsed >= cooldown {
+            return true;
+        }
+        info!(
+            "Range {} is new, waiting another {}s for it to stay the same before applying it",
+            target_range,
+            (cooldown - elapsed).as_secs()
+        );
+        false
+    }
+}
+
+/// Keeps the dynamic range of a MetalLB pool in sync with the network reported by a source.
+/// Reconciliations can either be driven by the caller through [`Reconciler::reconcile_once`],
+/// or periodically through [`Reconciler::run_loop`]
+pub struct Reconciler<'a> {
+    source: Box<dyn PrefixSource>,
+    connector: Box<dyn Connector + 'a>,
+    options: ReconcileOptions,
+    state: LoopState,
+    status: Arc<Mutex<RunStatus>>,
+    listeners: Vec<Box<dyn OutcomeListener>>,
+}
+
+impl<'a> Reconciler<'a> {
+    pub fn new(
+        source: Box<dyn PrefixSource>,
+        connector: Box<dyn Connector + 'a>,
+        options: ReconcileOptions,
+    ) -> Reconciler<'a> {
+        Reconciler {
+            source,
+            connector,
+            options,
+            state: LoopState::default(),
+            status: Arc::new(Mutex::new(RunStatus::default())),
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Registers a listener that is notified after each reconciliation
+    pub fn add_listener(&mut self, listener: Box<dyn OutcomeListener>) {
+        self.listeners.push(listener);
+    }
+
+    /// Shared status of the reconciler, updated during every run
+    pub fn status(&self) -> Arc<Mutex<RunStatus>> {
+        self.status.clone()
+    }
+
+    fn update_status(&self, f: impl FnOnce(&mut RunStatus)) {
+        match self.status.lock() {
+            Ok(mut status) => f(&mut status),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+
+    /// Runs reconciliations until `shutdown` completes.
+    /// Errors are logged and retried with an exponential backoff, changes reported by the source or the connector trigger an early run.
+    /// A running reconciliation is finished before returning
+    pub async fn run_loop(&mut self, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        let mut backoff = Backoff::new(
+            INITIAL_BACKOFF.min(self.options.interval),
+            self.options.max_backoff,
+        );
+        let mut error_log = ErrorLog::new(self.options.error_log_interval);
+        loop {
+            // Created before the run, so that changes during the run are not missed
+            let source_changes = self.source.changes();
+            let source_changed = notified(source_changes.as_deref());
+            let pool_changes = self.connector.changes();
+            let pool_changed = notified(pool_changes.as_deref());
+            let delay = match self.reconcile_once().await {
+                Ok(_) => {
+                    backoff.reset();
+                    if let Some(e) = error_log.succeeded() {
+                        info!("Recovered from error: {}", e);
+                    }
+                    self.options.interval
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    let retry_at = chrono::Duration::from_std(delay)
+                        .ok()
+                        .and_then(|d| Local::now().checked_add_signed(d));
+                    let e = e.to_string();
+                    match error_log.failed(&e) {
+                        Some(repeats) => error!(
+                            "Error: {}, retrying in {}s{}{}",
+                            e,
+                            delay.as_secs(),
+                            retry_at
+                                .map(|t| format!(" at {}", t.format("%H:%M:%S")))
+                                .unwrap_or_default(),
+                            match repeats {
+                                0 => String::new(),
+                                n => format!(" (occurred {} more times since last logged)", n),
+                            }
+                        ),
+                        None => debug!("Error: {}, retrying in {}s", e, delay.as_secs()),
+                    }
+                    delay
+                }
+            };
+            tokio::select! {
+                _ = &mut shutdown => {
+                    info!("Shutting down");
+                    return;
+                }
+                _ = sleep(delay) => {}
+                _ = source_changed => info!("Source reported a change, running early"),
+                _ = pool_changed => info!("Pool was changed, running early"),
+            }
+        }
+    }
+
+    /// Runs a single reconciliation, records its result in the status and notifies the listeners.
+    /// Panics are turned into errors so that one bad run doesn't take down the caller.
+    /// Returns one outcome per host range, in the order of [`ReconcileOptions::host_ranges`]
+    pub async fn reconcile_once(&mut self) -> Result<Vec<ReconcileOutcome>, ReconcileError> {
+        let requests_before = self.connector.request_counts();
+        let mut applied = Vec::new();
+        let result = match AssertUnwindSafe(self.run(&mut applied))
+            .catch_unwind()
+            .await
+        {
+            Ok(result) => result,
+            Err(panic) => {
+                let msg = panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown cause".to_string());
+                Err(ReconcileError::Panic(msg))
+            }
+        };
+        let requests = self.connector.request_counts() - requests_before;
+        // Runs that only read the pool are the common case and not worth an info line each
+        let level = match requests.writes {
+            0 => Level::Debug,
+            _ => Level::Info,
+        };
+        log!(
+            level,
+            "Reconcile issued {} API requests ({})",
+            requests.reads + requests.writes,
+            requests
+        );
+        self.update_status(|s| {
+            s.record_result(match &result {
+                Ok(outcomes) => Ok(outcomes.clone()),
+                Err(e) => Err(e.to_string()),
+            });
+            s.api_requests = requests;
+        });
+        // Changes made before the run failed are reported, so that they aren't lost to the listeners
+        let partial = result.is_err() && applied.iter().any(ReconcileOutcome::changed);
+        for listener in &self.listeners {
+            match &result {
+                Ok(outcomes) => listener.outcome(outcomes).await,
+                Err(e) if partial => listener.partial_failure(&applied, e).await,
+                Err(e) => listener.error(e).await,
+            }
+        }
+        result
+    }
+
+    // A failed IPv4 update doesn't fail the run, the IPv6 changes were applied regardless and have to be reported.
+    // The outcomes of the host ranges are collected in `applied` as they are handled, so that they are still
+    // available if a later host range fails
+    async fn run(
+        &mut self,
+        applied: &mut Vec<ReconcileOutcome>,
+    ) -> Result<Vec<ReconcileOutcome>, ReconcileError> {
+        self.run_v6(applied).await?;
+        let outcomes = applied.clone();
+        if let Some(v4) = self.options.v4 {
+            if let Err(e) = self.run_v4(&v4).await {
+                error!(
+                    "Unable to update the IPv4 range of {}: {}",
+                    self.connector.describe(),
+                    e
+                );
+            }
+        }
+        Ok(outcomes)
+    }
+
+    async fn run_v6(&mut self, outcomes: &mut Vec<ReconcileOutcome>) -> Result<(), ReconcileError> {
+        let source = self.source.as_ref();
+        let pool_conn = self.connector.as_ref();
+        let options = &self.options;
+
+        let target_network = match source.v6_network().await {
+            Ok(network) => {
+                self.state.last_known = Some((network, Instant::now()));
+                network
+            }
+            Err(e) => match self.state.last_known(options.last_known_max_age) {
+                Some((network, age)) => {
+                    warn!(
+                        "{}: {}, using the last known network {} from {}s ago",
+                        source.describe(),
+                        e,
+                        network,
+                        age.as_secs()
+                    );
+                    network
+                }
+                None => return Err(ReconcileError::Source(source.describe(), e)),
+            },
+        };
+        info!("Determined desired IPv6 network to be {}", target_network);
+        self.update_status(|s| s.network = Some(target_network));
+        check_prefix_size(&target_network, options);
+        if !self.state.observe(target_network, options.stabilize_count) {
+            info!(
+                "Waiting for network {} to stabilize before publishing it",
+                target_network
+            );
+            outcomes.resize(options.host_ranges.len(), ReconcileOutcome::NoChange);
+            return Ok(());
+        }
+        let target_network = transform_network(target_network, options)?;
+
+        let current_ranges = pool_conn.v6_ranges().await?;
+        info!(
+            "Found the following Ipv6 ranges in {}: {:?}",
+            pool_conn.describe(),
+            current_ranges
+        );
+        self.update_status(|s| s.pool_ranges = current_ranges.clone());
+        let mask = host_mask(options.host_combine, target_network.prefix_len());
+        let matches = find_dynamic_mlb_range(&current_ranges, &options.host_ranges, mask)
+            .into_iter()
+            .map(single_match)
+            .collect::<Result<Vec<_>, _>>()?;
+        let targets = options
+            .host_ranges
+            .iter()
+            .map(|host_range| generate_target_range(&target_network, host_range, mask))
+            .collect::<Result<Vec<_>, _>>()?;
+        info!("Calculated desired MetalLB ranges: {:?}", targets);
+        self.update_status(|s| s.targets = targets.clone());
+
+        // The apply interval is measured from the previous run, so that all ranges can change in the same run
+        let last_apply = self.state.last_apply;
+        let mut decisions = Vec::with_capacity(targets.len());
+        for ((host_range, current_range), target_range) in self
+            .options
+            .host_ranges
+            .clone()
+            .into_iter()
+            .zip(matches)
+            .zip(targets)
+        {
+            let decision = self
+                .decide_range(host_range, current_range, target_range, last_apply)
+                .await?;
+            decisions.push((host_range, current_range, target_range, decision));
+        }
+
+        // A single canary window covers all ranges changed in this run
+        let changes: Vec<_> = decisions
+            .iter()
+            .filter(|(.., decision)| decision.is_none())
+            .map(|(_, _, target_range, _)| *target_range)
+            .collect();
+        let aborted = !changes.is_empty() && !canary_window(&changes, &self.options).await;
+        for (host_range, current_range, target_range, decision) in decisions {
+            let outcome = match decision {
+                Some(outcome) => outcome,
+                None if aborted => ReconcileOutcome::Deferred(target_range),
+                None => {
+                    self.apply_range(host_range, current_range, target_range)
+                        .await?
+                }
+            };
+            outcomes.push(outcome);
+        }
+        Ok(())
+    }
+
+    /// Decides what to do about the range matching `host_range` in the pool.
+    /// Returns the outcome if the range is left as it is, `None` if it has to be brought to `target_range`
+    async fn decide_range(
+        &mut self,
+        host_range: Ipv6Net,
+        current_range: Option<&Ipv6Net>,
+        target_range: Ipv6Net,
+        last_apply: Option<Instant>,
+    ) -> Result<Option<ReconcileOutcome>, ReconcileError> {
+        let pool_conn = self.connector.as_ref();
+        let options = &self.options;
+
+        match current_range {
+            Some(current_range) => {
+                if current_range == &target_range {
+                    self.state.pending.remove(&host_range);
+                    info!(
+                        "Target IPv6 range {} already present in MetalLB pool, nothing to do",
+                        target_range
+                    );
+                    return Ok(Some(ReconcileOutcome::NoChange));
+                }
+                info!(
+                    "Range in MetalLB pool ({}) outdated, replacing with new range: {}",
+                    current_range, target_range
+                );
+                if !self
+                    .state
+                    .cooled_down(host_range, target_range, options.change_cooldown)
+                    || !in_change_window(&target_range, options)
+                    || !apply_interval_passed(last_apply, &target_range, options)
+                {
+                    return Ok(Some(ReconcileOutcome::Deferred(target_range)));
+                }
+                if options.dry_run {
+                    if options.validate_dry_run {
+                        pool_conn.replace(current_range, &target_range).await?;
+                    }
+                    info!(
+                        "Dry run, not replacing {} with {}",
+                        current_range, target_range
+                    );
+                    return Ok(Some(ReconcileOutcome::NoChange));
+                }
+            }
+            None => {
+                info!(
+                    "No existing IPv6 range matches {}, adding range {}",
+                    pool_conn.describe(),
+                    target_range
+                );
+                if !in_change_window(&target_range, options)
+                    || !apply_interval_passed(last_apply, &target_range, options)
+                {
+                    return Ok(Some(ReconcileOutcome::Deferred(target_range)));
+                }
+                if options.dry_run {
+                    if options.validate_dry_run {
+                        pool_conn.insert(&target_range).await?;
+                    }
+                    info!("Dry run, not inserting {}", target_range);
+                    return Ok(Some(ReconcileOutcome::NoChange));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Brings the range matching `host_range` in the pool to `target_range`
+    async fn apply_range(
+        &mut self,
+        host_range: Ipv6Net,
+        current_range: Option<&Ipv6Net>,
+        target_range: Ipv6Net,
+    ) -> Result<ReconcileOutcome, ReconcileError> {
+        let pool_conn = self.connector.as_ref();
+        let options = &self.options;
+
+        match current_range {
+            Some(current_range) => {
+                pool_conn.replace(current_range, &target_range).await?;
+                self.state.last_apply = Some(Instant::now());
+                self.state.pending.remove(&host_range);
+                verify_propagation(pool_conn, &target_range, options).await?;
+                Ok(ReconcileOutcome::Replaced {
+                    old: *current_range,
+                    new: target_range,
+                })
+            }
+            None => {
+                pool_conn.insert(&target_range).await?;
+                self.state.last_apply = Some(Instant::now());
+                verify_propagation(pool_conn, &target_range, options).await?;
+                info!("Pool updated");
+                Ok(ReconcileOutcome::Inserted(target_range))
+            }
+        }
+    }
+}
+
+impl Reconciler<'_> {
+    /// Computes the changes a run would make to the pool without making them, e.g. for scripting.
+    /// Only the pool is read, the stabilization, cooldown, change window and apply interval settings are ignored.
+    /// Returns one plan per host range
+    pub async fn plan(&self) -> Result<Vec<Plan>, ReconcileError> {
+        let source = self.source.as_ref();
+        let network = source
+            .v6_network()
+            .await
+            .map_err(|e| ReconcileError::Source(source.describe(), e))?;
+        let network = transform_network(network, &self.options)?;
+        let ranges = self.connector.v6_ranges().await?;
+        let mask = host_mask(self.options.host_combine, network.prefix_len());
+        let matches = find_dynamic_mlb_range(&ranges, &self.options.host_ranges, mask);
+        let mut plans = Vec::with_capacity(matches.len());
+        for (host_range, matching) in self.options.host_ranges.iter().zip(matches) {
+            let target = generate_target_range(&network, host_range, mask)?;
+            let action = match single_match(matching)? {
+                Some(current) if current == &target => PlannedAction::Noop,
+                Some(current) => PlannedAction::Replace(*current),
+                None => PlannedAction::Insert,
+            };
+            plans.push(Plan { target, action });
+        }
+        Ok(plans)
+    }
+}
+
+/// What a reconciliation would change in the pool, see [`Reconciler::plan`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlannedAction {
+    /// The target range would be added to the pool
+    Insert,
+    /// The given range would be replaced with the target range
+    Replace(Ipv6Net),
+    /// The target range is already in the pool
+    Noop,
+}
+
+/// Result of [`Reconciler::plan`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Plan {
+    /// Range that the pool should contain
+    pub target: Ipv6Net,
+    pub action: PlannedAction,
+}
+
+impl Plan {
+    /// Machine-readable form of the plan for the given pool,
+    /// e.g. `{"prefix":"2001:db8:1::beef:0:0:0/80","pool":"default","action":"replace"}`
+    pub fn to_json(&self, pool: &str) -> serde_json::Value {
+        let action = match self.action {
+            PlannedAction::Insert => "insert",
+            PlannedAction::Replace(_) => "replace",
+            PlannedAction::Noop => "noop",
+        };
+        serde_json::json!({
+            "prefix": self.target.to_string(),
+            "pool": pool,
+            "action": action,
+        })
+    }
+}
+
+/// Reconciles the pool managed by `connector` with the network reported by `source` a single time.
+/// This is a shorthand for [`Reconciler::reconcile_once`] on a new reconciler, so settings that rely on
+/// earlier runs, like `stabilize_count`, `apply_interval` and `change_cooldown`, have no effect
+pub async fn reconcile(
+    source: Box<dyn PrefixSource>,
+    connector: Box<dyn Connector + '_>,
+    options: ReconcileOptions,
+) -> Result<Vec<ReconcileOutcome>, ReconcileError> {
+    Reconciler::new(source, connector, options)
+        .reconcile_once()
+        .await
+}
+
+impl Reconciler<'_> {
+    async fn run_v4(&mut self, v4: &V4Options) -> Result<(), ReconcileError> {
+        let source = self.source.as_ref();
+        let pool_conn = self.connector.as_ref();
+        let options = &self.options;
+
+        let address = source
+            .v4_network()
+            .await
+            .map_err(|e| ReconcileError::Source(source.describe(), e))?;
+        let target_network = Ipv4Net::new(address.addr(), v4.network_length)?.trunc();
+        info!("Determined desired IPv4 network to be {}", target_network);
+
+        let current_ranges = pool_conn.v4_ranges().await?;
+        info!(
+            "Found the following Ipv4 ranges in {}: {:?}",
+            pool_conn.describe(),
+            current_ranges
+        );
+        let mask = !netmask_for_v4(v4.network_length);
+        let current_range = single_match(find_dynamic_mlb_range_v4(
+            &current_ranges,
+            &v4.host_range,
+            mask,
+        ))?;
+        let target_range = generate_target_range_v4(&target_network, &v4.host_range, mask)?;
+        info!("Calculated desired MetalLB IPv4 range: {}", target_range);
+
+        if current_range == Some(&target_range) {
+            info!(
+                "Target IPv4 range {} already present in MetalLB pool, nothing to do",
+                target_range
+            );
+            return Ok(());
+        }
+        if !in_change_window(&target_range, options) {
+            return Ok(());
+        }
+        match current_range {
+            Some(current_range) => {
+                info!(
+                    "IPv4 range in MetalLB pool ({}) outdated, replacing with new range: {}",
+                    current_range, target_range
+                );
+                if options.dry_run {
+                    if options.validate_dry_run {
+                        pool_conn.replace_v4(current_range, &target_range).await?;
+                    }
+                    info!(
+                        "Dry run, not replacing {} with {}",
+                        current_range, target_range
+                    );
+                    return Ok(());
+                }
+                pool_conn.replace_v4(current_range, &target_range).await?;
+            }
+            None => {
+                info!(
+                    "No existing IPv4 range matches {}, adding range {}",
+                    pool_conn.describe(),
+                    target_range
+                );
+                if options.dry_run {
+                    if options.validate_dry_run {
+                        pool_conn.insert_v4(&target_range).await?;
+                    }
+                    info!("Dry run, not inserting {}", target_range);
+                    return Ok(());
+                }
+                pool_conn.insert_v4(&target_range).await?;
+            }
+        }
+        info!("Pool updated with IPv4 range {}", target_range);
+        Ok(())
+    }
+}
+
+fn transform_network(
+    network: Ipv6Net,
+    options: &ReconcileOptions,
+) -> Result<Ipv6Net, TransformError> {
+    let Some(transform) = &options.prefix_transform else {
+        return Ok(network);
+    };
+    let transformed = transform.apply(&network)?;
+    info!(
+        "Transformed network {} to {} using `{}`",
+        network, transformed, transform
+    );
+    Ok(transformed)
+}
+
+// Refuses to pick one of several matching ranges, which would make the runs fight over which one to update
+fn single_match<T: Display>(matches: Vec<&T>) -> Result<Option<&T>, ReconcileError> {
+    match matches.as_slice() {
+        [] => Ok(None),
+        [range] => Ok(Some(*range)),
+        ranges => Err(ReconcileError::AmbiguousRange(
+            ranges.iter().map(|r| r.to_string()).collect(),
+        )),
+    }
+}
+
+// Completes on the next notification, never if there is nothing to be notified by
+fn notified(changes: Option<&Notify>) -> impl Future<Output = ()> + '_ {
+    let notified = changes.map(Notify::notified);
+    async {
+        match notified {
+            Some(notified) => notified.await,
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+/// Warns if the network is sized unlike a regular end-site delegation (see RFC 6177).
+/// This is only a sanity check, the network is used regardless.
+fn check_prefix_size(network: &Ipv6Net, options: &ReconcileOptions) -> bool {
+    let len = network.prefix_len();
+    if len < options.expected_prefix_min || len > options.expected_prefix_max {
+        warn!(
+            "Network {} is outside the expected prefix lengths /{}-/{}, this may indicate a detection error",
+            network, options.expected_prefix_min, options.expected_prefix_max
+        );
+        return false;
+    }
+    true
+}
+
+/// Checks whether changes may currently be applied according to the configured change window
+fn in_change_window(target_range: &impl Display, options: &ReconcileOptions) -> bool {
+    let Some(window) = &options.change_window else {
+        return true;
+    };
+    if window.contains(Local::now().time()) {
+        return true;
+    }
+    info!(
+        "Outside of change window {}, change to range {} is pending",
+        window, target_range
+    );
+    false
+}
+
+/// Checks whether enough time has passed since the last change to the pool to apply another one
+fn apply_interval_passed(
+    last_apply: Option<Instant>,
+    target_range: &Ipv6Net,
+    options: &ReconcileOptions,
+) -> bool {
+    let Some(last_apply) = last_apply else {
+        return true;
+    };
+    let interval = options.apply_interval;
+    let elapsed = last_apply.elapsed();
+    if elapsed >= interval {
+        return true;
+    }
+    info!(
+        "Pool was changed {}s ago, change to range {} is pending for another {}s",
+        elapsed.as_secs(),
+        target_range,
+        (interval - elapsed).as_secs()
+    );
+    false
+}
+
+/// Re-reads the pool until the range shows up or the configured timeout has passed
+async fn verify_propagation(
+    pool_conn: &dyn Connector,
+    target_range: &Ipv6Net,
+    options: &ReconcileOptions,
+) -> Result<(), ReconcileError> {
+    let Some(timeout) = options.verify_propagation else {
+        return Ok(());
+    };
+    let start = Instant::now();
+    loop {
+        if pool_conn.v6_ranges().await?.contains(target_range) {
+            info!(
+                "Verified that range {} is present in {}",
+                target_range,
+                pool_conn.describe()
+            );
+            return Ok(());
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            info!(
+                "Range {} is still missing from {} after {:?}",
+                target_range,
+                pool_conn.describe(),
+                timeout
+            );
+            return Err(ReconcileError::NotPropagated(
+                target_range.to_string(),
+                timeout,
+            ));
+        }
+        debug!("Range {} not yet present, checking again", target_range);
+        sleep(VERIFY_POLL_INTERVAL.min(timeout - elapsed)).await;
+    }
+}
+
+/// Waits for the configured canary delay before the changes to `target_ranges` are applied, polling for an abort.
+/// Returns whether the changes should go ahead.
+async fn canary_window(target_ranges: &[Ipv6Net], options: &ReconcileOptions) -> bool {
+    let delay = options.canary_delay;
+    if delay.is_zero() {
+        return true;
+    }
+    match &options.abort_file {
+        Some(path) => info!(
+            "Will apply new ranges {:?} in {:?} (create {} to cancel)",
+            target_ranges,
+            delay,
+            path.display()
+        ),
+        None => info!("Will apply new ranges {:?} in {:?}", target_ranges, delay),
+    }
+    let deadline = Instant::now() + delay;
+    loop {
+        if abort_requested(options) {
+            warn!("Change to ranges {:?} was aborted", target_ranges);
+            return false;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        sleep(remaining.min(CANARY_POLL_INTERVAL)).await;
+    }
+}
+
+fn abort_requested(options: &ReconcileOptions) -> bool {
+    matches!(&options.abort_env, Some(env) if std::env::var_os(env).is_some())
+        || matches!(&options.abort_file, Some(path) if path.exists())
+}
+
+/// Returns the mask selecting the bits that are taken from the host range.
+/// All other bits are taken from the dynamic network, so the two parts never overlap.
+pub fn host_mask(combine: HostCombine, network_length: u8) -> u128 {
+    match combine {
+        HostCombine::Or => !netmask_for(64),
+        HostCombine::Replace => !netmask_for(network_length),
+    }
+}
+
+/// Combines the dynamic network with the host range, taking the bits selected by `host_mask` from the host range
+pub fn generate_target_range(
+    dyn_net: &Ipv6Net,
+    mlb_range: &Ipv6Net,
+    host_mask: u128,
+) -> Result<Ipv6Net, PrefixLenError> {
+    let net_sanitized = u128::from(dyn_net.addr()) & !host_mask;
+    let range_sanitized = u128::from(mlb_range.addr()) & host_mask;
+
+    Ipv6Net::new(
+        (net_sanitized | range_sanitized).into(),
+        mlb_range.prefix_len(),
+    )
+}
+
+/// IPv4 counterpart of [`generate_target_range`]
+pub fn generate_target_range_v4(
+    dyn_net: &Ipv4Net,
+    mlb_range: &Ipv4Net,
+    host_mask: u32,
+) -> Result<Ipv4Net, PrefixLenError> {
+    let net_sanitized = u32::from(dyn_net.addr()) & !host_mask;
+    let range_sanitized = u32::from(mlb_range.addr()) & host_mask;
+
+    Ipv4Net::new(
+        (net_sanitized | range_sanitized).into(),
+        mlb_range.prefix_len(),
+    )
+}
+
+/// IPv4 counterpart of [`find_dynamic_mlb_range`] for a single host range
+pub fn find_dynamic_mlb_range_v4<'a>(
+    ranges: &'a [Ipv4Net],
+    host_range: &Ipv4Net,
+    host_mask: u32,
+) -> Vec<&'a Ipv4Net> {
+    let wanted = u32::from(host_range.addr()) & host_mask;
+    ranges
+        .iter()
+        .filter(|r| u32::from(r.addr()) & host_mask == wanted)
+        .collect()
+}
+
+/// Finds the ranges in the pool whose host part matches each of the host ranges.
+/// A well-formed pool contains at most one per host range, ranges matching none of them are left alone
+pub fn find_dynamic_mlb_range<'a>(
+    ranges: &'a [Ipv6Net],
+    host_ranges: &[Ipv6Net],
+    host_mask: u128,
+) -> Vec<Vec<&'a Ipv6Net>> {
+    host_ranges
+        .iter()
+        .map(|host_range| {
+            let wanted = u128::from(host_range.addr()) & host_mask;
+            ranges
+                .iter()
+                .filter(|r| u128::from(r.addr()) & host_mask == wanted)
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use async_trait::async_trait;
+    use chrono::Local;
+    use ipnet::{Ipv4Net, Ipv6Net};
+    use mockall::{mock, predicate, Sequence};
+    use tokio::sync::Notify;
+
+    use super::{
+        check_prefix_size, find_dynamic_mlb_range, generate_target_range, generate_target_range_v4,
+        host_mask, reconcile, ChangeWindow, HostCombine, LoopState, OutcomeListener, Plan,
+        PlannedAction, ReconcileError, ReconcileOptions, ReconcileOutcome, Reconciler, V4Options,
+    };
+    use crate::{
+        metallb::{Connector, ConnectorError, ConnectorErrorKind},
+        netmask_for, netmask_for_v4,
+        prefix::{PrefixSource, SourceError},
+    };
+
+    fn host_range() -> Ipv6Net {
+        Ipv6Net::from_str("::abab:cdcd:0:0/80").unwrap()
+    }
+    fn options(dry_run: bool) -> ReconcileOptions {
+        ReconcileOptions {
+            host_ranges: vec![host_range()],
+            dry_run,
+            ..Default::default()
+        }
+    }
+
+    const TARGET_NET: &str = "2001:db8:1111:1111::/64";
+    fn range_other() -> Ipv6Net {
+        Ipv6Net::from_str("fd42:aaaa::/64").unwrap()
+    }
+    fn range_outdated() -> Ipv6Net {
+        Ipv6Net::from_str("2001:db8:0:0:abab:cdcd:0:0/80").unwrap()
+    }
+    fn range_correct() -> Ipv6Net {
+        Ipv6Net::from_str("2001:db8:1111:1111:abab:cdcd:0:0/80").unwrap()
+    }
+
+    mock! {
+        PrefixSource {}
+        #[async_trait]
+        impl PrefixSource for PrefixSource {
+            async fn v6_network(&self) -> Result<Ipv6Net, SourceError>;
+            fn describe(&self) -> String;
+            async fn v4_network(&self) -> Result<Ipv4Net, SourceError>;
+        }
+    }
+    mock! {
+        Connector {}
+        #[async_trait]
+        impl Connector for Connector {
+            async fn v6_ranges(&self) -> Result<Vec<Ipv6Net>, ConnectorError>;
+            async fn replace(&self, old: &Ipv6Net, new: &Ipv6Net) -> Result<(), ConnectorError>;
+            async fn insert(&self, range: &Ipv6Net) -> Result<(), ConnectorError>;
+            async fn v4_ranges(&self) -> Result<Vec<Ipv4Net>, ConnectorError>;
+            async fn replace_v4(&self, old: &Ipv4Net, new: &Ipv4Net) -> Result<(), ConnectorError>;
+            async fn insert_v4(&self, range: &Ipv4Net) -> Result<(), ConnectorError>;
+            fn describe(&self) -> String;
+            fn changes(&self) -> Option<Arc<Notify>>;
+        }
+    }
+
+    fn mock_source() -> MockPrefixSource {
+        let mut mock = MockPrefixSource::new();
+        mock.expect_v6_network()
+            .returning(|| Ok(Ipv6Net::from_str(TARGET_NET).unwrap()));
+        mock
+    }
+
+    fn reconciler(
+        source: MockPrefixSource,
+        connector: MockConnector,
+        options: ReconcileOptions,
+    ) -> Reconciler<'static> {
+        Reconciler::new(Box::new(source), Box::new(connector), options)
+    }
+
+    #[tokio::test]
+    async fn creates_missing_range() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_other()]));
+        mock_connector
+            .expect_insert()
+            .once()
+            .with(predicate::eq(range_correct()))
+            .returning(|_| Ok(()));
+
+        let outcome = reconciler(mock_source(), mock_connector, options(false))
+            .reconcile_once()
+            .await
+            .unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::Inserted(range_correct())]);
+    }
+
+    #[tokio::test]
+    async fn reconciles_through_library_entry_point() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_correct()]));
+
+        let outcome = reconcile(
+            Box::new(mock_source()),
+            Box::new(mock_connector),
+            options(false),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::NoChange]);
+    }
+
+    #[tokio::test]
+    async fn refuses_ambiguous_ranges() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_outdated(), range_other(), range_correct()]));
+        mock_connector.expect_replace().never();
+        mock_connector.expect_insert().never();
+
+        let result = reconciler(mock_source(), mock_connector, options(false))
+            .reconcile_once()
+            .await;
+        assert!(matches!(result, Err(ReconcileError::AmbiguousRange(ranges)) if ranges.len() == 2));
+    }
+
+    #[tokio::test]
+    async fn plans_without_changes() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .times(2)
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        mock_connector.expect_replace().never();
+        mock_connector.expect_insert().never();
+
+        let reconciler = reconciler(mock_source(), mock_connector, options(false));
+        let plan = reconciler.plan().await.unwrap();
+        assert_eq!(
+            plan,
+            [Plan {
+                target: range_correct(),
+                action: PlannedAction::Replace(range_outdated())
+            }]
+        );
+        assert_eq!(
+            reconciler.plan().await.unwrap()[0].to_json("my-pool"),
+            serde_json::json!({
+                "prefix": "2001:db8:1111:1111:abab:cdcd::/80",
+                "pool": "my-pool",
+                "action": "replace",
+            })
+        );
+        assert_eq!(
+            Plan {
+                target: range_correct(),
+                action: PlannedAction::Noop
+            }
+            .to_json("my-pool")["action"],
+            "noop"
+        );
+    }
+
+    #[tokio::test]
+    async fn updates_outdated_range() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        mock_connector
+            .expect_replace()
+            .once()
+            .with(
+                predicate::eq(range_outdated()),
+                predicate::eq(range_correct()),
+            )
+            .returning(|_, _| Ok(()));
+
+        let outcome = reconciler(mock_source(), mock_connector, options(false))
+            .reconcile_once()
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            [ReconcileOutcome::Replaced {
+                old: range_outdated(),
+                new: range_correct()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_propagation() {
+        let verifying = ReconcileOptions {
+            verify_propagation: Some(Duration::from_millis(20)),
+            ..options(false)
+        };
+
+        let mut mock_connector = MockConnector::new();
+        let mut seq = Sequence::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .in_sequence(&mut seq)
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        mock_connector
+            .expect_replace()
+            .once()
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .in_sequence(&mut seq)
+            .returning(|| Ok(vec![range_correct(), range_other()]));
+        let outcome = reconciler(mock_source(), mock_connector, verifying.clone())
+            .reconcile_once()
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            [ReconcileOutcome::Replaced {
+                old: range_outdated(),
+                new: range_correct()
+            }]
+        );
+
+        // The change is accepted, but the range never shows up
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .returning(|| Ok(vec![range_other()]));
+        mock_connector.expect_insert().once().returning(|_| Ok(()));
+        assert!(matches!(
+            reconciler(mock_source(), mock_connector, verifying)
+                .reconcile_once()
+                .await,
+            Err(ReconcileError::NotPropagated(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn detects_correct_range() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_correct(), range_other()]));
+        let outcome = reconciler(mock_source(), mock_connector, options(false))
+            .reconcile_once()
+            .await
+            .unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::NoChange]);
+    }
+
+    #[test]
+    fn finds_ranges_per_host_range() {
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let pool = [
+            net("2001:db8::abab:cdcd:0:0/80"),
+            net("fd42:aaaa::/64"),
+            net("2001:db8::beef:0:0:0/80"),
+            net("2001:db8:1111:1111:beef::/80"),
+        ];
+        let host_ranges = [host_range(), net("::beef:0:0:0/80"), net("::cafe:0:0:0/80")];
+
+        assert_eq!(
+            find_dynamic_mlb_range(&pool, &host_ranges, host_mask(HostCombine::Or, 64)),
+            [vec![&pool[0]], vec![&pool[2], &pool[3]], vec![]]
+        );
+    }
+
+    #[tokio::test]
+    async fn updates_each_host_range() {
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let mut mock_connector = MockConnector::new();
+        // A mixed pool with a static range, an outdated range for the first host range and nothing for the second
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        mock_connector
+            .expect_replace()
+            .once()
+            .with(
+                predicate::eq(range_outdated()),
+                predicate::eq(range_correct()),
+            )
+            .returning(|_, _| Ok(()));
+        mock_connector
+            .expect_insert()
+            .once()
+            .with(predicate::eq(net("2001:db8:1111:1111:beef::/80")))
+            .returning(|_| Ok(()));
+        let options = ReconcileOptions {
+            host_ranges: vec![host_range(), net("::beef:0:0:0/80")],
+            // Changes in the same run don't hold each other back
+            apply_interval: Duration::from_secs(60),
+            ..options(false)
+        };
+
+        let mut reconciler = reconciler(mock_source(), mock_connector, options);
+        assert_eq!(
+            reconciler.reconcile_once().await.unwrap(),
+            [
+                ReconcileOutcome::Replaced {
+                    old: range_outdated(),
+                    new: range_correct()
+                },
+                ReconcileOutcome::Inserted(net("2001:db8:1111:1111:beef::/80"))
+            ]
+        );
+        assert_eq!(
+            reconciler.status().lock().unwrap().targets,
+            [range_correct(), net("2001:db8:1111:1111:beef::/80")]
+        );
+    }
+
+    #[tokio::test]
+    async fn plans_each_host_range() {
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(move || Ok(vec![range_other(), net("2001:db8:1111:1111:beef::/80")]));
+        let options = ReconcileOptions {
+            host_ranges: vec![host_range(), net("::beef:0:0:0/80")],
+            ..options(false)
+        };
+
+        let plans = reconciler(mock_source(), mock_connector, options)
+            .plan()
+            .await
+            .unwrap();
+        assert_eq!(
+            plans,
+            [
+                Plan {
+                    target: range_correct(),
+                    action: PlannedAction::Insert
+                },
+                Plan {
+                    target: net("2001:db8:1111:1111:beef::/80"),
+                    action: PlannedAction::Noop
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn combines_network_and_host_range() {
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let combine = |network, host_range, mask| {
+            generate_target_range(&net(network), &net(host_range), mask)
+                .unwrap()
+                .to_string()
+        };
+
+        // Or: bits below /64 always come from the host range, bits above from the network,
+        // even if the network is shorter than /64 or the host range has bits set above /64
+        let or = host_mask(HostCombine::Or, 56);
+        assert_eq!(or, !netmask_for(64));
+        assert_eq!(
+            combine("2001:db8:0:ab00::/56", "0:0:0:12:beef::/80", or),
+            "2001:db8:0:ab00:beef::/80"
+        );
+        assert_eq!(
+            combine("2001:db8:0:abff::/64", "ffff::beef:0:0:0/80", or),
+            "2001:db8:0:abff:beef::/80"
+        );
+
+        // Replace: the host range defines everything below the network length
+        let replace = host_mask(HostCombine::Replace, 56);
+        assert_eq!(
+            combine("2001:db8:0:ab00::/56", "0:0:0:12:beef::/80", replace),
+            "2001:db8:0:ab12:beef::/80"
+        );
+        assert_eq!(
+            combine("2001:db8:0:ab00::/56", "ffff::beef:0:0:0/80", replace),
+            "2001:db8:0:ab00:beef::/80"
+        );
+        assert_eq!(
+            host_mask(HostCombine::Replace, 64),
+            host_mask(HostCombine::Or, 64)
+        );
+        // The network length decides where the host range starts
+        let host_range = "0:0:1234:5678:beef::/80";
+        let network = "2003:ee:970c:80aa::/64";
+        assert_eq!(
+            combine(network, host_range, host_mask(HostCombine::Replace, 48)),
+            "2003:ee:970c:5678:beef::/80"
+        );
+        assert_eq!(
+            combine(network, host_range, host_mask(HostCombine::Replace, 56)),
+            "2003:ee:970c:8078:beef::/80"
+        );
+        assert_eq!(
+            combine(network, host_range, host_mask(HostCombine::Replace, 64)),
+            "2003:ee:970c:80aa:beef::/80"
+        );
+        assert_eq!(host_mask(HostCombine::Replace, 0), u128::MAX);
+        assert_eq!(host_mask(HostCombine::Replace, 128), 0);
+    }
+
+    #[test]
+    fn warns_on_unusual_prefix_size() {
+        let options = ReconcileOptions {
+            expected_prefix_min: 48,
+            expected_prefix_max: 64,
+            ..options(false)
+        };
+        assert!(check_prefix_size(
+            &Ipv6Net::from_str("2001:db8::/56").unwrap(),
+            &options
+        ));
+        assert!(check_prefix_size(
+            &Ipv6Net::from_str("2001:db8::/64").unwrap(),
+            &options
+        ));
+        assert!(!check_prefix_size(
+            &Ipv6Net::from_str("2001:db8::/32").unwrap(),
+            &options
+        ));
+        assert!(!check_prefix_size(
+            &Ipv6Net::from_str("2001:db8::1/128").unwrap(),
+            &options
+        ));
+    }
+
+    #[tokio::test]
+    async fn aborts_during_canary_window() {
+        let abort_file =
+            std::env::temp_dir().join(format!("v6helper-test-abort-{}", std::process::id()));
+        std::fs::write(&abort_file, "").unwrap();
+
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        let options = ReconcileOptions {
+            canary_delay: Duration::from_secs(5),
+            abort_file: Some(abort_file.clone()),
+            ..options(false)
+        };
+        let outcome = reconciler(mock_source(), mock_connector, options)
+            .reconcile_once()
+            .await;
+        std::fs::remove_file(abort_file).unwrap();
+        // The change is held back, not reported as nothing to do
+        assert_eq!(
+            outcome.unwrap(),
+            [ReconcileOutcome::Deferred(range_correct())]
+        );
+    }
+
+    #[tokio::test]
+    async fn waits_once_for_all_ranges() {
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        mock_connector
+            .expect_replace()
+            .once()
+            .returning(|_, _| Ok(()));
+        mock_connector.expect_insert().once().returning(|_| Ok(()));
+        let options = ReconcileOptions {
+            host_ranges: vec![host_range(), net("::beef:0:0:0/80")],
+            // Sub-second delays are not rounded down
+            canary_delay: Duration::from_millis(300),
+            ..options(false)
+        };
+
+        let started = Instant::now();
+        let outcomes = reconciler(mock_source(), mock_connector, options)
+            .reconcile_once()
+            .await
+            .unwrap();
+        let elapsed = started.elapsed();
+        assert!(outcomes.iter().all(ReconcileOutcome::changed));
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn defers_changes_outside_window() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+
+        // A window an hour from now that only lasts a nanosecond
+        let now = Local::now().time();
+        let window = ChangeWindow {
+            start: now + chrono::Duration::hours(1),
+            end: now + chrono::Duration::hours(1) + chrono::Duration::nanoseconds(1),
+        };
+        let options = ReconcileOptions {
+            change_window: Some(window),
+            ..options(false)
+        };
+        let outcome = reconciler(mock_source(), mock_connector, options)
+            .reconcile_once()
+            .await
+            .unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::Deferred(range_correct())]);
+    }
+
+    #[tokio::test]
+    async fn throttles_changes_by_apply_interval() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .times(2)
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        mock_connector
+            .expect_replace()
+            .once()
+            .returning(|_, _| Ok(()));
+        let options = ReconcileOptions {
+            apply_interval: Duration::from_secs(60),
+            ..options(false)
+        };
+
+        let mut reconciler = reconciler(mock_source(), mock_connector, options);
+        reconciler.state.last_apply = Some(Instant::now());
+        let outcome = reconciler.reconcile_once().await.unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::Deferred(range_correct())]);
+
+        reconciler.state.last_apply = Instant::now().checked_sub(Duration::from_secs(61));
+        let outcome = reconciler.reconcile_once().await.unwrap();
+        assert_eq!(
+            outcome,
+            [ReconcileOutcome::Replaced {
+                old: range_outdated(),
+                new: range_correct()
+            }]
+        );
+        assert!(reconciler.state.last_apply.unwrap().elapsed() < Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn waits_for_change_cooldown() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .times(3)
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        mock_connector
+            .expect_replace()
+            .once()
+            .returning(|_, _| Ok(()));
+        let options = ReconcileOptions {
+            change_cooldown: Duration::from_secs(60),
+            ..options(false)
+        };
+
+        let mut reconciler = reconciler(mock_source(), mock_connector, options);
+        let outcome = reconciler.reconcile_once().await.unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::Deferred(range_correct())]);
+        let (pending, since) = reconciler.state.pending[&host_range()];
+        assert_eq!(pending, range_correct());
+
+        // Another run within the cooldown keeps the original timestamp
+        let outcome = reconciler.reconcile_once().await.unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::Deferred(range_correct())]);
+        assert_eq!(
+            reconciler.state.pending[&host_range()],
+            (range_correct(), since)
+        );
+
+        reconciler.state.pending.insert(
+            host_range(),
+            (
+                range_correct(),
+                Instant::now().checked_sub(Duration::from_secs(61)).unwrap(),
+            ),
+        );
+        let outcome = reconciler.reconcile_once().await.unwrap();
+        assert_eq!(
+            outcome,
+            [ReconcileOutcome::Replaced {
+                old: range_outdated(),
+                new: range_correct()
+            }]
+        );
+        assert!(reconciler.state.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reuses_last_known_network() {
+        let mut seq = Sequence::new();
+        let mut mock_source = MockPrefixSource::new();
+        mock_source
+            .expect_v6_network()
+            .once()
+            .in_sequence(&mut seq)
+            .returning(|| Ok(Ipv6Net::from_str(TARGET_NET).unwrap()));
+        mock_source
+            .expect_v6_network()
+            .times(2)
+            .in_sequence(&mut seq)
+            .returning(|| Err(SourceError::test_new("no address")));
+        mock_source
+            .expect_describe()
+            .returning(|| "mock source".to_string());
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .times(2)
+            .returning(|| Ok(vec![range_correct(), range_other()]));
+        let options = ReconcileOptions {
+            last_known_max_age: Some(Duration::from_secs(60)),
+            ..options(false)
+        };
+
+        let mut reconciler = reconciler(mock_source, mock_connector, options);
+        assert_eq!(
+            reconciler.reconcile_once().await.unwrap(),
+            [ReconcileOutcome::NoChange]
+        );
+        // The source fails, but the network from the previous run is still recent enough
+        assert_eq!(
+            reconciler.reconcile_once().await.unwrap(),
+            [ReconcileOutcome::NoChange]
+        );
+
+        reconciler.state.last_known = Some((
+            Ipv6Net::from_str(TARGET_NET).unwrap(),
+            Instant::now().checked_sub(Duration::from_secs(61)).unwrap(),
+        ));
+        assert!(matches!(
+            reconciler.reconcile_once().await,
+            Err(ReconcileError::Source(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn restarts_cooldown_on_flapping_range() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .returning(|| Ok(vec![range_correct(), range_other()]));
+        mock_connector.expect_replace().never();
+        let options = ReconcileOptions {
+            change_cooldown: Duration::from_secs(60),
+            ..options(false)
+        };
+
+        // The source briefly reported another network, but is back to the one in the pool
+        let mut reconciler = reconciler(mock_source(), mock_connector, options);
+        reconciler.state.pending.insert(
+            host_range(),
+            (
+                range_outdated(),
+                Instant::now().checked_sub(Duration::from_secs(30)).unwrap(),
+            ),
+        );
+        let outcome = reconciler.reconcile_once().await.unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::NoChange]);
+        assert!(reconciler.state.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn survives_panicking_run() {
+        let mut panicking_source = MockPrefixSource::new();
+        panicking_source
+            .expect_v6_network()
+            .once()
+            .returning(|| panic!("source exploded"));
+        let err = reconciler(panicking_source, MockConnector::new(), options(false))
+            .reconcile_once()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("source exploded"));
+
+        // The next run works as usual
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_correct()]));
+        let outcome = reconciler(mock_source(), mock_connector, options(false))
+            .reconcile_once()
+            .await
+            .unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::NoChange]);
+    }
+
+    #[tokio::test]
+    async fn respects_dry_run() {
+        // Part 1, missing range
+        let mut insert_connector = MockConnector::new();
+        insert_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_other()]));
+        let outcome = reconciler(mock_source(), insert_connector, options(true))
+            .reconcile_once()
+            .await
+            .unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::NoChange]);
+
+        // Part 2, update range
+        let mut update_connector = MockConnector::new();
+        update_connector
+            .expect_v6_ranges()
+            .once()
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        let outcome = reconciler(mock_source(), update_connector, options(true))
+            .reconcile_once()
+            .await
+            .unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::NoChange]);
+    }
+
+    #[tokio::test]
+    async fn validates_dry_run_with_connector() {
+        let options = ReconcileOptions {
+            validate_dry_run: true,
+            ..options(true)
+        };
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .times(2)
+            .returning(|| Ok(vec![range_outdated(), range_other()]));
+        mock_connector
+            .expect_replace()
+            .with(
+                predicate::eq(range_outdated()),
+                predicate::eq(range_correct()),
+            )
+            .times(2)
+            .returning(|_, _| Ok(()));
+        let mut reconciler = reconciler(mock_source(), mock_connector, options);
+        // Nothing is applied, so the next run validates the change again
+        for _ in 0..2 {
+            let outcome = reconciler.reconcile_once().await.unwrap();
+            assert_eq!(outcome, [ReconcileOutcome::NoChange]);
+        }
+        assert!(reconciler.state.last_apply.is_none());
+    }
+
+    #[tokio::test]
+    async fn reconciles_v4_range() {
+        let options = ReconcileOptions {
+            v4: Some(V4Options {
+                host_range: Ipv4Net::from_str("0.0.0.8/29").unwrap(),
+                network_length: 24,
+            }),
+            ..options(false)
+        };
+        let mut mock_source = mock_source();
+        mock_source
+            .expect_v4_network()
+            .returning(|| Ok(Ipv4Net::from_str("198.51.100.77/32").unwrap()));
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .returning(|| Ok(vec![range_correct()]));
+        mock_connector.expect_v4_ranges().returning(|| {
+            Ok(vec![
+                Ipv4Net::from_str("192.168.1.0/24").unwrap(),
+                Ipv4Net::from_str("203.0.113.8/29").unwrap(),
+            ])
+        });
+        mock_connector
+            .expect_replace_v4()
+            .with(
+                predicate::eq(Ipv4Net::from_str("203.0.113.8/29").unwrap()),
+                predicate::eq(Ipv4Net::from_str("198.51.100.8/29").unwrap()),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut reconciler = reconciler(mock_source, mock_connector, options);
+        let outcome = reconciler.reconcile_once().await.unwrap();
+        assert_eq!(outcome, [ReconcileOutcome::NoChange]);
+
+        assert_eq!(
+            generate_target_range_v4(
+                &Ipv4Net::from_str("198.51.100.77/32").unwrap(),
+                &Ipv4Net::from_str("0.0.0.77/32").unwrap(),
+                !netmask_for_v4(32)
+            )
+            .unwrap(),
+            Ipv4Net::from_str("198.51.100.77/32").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_v6_outcomes_when_v4_fails() {
+        let options = ReconcileOptions {
+            v4: Some(V4Options {
+                host_range: Ipv4Net::from_str("0.0.0.8/29").unwrap(),
+                network_length: 24,
+            }),
+            ..options(false)
+        };
+        let mut mock_source = mock_source();
+        mock_source
+            .expect_v4_network()
+            .returning(|| Ok(Ipv4Net::from_str("198.51.100.77/32").unwrap()));
+        mock_source
+            .expect_describe()
+            .returning(|| "mock".to_string());
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .returning(|| Ok(vec![range_outdated()]));
+        mock_connector
+            .expect_replace()
+            .once()
+            .returning(|_, _| Ok(()));
+        mock_connector
+            .expect_v4_ranges()
+            .returning(|| Err(ConnectorError::new(ConnectorErrorKind::Network, "timeout")));
+        mock_connector
+            .expect_describe()
+            .returning(|| "mock pool".to_string());
+        let mut reconciler = reconciler(mock_source, mock_connector, options);
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        reconciler.add_listener(Box::new(RecordingListener(outcomes.clone())));
+
+        let replaced = ReconcileOutcome::Replaced {
+            old: range_outdated(),
+            new: range_correct(),
+        };
+        assert_eq!(reconciler.reconcile_once().await.unwrap(), [replaced]);
+        assert_eq!(*outcomes.lock().unwrap(), [replaced]);
+    }
+
+    #[tokio::test]
+    async fn reports_ranges_applied_before_failure() {
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .returning(|| Ok(vec![range_outdated()]));
+        mock_connector
+            .expect_replace()
+            .once()
+            .returning(|_, _| Ok(()));
+        mock_connector
+            .expect_insert()
+            .once()
+            .returning(|_| Err(ConnectorError::new(ConnectorErrorKind::Network, "timeout")));
+        mock_connector
+            .expect_describe()
+            .returning(|| "mock pool".to_string());
+        let options = ReconcileOptions {
+            host_ranges: vec![host_range(), net("::beef:0:0:0/80")],
+            apply_interval: Duration::from_secs(60),
+            ..options(false)
+        };
+        let mut reconciler = reconciler(mock_source(), mock_connector, options);
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        reconciler.add_listener(Box::new(RecordingListener(outcomes.clone())));
+
+        // The run fails, but the listeners still learn about the range that was replaced
+        assert!(matches!(
+            reconciler.reconcile_once().await,
+            Err(ReconcileError::Connector(_))
+        ));
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            [ReconcileOutcome::Replaced {
+                old: range_outdated(),
+                new: range_correct()
+            }]
+        );
+    }
+
+    #[test]
+    fn stabilizes_before_first_publish() {
+        let transient = Ipv6Net::from_str("fd00::/64").unwrap();
+        let target = Ipv6Net::from_str(TARGET_NET).unwrap();
+        let mut state = LoopState::default();
+
+        assert!(!state.observe(transient, 3));
+        assert!(!state.observe(target, 3));
+        assert!(!state.observe(target, 3));
+        assert!(state.observe(target, 3));
+        // Once stabilized, changes are no longer held back
+        assert!(state.observe(transient, 3));
+
+        assert!(LoopState::default().observe(target, 1));
+    }
+
+    struct RecordingListener(Arc<Mutex<Vec<ReconcileOutcome>>>);
+
+    #[async_trait]
+    impl OutcomeListener for RecordingListener {
+        async fn outcome(&self, outcomes: &[ReconcileOutcome]) {
+            self.0.lock().unwrap().extend_from_slice(outcomes);
+        }
+    }
+
+    // Requests a shutdown once the given number of runs has completed
+    struct ShutdownListener {
+        runs: AtomicUsize,
+        shutdown: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl OutcomeListener for ShutdownListener {
+        async fn outcome(&self, _outcomes: &[ReconcileOutcome]) {
+            if self.runs.fetch_sub(1, Ordering::SeqCst) == 1 {
+                self.shutdown.notify_one();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_loop_until_shutdown() {
+        let mut mock_connector = MockConnector::new();
+        mock_connector
+            .expect_v6_ranges()
+            .times(2)
+            .returning(|| Ok(vec![range_correct()]));
+        mock_connector.expect_changes().returning(|| None);
+        let options = ReconcileOptions {
+            interval: Duration::from_millis(10),
+            ..options(false)
+        };
+        let mut reconciler = reconciler(mock_source(), mock_connector, options);
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        reconciler.add_listener(Box::new(RecordingListener(outcomes.clone())));
+        // Shutdown is requested by the second run instead of after some time, so that the test doesn't depend
+        // on how fast the runs are scheduled
+        let shutdown = Arc::new(Notify::new());
+        reconciler.add_listener(Box::new(ShutdownListener {
+            runs: AtomicUsize::new(2),
+            shutdown: shutdown.clone(),
+        }));
+
+        reconciler.run_loop(shutdown.notified()).await;
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            vec![ReconcileOutcome::NoChange, ReconcileOutcome::NoChange]
+        );
+        let status = reconciler.status();
+        let status = status.lock().unwrap();
+        assert_eq!(status.targets, [range_correct()]);
+        assert!(matches!(
+            status.last_result.as_ref(),
+            Some((_, Ok(outcomes))) if outcomes == &[ReconcileOutcome::NoChange]
+        ));
+    }
+
+    #[tokio::test]
+    async fn runs_early_on_pool_change() {
+        let changes = Arc::new(Notify::new());
+        let shutdown = Arc::new(Notify::new());
+        let mut mock_connector = MockConnector::new();
+        // The first run reports a pool change and the second one, which only happens early because of that
+        // change, requests shutdown, so that the test doesn't depend on timing
+        let (run_changes, run_shutdown) = (changes.clone(), shutdown.clone());
+        let runs = AtomicUsize::new(0);
+        mock_connector
+            .expect_v6_ranges()
+            .times(2)
+            .returning(move || {
+                match runs.fetch_add(1, Ordering::SeqCst) {
+                    0 => run_changes.notify_one(),
+                    _ => run_shutdown.notify_one(),
+                }
+                Ok(vec![range_correct()])
+            });
+        let pool_changes = changes.clone();
+        mock_connector
+            .expect_changes()
+            .returning(move || Some(pool_changes.clone()));
+        let options = ReconcileOptions {
+            interval: Duration::from_secs(3600),
+            ..options(false)
+        };
+        let mut reconciler = reconciler(mock_source(), mock_connector, options);
+
+        reconciler.run_loop(shutdown.notified()).await;
+    }
+}
@@ -0,0 +1,109 @@
+use chrono::{DateTime, Local, SecondsFormat};
+use ipnet::Ipv6Net;
+
+use super::ReconcileOutcome;
+use crate::metallb::RequestCounts;
+
+/// Outcomes of a run, one per host range, or the error it failed with
+pub type RunResult = Result<Vec<ReconcileOutcome>, String>;
+
+/// What the reconciler last saw and did, e.g. for dumping on request
+#[derive(Debug, Default)]
+pub struct RunStatus {
+    /// Network last reported by the source
+    pub network: Option<Ipv6Net>,
+    /// Ranges last computed from the network, one per host range
+    pub targets: Vec<Ipv6Net>,
+    /// IPv6 ranges found in the pool during the last run
+    pub pool_ranges: Vec<Ipv6Net>,
+    /// Ranges that the last successful run deferred until the change window opens
+    pub pending: Vec<Ipv6Net>,
+    /// Result of the last run and when it finished
+    pub last_result: Option<(DateTime<Local>, RunResult)>,
+    /// API requests issued during the last run
+    pub api_requests: RequestCounts,
+}
+
+impl RunStatus {
+    /// Records the result of a run. A failed run leaves the pending ranges as they were, as it doesn't tell
+    /// whether they are still pending
+    pub fn record_result(&mut self, result: RunResult) {
+        if let Ok(outcomes) = &result {
+            self.pending = outcomes
+                .iter()
+                .filter_map(|outcome| match outcome {
+                    ReconcileOutcome::Deferred(range) => Some(*range),
+                    _ => None,
+                })
+                .collect();
+        }
+        self.last_result = Some((Local::now(), result));
+    }
+
+    /// Formats the status as a single line of `key=value` pairs
+    pub fn summary(&self) -> String {
+        let (finished, result) = match &self.last_result {
+            Some((time, Ok(outcomes))) => (
+                time.to_rfc3339_opts(SecondsFormat::Secs, false),
+                format!("{:?}", outcomes),
+            ),
+            Some((time, Err(e))) => (
+                time.to_rfc3339_opts(SecondsFormat::Secs, false),
+                format!("error({})", e),
+            ),
+            None => ("never".to_string(), "none".to_string()),
+        };
+        format!(
+            "network={} targets={:?} pool_ranges={:?} pending={:?} last_result={} last_run={} api_reads={} api_writes={}",
+            display_opt(&self.network),
+            self.targets,
+            self.pool_ranges,
+            self.pending,
+            result,
+            finished,
+            self.api_requests.reads,
+            self.api_requests.writes
+        )
+    }
+}
+
+fn display_opt(net: &Option<Ipv6Net>) -> String {
+    net.map(|n| n.to_string())
+        .unwrap_or_else(|| "none".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ipnet::Ipv6Net;
+
+    use super::RunStatus;
+    use crate::reconcile::ReconcileOutcome;
+
+    #[test]
+    fn summarizes_status() {
+        let mut status = RunStatus::default();
+        assert_eq!(
+            status.summary(),
+            "network=none targets=[] pool_ranges=[] pending=[] last_result=none last_run=never api_reads=0 api_writes=0"
+        );
+
+        let target = Ipv6Net::from_str("2001:db8:1111:1111:abab:cdcd::/96").unwrap();
+        status.network = Some(Ipv6Net::from_str("2001:db8:1111:1111::/64").unwrap());
+        status.targets = vec![target];
+        status.record_result(Ok(vec![ReconcileOutcome::Inserted(target)]));
+        let summary = status.summary();
+        assert!(summary.starts_with(
+            "network=2001:db8:1111:1111::/64 targets=[2001:db8:1111:1111:abab:cdcd::/96] pool_ranges=[] pending=[] last_result=[Inserted(2001:db8:1111:1111:abab:cdcd::/96)] last_run="
+        ), "{}", summary);
+
+        status.record_result(Ok(vec![ReconcileOutcome::Deferred(target)]));
+        assert_eq!(status.pending, [target]);
+        // A failed run doesn't clear the pending ranges
+        status.record_result(Err("connection refused".to_string()));
+        assert!(status.summary().contains(
+            "pending=[2001:db8:1111:1111:abab:cdcd::/96] last_result=error(connection refused)"
+        ));
+    }
+}
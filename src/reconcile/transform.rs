@@ -0,0 +1,182 @@
+use std::{fmt::Display, net::Ipv6Addr, str::FromStr};
+
+use ipnet::Ipv6Net;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TransformError {
+    #[error("Cannot extend network {0} to the shorter length /{1}")]
+    ShorterLength(Ipv6Net, u8),
+    #[error("Network {0} has no subnet {1} below /{2}")]
+    SubnetOutOfRange(Ipv6Net, u128, u8),
+}
+
+/// A single step of a [`PrefixTransform`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TransformStep {
+    /// Makes the network longer, keeping its address. This selects the first subnet of the new length
+    Extend(u8),
+    /// Selects a subnet by its index, counting the subnets of the current length within the network before the last extend
+    Subnet(u128),
+}
+
+/// Rules applied to the network from the source before it is combined with the host range,
+/// e.g. `extend=60,subnet=3` to use the fourth /60 out of a delegated /56.
+/// Steps are separated by commas and applied in order
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PrefixTransform {
+    pub steps: Vec<TransformStep>,
+}
+
+impl PrefixTransform {
+    pub fn apply(&self, network: &Ipv6Net) -> Result<Ipv6Net, TransformError> {
+        let mut network = network.trunc();
+        let mut parent_len = network.prefix_len();
+        for step in &self.steps {
+            match *step {
+                TransformStep::Extend(len) => {
+                    if len < network.prefix_len() {
+                        return Err(TransformError::ShorterLength(network, len));
+                    }
+                    parent_len = network.prefix_len();
+                    network = Ipv6Net::new(network.addr(), len)
+                        .map_err(|_| TransformError::ShorterLength(network, len))?;
+                }
+                TransformStep::Subnet(index) => {
+                    let len = network.prefix_len();
+                    let subnet_bits = u32::from(len - parent_len);
+                    if subnet_bits < 128 && index >> subnet_bits != 0 {
+                        return Err(TransformError::SubnetOutOfRange(network, index, len));
+                    }
+                    let parent_mask = !u128::MAX.checked_shr(u32::from(parent_len)).unwrap_or(0);
+                    let offset = index.checked_shl(128 - u32::from(len)).unwrap_or(0);
+                    let addr = (u128::from(network.addr()) & parent_mask) | offset;
+                    network = Ipv6Net::new(Ipv6Addr::from(addr), len)
+                        .map_err(|_| TransformError::SubnetOutOfRange(network, index, len))?;
+                }
+            }
+        }
+        Ok(network)
+    }
+}
+
+impl FromStr for PrefixTransform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let steps = s
+            .split(',')
+            .map(|step| {
+                let (key, value) = step
+                    .split_once('=')
+                    .ok_or_else(|| format!("`{}` is not of the form key=value", step))?;
+                match key.trim() {
+                    "extend" => match value.trim().parse::<u8>() {
+                        Ok(len) if len <= 128 => Ok(TransformStep::Extend(len)),
+                        _ => Err(format!("Invalid network length `{}`", value)),
+                    },
+                    "subnet" => value
+                        .trim()
+                        .parse::<u128>()
+                        .map(TransformStep::Subnet)
+                        .map_err(|e| format!("Invalid subnet index `{}`: {}", value, e)),
+                    k => Err(format!(
+                        "Unknown transform `{}`, expected `extend` or `subnet`",
+                        k
+                    )),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(PrefixTransform { steps })
+    }
+}
+
+impl Display for PrefixTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let steps: Vec<_> = self
+            .steps
+            .iter()
+            .map(|step| match step {
+                TransformStep::Extend(len) => format!("extend={}", len),
+                TransformStep::Subnet(index) => format!("subnet={}", index),
+            })
+            .collect();
+        write!(f, "{}", steps.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ipnet::Ipv6Net;
+
+    use super::{PrefixTransform, TransformError, TransformStep};
+
+    fn transform(rule: &str, network: &str) -> Result<Ipv6Net, TransformError> {
+        PrefixTransform::from_str(rule)
+            .unwrap()
+            .apply(&Ipv6Net::from_str(network).unwrap())
+    }
+
+    #[test]
+    fn parses_transform() {
+        let t = PrefixTransform::from_str("extend=60, subnet=3").unwrap();
+        assert_eq!(
+            t.steps,
+            vec![TransformStep::Extend(60), TransformStep::Subnet(3)]
+        );
+        assert_eq!(t.to_string(), "extend=60,subnet=3");
+
+        assert!(PrefixTransform::from_str("extend=129").is_err());
+        assert!(PrefixTransform::from_str("extend").is_err());
+        assert!(PrefixTransform::from_str("shift=2").is_err());
+        assert!(PrefixTransform::from_str("subnet=-1").is_err());
+    }
+
+    #[test]
+    fn applies_transform() {
+        let net = |s| Ipv6Net::from_str(s).unwrap();
+        assert_eq!(
+            transform("extend=60", "2001:db8:0:ab00::/56"),
+            Ok(net("2001:db8:0:ab00::/60"))
+        );
+        assert_eq!(
+            transform("extend=60,subnet=3", "2001:db8:0:ab00::/56"),
+            Ok(net("2001:db8:0:ab30::/60"))
+        );
+        assert_eq!(
+            transform(
+                "extend=60,subnet=3,extend=64,subnet=15",
+                "2001:db8:0:ab00::/56"
+            ),
+            Ok(net("2001:db8:0:ab3f::/64"))
+        );
+        // Selecting a subnet again replaces the previous selection
+        assert_eq!(
+            transform("extend=64,subnet=255,subnet=1", "2001:db8:0:ab00::/56"),
+            Ok(net("2001:db8:0:ab01::/64"))
+        );
+        assert_eq!(
+            transform("extend=64,subnet=256", "2001:db8:0:ab00::/56"),
+            Err(TransformError::SubnetOutOfRange(
+                net("2001:db8:0:ab00::/64"),
+                256,
+                64
+            ))
+        );
+        assert_eq!(
+            transform("extend=48", "2001:db8:0:ab00::/56"),
+            Err(TransformError::ShorterLength(
+                net("2001:db8:0:ab00::/56"),
+                48
+            ))
+        );
+        // Without an extend, there is only subnet 0
+        assert_eq!(
+            transform("subnet=0", "2001:db8:0:ab00::/56"),
+            Ok(net("2001:db8:0:ab00::/56"))
+        );
+        assert!(transform("subnet=1", "2001:db8:0:ab00::/56").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::{fmt::Display, str::FromStr};
+
+use chrono::NaiveTime;
+
+/// Daily time window in which changes to the pool may be applied, e.g. `22:00-04:00`.
+/// Windows that end before they start wrap around midnight.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ChangeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+impl ChangeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+impl FromStr for ChangeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("`{}` is not of the form HH:MM-HH:MM", s))?;
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .map_err(|e| format!("Invalid time `{}`: {}", t, e))
+        };
+        Ok(ChangeWindow {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+impl Display for ChangeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::NaiveTime;
+
+    use super::ChangeWindow;
+
+    fn time(t: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(t, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn change_window_contains() {
+        let day = ChangeWindow::from_str("08:00-17:30").unwrap();
+        assert!(day.contains(time("08:00")));
+        assert!(day.contains(time("12:00")));
+        assert!(!day.contains(time("17:30")));
+        assert!(!day.contains(time("03:00")));
+
+        let night = ChangeWindow::from_str("22:00-04:00").unwrap();
+        assert!(night.contains(time("23:00")));
+        assert!(night.contains(time("01:00")));
+        assert!(!night.contains(time("04:00")));
+        assert!(!night.contains(time("12:00")));
+        assert_eq!(night.to_string(), "22:00-04:00");
+
+        assert!(ChangeWindow::from_str("22:00").is_err());
+        assert!(ChangeWindow::from_str("25:00-04:00").is_err());
+    }
+}